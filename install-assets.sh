@@ -6,6 +6,10 @@ PREFIX="${PREFIX:-/usr/local}"
 install -d "${PREFIX}/libexec/ciel-plugin"
 install -Dvm755 plugins/* "${PREFIX}/libexec/ciel-plugin"
 
+# `cield` runs the daemon of the workspace
+install -dv "${PREFIX}/bin"
+ln -sfv ciel "${PREFIX}/bin/cield"
+
 # install completions
 install -dv "${PREFIX}/share/zsh/functions/Completion/Linux/"
 install -Dvm644 completions/_ciel "${PREFIX}/share/zsh/functions/Completion/Linux/"
@@ -13,3 +17,9 @@ install -dv "${PREFIX}/share/fish/vendor_completions.d/"
 install -Dvm644 completions/ciel.fish "${PREFIX}/share/fish/vendor_completions.d/"
 install -dv "${PREFIX}/share/bash-completion/completions/"
 install -Dvm644 completions/ciel.bash "${PREFIX}/share/bash-completion/completions/"
+
+# install the pinned keyrings for verifying the OS tarballs
+if [ -d keyrings ]; then
+    install -dv "${PREFIX}/share/ciel/keyrings/"
+    install -Dvm644 keyrings/*.gpg "${PREFIX}/share/ciel/keyrings/"
+fi
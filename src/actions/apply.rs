@@ -0,0 +1,87 @@
+use anyhow::{anyhow, Result};
+use console::{style, user_attended};
+use dialoguer::{theme::ColorfulTheme, Confirm};
+use std::{collections::BTreeMap, fs, path::Path};
+
+use crate::{
+    common::CIEL_INST_DIR,
+    config::InstanceConfig,
+    error, info,
+    manifest::{plan, Change, Manifest, MANIFEST_LOCATION},
+    warn,
+};
+
+use super::container::{add_instance, container_down, remove_instance};
+
+/// Load the configuration of the instances existing on the disk
+fn existing_instances() -> Result<BTreeMap<String, InstanceConfig>> {
+    let mut instances = BTreeMap::new();
+    for entry in fs::read_dir(CIEL_INST_DIR)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            instances.insert(name, InstanceConfig::load(&entry.path())?);
+        }
+    }
+
+    Ok(instances)
+}
+
+fn apply_change(change: &Change, yes: bool) -> Result<()> {
+    match change {
+        Change::Create(name, inst_config) => add_instance(name, inst_config),
+        Change::Update(name, inst_config) => {
+            // the new settings take effect on the next mount
+            container_down(name)?;
+            inst_config.save(&Path::new(CIEL_INST_DIR).join(name))?;
+            info!("{}: instance updated.", name);
+            Ok(())
+        }
+        Change::Remove(name) => {
+            let confirmed = yes
+                || (user_attended()
+                    && Confirm::with_theme(&ColorfulTheme::default())
+                        .with_prompt(format!(
+                            "Instance `{}` is not in the manifest, remove it?",
+                            name
+                        ))
+                        .default(false)
+                        .interact()?);
+            if confirmed {
+                remove_instance(name)
+            } else {
+                warn!("{}: kept (not in the manifest).", name);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Reconcile the instances of the workspace with the manifest
+pub fn apply_manifest(yes: bool, dry_run: bool) -> Result<()> {
+    let manifest = Manifest::load(Path::new(MANIFEST_LOCATION))?;
+    let changes = plan(&manifest, &existing_instances()?);
+    if changes.is_empty() {
+        info!("The workspace is up to date.");
+        return Ok(());
+    }
+    if dry_run {
+        for change in changes.iter() {
+            info!("Would {}", change);
+        }
+        return Ok(());
+    }
+    let mut failed = 0;
+    for change in changes.iter() {
+        if let Err(e) = apply_change(change, yes) {
+            error!("Unable to {}: {}", change, e);
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        return Err(anyhow!("{} change(s) could not be applied.", failed));
+    }
+    info!("Workspace is now in sync with {}.", MANIFEST_LOCATION);
+
+    Ok(())
+}
@@ -1,25 +1,46 @@
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Result};
 use console::{style, user_attended};
 use dialoguer::{theme::ColorfulTheme, Confirm, Input};
 use git2::Repository;
-use nix::unistd::sync;
+use indicatif::{HumanBytes, HumanDuration};
 use rand::random;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
 use std::{
     ffi::OsStr,
     fs,
+    io::{Read, Write},
     path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
+use tabwriter::TabWriter;
 
 use crate::{
     actions::ensure_host_sanity,
+    activity::{self, Activity},
+    cache, capability,
     common::*,
-    config, error, info,
+    compat, config, dist, error,
+    errors::CielError,
+    hooks::{run_hook, Hook, HookContext},
+    info, lsm,
     machine::{self, get_container_ns_name, inspect_instance, spawn_container},
-    network::download_file_progress,
-    overlayfs, warn,
+    network::{
+        download_file, download_file_p2p, download_file_segmented, git_switch_branch, is_offline,
+        is_p2p,
+    },
+    output, overlayfs,
+    pkgmgr::{self, PackageManager},
+    profile::load_profile,
+    progress::{Progress, Unit},
+    quota, repo, seccomp, trash, tree, verify, warn,
 };
 
-use super::{for_each_instance, UPDATE_SCRIPT};
+use super::{
+    for_each_instance,
+    plan::{Plan, Step},
+    DPKG_CHECK_SCRIPT,
+};
 
 /// Get the branch name of the workspace TREE repository
 #[inline]
@@ -33,56 +54,94 @@ fn get_branch_name() -> Result<String> {
         .to_owned())
 }
 
-/// Determine the output directory name
+/// Determine the OUTPUT directory name, with the component (if any) as a sub-directory
 #[inline]
-pub fn get_output_directory(sep_mount: bool) -> String {
-    if sep_mount {
+pub fn get_output_directory(sep_mount: bool, component: Option<&str>) -> String {
+    let output = if sep_mount {
         format!(
             "OUTPUT-{}",
             get_branch_name().unwrap_or_else(|_| "HEAD".to_string())
         )
     } else {
         "OUTPUT".to_string()
+    };
+    match component {
+        Some(component) => format!("{}/{}", output, component),
+        None => output,
     }
 }
 
-fn commit(instance: &str) -> Result<()> {
-    get_instance_ns_name(instance)?;
-    info!("Un-mounting all the instances...");
-    // Un-mount all the instances
-    for_each_instance(&container_down)?;
-    info!("{}: committing instance...", instance);
-    let spinner = create_spinner("Committing upper layer...", 200);
-    let man = &mut *overlayfs::get_overlayfs_manager(instance)?;
-    man.commit()?;
-    sync();
-    spinner.finish_and_clear();
+#[inline]
+pub fn is_valid_component_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && name != "debs"
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
 
-    Ok(())
+/// Return the OUTPUT component selected by `--component` (`CIEL_COMPONENT`),
+/// or the default one of the workspace. The packages of the stage 2 builds are kept apart
+/// from the regular ones, in the `stage2` (or `<component>-stage2`) component
+pub fn get_output_component(conf: &config::CielConfig) -> Option<String> {
+    let component = std::env::var("CIEL_COMPONENT")
+        .ok()
+        .filter(|c| !c.is_empty())
+        .or_else(|| conf.output_component.clone());
+    if std::env::var("CIEL_STAGE2").is_err() {
+        return component;
+    }
+
+    Some(component.map_or_else(|| "stage2".to_owned(), |c| format!("{}-stage2", c)))
 }
 
-/// Rollback the container (by removing the upper layer)
-fn rollback(instance: &str) -> Result<()> {
-    get_instance_ns_name(instance)?;
-    info!("{}: rolling back instance...", instance);
-    let spinner = create_spinner("Removing upper layer...", 200);
-    let man = &mut *overlayfs::get_overlayfs_manager(instance)?;
-    man.rollback()?;
-    sync();
-    spinner.finish_and_clear();
+/// Plan removing everything in the current workspace, into the trash unless `permanent`
+pub fn farewell_plan(path: &Path, permanent: bool) -> Result<Plan> {
+    let mut plan = all_down_plan()?;
+    plan.push(Step::Unlock);
+    if permanent {
+        plan.push(Step::RemoveDir(path.join(".ciel")));
+    } else {
+        let retention =
+            config::read_config().map_or(config::DEFAULT_TRASH_RETENTION, |c| c.trash_retention());
+        plan.push(Step::Trash(path.to_path_buf(), retention));
+    }
+    for entry in trash::expired(path)? {
+        plan.push(Step::PurgeTrash(entry));
+    }
 
-    Ok(())
+    Ok(plan)
 }
 
-/// Remove everything in the current workspace
-pub fn farewell(path: &Path) -> Result<()> {
+/// Plan deleting the removed workspaces in the trash of the directory permanently: the
+/// specified one, or the expired ones. No workspace is needed
+pub fn purge_trash_plan(path: &Path, id: Option<&str>) -> Result<Plan> {
+    let mut plan = Plan::new();
+    match id {
+        Some(id) => {
+            let entry = trash::list(path)?
+                .into_iter()
+                .find(|e| e.id == id)
+                .ok_or_else(|| anyhow!("No removed workspace {} in the trash.", id))?;
+            plan.push(Step::PurgeTrash(entry));
+        }
+        None => {
+            for entry in trash::expired(path)? {
+                plan.push(Step::PurgeTrash(entry));
+            }
+        }
+    }
+
+    Ok(plan)
+}
+
+/// Remove everything in the current workspace, into the trash unless `permanent`
+pub fn farewell(path: &Path, permanent: bool) -> Result<()> {
     if !user_attended() {
         eprintln!("DELETE THIS CIEL WORKSPACE?");
         info!("Not controlled by an user. Automatically confirmed.");
-        // Un-mount all the instances
-        for_each_instance(&container_down)?;
-        fs::remove_dir_all(path.join(".ciel"))?;
-        return Ok(());
+        return farewell_plan(path, permanent)?.execute();
     }
     let theme = ColorfulTheme::default();
     let delete = Confirm::with_theme(&theme)
@@ -107,34 +166,55 @@ pub fn farewell(path: &Path) -> Result<()> {
     }
 
     info!("... as you wish. Commencing destruction ...");
-    info!("Un-mounting all the instances...");
-    // Un-mount all the instances
-    for_each_instance(&container_down)?;
-    fs::remove_dir_all(path.join(".ciel"))?;
+    farewell_plan(path, permanent)?.execute()?;
+    if !permanent {
+        info!("Run `ciel farewell --restore` to bring the workspace back.");
+        info!("Run `ciel farewell --purge` to reclaim the disk space once the trash expires.");
+    }
 
     Ok(())
 }
 
-/// Download the OS tarball and then extract it for use as the base layer
-pub fn load_os(url: &str, sha256: Option<String>) -> Result<()> {
-    info!("Downloading base OS tarball...");
-    let path = Path::new(url);
-    let filename = path
-        .file_name()
-        .ok_or_else(|| anyhow!("Unable to convert path to string"))?
-        .to_str()
-        .ok_or_else(|| anyhow!("Unable to decode path string"))?;
-    let is_local_file = path.is_file();
-    let total = if !is_local_file {
-        download_file_progress(url, filename)?
-    } else {
-        let tarball = fs::File::open(path)?;
-        tarball.metadata()?.len()
-    };
+/// The OS tarball fetched by `fetch_tarball`
+enum FetchedTarball {
+    /// Path to the tarball and its size
+    File(PathBuf, u64),
+    /// The tarball was extracted while being downloaded
+    Extracted,
+}
+
+/// Copies the data read to the file and computes its checksum
+struct TeeReader<R> {
+    inner: R,
+    file: fs::File,
+    hasher: Sha256,
+}
+
+impl<R: Read> Read for TeeReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.file.write_all(&buf[..n])?;
+        self.hasher.update(&buf[..n]);
+
+        Ok(n)
+    }
+}
+
+/// Verify the checksum and the signature of the downloaded tarball and store it in the cache,
+/// returns the path to the tarball
+fn verify_download(
+    filename: &str,
+    url: &str,
+    sha256: Option<&str>,
+    checksum: Option<String>,
+    verify: bool,
+) -> Result<PathBuf> {
     if let Some(sha256) = sha256 {
         info!("Verifying tarball checksum...");
-        let tarball = fs::File::open(Path::new(filename))?;
-        let checksum = sha256sum(tarball)?;
+        let checksum = match checksum {
+            Some(checksum) => checksum,
+            None => sha256sum(fs::File::open(Path::new(filename))?)?,
+        };
         if sha256 == checksum {
             info!("Checksum verified.");
         } else {
@@ -145,11 +225,146 @@ pub fn load_os(url: &str, sha256: Option<String>) -> Result<()> {
             ));
         }
     }
-    if is_local_file {
-        extract_system_tarball(&PathBuf::from(path), total)?;
+    if verify {
+        if let Err(e) = verify::verify_tarball(Path::new(filename), url) {
+            // never leave an unverified tarball around
+            fs::remove_file(filename).ok();
+            return Err(e);
+        }
+    }
+    if let Some(sha256) = sha256 {
+        match cache::store(Path::new(filename), sha256, verify) {
+            Ok(cached) => return Ok(cached),
+            Err(e) => warn!("Unable to store the tarball in the cache: {}", e),
+        }
+    }
+
+    Ok(PathBuf::from(filename))
+}
+
+/// Download the tarball and extract it to the dist layer at the same time.
+/// The tarball is saved while being downloaded, and verified once the download is finished;
+/// the extracted files are removed if the verification fails
+fn stream_tarball(url: &str, filename: &str, sha256: Option<&str>, verify: bool) -> Result<()> {
+    let resp = download_file(url)?.error_for_status()?;
+    let total = resp.content_length().unwrap_or(0);
+    let mut reader = TeeReader {
+        inner: resp,
+        file: fs::File::create(filename)?,
+        hasher: Sha256::new(),
+    };
+    dist::with_unlocked(|| {
+        let result = extract_system_stream(&mut reader, total)
+            .and_then(|_| {
+                // the rest of the stream (e.g. the padding) is still part of the checksum
+                std::io::copy(&mut reader, &mut std::io::sink())?;
+                let checksum = format!("{:x}", reader.hasher.finalize_reset());
+                verify_download(filename, url, sha256, Some(checksum), verify)
+            })
+            .and_then(|_| compat::check_arch(compat::extracted_arch(Path::new(CIEL_DIST_DIR))?));
+        if result.is_err() {
+            fs::remove_dir_all(CIEL_DIST_DIR).ok();
+            fs::create_dir_all(CIEL_DIST_DIR)?;
+        }
+
+        result
+    })
+}
+
+/// Download the OS tarball (unless it is a local file) and verify its checksum and signature.
+/// Unless multiple connections or the metalink/torrent backend are used, the tarball is
+/// extracted while being downloaded
+fn fetch_tarball(
+    url: &str,
+    sha256: Option<&str>,
+    segments: usize,
+    verify: bool,
+) -> Result<FetchedTarball> {
+    let path = Path::new(url);
+    // local tarballs are supplied by the user and trusted as-is
+    if path.is_file() {
+        return Ok(FetchedTarball::File(
+            path.to_path_buf(),
+            path.metadata()?.len(),
+        ));
+    }
+    if let Some(cached) = sha256.and_then(cache::lookup) {
+        info!("Using cached tarball {}", cached.display());
+        if verify && is_offline() {
+            // the signature can not be downloaded, rely on the verification when it was cached
+            if !cache::metadata(&cached).is_some_and(|m| m.verified) {
+                bail!("The cached tarball was never verified and Ciel is working offline, pass --no-verify to use it anyway");
+            }
+        } else if verify {
+            verify::verify_tarball(&cached, url)?;
+        }
+        let total = cached.metadata()?.len();
+        return Ok(FetchedTarball::File(cached, total));
+    }
+    let filename = path
+        .file_name()
+        .ok_or_else(|| anyhow!("Unable to convert path to string"))?
+        .to_str()
+        .ok_or_else(|| anyhow!("Unable to decode path string"))?;
+    let mut checksum = None;
+    let mut p2p_total = None;
+    if is_p2p() {
+        match download_file_p2p(url, filename) {
+            Ok(total) => p2p_total = Some(total),
+            Err(e) => {
+                warn!(
+                    "Unable to download the tarball using metalink or torrent: {}",
+                    e
+                );
+                info!("Falling back to HTTPS...");
+            }
+        }
+    }
+    let total = if let Some(total) = p2p_total {
+        total
+    } else if segments > 1 {
+        // the checksum is computed while downloading
+        let (total, downloaded_checksum) = download_file_segmented(url, filename, segments)?;
+        checksum = Some(downloaded_checksum);
+        total
     } else {
-        extract_system_tarball(Path::new(filename), total)?;
+        stream_tarball(url, filename, sha256, verify)?;
+        return Ok(FetchedTarball::Extracted);
+    };
+    let tarball = verify_download(filename, url, sha256, checksum, verify)?;
+
+    Ok(FetchedTarball::File(tarball, total))
+}
+
+/// Download the OS tarball and extract it for use as the base layer.
+/// The URLs are tried in order until the tarball is downloaded successfully.
+/// Unless `verify` is false, downloaded tarballs must carry a valid signature from the pinned keyrings
+pub fn load_os(
+    urls: &[String],
+    sha256: Option<String>,
+    segments: usize,
+    verify: bool,
+) -> Result<()> {
+    info!("Downloading base OS tarball...");
+    if !verify {
+        warn!("Signature verification of the tarball is disabled.");
     }
+    let mut result = Err(anyhow!("No URL to download the tarball from"));
+    for (i, url) in urls.iter().enumerate() {
+        result = fetch_tarball(url, sha256.as_deref(), segments, verify);
+        match &result {
+            Ok(_) => break,
+            Err(e) if i + 1 < urls.len() => {
+                warn!("Unable to download the tarball from {}: {}", url, e);
+                info!("Trying the next mirror...");
+            }
+            Err(_) => (),
+        }
+    }
+    if let FetchedTarball::File(tarball, total) = result? {
+        dist::with_unlocked(|| extract_system_tarball(&tarball, total))?;
+    }
+    run_hook(Hook::PostLoadOs, &HookContext::default())?;
 
     Ok(())
 }
@@ -158,7 +373,7 @@ pub fn load_os(url: &str, sha256: Option<String>) -> Result<()> {
 pub fn config_os(instance: Option<&str>) -> Result<()> {
     let config;
     let mut prev_volatile = None;
-    if let Ok(c) = config::read_config() {
+    if let Ok(c) = config::read_config_file() {
         prev_volatile = Some(c.volatile_mount);
         config = config::ask_for_config(Some(c));
     } else {
@@ -166,7 +381,7 @@ pub fn config_os(instance: Option<&str>) -> Result<()> {
     }
     let path;
     if let Some(instance) = instance {
-        let man = &mut *overlayfs::get_overlayfs_manager(instance)?;
+        let man = &mut *overlayfs::get_layer_manager(instance)?;
         path = man.get_config_layer()?;
     } else {
         path = PathBuf::from(CIEL_DIST_DIR);
@@ -178,12 +393,12 @@ pub fn config_os(instance: Option<&str>) -> Result<()> {
         } else {
             for_each_instance(&container_down)?;
         }
-        config::apply_config(path, &c)?;
-        fs::create_dir_all(CIEL_DATA_DIR)?;
-        fs::write(
-            Path::new(CIEL_DATA_DIR).join("config.toml"),
-            c.save_config()?,
-        )?;
+        if instance.is_some() {
+            config::apply_config(path, &c)?;
+        } else {
+            dist::with_unlocked(|| config::apply_config(path, &c))?;
+        }
+        c.save_config_file()?;
         info!("Configurations applied.");
         let volatile_changed = if let Some(prev_voltile) = prev_volatile {
             prev_voltile != c.volatile_mount
@@ -209,11 +424,117 @@ pub fn config_os(instance: Option<&str>) -> Result<()> {
     Ok(())
 }
 
+/// Switch the workspace to the given configuration profile
+pub fn use_profile(name: &str) -> Result<()> {
+    let profile = load_profile(name)?;
+    let mut config = config::read_config_file()?;
+    profile.apply(name, &mut config);
+    apply_base_config(&config)?;
+    if let Some(branch) = &profile.branch {
+        let mut repo = Repository::open("TREE")?;
+        git_switch_branch(&mut repo, branch, None)?;
+        info!("Switched the ABBS tree to {}.", branch);
+    }
+    info!("Now using profile `{}`.", name);
+
+    Ok(())
+}
+
+/// Set the configuration values given as `key=value`
+pub fn set_config(assignments: &[&String]) -> Result<()> {
+    let mut config = config::read_config_file()?;
+    let mut rootfs_changed = false;
+    for assignment in assignments {
+        let (key, value) = assignment
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Expected `key=value`, got `{}`.", assignment))?;
+        config.set(key.trim(), value.trim())?;
+        // these options are written into the base system
+        let top_key = key.trim().split('.').next().unwrap_or_default();
+        rootfs_changed |=
+            ["maintainer", "dnssec", "apt_sources", "repositories"].contains(&top_key);
+    }
+    if rootfs_changed {
+        apply_base_config(&config)?;
+    } else {
+        config.save_config_file()?;
+    }
+    info!("Configuration updated.");
+
+    Ok(())
+}
+
+/// Apply the configuration to the base system and save it
+pub(crate) fn apply_base_config(config: &config::CielConfig) -> Result<()> {
+    info!("Shutting down instance(s) before applying config...");
+    for_each_instance(&container_down)?;
+    dist::with_unlocked(|| config::apply_config(CIEL_DIST_DIR, config))?;
+    config.save_config_file()?;
+    warn!("Please rollback all your instances for the new config to take effect!");
+
+    Ok(())
+}
+
+/// Add (or replace) an extra APT repository
+pub fn add_apt_repository(name: &str, repo: config::AptRepository) -> Result<()> {
+    let mut config = config::read_config_file()?;
+    if let Err(e) = config::validate_apt_source(&repo.source_line(name)) {
+        return Err(anyhow!("Invalid repository: {}", e));
+    }
+    if let Some(key) = &repo.signed_by {
+        if !Path::new(key).is_file() {
+            return Err(anyhow!("Signing key {} does not exist.", key));
+        }
+    }
+    if config.repositories.insert(name.to_owned(), repo).is_some() {
+        info!("Replacing the existing repository `{}`...", name);
+    }
+    apply_base_config(&config)?;
+    info!("Repository `{}` added.", name);
+
+    Ok(())
+}
+
+/// Remove an extra APT repository
+pub fn remove_apt_repository(name: &str) -> Result<()> {
+    let mut config = config::read_config_file()?;
+    if config.repositories.remove(name).is_none() {
+        return Err(anyhow!("Repository `{}` does not exist.", name));
+    }
+    apply_base_config(&config)?;
+    info!("Repository `{}` removed.", name);
+
+    Ok(())
+}
+
+/// List the extra APT repositories
+pub fn list_apt_repositories() -> Result<()> {
+    let config = config::read_config()?;
+    let mut formatter = TabWriter::new(std::io::stderr());
+    writeln!(&mut formatter, "NAME\tPRIORITY\tSOURCE")?;
+    for (name, repo) in config.repositories.iter() {
+        writeln!(
+            &mut formatter,
+            "{}\t{}\t{}",
+            name,
+            repo.pin_priority
+                .map(|p| p.to_string())
+                .unwrap_or_else(|| "-".to_string()),
+            repo.source_line(name)
+        )?;
+    }
+    formatter.flush()?;
+
+    Ok(())
+}
+
 /// Mount the filesystem of the instance
 pub fn mount_fs(instance: &str) -> Result<()> {
+    materialize_instance(instance)?;
     let config = config::read_config()?;
-    let man = &mut *overlayfs::get_overlayfs_manager(instance)?;
+    let man = &mut *overlayfs::get_layer_manager(instance)?;
     man.set_volatile(config.volatile_mount)?;
+    dist::ensure_locked();
     machine::mount_layers(man, instance)?;
     info!("{}: filesystem mounted.", instance);
 
@@ -222,7 +543,7 @@ pub fn mount_fs(instance: &str) -> Result<()> {
 
 /// Un-mount the filesystem of the container
 pub fn unmount_fs(instance: &str) -> Result<()> {
-    let man = &mut *overlayfs::get_overlayfs_manager(instance)?;
+    let man = &mut *overlayfs::get_layer_manager(instance)?;
     let target = std::env::current_dir()?.join(instance);
     let mut retry = 0usize;
     while man.is_mounted(&target)? {
@@ -266,14 +587,33 @@ pub fn remove_mount(instance: &str) -> Result<()> {
     Ok(())
 }
 
+/// Create the instance if it is declared in the workspace configuration but does not exist yet
+/// (only when it is about to be mounted, started or built in)
+pub fn materialize_instance(instance: &str) -> Result<()> {
+    if is_instance_exists(instance) {
+        return Ok(());
+    }
+    let declared = config::read_config()
+        .ok()
+        .and_then(|mut c| c.instances.remove(instance));
+    if let Some(inst_config) = declared {
+        info!(
+            "{}: creating the instance declared in the configuration...",
+            instance
+        );
+        add_instance(instance, &inst_config)?;
+    }
+
+    Ok(())
+}
+
 fn get_instance_ns_name(instance: &str) -> Result<String> {
     if !is_instance_exists(instance) {
-        error!("Instance `{}` does not exist.", instance);
         info!(
             "You can add a new instance like this: `ciel add {}`",
             instance
         );
-        return Err(anyhow!("Unable to acquire container information."));
+        return Err(CielError::InstanceNotFound(instance.to_owned()).into());
     }
     let legacy = is_legacy_workspace()?;
 
@@ -282,33 +622,176 @@ fn get_instance_ns_name(instance: &str) -> Result<String> {
 
 /// Start the container/instance, also mounting the container filesystem prior to the action
 pub fn start_container(instance: &str) -> Result<String> {
+    materialize_instance(instance)?;
     let ns_name = get_instance_ns_name(instance)?;
     let inst = inspect_instance(instance, &ns_name)?;
     let (mut extra_options, mounts) = ensure_host_sanity()?;
-    if std::env::var("CIEL_OFFLINE").is_ok() {
-        // FIXME: does not work with current version of systemd
-        // add the offline option (private-network means don't share the host network)
-        extra_options.push("--private-network".to_string());
-        info!("{}: network disconnected.", instance);
+    let inst_config = config::InstanceConfig::load(&Path::new(CIEL_INST_DIR).join(instance))?;
+    let mut mounts: Vec<(String, &str)> = mounts;
+    if !inst.started {
+        if let Some(merged) = config::read_config()
+            .ok()
+            .map(|c| tree::mount_merged_tree(&c))
+            .transpose()?
+            .flatten()
+        {
+            for mount in mounts.iter_mut().filter(|m| m.1 == "/tree") {
+                mount.0 = merged.to_string_lossy().to_string();
+            }
+        }
     }
+    // the packages built are staged in `/debs`, then merged into the repository
+    if let Some(root) = mounts
+        .iter()
+        .find(|m| m.1 == repo::LOCAL_REPO_MOUNT)
+        .and_then(|m| Path::new(&m.0).parent().map(Path::to_owned))
+    {
+        let staging = repo::staging::staging_dir(&root, instance);
+        fs::create_dir_all(&staging)?;
+        mounts.push((staging.to_string_lossy().to_string(), "/debs/"));
+    }
+    mounts.extend(inst_config.bind_mounts()?);
+    extra_options.extend(seccomp::nspawn_options(&inst_config.seccomp)?);
+    extra_options.extend(capability::nspawn_options(&inst_config.capabilities)?);
+    extra_options.extend(inst_config.nspawn_options.iter().cloned());
     if !inst.mounted {
         mount_fs(instance)?;
     }
     if !inst.started {
+        run_hook(Hook::PreStart, &HookContext::instance(instance))?;
+        let mut trees = vec![
+            PathBuf::from(CIEL_DIST_DIR),
+            Path::new(CIEL_INST_DIR).join(instance),
+        ];
+        trees.extend(mounts.iter().map(|m| PathBuf::from(&m.0)));
+        lsm::label_trees(Path::new("."), &trees)?;
         spawn_container(&ns_name, instance, &extra_options, &mounts)?;
+        run_hook(Hook::PostStart, &HookContext::instance(instance))?;
+        activity::touch(instance)?;
     }
 
     Ok(ns_name)
 }
 
-/// Execute the specified command in the container
-pub fn run_in_container<S: AsRef<OsStr>>(instance: &str, args: &[S]) -> Result<i32> {
+/// Start the container with the command recorded as running in it, so that the idle sweep can
+/// not stop it before the command starts
+fn start_active(instance: &str) -> Result<(String, Activity)> {
+    materialize_instance(instance)?;
+    // refuse to run anything in an instance which has no space left
+    check_quota(instance)?;
+    get_instance_ns_name(instance)?;
+    let activity = Activity::begin(instance)?;
     let ns_name = start_container(instance)?;
+
+    Ok((ns_name, activity))
+}
+
+/// Execute the specified command in the container, mounting and booting it if it is not running
+#[inline]
+pub fn run_in_container<S: AsRef<OsStr>>(instance: &str, args: &[S]) -> Result<i32> {
+    exec_in_container(instance, args, true)
+}
+
+/// Execute the specified command in the container. If the container is not running, it is
+/// mounted and booted first, or an error is returned if `autostart` is `false`
+pub fn exec_in_container<S: AsRef<OsStr>>(
+    instance: &str,
+    args: &[S],
+    autostart: bool,
+) -> Result<i32> {
+    let (ns_name, _activity) = if autostart {
+        start_active(instance)?
+    } else {
+        let ns_name = get_instance_ns_name(instance)?;
+        if !inspect_instance(instance, &ns_name)?.running {
+            bail!(
+                "{}: instance is not running (omit --no-autostart to boot it).",
+                instance
+            );
+        }
+        // the idle sweep may have stopped it meanwhile
+        let activity = Activity::begin(instance)?;
+        if !inspect_instance(instance, &ns_name)?.running {
+            bail!("{}: instance has been stopped.", instance);
+        }
+        check_quota(instance)?;
+        (ns_name, activity)
+    };
     let status = machine::execute_container_command(&ns_name, args)?;
+    if status != 0 {
+        check_quota(instance)?;
+    }
 
     Ok(status)
 }
 
+/// Run the command in the container, passing each line of the output to the watcher
+/// (see [machine::execute_container_command_watched])
+pub fn run_in_container_watched<S: AsRef<OsStr>, F: FnMut(&str) -> bool>(
+    instance: &str,
+    args: &[S],
+    watch: F,
+) -> Result<i32> {
+    let (ns_name, _activity) = start_active(instance)?;
+    let status = machine::execute_container_command_watched(&ns_name, args, watch)?;
+    if status != 0 {
+        check_quota(instance)?;
+    }
+
+    Ok(status)
+}
+
+/// Run the command in the container, the output is also written into the log. The container
+/// is terminated if the command is still running at the deadline
+pub fn run_in_container_logged<S: AsRef<OsStr>>(
+    instance: &str,
+    args: &[S],
+    log: &Path,
+    deadline: Option<Instant>,
+) -> Result<machine::Completion> {
+    let (ns_name, _activity) = start_active(instance)?;
+    let completion = machine::execute_container_command_logged(&ns_name, args, log, deadline)?;
+    if let machine::Completion::Exited(status) = completion {
+        if status != 0 {
+            check_quota(instance)?;
+        }
+    }
+
+    Ok(completion)
+}
+
+/// Return the cgroup of the running container of the instance
+pub fn instance_cgroup(instance: &str) -> Result<PathBuf> {
+    machine::container_cgroup(&get_instance_ns_name(instance)?)
+}
+
+/// Check if the instance has (almost) run out of its disk quota, as accounted by the filesystem
+/// (the quota is not applied to the instances in a tmpfs)
+fn check_quota(instance: &str) -> Result<()> {
+    let inst_dir = Path::new(CIEL_INST_DIR).join(instance);
+    let inst_config = config::InstanceConfig::load(&inst_dir)?;
+    let limit = inst_config.quota.as_deref().and_then(parse_size);
+    if let (Some(limit), None) = (limit, &inst_config.tmpfs_size) {
+        // the project is assigned when the instance is mounted
+        let used = match quota::project_usage(&inst_dir) {
+            Ok(used) => used,
+            Err(_) => return Ok(()),
+        };
+        // some space may be reserved by the filesystem
+        if used >= limit / 100 * 95 {
+            error!(
+                "{}: disk quota exceeded ({} used of {}).",
+                instance,
+                HumanBytes(used),
+                HumanBytes(limit)
+            );
+            return Err(anyhow!("Disk quota exceeded in instance {}", instance));
+        }
+    }
+
+    Ok(())
+}
+
 /// Stop the container/instance (without un-mounting the filesystem)
 pub fn stop_container(instance: &str) -> Result<()> {
     let ns_name = get_instance_ns_name(instance)?;
@@ -325,28 +808,123 @@ pub fn stop_container(instance: &str) -> Result<()> {
     Ok(())
 }
 
+/// Stop the running instances idle for at least `idle` seconds (see [crate::activity]), the
+/// instances running commands are left alone. Returns the instances stopped (or to be stopped,
+/// if `dry_run`)
+pub fn autostop(idle: u64, dry_run: bool) -> Result<Vec<String>> {
+    let mut stopped = Vec::new();
+    for inst in machine::list_instances()? {
+        if !inst.started {
+            continue;
+        }
+        // new commands wait until the instance is stopped
+        let lock = match activity::lock_idle(&inst.name, idle)? {
+            Some(lock) => lock,
+            None => continue,
+        };
+        let idle = HumanDuration(Duration::from_secs(lock.idle));
+        if dry_run {
+            info!("{}: idle for {}, would be stopped.", inst.name, idle);
+        } else {
+            info!("{}: idle for {}, stopping...", inst.name, idle);
+            machine::terminate_container_by_name(&inst.ns_name)?;
+            machine::clean_child_process();
+            info!("{}: instance stopped.", inst.name);
+        }
+        stopped.push(inst.name);
+    }
+
+    Ok(stopped)
+}
+
+/// Plan stopping and un-mounting the container and its filesystem
+pub fn down_plan(instance: &str) -> Result<Plan> {
+    // declared in the configuration but not created yet, nothing to stop
+    if !is_instance_exists(instance)
+        && config::read_config().map_or(false, |c| c.instances.contains_key(instance))
+    {
+        return Ok(Plan::new());
+    }
+    let ns_name = get_instance_ns_name(instance)?;
+    let inst = inspect_instance(instance, &ns_name)?;
+    let target = std::env::current_dir()?.join(instance);
+    let mut plan = Plan::new();
+    if inst.started {
+        plan.push(Step::Terminate(instance.to_owned(), ns_name));
+    }
+    if inst.mounted {
+        plan.push(Step::Unmount(instance.to_owned(), target.clone()));
+    }
+    // the mount point is kept if anything other than the filesystem is in it
+    let empty = fs::read_dir(&target).map_or(false, |mut entries| entries.next().is_none());
+    if inst.mounted || empty {
+        plan.push(Step::RemoveMountPoint(instance.to_owned(), target));
+    }
+
+    Ok(plan)
+}
+
+/// Plan stopping and un-mounting all the instances
+fn all_down_plan() -> Result<Plan> {
+    let mut plan = Plan::new();
+    for instance in machine::list_instances_simple()? {
+        plan.extend(down_plan(&instance)?);
+    }
+
+    Ok(plan)
+}
+
 /// Stop and un-mount the container and its filesystem
 pub fn container_down(instance: &str) -> Result<()> {
-    stop_container(instance)?;
-    unmount_fs(instance)?;
-    remove_mount(instance)?;
+    down_plan(instance)?.execute()
+}
 
-    Ok(())
+/// Plan committing the instance, all the instances are un-mounted as they share the base layer
+pub fn commit_plan(instance: &str) -> Result<Plan> {
+    let mut plan = down_plan(instance)?;
+    plan.extend(all_down_plan()?);
+    plan.push(Step::Commit(instance.to_owned()));
+
+    Ok(plan)
 }
 
 /// Commit the container/instance upper layer changes to the base layer of the filesystem
 pub fn commit_container(instance: &str) -> Result<()> {
-    container_down(instance)?;
-    commit(instance)?;
+    run_hook(Hook::PreCommit, &HookContext::instance(instance))?;
+    commit_plan(instance)?.execute()?;
     info!("{}: instance has been committed.", instance);
+    run_hook(Hook::PostCommit, &HookContext::instance(instance))?;
 
     Ok(())
 }
 
+/// Plan clearing the upper layer of the container/instance filesystem, preserving the paths
+/// matching the patterns (and `rollback-keep` in the configuration)
+pub fn rollback_plan(instance: &str, keep: &[String]) -> Result<Plan> {
+    let mut patterns = config::read_config()
+        .map(|c| c.rollback_keep)
+        .unwrap_or_default();
+    patterns.extend(keep.iter().cloned());
+    let mut plan = down_plan(instance)?;
+    let man = overlayfs::get_layer_manager(instance)?;
+    plan.push(Step::Rollback(
+        instance.to_owned(),
+        man.rollback_targets(),
+        patterns,
+    ));
+
+    Ok(plan)
+}
+
 /// Clear the upper layer of the container/instance filesystem
 pub fn rollback_container(instance: &str) -> Result<()> {
-    container_down(instance)?;
-    rollback(instance)?;
+    rollback_keeping(instance, &[])
+}
+
+/// Roll back the instance, preserving the paths in its upper layer matching the patterns
+/// (and `rollback-keep` in the configuration)
+pub fn rollback_keeping(instance: &str, keep: &[String]) -> Result<()> {
+    rollback_plan(instance, keep)?.execute()?;
     info!("{}: instance has been rolled back.", instance);
 
     Ok(())
@@ -354,37 +932,552 @@ pub fn rollback_container(instance: &str) -> Result<()> {
 
 /// Create a new instance
 #[inline]
-pub fn add_instance(instance: &str) -> Result<()> {
+pub fn add_instance(instance: &str, inst_config: &config::InstanceConfig) -> Result<()> {
     overlayfs::create_new_instance_fs(CIEL_INST_DIR, instance)?;
+    inst_config.save(&Path::new(CIEL_INST_DIR).join(instance))?;
     info!("{}: instance created.", instance);
 
     Ok(())
 }
 
+/// Install the packages of `provision` in the configuration into the instance, recording them
+/// in the configuration of the instance
+pub fn provision_instance(instance: &str) -> Result<()> {
+    let conf = match config::read_config() {
+        Ok(conf) => conf,
+        // not configured yet, nothing to provision
+        Err(_) => return Ok(()),
+    };
+    if conf.provision.is_empty() {
+        return Ok(());
+    }
+    let manager = pkgmgr::detect(conf.package_manager.as_deref(), Path::new(CIEL_DIST_DIR))?;
+    info!(
+        "{}: provisioning {} ...",
+        instance,
+        conf.provision.join(" ")
+    );
+    pkgmgr::run(instance, &*manager, &manager.install(&conf.provision))?;
+    let inst_dir = Path::new(CIEL_INST_DIR).join(instance);
+    let mut inst_config = config::InstanceConfig::load(&inst_dir)?;
+    inst_config.provisioned = conf.provision;
+    inst_config.save(&inst_dir)?;
+    info!("{}: instance provisioned.", instance);
+
+    Ok(())
+}
+
+/// Plan removing the container/instance and its filesystem
+pub fn remove_plan(instance: &str) -> Result<Plan> {
+    let mut plan = down_plan(instance)?;
+    plan.push(Step::Destroy(
+        instance.to_owned(),
+        Path::new(CIEL_INST_DIR).join(instance),
+    ));
+
+    Ok(plan)
+}
+
 /// Remove the container/instance and its filesystem from the host filesystem
 pub fn remove_instance(instance: &str) -> Result<()> {
-    container_down(instance)?;
-    info!("{}: removing instance...", instance);
-    let spinner = create_spinner("Removing the instance...", 200);
-    let man = &mut *overlayfs::get_overlayfs_manager(instance)?;
-    man.destroy()?;
-    spinner.finish_and_clear();
+    remove_plan(instance)?.execute()?;
     info!("{}: instance removed.", instance);
+    if default_instance().as_deref() == Some(instance) {
+        set_default_instance(None)?;
+        info!("{} is no longer the default instance.", instance);
+    }
+
+    Ok(())
+}
+
+/// Prefix of the names of the temporary instances
+pub const EPHEMERAL_PREFIX: &str = "ephemeral-";
+
+/// Create a temporary instance with the configuration, run the function with it, then remove
+/// the instance even if the function failed
+pub fn with_ephemeral_instance<T, F: FnOnce(&str) -> Result<T>>(
+    inst_config: &config::InstanceConfig,
+    f: F,
+) -> Result<T> {
+    let instance = format!("{}{:08x}", EPHEMERAL_PREFIX, random::<u32>());
+    add_instance(&instance, inst_config)?;
+    let result = f(&instance);
+    let removed = remove_instance(&instance);
+    match (result, removed) {
+        (Ok(value), Ok(())) => Ok(value),
+        (Ok(_), Err(e)) => Err(e.context(format!(
+            "Unable to remove the ephemeral instance {}",
+            instance
+        ))),
+        (Err(e), removed) => {
+            if let Err(removed) = removed {
+                error!(
+                    "Unable to remove the ephemeral instance {}: {}",
+                    instance, removed
+                );
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Select the instance to be used: the one specified, otherwise the default instance of the
+/// workspace
+pub fn select_instance(specified: Option<&str>) -> Result<String> {
+    if let Some(instance) = specified {
+        return Ok(instance.to_owned());
+    }
+    let instances = machine::list_instances_simple()?;
+    match default_instance() {
+        Some(instance) if instances.contains(&instance) => Ok(instance),
+        Some(instance) => Err(anyhow::Error::from(CielError::InstanceNotFound(instance))
+            .context("The default instance is gone, please select another one with `ciel use <INSTANCE>`")),
+        None if instances.is_empty() => bail!(
+            "No instance specified, and this workspace has no instances. You can add one like this: `ciel add <INSTANCE>`"
+        ),
+        None => bail!(
+            "No instance specified: please select one of {} with `-i <INSTANCE>`, or set the default instance with `ciel use <INSTANCE>`",
+            instances.join(", ")
+        ),
+    }
+}
+
+/// Print the default instance of the workspace
+pub fn show_default_instance() {
+    match default_instance() {
+        Some(instance) => println!("{}", instance),
+        None => info!("No default instance is set."),
+    }
+}
+
+/// Set the default instance of the workspace, or clear it if `instance` is `None`
+pub fn use_instance(instance: Option<&str>) -> Result<()> {
+    if let Some(instance) = instance {
+        if !machine::list_instances_simple()?
+            .iter()
+            .any(|i| i == instance)
+        {
+            return Err(CielError::InstanceNotFound(instance.to_owned()).into());
+        }
+    }
+    set_default_instance(instance)?;
+    match instance {
+        Some(instance) => info!("{} is now the default instance.", instance),
+        None => info!("The default instance is cleared."),
+    }
 
     Ok(())
 }
 
-/// Update AOSC OS in the container/instance
+/// Re-associate the instances with their containers after the workspace has been moved
+pub fn relocate_workspace() -> Result<()> {
+    if is_legacy_workspace()? {
+        return Err(anyhow!(
+            "Legacy workspaces can not be relocated. Please upgrade it using `ciel init --upgrade`."
+        ));
+    }
+    let cwd = std::env::current_dir()?;
+    let previous = match recorded_workspace_location() {
+        Some(previous) if previous != cwd => previous,
+        _ => {
+            record_workspace_location()?;
+            info!("This workspace has not been moved.");
+            return Ok(());
+        }
+    };
+    info!(
+        "Relocating workspace from {} ...",
+        style(previous.display()).cyan()
+    );
+    let mut mounted = Vec::new();
+    for instance in machine::list_instances_simple()? {
+        let previous_ns_name = machine::get_container_ns_name_at(&previous, &instance)?;
+        if machine::terminate_container_by_name(&previous_ns_name).is_ok() {
+            info!("{}: stopped container {}.", instance, previous_ns_name);
+        }
+        let man = overlayfs::get_layer_manager(&instance)?;
+        if man.is_mounted(&cwd.join(&instance))? {
+            mounted.push(instance.clone());
+        }
+        container_down(&instance)?;
+    }
+    machine::clean_child_process();
+    if crate::zfs::is_zfs_workspace() {
+        crate::zfs::update_dist_mountpoint()?;
+    }
+    record_workspace_location()?;
+    for instance in mounted {
+        mount_fs(&instance)?;
+    }
+    info!("Workspace relocated to {}.", cwd.display());
+
+    Ok(())
+}
+
+/// Repair the problem found in the overlay mounts
+pub fn repair_mount_issue(issue: &overlayfs::MountIssue) -> Result<()> {
+    match issue {
+        overlayfs::MountIssue::OrphanedWorkDir(instance) => {
+            overlayfs::clear_work_dir(instance)?;
+            info!("{}: work directory cleared.", instance);
+        }
+        overlayfs::MountIssue::StaleMount(instance) => {
+            container_down(instance)?;
+            mount_fs(instance)?;
+        }
+        overlayfs::MountIssue::DeletedInstance(instance) => {
+            let target = std::env::current_dir()?.join(instance);
+            if let Ok(ns_name) = get_container_ns_name(instance, is_legacy_workspace()?) {
+                // the container may still be running
+                machine::terminate_container_by_name(&ns_name).ok();
+            }
+            nix::mount::umount2(&target, nix::mount::MntFlags::MNT_DETACH)?;
+            remove_mount(instance)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Plan removing the junk files from the instance layers
+pub fn prune_plan(instance: &str) -> Result<Plan> {
+    let mut plan = down_plan(instance)?;
+    plan.push(Step::Prune(instance.to_owned()));
+
+    Ok(plan)
+}
+
+/// Remove the junk files (defined by the prune policy) from the instance layers
+pub fn prune_instance(instance: &str) -> Result<()> {
+    prune_plan(instance)?.execute()
+}
+
+/// When the base system was last updated, in seconds since the epoch
+const LAST_UPDATE: &str = ".ciel/data/last-update";
+
+/// Return how long (in seconds) since the base system was last updated, the base
+/// system is considered updated when it was loaded if `ciel update-os` was never run
+pub(crate) fn os_age() -> Option<u64> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+    let updated = match fs::read_to_string(LAST_UPDATE) {
+        Ok(content) => content.trim().parse::<u64>().ok()?,
+        Err(_) => fs::metadata(CIEL_DIST_DIR)
+            .and_then(|m| m.modified())
+            .ok()?
+            .duration_since(UNIX_EPOCH)
+            .ok()?
+            .as_secs(),
+    };
+
+    Some(now.saturating_sub(updated))
+}
+
+/// Check the base system against the staleness policy before building in the instance,
+/// the base system is updated if it is stale and the policy says so
+pub fn check_os_staleness(conf: &config::CielConfig, instance: &str) -> Result<()> {
+    let threshold = match conf.build.auto_update_threshold() {
+        Some(threshold) => threshold,
+        None => return Ok(()),
+    };
+    let age = match os_age() {
+        Some(age) if age > threshold => age,
+        _ => return Ok(()),
+    };
+    let days = age / 86400;
+    match conf.build.on_stale {
+        config::StaleAction::Warn => {
+            warn!(
+                "The base system was last updated {} day(s) ago, consider running `ciel update-os`.",
+                days
+            );
+        }
+        config::StaleAction::Update => {
+            info!(
+                "The base system was last updated {} day(s) ago, updating it before building...",
+                days
+            );
+            // the instance must not be mounted on top of the base layer being changed
+            container_down(instance)?;
+            update_os()?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Upgrade the packages in the instance, checking that dpkg is not left in a broken state
+fn upgrade_instance(
+    instance: &str,
+    manager: &dyn PackageManager,
+    progress: &Progress,
+) -> Result<()> {
+    for operation in manager.upgrade() {
+        progress.step(operation.description);
+        pkgmgr::run(instance, manager, &operation)?;
+    }
+    progress.step("Checking the package database");
+    let status = run_in_container(instance, &["/bin/bash", "-c", DPKG_CHECK_SCRIPT])?;
+    if status != 0 {
+        bail!("dpkg has been left in a broken state (see the problems reported above)");
+    }
+
+    Ok(())
+}
+
+/// Update AOSC OS in the container/instance, reverting the base system if anything fails
 pub fn update_os() -> Result<()> {
-    info!("Updating base OS...");
+    let name = config::read_config().ok().and_then(|c| c.package_manager);
+    let manager = pkgmgr::detect(name.as_deref(), Path::new(CIEL_DIST_DIR))?;
+    let steps = 6 + manager.upgrade().len() as u64;
+    let progress = Progress::new(
+        &format!("Updating base OS with {}...", manager.name()),
+        Unit::Steps,
+        Some(steps),
+    );
     let instance = format!("update-{:x}", random::<u32>());
-    add_instance(&instance)?;
-    let status = run_in_container(&instance, &["/bin/bash", "-ec", UPDATE_SCRIPT])?;
-    if status != 0 {
-        return Err(anyhow!("Failed to update OS: {}", status));
+    progress.step("Creating a temporary instance");
+    add_instance(&instance, &config::InstanceConfig::default())?;
+    progress.step("Taking a snapshot of the base system");
+    let man = &mut *overlayfs::get_layer_manager(&instance)?;
+    // taken before the instance is mounted (which clones the ZFS dataset of the instance)
+    let snapshot = match man.snapshot_base() {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            if let Err(removed) = remove_instance(&instance) {
+                warn!(
+                    "Unable to remove the temporary instance {}: {}",
+                    instance, removed
+                );
+            }
+            return Err(e.context("Unable to take a snapshot of the base system"));
+        }
+    };
+    // the base system is only touched when committing
+    let mut committing = false;
+    let result = upgrade_instance(&instance, &*manager, &progress).and_then(|()| {
+        committing = true;
+        progress.step("Committing the changes");
+        commit_container(&instance).map_err(|e| e.context("Unable to commit the changes"))
+    });
+    progress.step("Removing the temporary instance");
+    let removed = remove_instance(&instance);
+    if let Err(e) = result {
+        progress.finish();
+        if let Err(removed) = removed {
+            warn!(
+                "Unable to remove the temporary instance {}: {}",
+                instance, removed
+            );
+        }
+        if !committing {
+            if let Err(e) = man.remove_base_snapshot(&snapshot) {
+                warn!("Unable to remove the snapshot {}: {}", snapshot, e);
+            }
+            return Err(e.context("Failed to update OS (the base system is unchanged)"));
+        }
+        warn!("Unable to commit the update, reverting the base system to the snapshot...");
+        dist::with_unlocked(|| man.restore_base(&snapshot)).map_err(|restore| {
+            anyhow!(
+                "Failed to update OS: {:#}, and unable to revert the base system to {}: {:#}",
+                e,
+                snapshot,
+                restore
+            )
+        })?;
+        return Err(e.context("Failed to update OS (the base system has been reverted)"));
+    }
+    progress.step("Removing the snapshot");
+    if let Err(e) = man.remove_base_snapshot(&snapshot) {
+        warn!("Unable to remove the snapshot {}: {}", snapshot, e);
+    }
+    progress.finish();
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    fs::write(LAST_UPDATE, now.to_string())?;
+    // the update is done (and the snapshot removed), only the temporary instance is left
+    removed.map_err(|e| {
+        e.context(format!(
+            "Unable to remove the temporary instance {}",
+            instance
+        ))
+    })?;
+
+    Ok(())
+}
+
+/// What `ciel update-all` did to an instance
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UpdateAction {
+    RolledBack,
+    /// Kept for the changes in its upper layer
+    Kept,
+    Failed,
+}
+
+impl std::fmt::Display for UpdateAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UpdateAction::RolledBack => write!(f, "rolled back"),
+            UpdateAction::Kept => write!(f, "kept"),
+            UpdateAction::Failed => write!(f, "failed"),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct InstanceUpdate {
+    instance: String,
+    action: UpdateAction,
+    /// Number of the paths changed in the upper layer (excluding the junk files)
+    changes: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Return the paths changed in the upper layer of the instance, excluding the junk files
+/// (`prune-paths`) left by the builds
+fn manual_changes(instance: &str) -> Result<Vec<PathBuf>> {
+    let junk = match config::read_config() {
+        Ok(c) => c.prune_paths,
+        Err(_) => config::default_prune_paths(),
+    };
+    let man = &mut *overlayfs::get_layer_manager(instance)?;
+
+    Ok(man
+        .changes()?
+        .into_iter()
+        .filter(|path| !junk.iter().any(|junk| path.starts_with(junk)))
+        .collect())
+}
+
+/// Update the base system, then roll back the instances (in the order of their names) onto
+/// it. With `keep_modified`, the instances with changes in their upper layers are kept as is
+pub fn update_all(keep_modified: bool) -> Result<()> {
+    let mut instances = machine::list_instances_simple()?;
+    instances.sort();
+    // inspected before the update, the changes are relative to the previous base system
+    let mut changes = Vec::new();
+    for instance in instances.iter() {
+        changes.push(if keep_modified {
+            manual_changes(instance)?.len()
+        } else {
+            0
+        });
+    }
+    update_os()?;
+    let mut report = Vec::new();
+    for (instance, changes) in instances.into_iter().zip(changes) {
+        let (action, error) = if changes > 0 {
+            info!(
+                "{}: keeping the instance with {} changed path(s).",
+                instance, changes
+            );
+            (UpdateAction::Kept, None)
+        } else {
+            match rollback_container(&instance) {
+                Ok(()) => (UpdateAction::RolledBack, None),
+                Err(e) => {
+                    error!("{}: unable to roll back: {}", instance, e);
+                    (UpdateAction::Failed, Some(format!("{:#}", e)))
+                }
+            }
+        };
+        report.push(InstanceUpdate {
+            instance,
+            action,
+            changes,
+            error,
+        });
+    }
+    let failed = report
+        .iter()
+        .filter(|u| u.action == UpdateAction::Failed)
+        .count();
+    if output::json_output() {
+        output::print_json(&report)?;
+    } else {
+        let mut formatter = TabWriter::new(std::io::stderr());
+        writeln!(&mut formatter, "INSTANCE\tACTION\tCHANGES")?;
+        for update in report.iter() {
+            writeln!(
+                &mut formatter,
+                "{}\t{}\t{}",
+                update.instance, update.action, update.changes
+            )?;
+        }
+        formatter.flush()?;
+    }
+    if failed > 0 {
+        bail!("{} instance(s) failed to update.", failed);
+    }
+
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct InstanceUsage {
+    name: String,
+    size: u64,
+}
+
+#[derive(Serialize)]
+struct UsageReport {
+    dist: u64,
+    instances: Vec<InstanceUsage>,
+}
+
+/// Show the disk usage of the base layer and the layers of each instance
+pub fn disk_usage() -> Result<()> {
+    let spinner = Progress::spinner("Calculating disk usage...");
+    let mut dist = None;
+    let mut instances = Vec::new();
+    for instance in machine::list_instances_simple()? {
+        let man = &mut *overlayfs::get_layer_manager(&instance)?;
+        let usage = man.usage(dist.is_none())?;
+        if usage.base.is_some() {
+            dist = usage.base;
+        }
+        instances.push(InstanceUsage {
+            name: instance,
+            size: usage.upper,
+        });
+    }
+    let dist = match dist {
+        Some(dist) => dist,
+        None => overlayfs::disk_usage(Path::new(CIEL_DIST_DIR))?,
+    };
+    spinner.finish();
+    let report = UsageReport { dist, instances };
+    if output::json_output() {
+        return output::print_json(&report);
+    }
+
+    let mut formatter = TabWriter::new(std::io::stderr());
+    writeln!(&mut formatter, "LAYER\tSIZE")?;
+    writeln!(
+        &mut formatter,
+        "{}\t{}",
+        style("(base)").dim(),
+        HumanBytes(report.dist)
+    )?;
+    let mut total = report.dist;
+    for instance in report.instances {
+        total += instance.size;
+        writeln!(
+            &mut formatter,
+            "{}\t{}",
+            instance.name,
+            HumanBytes(instance.size)
+        )?;
     }
-    commit_container(&instance)?;
-    remove_instance(&instance)?;
+    writeln!(
+        &mut formatter,
+        "{}\t{}",
+        style("(total)").bold(),
+        HumanBytes(total)
+    )?;
+    formatter.flush()?;
 
     Ok(())
 }
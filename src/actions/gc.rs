@@ -0,0 +1,251 @@
+use anyhow::Result;
+use console::style;
+use indicatif::HumanBytes;
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    fmt, fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+use walkdir::WalkDir;
+
+use crate::{
+    common::CIEL_INST_DIR,
+    error, info, machine, overlayfs,
+    progress::Progress,
+    trash::{self, TrashEntry},
+    warn, zfs,
+};
+
+use super::container::{remove_instance, repair_mount_issue, EPHEMERAL_PREFIX};
+
+const TARBALL_SUFFIXES: &[&str] = &[".tar.xz", ".tar.zst", ".tar"];
+
+/// Reclaimable resources found in the workspace
+enum Garbage {
+    StaleMachine(String),
+    MountIssue(overlayfs::MountIssue),
+    OldPackage(PathBuf, u64),
+    Snapshot(String, u64),
+    Tarball(PathBuf, u64),
+    Trash(TrashEntry, u64),
+    /// A temporary instance left by an interrupted `--ephemeral` run
+    EphemeralInstance(String, u64),
+}
+
+impl Garbage {
+    fn size(&self) -> u64 {
+        match self {
+            Garbage::OldPackage(_, size)
+            | Garbage::Snapshot(_, size)
+            | Garbage::Tarball(_, size)
+            | Garbage::Trash(_, size)
+            | Garbage::EphemeralInstance(_, size) => *size,
+            _ => 0,
+        }
+    }
+
+    fn reclaim(&self) -> Result<()> {
+        match self {
+            Garbage::StaleMachine(ns_name) => machine::terminate_container_by_name(ns_name),
+            Garbage::MountIssue(issue) => repair_mount_issue(issue),
+            Garbage::OldPackage(path, _) | Garbage::Tarball(path, _) => Ok(fs::remove_file(path)?),
+            Garbage::Snapshot(name, _) => zfs::destroy_snapshot(name),
+            Garbage::Trash(entry, _) => trash::purge(entry),
+            Garbage::EphemeralInstance(instance, _) => remove_instance(instance),
+        }
+    }
+}
+
+impl fmt::Display for Garbage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Garbage::StaleMachine(ns_name) => write!(f, "stale container: {}", ns_name),
+            Garbage::MountIssue(issue) => write!(f, "{}", issue),
+            Garbage::OldPackage(path, size) => write!(
+                f,
+                "superseded package: {} ({})",
+                path.display(),
+                HumanBytes(*size)
+            ),
+            Garbage::Snapshot(name, size) => {
+                write!(f, "unused snapshot: {} ({})", name, HumanBytes(*size))
+            }
+            Garbage::Tarball(path, size) => write!(
+                f,
+                "cached tarball: {} ({})",
+                path.display(),
+                HumanBytes(*size)
+            ),
+            Garbage::Trash(entry, size) => write!(
+                f,
+                "expired trash: {} ({})",
+                entry.path.display(),
+                HumanBytes(*size)
+            ),
+            Garbage::EphemeralInstance(instance, size) => write!(
+                f,
+                "left over ephemeral instance: {} ({})",
+                instance,
+                HumanBytes(*size)
+            ),
+        }
+    }
+}
+
+/// Split the package file name (`<name>_<version>_<arch>.deb`) into the name and the architecture
+fn package_key(file_name: &str) -> Option<(&str, &str)> {
+    let stem = file_name.strip_suffix(".deb")?;
+    let (name, rest) = stem.split_once('_')?;
+    let (_, arch) = rest.rsplit_once('_')?;
+
+    Some((name, arch))
+}
+
+/// Find the packages in the output directories superseded by a newer build of the same package
+fn find_old_packages() -> Result<Vec<Garbage>> {
+    let mut newest: HashMap<(PathBuf, String, String), (SystemTime, PathBuf, u64)> = HashMap::new();
+    let mut old = Vec::new();
+    for entry in fs::read_dir(".")? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !entry.file_type()?.is_dir() || !(name == "OUTPUT" || name.starts_with("OUTPUT-")) {
+            continue;
+        }
+        // the packages being staged are not in the repository yet
+        for entry in WalkDir::new(entry.path())
+            .into_iter()
+            .filter_entry(|e| e.file_name() != crate::repo::staging::STAGING_DIR)
+        {
+            let entry = entry?;
+            let file_name = entry.file_name().to_string_lossy();
+            let (package, arch) = match package_key(&file_name) {
+                Some(key) if entry.file_type().is_file() => key,
+                _ => continue,
+            };
+            let meta = entry.metadata()?;
+            let dir = entry
+                .path()
+                .parent()
+                .unwrap_or(Path::new("."))
+                .to_path_buf();
+            let key = (dir, package.to_owned(), arch.to_owned());
+            let current = (meta.modified()?, entry.path().to_path_buf(), meta.len());
+            match newest.entry(key) {
+                Entry::Occupied(mut e) if e.get().0 < current.0 => {
+                    let previous = e.insert(current);
+                    old.push(Garbage::OldPackage(previous.1, previous.2));
+                }
+                Entry::Occupied(_) => old.push(Garbage::OldPackage(current.1, current.2)),
+                Entry::Vacant(e) => {
+                    e.insert(current);
+                }
+            }
+        }
+    }
+
+    Ok(old)
+}
+
+/// Find the OS tarballs left in the workspace by `ciel load-os`
+fn find_cached_tarballs() -> Result<Vec<Garbage>> {
+    let mut tarballs = Vec::new();
+    for entry in fs::read_dir(".")? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if entry.file_type()?.is_file() && TARBALL_SUFFIXES.iter().any(|s| name.ends_with(s)) {
+            tarballs.push(Garbage::Tarball(entry.path(), entry.metadata()?.len()));
+        }
+    }
+
+    Ok(tarballs)
+}
+
+fn collect() -> Result<Vec<Garbage>> {
+    let mut garbage = Vec::new();
+    match machine::list_stale_machines() {
+        Ok(machines) => garbage.extend(machines.into_iter().map(Garbage::StaleMachine)),
+        Err(e) => warn!("Unable to query the containers: {}", e),
+    }
+    garbage.extend(overlayfs::verify()?.into_iter().map(Garbage::MountIssue));
+    garbage.extend(find_old_packages()?);
+    if zfs::is_zfs_workspace() {
+        garbage.extend(
+            zfs::list_unused_snapshots()?
+                .into_iter()
+                .map(|(name, size)| Garbage::Snapshot(name, size)),
+        );
+    }
+    garbage.extend(find_cached_tarballs()?);
+    // the mounted ones may still be in use
+    for instance in machine::list_instances()? {
+        if instance.name.starts_with(EPHEMERAL_PREFIX) && !instance.mounted {
+            let size = overlayfs::disk_usage(&Path::new(CIEL_INST_DIR).join(&instance.name))?;
+            garbage.push(Garbage::EphemeralInstance(instance.name, size));
+        }
+    }
+    for entry in trash::expired(Path::new("."))? {
+        let size = overlayfs::disk_usage(&entry.path)?;
+        garbage.push(Garbage::Trash(entry, size));
+    }
+
+    Ok(garbage)
+}
+
+/// Reclaim the resources no longer used by the workspace
+pub fn collect_garbage(dry_run: bool) -> Result<()> {
+    let spinner = Progress::spinner("Looking for reclaimable resources...");
+    let garbage = collect();
+    spinner.finish();
+    let garbage = garbage?;
+    if garbage.is_empty() {
+        info!("Nothing to clean up.");
+        return Ok(());
+    }
+    let total: u64 = garbage.iter().map(|g| g.size()).sum();
+    if dry_run {
+        for item in garbage.iter() {
+            info!("Would remove {}", item);
+        }
+        info!(
+            "{} item(s) can be cleaned up, {} can be reclaimed.",
+            garbage.len(),
+            HumanBytes(total)
+        );
+        return Ok(());
+    }
+    let mut reclaimed = 0;
+    let mut failed = 0;
+    for item in garbage.iter() {
+        match item.reclaim() {
+            Ok(()) => {
+                info!("Removed {}", item);
+                reclaimed += item.size();
+            }
+            Err(e) => {
+                error!("Unable to remove {}: {}", item, e);
+                failed += 1;
+            }
+        }
+    }
+    info!("{} reclaimed.", HumanBytes(reclaimed));
+    if failed > 0 {
+        warn!("{} item(s) could not be removed.", failed);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_package_key() {
+    assert_eq!(
+        package_key("bash_5.2.15-0_amd64.deb"),
+        Some(("bash", "amd64"))
+    );
+    assert_eq!(
+        package_key("linux+kernel_6.1.0_noarch.deb"),
+        Some(("linux+kernel", "noarch"))
+    );
+    assert_eq!(package_key("bash.deb"), None);
+    assert_eq!(package_key("Packages"), None);
+}
@@ -3,21 +3,31 @@ use console::style;
 
 use crate::machine;
 
+mod apply;
 mod container;
+mod gc;
 mod onboarding;
 mod packaging;
+mod plan;
+mod status;
+mod topic;
 
 // re-export all the functions from the sub
+pub use self::apply::*;
 pub use self::container::*;
-pub use self::onboarding::onboarding;
+pub use self::gc::*;
+pub use self::onboarding::{onboarding, OnboardingOptions};
 pub use self::packaging::*;
+pub use self::status::*;
+pub use self::topic::*;
 
 const DEFAULT_MOUNTS: &[(&str, &str)] = &[
-    ("OUTPUT/debs/", "/debs/"),
+    ("OUTPUT/debs/", crate::repo::LOCAL_REPO_MOUNT),
     ("TREE", "/tree"),
     ("SRCS", "/var/cache/acbs/tarballs"),
 ];
-const UPDATE_SCRIPT: &str = r#"export DEBIAN_FRONTEND=noninteractive;apt-get update -y --allow-releaseinfo-change && apt-get -y -o Dpkg::Options::="--force-confnew" full-upgrade --autoremove --purge && apt clean"#;
+/// Fails (reporting the problems) if any package is left half-installed or half-configured
+const DPKG_CHECK_SCRIPT: &str = r#"audit="$(dpkg --audit)"; if [ -n "$audit" ]; then echo "$audit"; exit 1; fi; apt-get check -q"#;
 
 /// Ensure that the directories exist and mounted
 pub fn ensure_host_sanity() -> Result<(Vec<String>, Vec<(String, &'static str)>), std::io::Error> {
@@ -30,14 +40,28 @@ pub fn ensure_host_sanity() -> Result<(Vec<String>, Vec<(String, &'static str)>)
         .collect();
     if let Ok(c) = crate::config::read_config() {
         extra_options = c.extra_options;
+        for option in extra_options.iter_mut() {
+            match crate::secrets::expand_secrets(option) {
+                Ok(expanded) => *option = expanded,
+                Err(e) => warn!("{}", e),
+            }
+        }
         if !c.local_sources {
             // remove SRCS
             mounts.swap_remove(2);
         }
-        if c.sep_mount {
-            mounts.push((format!("{}/debs", get_output_directory(true)), "/debs/"));
+        let component = get_output_component(&c);
+        if c.sep_mount || component.is_some() {
+            let output = get_output_directory(c.sep_mount, component.as_deref());
+            mounts.push((format!("{}/debs", output), crate::repo::LOCAL_REPO_MOUNT));
             mounts.swap_remove(0);
         }
+        if c.compiler_cache.is_some() {
+            mounts.push((
+                crate::compiler_cache::CACHE_DIR.to_string(),
+                crate::compiler_cache::CACHE_MOUNT,
+            ));
+        }
     } else {
         warn!("This workspace is not yet configured, default settings are used.");
     }
@@ -4,19 +4,55 @@ use dialoguer::{theme::ColorfulTheme, Confirm, Input};
 use std::{fs, path::Path};
 
 use crate::{
-    cli::GIT_TREE_URL,
+    cache,
     common::*,
-    config, error, info,
-    network::{download_git, pick_latest_tarball},
+    config, error,
+    hooks::{run_hook, Hook, HookContext},
+    info,
+    network::{
+        clone_repo, default_segments, git_switch_branch, is_offline, pick_latest_tarball,
+        select_mirrors, set_proxy, tarball_urls, CloneOptions,
+    },
     overlayfs::create_new_instance_fs,
+    profile::load_profile,
     repo::{init_repo, refresh_repo},
+    template::Template,
+    tree::tree_url,
     warn,
 };
 
 use super::{load_os, mount_fs};
 
+/// Options for creating the workspace given on the command line
+#[derive(Debug, Default, Clone)]
+pub struct OnboardingOptions {
+    /// Do not ask any questions (for CI environments)
+    pub non_interactive: bool,
+    pub config: config::ConfigOverrides,
+    /// Profile to base the configuration on
+    pub profile: Option<String>,
+    /// Git repository of the template to initialize the workspace from
+    pub template: Option<String>,
+    /// Install the hooks of the template without asking
+    pub trust_template: bool,
+    /// Branch of the ABBS tree to check out (overrides the branch of the profile)
+    pub branch: Option<String>,
+    /// Instance to add after initialization
+    pub instance: Option<String>,
+    /// Mirror to fetch the OS tarball from (tried before the other mirrors)
+    pub mirror: Option<String>,
+    /// Skip the signature verification of the OS tarball
+    pub no_verify: bool,
+    /// Options of cloning the ABBS tree
+    pub tree: CloneOptions,
+}
+
 /// Show interactive onboarding guide, triggered by issuing `ciel new`
-pub fn onboarding(custom_tarball: Option<&String>, arch: Option<&str>) -> Result<()> {
+pub fn onboarding(
+    custom_tarball: Option<&String>,
+    arch: Option<&str>,
+    options: &OnboardingOptions,
+) -> Result<()> {
     let theme = ColorfulTheme::default();
     info!("Welcome to ciel!");
     if Path::new(".ciel").exists() {
@@ -24,20 +60,98 @@ pub fn onboarding(custom_tarball: Option<&String>, arch: Option<&str>) -> Result
         info!("Please run `ciel farewell` to nuke it before running this command.");
         return Err(anyhow!("Unable to create a ciel workspace."));
     }
-    info!("Before continuing, I need to ask you a few questions:");
-    let real_arch = if let Some(arch) = arch {
+    let interactive = user_attended() && !options.non_interactive;
+    if interactive {
+        info!("Before continuing, I need to ask you a few questions:");
+    }
+    set_proxy(options.config.proxy.clone());
+    let template = match &options.template {
+        Some(url) => Some(Template::fetch(url)?),
+        None => None,
+    };
+    // the hooks run on the host as root, so nothing is installed without a review
+    if let Some(template) = template.as_ref().filter(|_| !options.trust_template) {
+        if !template.hooks()?.is_empty() {
+            template.show_hooks()?;
+            if !interactive {
+                return Err(anyhow!(
+                    "The template contains hooks, please review them and use --trust to install them."
+                ));
+            }
+            if !Confirm::with_theme(&theme)
+                .with_prompt("The template contains the hooks above, which run on this machine as root. Install them?")
+                .default(false)
+                .interact()?
+            {
+                return Err(anyhow!("The hooks of the template were not trusted."));
+            }
+        }
+    }
+    let template_arch = template.as_ref().and_then(|t| t.manifest.arch.as_deref());
+    let real_arch = if let Some(arch) = arch.or(template_arch) {
         arch
     } else if custom_tarball.is_some() {
         "custom"
+    } else if options.non_interactive {
+        return Err(anyhow!(
+            "Target architecture is required (use --arch or CIEL_ARCH)."
+        ));
     } else {
         ask_for_target_arch()?
     };
-    let config = config::ask_for_config(None)?;
-    let mut init_instance: Option<String> = None;
-    if user_attended()
-        && Confirm::with_theme(&theme)
-            .with_prompt("Do you want to add a new instance now?")
-            .interact()?
+    let profile = match &options.profile {
+        Some(name) => Some((name.as_str(), load_profile(name)?)),
+        None => None,
+    };
+    let mut config = config::CielConfig::default();
+    if let Some((name, profile)) = &profile {
+        profile.apply(name, &mut config);
+    }
+    let config = if let Some(template) = &template {
+        template.apply(&mut config)?;
+        config.apply_overrides(&options.config)?;
+        // only the values marked as required by the template are asked
+        let missing = template
+            .manifest
+            .required
+            .iter()
+            .filter(|key| !options.config.is_set(key))
+            .collect::<Vec<_>>();
+        if !missing.is_empty() && !interactive {
+            return Err(anyhow!(
+                "The template requires values for: {}",
+                missing
+                    .iter()
+                    .map(|s| s.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ));
+        }
+        for key in missing {
+            config::ask_for_value(&mut config, key)?;
+        }
+        config
+    } else if !options.non_interactive {
+        // the values from the command line become the defaults of the questions
+        config.apply_overrides(&options.config)?;
+        config::ask_for_config(Some(config))?
+    } else {
+        config::config_from_overrides(config, &options.config)?
+    };
+    // the configuration is saved after the OS is downloaded
+    set_proxy(config.network.proxy.clone());
+    let branch = options
+        .branch
+        .as_ref()
+        .or_else(|| template.as_ref().and_then(|t| t.manifest.branch.as_ref()))
+        .or_else(|| profile.as_ref().and_then(|(_, p)| p.branch.as_ref()))
+        .or(config.tree.branch.as_ref());
+    let mut init_instance: Option<String> = options.instance.clone();
+    if init_instance.is_some() || !interactive || template.is_some() {
+        // the instance (if any) is given on the command line or declared by the template
+    } else if Confirm::with_theme(&theme)
+        .with_prompt("Do you want to add a new instance now?")
+        .interact()?
     {
         let name: String = Input::with_theme(&theme)
             .with_prompt("Name of the instance")
@@ -54,24 +168,55 @@ pub fn onboarding(custom_tarball: Option<&String>, arch: Option<&str>) -> Result
     info!("Initializing workspace...");
     ciel_init()?;
     info!("Initializing container OS...");
-    let (tarball_url, tarball_sha256) = match custom_tarball {
+    let (tarball_urls, tarball_sha256) = match custom_tarball {
         Some(tarball) => {
             info!("Using custom tarball from {}", tarball);
-            (tarball.clone(), None)
+            (vec![tarball.clone()], None)
+        }
+        None if is_offline() => {
+            let cached = cache::latest_for_arch(real_arch, !options.no_verify)?;
+            info!(
+                "Working offline, using cached tarball {}",
+                cached.metadata.name
+            );
+            // the cached tarball is a local file, so it is not verified again
+            (
+                vec![cached.path.to_string_lossy().to_string()],
+                Some(cached.sha256),
+            )
         }
         None => {
             info!("Searching for latest AOSC OS buildkit release...");
-            auto_pick_tarball(&theme, real_arch)?
+            let mirrors = select_mirrors(options.mirror.as_deref(), &config.release_mirrors);
+            auto_pick_tarball(&theme, real_arch, &mirrors, !options.non_interactive)?
         }
     };
-    load_os(&tarball_url, tarball_sha256)?;
+    load_os(
+        &tarball_urls,
+        tarball_sha256,
+        default_segments(),
+        !options.no_verify,
+    )?;
     info!("Initializing ABBS tree...");
     if Path::new("TREE").is_dir() {
         warn!("TREE already exists, skipping this step...");
+        if let Some(branch) = branch {
+            let mut repo = git2::Repository::open("TREE")?;
+            git_switch_branch(&mut repo, branch, None)?;
+            info!("Switched the ABBS tree to {}.", branch);
+        }
+    } else if is_offline() {
+        return Err(anyhow!(
+            "TREE does not exist and Ciel is working offline, please clone the ABBS tree into TREE beforehand"
+        ));
     } else {
         // if TREE is a file, then remove it
         fs::remove_file("TREE").ok();
-        download_git(GIT_TREE_URL, Path::new("TREE"))?;
+        let clone_options = CloneOptions {
+            branch: branch.cloned(),
+            ..options.tree.clone()
+        };
+        clone_repo(tree_url(&config), Path::new("TREE"), &clone_options)?;
     }
     config::apply_config(CIEL_DIST_DIR, &config)?;
     info!("Applying configurations...");
@@ -95,6 +240,10 @@ pub fn onboarding(custom_tarball: Option<&String>, arch: Option<&str>) -> Result
             info!("{}: local repository initialized.", init_instance);
         }
     }
+    if let Some(template) = &template {
+        template.install_hooks()?;
+    }
+    run_hook(Hook::PostInit, &HookContext::default())?;
 
     Ok(())
 }
@@ -103,15 +252,18 @@ pub fn onboarding(custom_tarball: Option<&String>, arch: Option<&str>) -> Result
 fn auto_pick_tarball(
     theme: &dyn dialoguer::theme::Theme,
     arch: &str,
-) -> Result<(String, Option<String>)> {
-    if let Ok(tarball) = pick_latest_tarball(arch) {
+    mirrors: &[String],
+    interactive: bool,
+) -> Result<(Vec<String>, Option<String>)> {
+    if let Ok(tarball) = pick_latest_tarball(arch, mirrors) {
         info!(
             "Ciel has picked buildkit for {}, released on {}",
             tarball.arch, tarball.date
         );
-        Ok((
-            format!("https://releases.aosc.io/{}", tarball.path),
-            Some(tarball.sha256sum),
+        Ok((tarball_urls(&tarball, mirrors), Some(tarball.sha256sum)))
+    } else if !interactive {
+        Err(anyhow!(
+            "Ciel was unable to find a suitable buildkit release. Please specify the tarball using --from-tarball."
         ))
     } else {
         warn!(
@@ -120,6 +272,6 @@ fn auto_pick_tarball(
         let tarball_url = Input::<String>::with_theme(theme)
             .with_prompt("Tarball URL")
             .interact_text()?;
-        Ok((tarball_url, None))
+        Ok((vec![tarball_url], None))
     }
 }
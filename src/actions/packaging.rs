@@ -1,24 +1,47 @@
-use anyhow::{anyhow, Result};
-use console::style;
-use dialoguer::{theme::ColorfulTheme, Select};
+use anyhow::{anyhow, bail, Result};
+use console::{style, user_attended};
+use dialoguer::{theme::ColorfulTheme, Confirm, Select};
 use nix::unistd::gethostname;
 use serde::{Deserialize, Serialize};
 use std::{
+    cmp::Ordering,
+    collections::{BTreeSet, HashMap, HashSet},
     fs::{self, File},
     io::{BufRead, BufReader, Write},
-    path::Path,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
     thread::sleep,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime},
 };
 use walkdir::WalkDir;
 
-use crate::{common::create_spinner, config, error, info, repo, warn};
+use crate::{
+    build::{BuildRequest, BuildResult, FailureAction, PackageStatus},
+    buildlog,
+    common::{parse_size, CIEL_INST_DIR},
+    compiler_cache, config, error,
+    errors::EXIT_BUILD_TIMED_OUT,
+    hooks::{run_hook, Hook, HookContext},
+    info,
+    machine::Completion,
+    notify, pkgmgr, preflight,
+    progress::Progress,
+    repo::{self, prune::compare_versions},
+    sandbox, sources, stats, tree, warn,
+};
 
 use super::{
-    container::{get_output_directory, mount_fs, rollback_container, run_in_container},
-    UPDATE_SCRIPT,
+    container::{
+        check_os_staleness, get_output_component, get_output_directory, instance_cgroup,
+        materialize_instance, mount_fs, prune_instance, rollback_container, run_in_container,
+        run_in_container_logged,
+    },
+    plan::{Plan, Step},
 };
 
+/// Progress of the last build, updated after each package built
+pub const BUILD_STATE: &str = ".ciel/data/build-state.toml";
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct BuildCheckPoint {
     packages: Vec<String>,
@@ -27,18 +50,45 @@ pub struct BuildCheckPoint {
     attempts: usize,
 }
 
-#[derive(Debug, Copy, Clone)]
-pub struct BuildSettings {
-    pub offline: bool,
-    pub stage2: bool,
+impl BuildCheckPoint {
+    /// Skip the package the build stopped at (i.e. the failed package)
+    pub fn skip_failed(&mut self) {
+        if let Some(package) = self.packages.get(self.progress) {
+            info!("Skipping {}.", package);
+            self.progress += 1;
+        }
+    }
 }
 
+/// Load the checkpoint, either the persisted build state (TOML) or a check-point in `STATES`
 pub fn load_build_checkpoint<P: AsRef<Path>>(path: P) -> Result<BuildCheckPoint> {
+    let path = path.as_ref();
+    if path.extension().map_or(false, |e| e == "toml") {
+        let content = fs::read_to_string(path).map_err(|e| {
+            anyhow!(
+                "Unable to read the build state {}: {}, is there a build to resume?",
+                path.display(),
+                e
+            )
+        })?;
+        return Ok(toml::from_str(&content)?);
+    }
     let f = File::open(path)?;
 
     Ok(bincode::deserialize_from(f)?)
 }
 
+/// Persist the progress of the build, for `ciel build --resume`
+fn save_build_state(checkpoint: &BuildCheckPoint) -> Result<()> {
+    let path = Path::new(BUILD_STATE);
+    fs::create_dir_all(path.parent().unwrap())?;
+    let tmp_path = path.with_extension("toml.tmp");
+    fs::write(&tmp_path, toml::to_string(checkpoint)?)?;
+    fs::rename(tmp_path, path)?;
+
+    Ok(())
+}
+
 fn dump_build_checkpoint(checkpoint: &BuildCheckPoint) -> Result<()> {
     let save_state = bincode::serialize(checkpoint)?;
     let last_package = checkpoint
@@ -46,7 +96,7 @@ fn dump_build_checkpoint(checkpoint: &BuildCheckPoint) -> Result<()> {
         .get(checkpoint.progress)
         .map_or("unknown".to_string(), |x| x.to_owned());
     let last_package = last_package.replace('/', "_");
-    let current = std::time::SystemTime::now()
+    let current = SystemTime::now()
         .duration_since(std::time::UNIX_EPOCH)?
         .as_secs();
     fs::create_dir_all("./STATES")?;
@@ -69,13 +119,18 @@ fn format_duration(seconds: u64) -> String {
 }
 
 fn read_package_list<P: AsRef<Path>>(filename: P, depth: usize) -> Result<Vec<String>> {
+    let f = fs::File::open(filename)?;
+
+    parse_package_list(BufReader::new(f), depth)
+}
+
+/// Parse a list of packages (one per line, with comments and nested groups)
+fn parse_package_list<R: BufRead>(reader: R, depth: usize) -> Result<Vec<String>> {
     if depth > 32 {
         return Err(anyhow!(
             "Nested group exceeded 32 levels! Potential infinite loop."
         ));
     }
-    let f = fs::File::open(filename)?;
-    let reader = BufReader::new(f);
     let mut results = Vec::new();
     for line in reader.lines() {
         let line = line?;
@@ -102,23 +157,35 @@ fn read_package_list<P: AsRef<Path>>(filename: P, depth: usize) -> Result<Vec<St
     Ok(results)
 }
 
-/// Expand the packages list to an array of packages
-fn expand_package_list<S: AsRef<str>, I: IntoIterator<Item = S>>(packages: I) -> Vec<String> {
+/// Expand the packages list to an array of packages, the duplicates are kept (e.g. a package
+/// built twice in a bootstrap sequence, see [dedup_packages]). Besides the package names,
+/// the list may contain:
+///
+/// - `groups/<name>` or `@groups/<name>`: the packages in the group of the tree
+/// - `@<file>`: the packages listed in the file (in the same format as the groups)
+/// - `-`: the packages listed in the standard input
+pub fn expand_package_list<S: AsRef<str>, I: IntoIterator<Item = S>>(packages: I) -> Vec<String> {
     let mut expanded = Vec::new();
     for package in packages {
         let package = package.as_ref();
-        if !package.starts_with("groups/") {
+        let name = package.strip_prefix('@').unwrap_or(package);
+        let list = if name.starts_with("groups/") {
+            read_package_list(Path::new("./TREE").join(name), 0)
+        } else if package.starts_with('@') {
+            read_package_list(name, 0)
+        } else if package == "-" {
+            parse_package_list(std::io::stdin().lock(), 0)
+        } else {
             expanded.push(package.to_string());
             continue;
-        }
-        let list_file = Path::new("./TREE").join(package);
-        match read_package_list(list_file, 0) {
+        };
+        match list {
             Ok(list) => {
                 info!("Read {} packages from {}", list.len(), package);
                 expanded.extend(list);
             }
             Err(e) => {
-                warn!("Unable to read package group `{}`: {}", package, e);
+                warn!("Unable to read package list `{}`: {}", package, e);
             }
         }
     }
@@ -126,17 +193,142 @@ fn expand_package_list<S: AsRef<str>, I: IntoIterator<Item = S>>(packages: I) ->
     expanded
 }
 
+/// Remove the duplicated packages, keeping the first occurrences
+pub fn dedup_packages(packages: Vec<String>) -> Vec<String> {
+    let mut seen = HashSet::new();
+    packages
+        .into_iter()
+        .filter(|p| seen.insert(p.clone()))
+        .collect()
+}
+
+/// Refresh the local repository and update the OS in the instance before building,
+/// returns the exit status of the update
+fn prepare_instance(root: &Path, instance: &str) -> Result<i32> {
+    // e.g. built by hand in the shell
+    merge_staged(root, instance)?;
+    info!("Refreshing local repository...");
+    repo::init_repo(root, Path::new(instance))?;
+    let name = config::read_config().ok().and_then(|c| c.package_manager);
+    let manager = pkgmgr::detect(name.as_deref(), Path::new(instance))?;
+    // rolling back removes the packages provisioned
+    let provisioned =
+        config::InstanceConfig::load(&Path::new(CIEL_INST_DIR).join(instance))?.provisioned;
+    let mut status = -1;
+    for i in 1..=5 {
+        // the indices are not replaced while they are being read, the lock is released while
+        // waiting to retry
+        let lock = repo::staging::lock_shared(root)?;
+        let result = pkgmgr::upgrade(instance, &*manager).and_then(|()| {
+            if provisioned.is_empty() {
+                return Ok(());
+            }
+            pkgmgr::run(instance, &*manager, &manager.install(&provisioned))
+        });
+        drop(lock);
+        status = match result {
+            Ok(()) => 0,
+            Err(e) => {
+                error!("{:#}", e);
+                e.downcast_ref::<pkgmgr::Failure>()
+                    .map_or(-1, |failure| failure.status)
+            }
+        };
+        if status == 0 {
+            break;
+        } else {
+            let interval = 3u64.pow(i);
+            warn!(
+                "Failed to update the OS, will retry in {} seconds ...",
+                interval
+            );
+            sleep(Duration::from_secs(interval));
+        }
+    }
+
+    Ok(status)
+}
+
+/// Merge the packages staged by the instance into the repository, returns the files merged
+/// (relative to the repository)
+fn merge_staged(root: &Path, instance: &str) -> Result<Vec<String>> {
+    let merged = repo::staging::merge(root, instance)?;
+    if !merged.is_empty() {
+        info!(
+            "{}: {} file(s) merged into the local repository.",
+            instance,
+            merged.len()
+        );
+    }
+
+    Ok(merged)
+}
+
+/// Return the build directory (in the container) ACBS used last, descending into the
+/// source directory if it is the only entry
+fn latest_build_dir(instance: &str) -> Option<String> {
+    let build_root = Path::new(instance).join("var/cache/acbs/build");
+    let mut dir = fs::read_dir(&build_root)
+        .ok()?
+        .filter_map(|e| e.ok())
+        .filter(|e| e.path().is_dir())
+        .max_by_key(|e| e.metadata().and_then(|m| m.modified()).ok())?
+        .path();
+    if let Ok(entries) = fs::read_dir(&dir) {
+        let entries = entries.filter_map(|e| e.ok()).collect::<Vec<_>>();
+        if entries.len() == 1 && entries[0].path().is_dir() {
+            dir = entries[0].path();
+        }
+    }
+
+    Some(format!("/{}", dir.strip_prefix(instance).ok()?.display()))
+}
+
+/// Open a shell in the instance at the build directory of the failed package, the instance
+/// is kept as-is for the investigation
+fn triage_shell(instance: &str) -> Result<()> {
+    let dir = latest_build_dir(instance).unwrap_or_else(|| "/".to_owned());
+    info!(
+        "Opening a shell in {} ({}), exit the shell to continue.",
+        dir, instance
+    );
+    run_in_container(
+        instance,
+        &[
+            "/bin/bash",
+            "-c",
+            "cd \"$1\" && exec /bin/bash",
+            "bash",
+            &dir,
+        ],
+    )?;
+
+    Ok(())
+}
+
 #[inline]
 fn package_build_inner<P: AsRef<Path>>(
+    request: &BuildRequest,
+    conf: &config::CielConfig,
     packages: &[String],
     instance: &str,
     root: P,
-) -> Result<(i32, usize)> {
+    result: &mut BuildResult,
+    checkpoint: &mut BuildCheckPoint,
+) -> Result<()> {
     let total = packages.len();
     let hostname = gethostname().map_or_else(
         |_| "unknown".to_string(),
         |s| s.into_string().unwrap_or_else(|_| "unknown".to_string()),
     );
+    let mut cache = None;
+    let package_timeout = request
+        .package_timeout
+        .or_else(|| conf.build.package_timeout());
+    let deadline = request
+        .timeout
+        .or_else(|| conf.build.timeout())
+        .map(|t| Instant::now() + Duration::from_secs(t));
     for (index, package) in packages.iter().enumerate() {
         // set terminal title, \r is for hiding the message if the terminal does not support the sequence
         eprint!(
@@ -149,45 +341,204 @@ fn package_build_inner<P: AsRef<Path>>(
         );
         // hopefully the sequence gets flushed together with the `info!` below
         info!("[{}/{}] Building {}...", index + 1, total, package);
+        if deadline.map_or(false, |d| Instant::now() >= d) {
+            error!(
+                "The build ran out of its time limit, {} package(s) are not built.",
+                total - index
+            );
+            result.exit_status = EXIT_BUILD_TIMED_OUT;
+            return Ok(());
+        }
+        let start = Instant::now();
         mount_fs(instance)?;
-        info!("Refreshing local repository...");
-        repo::init_repo(root.as_ref(), Path::new(instance))?;
-        let mut status = -1;
-        for i in 1..=5 {
-            status = run_in_container(instance, &["/bin/bash", "-ec", UPDATE_SCRIPT]).unwrap_or(-1);
-            if status == 0 {
-                break;
-            } else {
-                let interval = 3u64.pow(i);
-                warn!(
-                    "Failed to update the OS, will retry in {} seconds ...",
-                    interval
-                );
-                sleep(Duration::from_secs(interval));
+        if index == 0 {
+            if let Some(name) = &conf.compiler_cache {
+                cache = compiler_cache::enable(name, instance)?;
             }
         }
+        let status = prepare_instance(root.as_ref(), instance)?;
+        let package_result = result
+            .pending_mut(package)
+            .ok_or_else(|| anyhow!("{} is not in the build", package))?;
         if status != 0 {
             error!("Failed to update the OS before building packages");
-            return Ok((status, index));
+            package_result.status = PackageStatus::Failed {
+                exit_status: status,
+            };
+            result.exit_status = status;
+            return Ok(());
         }
-        let status = run_in_container(instance, &["/bin/acbs-build", "--", package])?;
+        let mut attempt = 1;
+        let (completion, log, record) = loop {
+            let cgroup = instance_cgroup(instance)
+                .map(|cgroup| stats::CgroupSnapshot::take(&cgroup))
+                .ok();
+            let started = SystemTime::now();
+            let started_secs = started
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs());
+            let log = buildlog::log_path(package, started_secs);
+            if let Some(cache) = cache {
+                compiler_cache::reset_stats(cache);
+            }
+            let block = if request.offline || request.block_network {
+                Some(sandbox::NetworkBlock::apply(
+                    Path::new(instance),
+                    &instance_cgroup(instance)?,
+                    !request.offline,
+                )?)
+            } else {
+                None
+            };
+            // the package is stopped at its own time limit or the one of the whole build
+            let package_deadline = package_timeout
+                .map(|t| Instant::now() + Duration::from_secs(t))
+                .into_iter()
+                .chain(deadline)
+                .min();
+            let completion = run_in_container_logged(
+                instance,
+                &["/bin/acbs-build", "--", package],
+                &log,
+                package_deadline,
+            )?;
+            let status = completion.exit_status();
+            // only the packages of a successful build make it into the repository
+            let debs = if status == 0 {
+                merge_staged(root.as_ref(), instance)?
+                    .into_iter()
+                    .filter(|f| f.ends_with(".deb"))
+                    .collect()
+            } else {
+                repo::staging::discard(root.as_ref(), instance)?;
+                Vec::new()
+            };
+            if let Some(block) = block {
+                block.finish(package);
+            }
+            if let Some(cache) = cache {
+                compiler_cache::report(cache, instance);
+            }
+            let record = buildlog::BuildRecord {
+                package: package.clone(),
+                instance: instance.to_owned(),
+                started: started_secs,
+                wall_time: started.elapsed().map_or(0.0, |d| d.as_secs_f64()),
+                exit_status: status,
+                log: log.to_string_lossy().to_string(),
+                stage2: request.stage2,
+                cpu_time: cgroup.as_ref().and_then(|c| c.cpu_time()),
+                peak_memory: cgroup.as_ref().and_then(|c| c.peak_memory()),
+                artifact_size: debs
+                    .iter()
+                    .filter_map(|deb| fs::metadata(root.as_ref().join("debs").join(deb)).ok())
+                    .map(|meta| meta.len())
+                    .sum(),
+                debs,
+            };
+            if let Err(e) = buildlog::record(&record) {
+                warn!("Unable to record the build of {}: {}", package, e);
+            }
+            // a stuck build is likely to get stuck again
+            if status == 0 || completion == Completion::TimedOut || attempt > request.retry {
+                break (completion, log, record);
+            }
+            warn!(
+                "Build failed with status {} (attempt {}/{}), retrying ...",
+                status,
+                attempt,
+                request.retry + 1
+            );
+            attempt += 1;
+            if request.reset_on_retry {
+                rollback_container(instance)?;
+                mount_fs(instance)?;
+                let status = prepare_instance(root.as_ref(), instance)?;
+                if status != 0 {
+                    error!("Failed to update the OS before retrying the build");
+                    package_result.status = PackageStatus::Failed {
+                        exit_status: status,
+                    };
+                    result.exit_status = status;
+                    return Ok(());
+                }
+            }
+        };
+        let status = completion.exit_status();
+        package_result.duration = Some(start.elapsed().as_secs());
+        package_result.attempts = attempt;
+        if attempt > 1 {
+            package_result.reproducible = Some(status != 0);
+            if status == 0 {
+                warn!(
+                    "{} is built on attempt {}, the earlier failures were not reproducible.",
+                    package, attempt
+                );
+            } else {
+                error!(
+                    "{} failed in all {} attempts, the failure is reproducible.",
+                    package, attempt
+                );
+            }
+        }
+        package_result.log = Some(log.to_string_lossy().to_string());
         if status != 0 {
+            if completion == Completion::TimedOut {
+                error!(
+                    "{} ran out of the time limit, the build is terminated.",
+                    package
+                );
+                package_result.status = PackageStatus::TimedOut;
+                result.exit_status = status;
+                return Ok(());
+            }
             error!("Build failed with status: {}", status);
-            return Ok((status, index));
+            package_result.status = PackageStatus::Failed {
+                exit_status: status,
+            };
+            result.exit_status = status;
+            let open_shell = match request.on_fail {
+                FailureAction::Stop => false,
+                FailureAction::Shell => true,
+                FailureAction::Ask => {
+                    user_attended()
+                        && Confirm::with_theme(&ColorfulTheme::default())
+                            .with_prompt("Open a shell in the build directory to investigate?")
+                            .default(false)
+                            .interact()?
+                }
+            };
+            if open_shell {
+                triage_shell(instance)?;
+            }
+            return Ok(());
         }
+        package_result.status = PackageStatus::Built;
+        if conf.archive_sources {
+            if let Err(e) = repo::archive::archive_sources(conf, root.as_ref(), package, &record) {
+                warn!("Unable to archive the sources of {}: {}", package, e);
+            }
+        }
+        if conf.qa.enabled {
+            match repo::qa::check_build(&conf.qa, root.as_ref(), &record.debs) {
+                Ok(findings) => package_result.qa = findings,
+                Err(e) => warn!("Unable to check the packages of {}: {}", package, e),
+            }
+        }
+        package_result.artifacts = record.debs;
+        checkpoint.progress = index + 1;
+        save_build_state(checkpoint)?;
         rollback_container(instance)?;
     }
 
-    Ok((0, 0))
+    Ok(())
 }
 
-pub fn packages_stage_select<S: AsRef<str>, K: Clone + ExactSizeIterator<Item = S>>(
-    instance: &str,
-    packages: K,
-    settings: BuildSettings,
+pub fn packages_stage_select(
+    request: &BuildRequest,
     start_package: Option<&String>,
-) -> Result<i32> {
-    let packages = expand_package_list(packages);
+) -> Result<BuildResult> {
+    let packages = &request.packages;
 
     let selection = if let Some(start_package) = start_package {
         packages
@@ -204,21 +555,18 @@ pub fn packages_stage_select<S: AsRef<str>, K: Clone + ExactSizeIterator<Item =
             .with_prompt(
                 "Choose a package to start building from (left/right arrow keys to change pages)",
             )
-            .items(&packages)
+            .items(packages)
             .interact()?
     };
-    let empty: Vec<&str> = Vec::new();
 
     package_build(
-        instance,
-        empty.into_iter(),
+        request,
         Some(BuildCheckPoint {
-            packages,
+            packages: packages.clone(),
             progress: selection,
             time_elapsed: 0,
             attempts: 1,
         }),
-        settings,
     )
 }
 
@@ -236,6 +584,7 @@ pub fn package_fetch<S: AsRef<str>>(instance: &str, packages: &[S]) -> Result<i3
     mount_fs(instance)?;
     rollback_container(instance)?;
 
+    let _lock = sources::lock_shared()?;
     let mut cmd = vec!["/bin/acbs-build", "-g", "--"];
     cmd.extend(packages.iter().map(|p| p.as_ref()));
     let status = run_in_container(instance, &cmd)?;
@@ -243,19 +592,77 @@ pub fn package_fetch<S: AsRef<str>>(instance: &str, packages: &[S]) -> Result<i3
     Ok(status)
 }
 
-/// Build packages in the container
-pub fn package_build<S: AsRef<str>, K: Clone + ExactSizeIterator<Item = S>>(
-    instance: &str,
-    packages: K,
+/// Where the stub of autobuild for unpacking the sources is placed (in the instance)
+const UNPACK_STUB_DIR: &str = "var/tmp/ciel-unpack";
+/// The stub records the build directory prepared by ACBS, then stops the build
+const UNPACK_STUB: &str = "#!/bin/sh\necho \"$PWD\" > /var/tmp/ciel-unpack/directory\nexit 1\n";
+
+/// Fetch and unpack the sources of the packages into the build directories, without building.
+/// ACBS prepares the build directory of each package as usual, but the autobuild it invokes
+/// afterwards is replaced with a stub, so the build directories are kept for inspection
+pub fn package_unpack(instance: &str, packages: &[String]) -> Result<BuildResult> {
+    let mut result = BuildResult::new(instance, packages);
+    let start = Instant::now();
+    mount_fs(instance)?;
+    rollback_container(instance)?;
+    mount_fs(instance)?;
+    let stub_dir = Path::new(instance).join(UNPACK_STUB_DIR);
+    fs::create_dir_all(stub_dir.join("bin"))?;
+    let stub = stub_dir.join("bin/autobuild");
+    fs::write(&stub, UNPACK_STUB)?;
+    fs::set_permissions(&stub, fs::Permissions::from_mode(0o755))?;
+    let path = format!(
+        "PATH=/{}/bin:/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin",
+        UNPACK_STUB_DIR
+    );
+    for package in packages.iter() {
+        info!("Unpacking {}...", package);
+        let marker = stub_dir.join("directory");
+        fs::remove_file(&marker).ok();
+        let package_start = Instant::now();
+        let status = run_in_container(
+            instance,
+            &["/usr/bin/env", &path, "/bin/acbs-build", "--", package],
+        )?;
+        let package_result = result
+            .pending_mut(package)
+            .ok_or_else(|| anyhow!("{} is not in the build", package))?;
+        package_result.duration = Some(package_start.elapsed().as_secs());
+        match fs::read_to_string(&marker) {
+            Ok(directory) => {
+                let directory = directory.trim().to_owned();
+                info!("{}: sources unpacked in {}", package, directory);
+                package_result.status = PackageStatus::Unpacked { directory };
+            }
+            Err(_) => {
+                error!("Failed to unpack {} (status: {})", package, status);
+                package_result.status = PackageStatus::Failed {
+                    exit_status: status,
+                };
+                result.exit_status = if status == 0 { 1 } else { status };
+                break;
+            }
+        }
+    }
+    result.duration = start.elapsed().as_secs();
+
+    Ok(result)
+}
+
+/// Build packages in the container, the packages of the request are ignored when continuing
+/// from a checkpoint
+pub fn package_build(
+    request: &BuildRequest,
     state: Option<BuildCheckPoint>,
-    settings: BuildSettings,
-) -> Result<i32> {
+) -> Result<BuildResult> {
     let conf = config::read_config();
     if conf.is_err() {
         return Err(anyhow!("Please configure this workspace first!"));
     }
     let conf = conf.unwrap();
+    let instance = request.instance.as_str();
     let mut attempts = 1usize;
+    materialize_instance(instance)?;
 
     let packages = if let Some(p) = state {
         attempts = p.attempts + 1;
@@ -264,80 +671,364 @@ pub fn package_build<S: AsRef<str>, K: Clone + ExactSizeIterator<Item = S>>(
             attempts
         );
         p.packages[p.progress..].to_owned()
+    } else if request.with_deps {
+        plan_dependencies(instance, &conf, &request.packages)?
     } else {
-        expand_package_list(packages)
+        request.packages.clone()
     };
 
-    if settings.offline || std::env::var("CIEL_OFFLINE").is_ok() {
+    if !request.skip_preflight {
+        preflight::check_packages(&conf, &packages)?;
+    }
+    check_os_staleness(&conf, instance)?;
+    if request.block_network && !request.offline {
+        info!("Fetching source packages first ...");
+        package_fetch(instance, &packages)?;
+    }
+    if request.offline {
         info!("Preparing offline mode. Fetching source packages first ...");
         package_fetch(instance, &packages)?;
-        std::env::set_var("CIEL_OFFLINE", "ON");
-        // FIXME: does not work with current version of systemd
-        info!("Running in offline mode. Network access disabled.");
     }
 
-    if settings.stage2 {
+    let inst_config = config::InstanceConfig::load(&Path::new(CIEL_INST_DIR).join(instance))?;
+    if request.stage2 && !inst_config.bootstrap {
+        bail!(
+            "{} is not a bootstrap instance, stage 2 builds need one (see `ciel add --bootstrap`).",
+            instance
+        );
+    }
+    if !request.stage2 && inst_config.bootstrap {
+        bail!(
+            "{} is a bootstrap instance, only stage 2 builds (`--stage 2`) are allowed in it.",
+            instance
+        );
+    }
+    if request.stage2 {
         std::env::set_var("CIEL_STAGE2", "ON");
         info!("Running in stage 2 mode. ACBS and autobuild3 may behave differently.");
+    } else {
+        // e.g. inherited from the environment, but refused by the instance
+        std::env::remove_var("CIEL_STAGE2");
+    }
+    let priority = request.priority.unwrap_or(conf.build.priority);
+    // also resets the weights of a container used by a build at another priority
+    std::env::set_var("CIEL_PRIORITY", priority.name());
+    if priority != config::BuildPriority::Normal {
+        info!("Running the builds at the {} priority.", priority.name());
     }
 
+    run_hook(
+        Hook::PreBuild,
+        &HookContext {
+            instance: Some(instance),
+            packages: &packages,
+            exit_status: None,
+            target: None,
+        },
+    )?;
+    let hook_packages = packages.clone();
+    notify::notify(
+        &conf.notify,
+        &notify::BuildEvent {
+            event: "start",
+            instance,
+            packages: &hook_packages,
+            package: None,
+            duration: None,
+            excerpt: None,
+        },
+    );
+    let lock = sources::lock_shared()?;
+    let result = build_packages(request, &conf, packages, attempts);
+    drop(lock);
+    let failed = result.as_ref().ok().and_then(|r| r.failed());
+    notify::notify(
+        &conf.notify,
+        &notify::BuildEvent {
+            event: match &result {
+                Ok(r) if r.success() => "success",
+                _ => "failure",
+            },
+            instance,
+            packages: &hook_packages,
+            package: failed.map(|p| p.package.as_str()),
+            duration: result.as_ref().ok().map(|r| r.duration),
+            excerpt: failed
+                .and_then(|p| p.log.as_deref())
+                .and_then(|log| notify::log_excerpt(Path::new(log))),
+        },
+    );
+    if let Some(max_size) = conf.source_cache_size.as_deref().and_then(parse_size) {
+        if let Err(e) = sources::evict(max_size) {
+            warn!("Unable to evict the source cache: {}", e);
+        }
+    }
+    run_hook(
+        Hook::PostBuild,
+        &HookContext {
+            instance: Some(instance),
+            packages: &hook_packages,
+            exit_status: Some(result.as_ref().map_or(-1, |r| r.exit_status)),
+            target: None,
+        },
+    )?;
+
+    result
+}
+
+fn build_packages(
+    request: &BuildRequest,
+    conf: &config::CielConfig,
+    packages: Vec<String>,
+    attempts: usize,
+) -> Result<BuildResult> {
+    let instance = request.instance.as_str();
+    let mut result = BuildResult::new(instance, &packages);
+    if request.stage2 {
+        result.stage = 2;
+    }
+    let start = Instant::now();
     mount_fs(instance)?;
     rollback_container(instance)?;
+    let component = get_output_component(&conf);
+    if let Some(component) = &component {
+        info!("Building into component {}.", component);
+    }
+    let output_dir = get_output_directory(conf.sep_mount, component.as_deref());
+    let root = std::env::current_dir()?.join(output_dir);
 
     if !conf.local_repo {
         let mut cmd = vec!["/bin/acbs-build".to_string(), "--".to_string()];
         cmd.extend(packages.into_iter());
         let status = run_in_container(instance, &cmd)?;
-        return Ok(status);
+        // the packages built before a failure are kept
+        merge_staged(&root, instance)?;
+        if status == 0 && conf.prune_after_build {
+            prune_instance(instance)?;
+        }
+        // ACBS builds all the packages in one go, which one failed is unknown
+        if status == 0 {
+            result.set_pending(PackageStatus::Built);
+        }
+        result.exit_status = status;
+        result.duration = start.elapsed().as_secs();
+        return Ok(result);
     }
 
-    let output_dir = get_output_directory(conf.sep_mount);
-    let root = std::env::current_dir()?.join(output_dir);
-    let total = packages.len();
-    let start = Instant::now();
-    let (exit_status, progress) = package_build_inner(&packages, instance, root)?;
-    if exit_status != 0 {
-        let checkpoint = BuildCheckPoint {
-            packages,
-            progress,
-            attempts,
-            time_elapsed: 0,
-        };
+    let remaining = check_local_repo(conf, &root, packages.clone(), request.skip_built)?;
+    if remaining.len() < packages.len() {
+        result.set_pending(PackageStatus::Skipped);
+        for package in remaining.iter() {
+            if let Some(skipped) = result
+                .packages
+                .iter_mut()
+                .find(|p| &p.package == package && p.status == PackageStatus::Skipped)
+            {
+                skipped.status = PackageStatus::Pending;
+            }
+        }
+    }
+    if remaining.is_empty() {
+        info!("All the packages are already built.");
+        return Ok(result);
+    }
+    let total = remaining.len();
+    let mut checkpoint = BuildCheckPoint {
+        packages: remaining,
+        progress: 0,
+        attempts,
+        time_elapsed: 0,
+    };
+    save_build_state(&checkpoint)?;
+    package_build_inner(
+        request,
+        conf,
+        &checkpoint.packages.clone(),
+        instance,
+        root,
+        &mut result,
+        &mut checkpoint,
+    )?;
+    result.duration = start.elapsed().as_secs();
+    if !result.success() {
         dump_build_checkpoint(&checkpoint)?;
-        return Ok(exit_status);
+        info!("Run `ciel build --resume` to continue from the failed package.");
+        return Ok(result);
     }
-    let duration = start.elapsed().as_secs();
+    fs::remove_file(BUILD_STATE).ok();
     eprintln!(
         "{} - {} packages in {}",
         style("BUILD SUCCESSFUL").bold().green(),
         total,
-        format_duration(duration)
+        format_duration(result.duration)
     );
 
-    Ok(0)
+    Ok(result)
 }
 
-/// Clean up output directories
-pub fn cleanup_outputs() -> Result<()> {
-    let spinner = create_spinner("Removing output directories ...", 200);
+/// Order the packages by their dependencies, including the dependencies in the trees newer than
+/// the versions available to the instance (from the APT repositories or the local repository)
+fn plan_dependencies(
+    instance: &str,
+    conf: &config::CielConfig,
+    packages: &[String],
+) -> Result<Vec<String>> {
+    info!("Planning the build order...");
+    mount_fs(instance)?;
+    let mut indices = Vec::new();
+    if let Ok(lists) = fs::read_dir(Path::new(instance).join("var/lib/apt/lists")) {
+        for list in lists.filter_map(|l| l.ok()) {
+            if list.file_name().to_string_lossy().ends_with("_Packages") {
+                indices.push(list.path());
+            }
+        }
+    }
+    if conf.local_repo {
+        let component = get_output_component(conf);
+        let output_dir = get_output_directory(conf.sep_mount, component.as_deref());
+        indices.push(Path::new(&output_dir).join("debs/Packages"));
+    }
+    let available = repo::query::available_versions(&indices);
+    let planned = tree::plan_build(conf, packages, |spec| match &spec.version {
+        Some(version) => available
+            .get(&spec.name)
+            .map_or(true, |a| compare_versions(a, version) == Ordering::Less),
+        None => false,
+    })?;
+    info!("{} packages to build.", planned.len());
+
+    Ok(planned)
+}
+
+/// Check the packages to build against the local repository: the packages already built at the
+/// same or a newer version are reported (and skipped with `skip_built`), and the repository is
+/// refreshed first if some of the dependencies are built but not indexed yet
+fn check_local_repo(
+    conf: &config::CielConfig,
+    root: &Path,
+    packages: Vec<String>,
+    skip_built: bool,
+) -> Result<Vec<String>> {
+    let specs = packages
+        .iter()
+        .map(|p| tree::find_package(conf, p).and_then(|dir| tree::read_package_spec(&dir).ok()))
+        .collect::<Vec<_>>();
+    let indexed = repo::query::read_index(root)
+        .map(|entries| entries.into_iter().map(|e| e.filename).collect())
+        .unwrap_or_else(|_| HashSet::new());
+    let debs = root.join("debs");
+    let mut unindexed = HashSet::new();
+    for entry in WalkDir::new(&debs).into_iter().filter_map(|e| e.ok()) {
+        let name = entry.file_name().to_string_lossy();
+        if !name.ends_with(".deb") {
+            continue;
+        }
+        let rel_path = entry.path().strip_prefix(&debs)?.to_string_lossy();
+        if !indexed.contains(&*rel_path) {
+            unindexed.insert(name.split('_').next().unwrap_or_default().to_owned());
+        }
+    }
+    let stale = specs
+        .iter()
+        .flatten()
+        .flat_map(|spec| spec.build_deps.iter())
+        .filter(|dep| unindexed.contains(dep.as_str()))
+        .collect::<BTreeSet<_>>();
+    if !stale.is_empty() {
+        warn!(
+            "Dependencies not indexed in the local repository yet: {}",
+            stale.into_iter().cloned().collect::<Vec<_>>().join(", ")
+        );
+        info!("Refreshing local repository...");
+        repo::refresh_repo(root)?;
+    }
+    let mut built: HashMap<String, String> = HashMap::new();
+    for entry in repo::query::read_index(root).unwrap_or_default() {
+        match built.get(&entry.package) {
+            Some(version) if compare_versions(version, &entry.version) != Ordering::Less => (),
+            _ => {
+                built.insert(entry.package, entry.version);
+            }
+        }
+    }
+    let mut remaining = Vec::new();
+    for (package, spec) in packages.into_iter().zip(specs) {
+        let spec = match spec {
+            Some(spec) => spec,
+            None => {
+                remaining.push(package);
+                continue;
+            }
+        };
+        let existing = built.get(&spec.name);
+        let up_to_date = match (&spec.version, existing) {
+            (Some(version), Some(existing)) => {
+                compare_versions(existing, version) != Ordering::Less
+            }
+            _ => false,
+        };
+        if !up_to_date {
+            remaining.push(package);
+            continue;
+        }
+        let existing = existing.unwrap();
+        if skip_built {
+            info!("Skipping {}: {} is already built.", package, existing);
+        } else {
+            warn!(
+                "{} {} is already in the local repository, it will be built again.",
+                spec.name, existing
+            );
+            remaining.push(package);
+        }
+    }
+
+    Ok(remaining)
+}
+
+/// Plan cleaning up the output directories
+pub fn cleanup_plan() -> Result<Plan> {
+    let mut plan = Plan::new();
     for entry in WalkDir::new(".").max_depth(1) {
         let entry = entry?;
         if entry.file_type().is_dir() && entry.file_name().to_string_lossy().starts_with("OUTPUT-")
         {
-            fs::remove_dir_all(entry.path())?;
+            plan.push(Step::RemoveDir(entry.path().to_path_buf()));
         }
     }
-    if Path::new("./SRCS").is_dir() {
-        fs::remove_dir_all("./SRCS")?;
-    }
-    if Path::new("./STATES").is_dir() {
-        fs::remove_dir_all("./STATES")?;
+    for dir in [sources::SOURCE_CACHE, "./STATES"] {
+        if Path::new(dir).is_dir() {
+            plan.push(Step::RemoveDir(PathBuf::from(dir)));
+        }
     }
-    spinner.finish_with_message("Done.");
+
+    Ok(plan)
+}
+
+/// Clean up output directories
+pub fn cleanup_outputs() -> Result<()> {
+    let plan = cleanup_plan()?;
+    let spinner = Progress::spinner("Removing output directories ...");
+    // the source cache is not removed while it is used
+    let _lock = if Path::new(sources::SOURCE_CACHE).is_dir() {
+        Some(sources::lock_exclusive()?)
+    } else {
+        None
+    };
+    plan.execute()?;
+    spinner.finish();
 
     Ok(())
 }
 
+#[test]
+fn test_parse_package_list() {
+    let list = "# comment\nfoo\n\n  bar  \nfoo\n";
+    let packages = parse_package_list(std::io::Cursor::new(list), 0).unwrap();
+    assert_eq!(packages, vec!["foo", "bar", "foo"]);
+    assert_eq!(dedup_packages(packages), vec!["foo", "bar"]);
+}
+
 #[test]
 fn test_time_format() {
     let test_dur = 3661;
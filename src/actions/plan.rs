@@ -0,0 +1,240 @@
+use anyhow::Result;
+use console::style;
+use indicatif::HumanBytes;
+use nix::unistd::sync;
+use std::{fmt, fs, path::PathBuf};
+
+use crate::{
+    common::CIEL_DATA_DIR,
+    config, dist, error, info, machine, overlayfs,
+    progress::Progress,
+    transfer,
+    trash::{self, TrashEntry},
+};
+
+use super::container::{remove_mount, unmount_fs};
+
+/// A step of a destructive operation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Step {
+    /// Terminate the container (instance name, container name)
+    Terminate(String, String),
+    /// Un-mount the filesystem of the instance (instance name, mount point)
+    Unmount(String, PathBuf),
+    /// Remove the empty mount point of the instance (instance name, mount point)
+    RemoveMountPoint(String, PathBuf),
+    /// Merge the upper layer of the instance into the base system
+    Commit(String),
+    /// Remove the upper layer of the instance (instance name, directories or datasets removed,
+    /// patterns of the paths preserved)
+    Rollback(String, Vec<String>, Vec<String>),
+    /// Remove the instance with its layers (instance name, instance directory)
+    Destroy(String, PathBuf),
+    /// Remove the junk files from the instance layers
+    Prune(String),
+    /// Make the base system writable
+    Unlock,
+    /// Remove the directory and everything in it
+    RemoveDir(PathBuf),
+    /// Move the workspace data into the trash (workspace directory, retention in seconds)
+    Trash(PathBuf, u64),
+    /// Delete the removed workspace in the trash permanently
+    PurgeTrash(TrashEntry),
+}
+
+/// Remove the upper layer of the instance
+fn rollback_layers(instance: &str) -> Result<()> {
+    info!("{}: rolling back instance...", instance);
+    let spinner = Progress::spinner("Removing upper layer...");
+    let man = &mut *overlayfs::get_layer_manager(instance)?;
+    man.rollback()?;
+    sync();
+    spinner.finish();
+
+    Ok(())
+}
+
+impl Step {
+    fn execute(&self) -> Result<()> {
+        match self {
+            Step::Terminate(instance, ns_name) => {
+                info!("{}: stopping...", instance);
+                machine::terminate_container_by_name(ns_name)?;
+                machine::clean_child_process();
+                info!("{}: instance stopped.", instance);
+            }
+            Step::Unmount(instance, _) => unmount_fs(instance)?,
+            Step::RemoveMountPoint(instance, _) => remove_mount(instance)?,
+            Step::Commit(instance) => {
+                info!("{}: committing instance...", instance);
+                let spinner = Progress::spinner("Committing upper layer...");
+                let man = &mut *overlayfs::get_layer_manager(instance)?;
+                dist::with_unlocked(|| man.commit())?;
+                sync();
+                spinner.finish();
+            }
+            Step::Rollback(instance, _, keep) if keep.is_empty() => rollback_layers(instance)?,
+            Step::Rollback(instance, _, keep) => {
+                // on the same filesystem as the instances, so the files can be reflinked
+                let saved_dir = tempfile::Builder::new()
+                    .prefix("rollback-keep.")
+                    .tempdir_in(CIEL_DATA_DIR)?;
+                let saved = transfer::save_matching(instance, keep, saved_dir.path())?;
+                let result = rollback_layers(instance)
+                    .and_then(|_| transfer::restore_saved(instance, saved_dir.path(), &saved));
+                if let Err(e) = result {
+                    let saved_dir = saved_dir.into_path();
+                    error!(
+                        "{}: the preserved files are left in {}",
+                        instance,
+                        saved_dir.display()
+                    );
+                    return Err(e);
+                }
+                info!("{}: {} path(s) preserved.", instance, saved.len());
+            }
+            Step::Destroy(instance, _) => {
+                info!("{}: removing instance...", instance);
+                let spinner = Progress::spinner("Removing the instance...");
+                let man = &mut *overlayfs::get_layer_manager(instance)?;
+                man.destroy()?;
+                spinner.finish();
+            }
+            Step::Prune(instance) => {
+                let paths = match config::read_config() {
+                    Ok(c) => c.prune_paths,
+                    Err(_) => config::default_prune_paths(),
+                };
+                info!("{}: pruning instance...", instance);
+                let man = &mut *overlayfs::get_layer_manager(instance)?;
+                let freed = man.prune(&paths)?;
+                info!("{}: {} freed.", instance, HumanBytes(freed));
+            }
+            Step::Unlock => dist::unlock()?,
+            Step::RemoveDir(path) => fs::remove_dir_all(path)?,
+            Step::Trash(workspace, retention) => {
+                let entry = trash::move_to_trash(workspace, *retention)?;
+                info!("Workspace moved into {}.", entry.path.display());
+            }
+            Step::PurgeTrash(entry) => trash::purge(entry)?,
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Step::Terminate(instance, ns_name) => {
+                write!(f, "terminate machine {} ({})", ns_name, instance)
+            }
+            Step::Unmount(_, target) => write!(f, "un-mount {}", target.display()),
+            Step::RemoveMountPoint(_, target) => {
+                write!(f, "remove mount point {}", target.display())
+            }
+            Step::Commit(instance) => {
+                write!(
+                    f,
+                    "commit the upper layer of {} into the base system",
+                    instance
+                )
+            }
+            Step::Rollback(instance, targets, keep) if keep.is_empty() => write!(
+                f,
+                "delete the upper layer of {}: {}",
+                instance,
+                targets.join(", ")
+            ),
+            Step::Rollback(instance, targets, keep) => write!(
+                f,
+                "delete the upper layer of {}: {} (preserving the paths matching {})",
+                instance,
+                targets.join(", "),
+                keep.join(", ")
+            ),
+            Step::Destroy(instance, dir) => {
+                write!(f, "delete instance {}: {}", instance, dir.display())
+            }
+            Step::Prune(instance) => write!(f, "prune the junk files from {}", instance),
+            Step::Unlock => write!(f, "make the base system writable"),
+            Step::RemoveDir(path) => write!(f, "delete {}", path.display()),
+            Step::Trash(workspace, retention) => write!(
+                f,
+                "move {} into {} (restorable for {})",
+                workspace.join(".ciel").display(),
+                workspace.join(trash::TRASH_DIR).display(),
+                trash::describe_retention(*retention)
+            ),
+            Step::PurgeTrash(entry) => {
+                write!(f, "delete the expired trash {}", entry.path.display())
+            }
+        }
+    }
+}
+
+/// The steps of a destructive operation, to be either printed (`--dry-run`) or executed
+#[derive(Debug, Default)]
+pub struct Plan {
+    steps: Vec<Step>,
+}
+
+impl Plan {
+    pub fn new() -> Self {
+        Plan::default()
+    }
+
+    pub fn push(&mut self, step: Step) {
+        self.steps.push(step);
+    }
+
+    /// Append the steps of another plan, skipping the steps already planned
+    pub fn extend(&mut self, other: Plan) {
+        for step in other.steps {
+            if !self.steps.contains(&step) {
+                self.steps.push(step);
+            }
+        }
+    }
+
+    /// Print the steps without executing them
+    pub fn print(&self) {
+        if self.steps.is_empty() {
+            info!("Nothing to do.");
+            return;
+        }
+        for step in self.steps.iter() {
+            info!("Would {}", step);
+        }
+    }
+
+    /// Execute the steps in order, stopping at the first failure
+    pub fn execute(&self) -> Result<()> {
+        for step in self.steps.iter() {
+            step.execute()?;
+        }
+
+        Ok(())
+    }
+}
+
+#[test]
+fn test_plan_extend() {
+    let mut plan = Plan::new();
+    plan.push(Step::Unmount("main".to_owned(), PathBuf::from("/ws/main")));
+    let mut other = Plan::new();
+    other.push(Step::Unmount("main".to_owned(), PathBuf::from("/ws/main")));
+    other.push(Step::Commit("main".to_owned()));
+    plan.extend(other);
+    assert_eq!(
+        plan.steps,
+        vec![
+            Step::Unmount("main".to_owned(), PathBuf::from("/ws/main")),
+            Step::Commit("main".to_owned()),
+        ]
+    );
+    assert_eq!(
+        plan.steps[1].to_string(),
+        "commit the upper layer of main into the base system"
+    );
+}
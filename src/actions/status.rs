@@ -0,0 +1,222 @@
+use anyhow::Result;
+use console::style;
+use indicatif::HumanBytes;
+use serde::Serialize;
+use std::{collections::HashMap, ffi::OsStr, fs, io::Write, path::Path};
+use tabwriter::TabWriter;
+use walkdir::WalkDir;
+
+use crate::{
+    common::CIEL_DIST_DIR,
+    config, diagnose,
+    machine::{self, CielInstance},
+    output,
+    progress::Progress,
+    trash::describe_retention,
+};
+
+use super::container::{get_output_directory, os_age};
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct OsStatus {
+    name: Option<String>,
+    version: Option<String>,
+    /// Seconds since the base system was last updated
+    age: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct TreeStatus {
+    branch: Option<String>,
+    commit: String,
+    summary: String,
+    /// The tree has uncommitted changes (including the untracked files)
+    dirty: bool,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct OutputStatus {
+    directory: String,
+    packages: usize,
+    size: u64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct WorkspaceStatus {
+    os: OsStatus,
+    tree: Option<TreeStatus>,
+    output: OutputStatus,
+    instances: Vec<CielInstance>,
+    /// Problems found by the checks of `ciel doctor`
+    issues: Vec<String>,
+}
+
+/// Parse the os-release(5) file into the variables
+fn parse_os_release(content: &str) -> HashMap<String, String> {
+    content
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| {
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .unwrap_or(value);
+            (key.trim().to_owned(), value.to_owned())
+        })
+        .collect()
+}
+
+fn os_status() -> OsStatus {
+    let dist = Path::new(CIEL_DIST_DIR);
+    let release = fs::read_to_string(dist.join("etc/os-release"))
+        .or_else(|_| fs::read_to_string(dist.join("usr/lib/os-release")))
+        .map(|content| parse_os_release(&content))
+        .unwrap_or_default();
+
+    OsStatus {
+        name: release
+            .get("PRETTY_NAME")
+            .or_else(|| release.get("NAME"))
+            .cloned(),
+        version: release
+            .get("VERSION")
+            .or_else(|| release.get("VERSION_ID"))
+            .cloned(),
+        age: os_age(),
+    }
+}
+
+fn tree_status() -> Option<TreeStatus> {
+    let repo = git2::Repository::open("TREE").ok()?;
+    let head = repo.head().ok()?;
+    let commit = head.peel_to_commit().ok()?;
+    let mut options = git2::StatusOptions::new();
+    options.include_untracked(true);
+    let dirty = repo
+        .statuses(Some(&mut options))
+        .map(|s| !s.is_empty())
+        .unwrap_or(true);
+
+    Some(TreeStatus {
+        branch: head
+            .shorthand()
+            .filter(|_| head.is_branch())
+            .map(str::to_owned),
+        commit: commit.id().to_string(),
+        summary: commit.summary().unwrap_or_default().to_owned(),
+        dirty,
+    })
+}
+
+fn output_status(sep_mount: bool) -> OutputStatus {
+    let directory = get_output_directory(sep_mount, None);
+    let debs = WalkDir::new(&directory)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file() && e.path().extension() == Some(OsStr::new("deb")))
+        .filter_map(|e| e.metadata().ok())
+        .map(|m| m.len())
+        .collect::<Vec<_>>();
+
+    OutputStatus {
+        directory,
+        packages: debs.len(),
+        size: debs.iter().sum(),
+    }
+}
+
+/// Show the overview of the workspace: the base system, the tree, the built packages, the
+/// instances, and the problems found by the checks of `ciel doctor`
+pub fn workspace_status() -> Result<()> {
+    let spinner = Progress::spinner("Inspecting the workspace...");
+    let sep_mount = config::read_config().map_or(true, |c| c.sep_mount);
+    let mut issues = diagnose::pending_issues();
+    let instances = machine::list_instances().unwrap_or_else(|e| {
+        issues.push(format!("Unable to inspect the instances: {}", e));
+        Vec::new()
+    });
+    let status = WorkspaceStatus {
+        os: os_status(),
+        tree: tree_status(),
+        output: output_status(sep_mount),
+        instances,
+        issues,
+    };
+    spinner.finish();
+    if output::json_output() {
+        return output::print_json(&status);
+    }
+
+    let mut formatter = TabWriter::new(std::io::stderr());
+    let os = match (&status.os.name, &status.os.version) {
+        (Some(name), Some(version)) if !name.contains(version.as_str()) => {
+            format!("{} ({})", name, version)
+        }
+        (Some(name), _) => name.clone(),
+        _ => style("(not loaded)").dim().to_string(),
+    };
+    let updated = status.os.age.map_or_else(
+        || "never updated".to_owned(),
+        |age| format!("updated {} ago", describe_retention(age)),
+    );
+    writeln!(&mut formatter, "Base system:\t{}, {}", os, updated)?;
+    match &status.tree {
+        Some(tree) => writeln!(
+            &mut formatter,
+            "Tree:\t{} at {}{} {}",
+            tree.branch.as_deref().unwrap_or("(detached)"),
+            &tree.commit[..tree.commit.len().min(10)],
+            if tree.dirty {
+                style(" (dirty)").yellow().to_string()
+            } else {
+                String::new()
+            },
+            style(&tree.summary).dim()
+        )?,
+        None => writeln!(&mut formatter, "Tree:\t{}", style("(not loaded)").dim())?,
+    }
+    writeln!(
+        &mut formatter,
+        "Output:\t{} package(s), {} in {}",
+        status.output.packages,
+        HumanBytes(status.output.size),
+        status.output.directory
+    )?;
+    formatter.flush()?;
+    eprintln!();
+    if status.instances.is_empty() {
+        eprintln!("{}", style("No instances.").dim());
+    } else {
+        machine::write_instances(&status.instances, std::io::stderr())?;
+    }
+    eprintln!();
+    if status.issues.is_empty() {
+        eprintln!(
+            "{} {}",
+            style("✓").green(),
+            style("No issues found").green()
+        );
+    }
+    for issue in status.issues.iter() {
+        eprintln!("{} {}", style("!").yellow(), style(issue).yellow().bold());
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_parse_os_release() {
+    let release = parse_os_release(
+        "# comment\nNAME=\"AOSC OS\"\nPRETTY_NAME=\"AOSC OS (11.4.0)\"\nVERSION_ID=11.4.0\n",
+    );
+    assert_eq!(release["NAME"], "AOSC OS");
+    assert_eq!(release["PRETTY_NAME"], "AOSC OS (11.4.0)");
+    assert_eq!(release["VERSION_ID"], "11.4.0");
+    assert!(!release.contains_key("# comment"));
+}
@@ -0,0 +1,204 @@
+use anyhow::{anyhow, bail, Result};
+use console::style;
+use git2::Repository;
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+use crate::{
+    config::{self, AptRepository},
+    info,
+    network::{fetch_repo, git_switch_branch, is_offline},
+    warn,
+};
+
+use super::container::{apply_base_config, is_valid_component_name};
+
+/// The topic being worked on, and what to restore when done
+const TOPIC_STATE: &str = ".ciel/data/topic.toml";
+/// Where the topic repositories of AOSC OS are published
+const TOPIC_REPO_URL: &str = "https://repo.aosc.io/debs/";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct TopicState {
+    name: String,
+    /// The branch of the tree before switching to the topic (None if detached)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    previous_branch: Option<String>,
+    /// The OUTPUT component set for the topic, if the output is not separated by branches
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    component: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    previous_component: Option<String>,
+}
+
+#[inline]
+fn repository_name(topic: &str) -> String {
+    format!("topic-{}", topic)
+}
+
+/// The APT repository the packages of the topic are published into
+fn topic_repository(topic: &str) -> AptRepository {
+    AptRepository {
+        url: TOPIC_REPO_URL.to_owned(),
+        suite: topic.to_owned(),
+        components: vec!["main".to_owned()],
+        pin_priority: None,
+        signed_by: None,
+    }
+}
+
+fn read_state() -> Result<Option<TopicState>> {
+    match fs::read_to_string(TOPIC_STATE) {
+        Ok(content) => Ok(Some(toml::from_str(&content)?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn open_tree() -> Result<Repository> {
+    let repo = if is_offline() {
+        Repository::open("TREE")?
+    } else {
+        fetch_repo("TREE")?
+    };
+    if repo.state() != git2::RepositoryState::Clean {
+        bail!("Cannot switch branches, because your tree seems to have an operation in progress.");
+    }
+
+    Ok(repo)
+}
+
+/// Start working on the topic: switch the tree to the topic branch, add the topic repository
+/// into the base system, and build into an OUTPUT component named after the topic
+pub fn topic_start(name: &str) -> Result<()> {
+    if let Some(state) = read_state()? {
+        bail!(
+            "Already working on topic `{}`, please finish it first with `ciel topic --finish`.",
+            state.name
+        );
+    }
+    if !is_valid_component_name(name) {
+        bail!("Invalid topic name `{}`.", name);
+    }
+    let mut repo = open_tree()?;
+    if repo.find_branch(name, git2::BranchType::Local).is_err()
+        && repo
+            .find_branch(&format!("origin/{}", name), git2::BranchType::Remote)
+            .is_err()
+    {
+        bail!("Topic branch `{}` is not found in the tree.", name);
+    }
+    let previous_branch = {
+        let head = repo.head()?;
+        head.shorthand()
+            .filter(|_| head.is_branch())
+            .map(str::to_owned)
+    };
+    if previous_branch.as_deref() == Some(name) {
+        info!("The tree is already on {}.", name);
+    } else if git_switch_branch(&mut repo, name, None)? {
+        info!(
+            "Switched the ABBS tree to {} (with your uncommitted changes).",
+            name
+        );
+    } else {
+        info!("Switched the ABBS tree to {}.", name);
+    }
+
+    let mut config = config::read_config_file()?;
+    config
+        .repositories
+        .insert(repository_name(name), topic_repository(name));
+    let previous_component = config.output_component.clone();
+    let component = if config.sep_mount {
+        // the output is already separated by the branch
+        None
+    } else {
+        config.output_component = Some(name.to_owned());
+        Some(name.to_owned())
+    };
+    apply_base_config(&config)?;
+    let state = TopicState {
+        name: name.to_owned(),
+        previous_branch,
+        component,
+        previous_component,
+    };
+    fs::write(TOPIC_STATE, toml::to_string(&state)?)?;
+    info!("Now working on topic `{}`.", name);
+
+    Ok(())
+}
+
+/// Finish working on the topic, restoring the branch of the tree, the repositories and the
+/// OUTPUT component used before
+pub fn topic_finish() -> Result<()> {
+    let state = read_state()?.ok_or_else(|| anyhow!("Not working on any topic."))?;
+    match &state.previous_branch {
+        Some(branch) if branch != &state.name => {
+            let mut repo = Repository::open("TREE")?;
+            if repo.state() != git2::RepositoryState::Clean {
+                bail!("Cannot switch branches, because your tree seems to have an operation in progress.");
+            }
+            git_switch_branch(&mut repo, branch, None)?;
+            info!("Switched the ABBS tree back to {}.", branch);
+        }
+        Some(_) => (),
+        None => warn!("The tree was not on a branch before the topic, leaving it as is."),
+    }
+
+    let mut config = config::read_config_file()?;
+    config.repositories.remove(&repository_name(&state.name));
+    // unless it has been changed since
+    if state.component.is_some() && config.output_component == state.component {
+        config.output_component = state.previous_component.clone();
+    }
+    apply_base_config(&config)?;
+    fs::remove_file(TOPIC_STATE)?;
+    info!("Finished working on topic `{}`.", state.name);
+
+    Ok(())
+}
+
+/// Show the topic being worked on
+pub fn topic_status() -> Result<()> {
+    match read_state()? {
+        Some(state) => {
+            info!("Working on topic `{}`.", state.name);
+            info!(
+                "Packages are installed from: {}",
+                topic_repository(&state.name).source_line(&repository_name(&state.name))
+            );
+            if let Some(component) = &state.component {
+                info!(
+                    "Packages are built into the OUTPUT component `{}`.",
+                    component
+                );
+            }
+        }
+        None => info!("Not working on any topic."),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_topic_state() {
+    let state = TopicState {
+        name: "gcc-14".to_owned(),
+        previous_branch: Some("stable".to_owned()),
+        component: Some("gcc-14".to_owned()),
+        previous_component: None,
+    };
+    let content = toml::to_string(&state).unwrap();
+    assert_eq!(
+        content,
+        "name = \"gcc-14\"\nprevious-branch = \"stable\"\ncomponent = \"gcc-14\"\n"
+    );
+    assert_eq!(toml::from_str::<TopicState>(&content).unwrap(), state);
+    assert_eq!(
+        topic_repository("gcc-14").source_line(&repository_name("gcc-14")),
+        "deb https://repo.aosc.io/debs/ gcc-14 main"
+    );
+}
@@ -0,0 +1,136 @@
+//! This module contains the activity tracking of the instances
+
+use anyhow::Result;
+use nix::fcntl::{flock, FlockArg};
+use std::{
+    fs,
+    os::unix::io::AsRawFd,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::common::CIEL_INST_DIR;
+
+const ACTIVITY_FILE: &str = "activity";
+
+#[inline]
+fn activity_file(instance: &str) -> PathBuf {
+    Path::new(CIEL_INST_DIR).join(instance).join(ACTIVITY_FILE)
+}
+
+#[inline]
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+fn open(path: &Path) -> Result<fs::File> {
+    Ok(fs::File::options()
+        .create(true)
+        .read(true)
+        .write(true)
+        .open(path)?)
+}
+
+#[inline]
+fn record(path: &Path) -> Result<()> {
+    fs::write(path, now().to_string())?;
+
+    Ok(())
+}
+
+#[inline]
+fn read_record(path: &Path) -> Option<u64> {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+}
+
+/// Record the activity in the instance now
+pub fn touch(instance: &str) -> Result<()> {
+    record(&activity_file(instance))
+}
+
+/// A command running in the instance, the activity is recorded again when dropped
+pub struct Activity {
+    path: PathBuf,
+    _file: fs::File,
+}
+
+impl Activity {
+    fn begin_at(path: PathBuf) -> Result<Self> {
+        let file = open(&path)?;
+        flock(file.as_raw_fd(), FlockArg::LockShared)?;
+        record(&path)?;
+
+        Ok(Activity { path, _file: file })
+    }
+
+    /// Record the command starting in the instance, blocks while the instance is being stopped
+    /// by the idle sweep
+    pub fn begin(instance: &str) -> Result<Self> {
+        Activity::begin_at(activity_file(instance))
+    }
+}
+
+impl Drop for Activity {
+    fn drop(&mut self) {
+        record(&self.path).ok();
+    }
+}
+
+/// An idle instance locked against new commands until dropped
+pub struct IdleLock {
+    /// How long the instance has been idle, in seconds
+    pub idle: u64,
+    _file: fs::File,
+}
+
+/// Lock the instance if it has been idle for at least `threshold` seconds. Returns None if
+/// a command is running in it or it has been active more recently. An instance with no
+/// activity recorded (e.g. started by an older version) is considered active now
+pub fn lock_idle(instance: &str, threshold: u64) -> Result<Option<IdleLock>> {
+    lock_idle_at(&activity_file(instance), threshold)
+}
+
+fn lock_idle_at(path: &Path, threshold: u64) -> Result<Option<IdleLock>> {
+    let file = open(path)?;
+    match flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+        Ok(()) => (),
+        Err(nix::Error::EWOULDBLOCK) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let last_active = match read_record(path) {
+        Some(last_active) => last_active,
+        None => {
+            record(path)?;
+            return Ok(None);
+        }
+    };
+    // the clock may have gone backwards
+    let idle = now().saturating_sub(last_active);
+    if idle < threshold {
+        return Ok(None);
+    }
+
+    Ok(Some(IdleLock { idle, _file: file }))
+}
+
+#[test]
+fn test_lock_idle() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join(ACTIVITY_FILE);
+    // nothing recorded yet
+    assert!(lock_idle_at(&path, 0).unwrap().is_none());
+    assert!(read_record(&path).is_some());
+    fs::write(&path, (now() - 600).to_string()).unwrap();
+    assert!(lock_idle_at(&path, 3600).unwrap().is_none());
+    assert!(lock_idle_at(&path, 300).unwrap().unwrap().idle >= 600);
+    let activity = Activity::begin_at(path.clone()).unwrap();
+    fs::write(&path, (now() - 600).to_string()).unwrap();
+    // a command is running
+    assert!(lock_idle_at(&path, 300).unwrap().is_none());
+    drop(activity);
+    assert!(lock_idle_at(&path, 300).unwrap().is_none());
+}
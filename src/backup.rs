@@ -0,0 +1,424 @@
+//! This module contains the workspace backups, for migrating the workspace to another machine
+
+use anyhow::{anyhow, bail, Result};
+use console::style;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    actions,
+    common::{ciel_init, get_host_arch_name, CIEL_DATA_DIR, CIEL_INST_DIR},
+    config::{self, InstanceConfig},
+    info,
+    network::{clone_repo, CloneOptions},
+    overlayfs,
+    progress::{Progress, Unit},
+    tree, warn,
+};
+
+const BACKUP_FORMAT_VERSION: usize = 1;
+const BACKUP_MANIFEST_NAME: &str = "ciel-backup.toml";
+const CONFIG_NAME: &str = "config.toml";
+/// Options of tar(1) keeping the attributes of the files in the layers (e.g. the whiteouts)
+const LAYER_TAR_OPTIONS: &[&str] = &["--xattrs", "--xattrs-include=*", "--numeric-owner"];
+
+/// Revision of the ABBS tree
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct TreeRevision {
+    url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    branch: Option<String>,
+    commit: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct BackupManifest {
+    version: usize,
+    /// When the backup was made, in seconds since the epoch
+    created: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    arch: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tree: Option<TreeRevision>,
+    instances: Vec<String>,
+    /// The instances whose upper layers are included
+    #[serde(default)]
+    layers: Vec<String>,
+}
+
+#[inline]
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+fn tree_revision(config: Option<&config::CielConfig>) -> Option<TreeRevision> {
+    let repo = git2::Repository::open("TREE").ok()?;
+    let head = repo.head().ok()?;
+    let commit = head.peel_to_commit().ok()?.id().to_string();
+    let url = repo
+        .find_remote("origin")
+        .ok()
+        .and_then(|remote| remote.url().map(str::to_owned))
+        .or_else(|| config.map(|c| tree::tree_url(c).to_owned()))?;
+
+    Some(TreeRevision {
+        url,
+        branch: head
+            .shorthand()
+            .filter(|_| head.is_branch())
+            .map(str::to_owned),
+        commit,
+    })
+}
+
+/// Return the names of the instances, sorted
+fn instance_names() -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    for entry in fs::read_dir(CIEL_INST_DIR)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            names.push(entry.file_name().to_string_lossy().to_string());
+        }
+    }
+    names.sort();
+
+    Ok(names)
+}
+
+fn append_data<W: Write>(builder: &mut tar::Builder<W>, name: &str, data: &[u8]) -> Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(now());
+    builder.append_data(&mut header, name, data)?;
+
+    Ok(())
+}
+
+/// Start packing the upper layer into a tarball, written to the standard output of tar(1)
+fn spawn_layer_tar(upper: &Path) -> Result<Child> {
+    Ok(Command::new("tar")
+        .args(LAYER_TAR_OPTIONS)
+        .arg("-C")
+        .arg(upper)
+        .arg("-cf")
+        .arg("-")
+        .arg(".")
+        .stdout(Stdio::piped())
+        .spawn()?)
+}
+
+/// Return the upper layer of the instance and the size of its tarball. The size is needed
+/// before the tarball is streamed into the backup, so the layer is packed twice instead of
+/// being kept in a temporary file
+fn measure_layer(instance: &str) -> Result<Option<(PathBuf, u64)>> {
+    let man = &mut *overlayfs::get_layer_manager(instance)?;
+    let upper = match man.get_upper_layer()? {
+        Some(upper) => upper,
+        None => return Ok(None),
+    };
+    let mut child = spawn_layer_tar(&upper)?;
+    let size = io::copy(child.stdout.as_mut().unwrap(), &mut io::sink())?;
+    let status = child.wait()?;
+    if !status.success() {
+        bail!(
+            "Unable to pack the upper layer of {}: tar exited with {}",
+            instance,
+            status
+        );
+    }
+
+    Ok(Some((upper, size)))
+}
+
+/// Stream the tarball of the upper layer into the backup
+fn append_layer<W: Write>(
+    builder: &mut tar::Builder<W>,
+    instance: &str,
+    upper: &Path,
+    size: u64,
+) -> Result<()> {
+    let mut child = spawn_layer_tar(upper)?;
+    let mut stdout = child.stdout.take().unwrap();
+    let mut header = tar::Header::new_gnu();
+    header.set_size(size);
+    header.set_mode(0o644);
+    header.set_mtime(now());
+    let mut layer = (&mut stdout).take(size);
+    builder.append_data(&mut header, format!("layers/{}.tar", instance), &mut layer)?;
+    let changed = layer.limit() > 0 || stdout.read(&mut [0u8; 1])? > 0;
+    // tar(1) is killed by SIGPIPE if it still has more to write
+    drop(stdout);
+    let status = child.wait()?;
+    if changed {
+        bail!("The upper layer of {} changed during the backup.", instance);
+    }
+    if !status.success() {
+        bail!(
+            "Unable to pack the upper layer of {}: tar exited with {}",
+            instance,
+            status
+        );
+    }
+
+    Ok(())
+}
+
+/// Back up the workspace into the file, with the upper layers of the instances if `layers`.
+/// The ABBS tree (recorded by its URL and revision) and the base system are fetched again when
+/// restoring, so the backup stays small
+pub fn backup(path: &Path, layers: bool) -> Result<()> {
+    let config_data = fs::read(Path::new(CIEL_DATA_DIR).join(CONFIG_NAME))
+        .map_err(|_| anyhow!("Please configure this workspace first!"))?;
+    let config = config::read_config().ok();
+    let instances = instance_names()?;
+    if layers && !instances.is_empty() {
+        info!("Shutting down instance(s) before backing up the layers...");
+        actions::for_each_instance(&actions::container_down)?;
+    }
+    let mut manifest = BackupManifest {
+        version: BACKUP_FORMAT_VERSION,
+        created: now(),
+        arch: get_host_arch_name().map(str::to_owned),
+        tree: tree_revision(config.as_ref()),
+        instances: instances.clone(),
+        layers: Vec::new(),
+    };
+    if manifest.tree.is_none() {
+        warn!("The ABBS tree is not loaded, it is not recorded in the backup.");
+    }
+    let mut uppers = Vec::new();
+    if layers {
+        let progress = Progress::new(
+            "Measuring the layers",
+            Unit::Objects,
+            Some(instances.len() as u64),
+        );
+        for instance in instances.iter() {
+            progress.set_message(instance);
+            if let Some((upper, size)) = measure_layer(instance)? {
+                manifest.layers.push(instance.clone());
+                uppers.push((instance, upper, size));
+            }
+            progress.inc(1);
+        }
+        progress.finish();
+    }
+
+    let tmp_path = path.with_extension("tmp");
+    let result = write_backup(&tmp_path, &manifest, &config_data, &instances, &uppers);
+    if let Err(e) = result {
+        fs::remove_file(&tmp_path).ok();
+        return Err(e);
+    }
+    fs::rename(tmp_path, path)?;
+
+    Ok(())
+}
+
+fn write_backup(
+    path: &Path,
+    manifest: &BackupManifest,
+    config_data: &[u8],
+    instances: &[String],
+    uppers: &[(&String, PathBuf, u64)],
+) -> Result<()> {
+    let encoder = zstd::Encoder::new(File::create(path)?, 0)?;
+    let mut builder = tar::Builder::new(encoder);
+    // the manifest goes first, so it is read before the rest when restoring
+    append_data(
+        &mut builder,
+        BACKUP_MANIFEST_NAME,
+        toml::to_string(manifest)?.as_bytes(),
+    )?;
+    append_data(&mut builder, CONFIG_NAME, config_data)?;
+    for instance in instances.iter() {
+        let inst_config = InstanceConfig::load(&Path::new(CIEL_INST_DIR).join(instance))?;
+        append_data(
+            &mut builder,
+            &format!("instances/{}.toml", instance),
+            toml::to_string(&inst_config)?.as_bytes(),
+        )?;
+    }
+    if !uppers.is_empty() {
+        let progress = Progress::new(
+            "Packing the layers",
+            Unit::Objects,
+            Some(uppers.len() as u64),
+        );
+        for (instance, upper, size) in uppers {
+            progress.set_message(instance);
+            append_layer(&mut builder, instance, upper, *size)?;
+            progress.inc(1);
+        }
+        progress.finish();
+    }
+    builder.into_inner()?.finish()?;
+
+    Ok(())
+}
+
+/// Return the name of the instance from the path in the backup, e.g. `main` for
+/// `instances/main.toml`
+fn entry_instance<'a>(name: &'a Path, dir: &str, ext: &str) -> Option<&'a str> {
+    if name.parent()? != Path::new(dir) || name.extension()? != ext {
+        return None;
+    }
+
+    name.file_stem()?.to_str()
+}
+
+/// Unpack the upper layer of the instance from the tarball
+fn unpack_layer<R: Read>(instance: &str, mut tarball: R) -> Result<()> {
+    let man = &mut *overlayfs::get_layer_manager(instance)?;
+    let upper = man
+        .get_upper_layer()?
+        .ok_or_else(|| anyhow!("{} has no upper layer to restore into.", instance))?;
+    let mut child = Command::new("tar")
+        .args(LAYER_TAR_OPTIONS)
+        .arg("-C")
+        .arg(&upper)
+        .arg("-xpf")
+        .arg("-")
+        .stdin(Stdio::piped())
+        .spawn()?;
+    std::io::copy(&mut tarball, child.stdin.as_mut().unwrap())?;
+    drop(child.stdin.take());
+    let status = child.wait()?;
+    if !status.success() {
+        bail!(
+            "Unable to restore the upper layer of {}: tar exited with {}",
+            instance,
+            status
+        );
+    }
+
+    Ok(())
+}
+
+/// Clone the ABBS tree and check out the revision in the backup
+fn restore_tree(revision: &TreeRevision) -> Result<()> {
+    let options = CloneOptions {
+        branch: revision.branch.clone(),
+        ..Default::default()
+    };
+    clone_repo(&revision.url, Path::new("TREE"), &options)?;
+    let repo = git2::Repository::open("TREE")?;
+    let commit = repo
+        .revparse_single(&revision.commit)
+        .map_err(|_| anyhow!("Commit {} is not found in the tree.", revision.commit))?;
+    repo.reset(&commit, git2::ResetType::Hard, None)?;
+
+    Ok(())
+}
+
+/// Reconstruct the workspace in the current directory from the backup
+pub fn restore(path: &Path) -> Result<()> {
+    if Path::new(".ciel").exists() {
+        bail!("This directory already contains a Ciel workspace.");
+    }
+    let mut archive = tar::Archive::new(zstd::Decoder::new(File::open(path)?)?);
+    let mut manifest: Option<BackupManifest> = None;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.into_owned();
+        if name == Path::new(BACKUP_MANIFEST_NAME) {
+            let mut data = String::new();
+            entry.read_to_string(&mut data)?;
+            let read = toml::from_str::<BackupManifest>(&data)?;
+            if read.version > BACKUP_FORMAT_VERSION {
+                bail!(
+                    "Backup format version {} is not supported by this version of Ciel",
+                    read.version
+                );
+            }
+            if read.arch.is_some() && read.arch.as_deref() != get_host_arch_name() {
+                warn!(
+                    "The backup was made on {}, which is different from the host architecture.",
+                    read.arch.as_deref().unwrap_or_default()
+                );
+            }
+            ciel_init()?;
+            manifest = Some(read);
+            continue;
+        }
+        if manifest.is_none() {
+            bail!("{} is not a Ciel workspace backup.", path.display());
+        }
+        if name == Path::new(CONFIG_NAME) {
+            entry.unpack(Path::new(CIEL_DATA_DIR).join(CONFIG_NAME))?;
+        } else if let Some(instance) = entry_instance(&name, "instances", "toml") {
+            let mut data = String::new();
+            entry.read_to_string(&mut data)?;
+            actions::add_instance(instance, &toml::from_str::<InstanceConfig>(&data)?)?;
+        } else if let Some(instance) = entry_instance(&name, "layers", "tar") {
+            let spinner =
+                Progress::spinner(&format!("Restoring the upper layer of {}...", instance));
+            unpack_layer(instance, &mut entry)?;
+            spinner.finish();
+        } else {
+            warn!("Unknown entry in the backup: {}", name.display());
+        }
+    }
+    let manifest =
+        manifest.ok_or_else(|| anyhow!("{} is not a Ciel workspace backup.", path.display()))?;
+
+    match &manifest.tree {
+        Some(revision) => {
+            info!("Cloning abbs tree...");
+            if let Err(e) = restore_tree(revision) {
+                warn!(
+                    "Unable to restore the tree at {}: {}. Please load it with `ciel load-tree`.",
+                    revision.commit, e
+                );
+            }
+        }
+        None => info!("The backup has no tree, please load it with `ciel load-tree`."),
+    }
+    if config::read_config().map_or(false, |c| !c.tree.overlays.is_empty()) {
+        if let Err(e) = tree::update_overlays() {
+            warn!("Unable to clone the overlay trees: {}", e);
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_backup_manifest() {
+    let manifest = BackupManifest {
+        version: BACKUP_FORMAT_VERSION,
+        created: 1_700_000_000,
+        arch: Some("amd64".to_owned()),
+        tree: Some(TreeRevision {
+            url: "https://github.com/AOSC-Dev/aosc-os-abbs".to_owned(),
+            branch: Some("stable".to_owned()),
+            commit: "0123456789abcdef".to_owned(),
+        }),
+        instances: vec!["main".to_owned(), "stage2".to_owned()],
+        layers: vec!["main".to_owned()],
+    };
+    let content = toml::to_string(&manifest).unwrap();
+    assert_eq!(
+        toml::from_str::<BackupManifest>(&content).unwrap(),
+        manifest
+    );
+    assert_eq!(
+        entry_instance(Path::new("instances/main.toml"), "instances", "toml"),
+        Some("main")
+    );
+    assert_eq!(
+        entry_instance(Path::new("layers/main.toml"), "instances", "toml"),
+        None
+    );
+}
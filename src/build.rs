@@ -0,0 +1,314 @@
+//! This module contains the typed requests and results of the package builds
+
+use anyhow::Result;
+use console::style;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fs, path::Path};
+
+use crate::{
+    actions::{self, BuildCheckPoint},
+    config, error, info,
+    repo::{self, qa::Finding},
+    tree, warn,
+};
+
+/// Where the packages of the first build are kept when checking the reproducibility
+const REPRO_DIR: &str = ".ciel/data/reproducible";
+
+/// How far the packages are taken
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BuildPhase {
+    /// Only fetch the sources of the packages
+    Fetch,
+    /// Fetch and unpack the sources into the build directories, without building
+    Unpack,
+    #[default]
+    Build,
+}
+
+/// What to do when a package fails to build
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FailureAction {
+    /// Offer a shell in the build directory, if the user is at the terminal
+    #[default]
+    Ask,
+    Shell,
+    Stop,
+}
+
+/// What to build, and how
+#[derive(Debug, Clone, Default)]
+pub struct BuildRequest {
+    pub instance: String,
+    /// Packages (or groups of packages) to build
+    pub packages: Vec<String>,
+    /// Packages changed in the tree since the last recorded revision (`--changed`), the
+    /// revision is recorded again once all of them are built
+    pub changed: Vec<String>,
+    /// Use the stage 2 mode of ACBS and autobuild3 (for bootstrapping), the stage 2 builds
+    /// are done in a bootstrap instance, and the packages go to a separate OUTPUT component
+    pub stage2: bool,
+    /// Fetch the sources first, then build without network access
+    pub offline: bool,
+    /// Fetch the sources first, then block the network access of the builds (except APT)
+    pub block_network: bool,
+    /// The phase to stop at
+    pub phase: BuildPhase,
+    /// Skip the packages already in the local repository at the same or a newer version
+    pub skip_built: bool,
+    /// Order the packages by their dependencies and include the dependencies not built yet
+    pub with_deps: bool,
+    /// Do not check the scripts of the packages before the build
+    pub skip_preflight: bool,
+    /// Times to retry a failed package build
+    pub retry: usize,
+    /// Roll back the instance before retrying a failed package build
+    pub reset_on_retry: bool,
+    /// What to do when a package fails to build (after the retries)
+    pub on_fail: FailureAction,
+    /// Build the packages twice and compare the results
+    pub check_reproducible: bool,
+    /// Time limit of each package build in seconds (overrides the configuration)
+    pub package_timeout: Option<u64>,
+    /// Time limit of the whole build in seconds (overrides the configuration)
+    pub timeout: Option<u64>,
+    /// CPU and IO priority of the builds (overrides the configuration)
+    pub priority: Option<config::BuildPriority>,
+}
+
+/// Status of a package in the build
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case", tag = "status")]
+pub enum PackageStatus {
+    /// Not built (yet), e.g. when a previous package failed
+    Pending,
+    Built,
+    Fetched,
+    /// The sources are unpacked into the build directory (in the instance)
+    Unpacked {
+        directory: String,
+    },
+    /// Already in the local repository
+    Skipped,
+    Failed {
+        #[serde(rename = "exit-status")]
+        exit_status: i32,
+    },
+    /// Killed for running out of the time limit
+    TimedOut,
+}
+
+/// Result of a package in the build
+#[derive(Debug, Clone, Serialize)]
+pub struct PackageResult {
+    pub package: String,
+    #[serde(flatten)]
+    pub status: PackageStatus,
+    /// Time spent on the package, in seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration: Option<u64>,
+    /// Times the package is built
+    pub attempts: usize,
+    /// Whether the failure happened again in the retries (if retried)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reproducible: Option<bool>,
+    /// Packages produced (relative to the repository)
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub artifacts: Vec<String>,
+    /// Copy of the build log
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log: Option<String>,
+    /// Findings of the quality checks, by the packages produced
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub qa: BTreeMap<String, Vec<Finding>>,
+}
+
+/// Result of the build
+#[derive(Debug, Clone, Serialize)]
+pub struct BuildResult {
+    pub instance: String,
+    /// Stage of the build (2 for the bootstrap builds)
+    pub stage: u8,
+    pub packages: Vec<PackageResult>,
+    /// Time spent on the build, in seconds
+    pub duration: u64,
+    /// Exit status of the build (0 if all the packages are built)
+    #[serde(rename = "exit-status")]
+    pub exit_status: i32,
+}
+
+impl BuildResult {
+    /// Create the result of the build with all the packages pending
+    pub fn new(instance: &str, packages: &[String]) -> Self {
+        BuildResult {
+            instance: instance.to_owned(),
+            stage: 1,
+            packages: packages
+                .iter()
+                .map(|package| PackageResult {
+                    package: package.clone(),
+                    status: PackageStatus::Pending,
+                    duration: None,
+                    attempts: 0,
+                    reproducible: None,
+                    artifacts: Vec::new(),
+                    log: None,
+                    qa: BTreeMap::new(),
+                })
+                .collect(),
+            duration: 0,
+            exit_status: 0,
+        }
+    }
+
+    #[inline]
+    pub fn success(&self) -> bool {
+        self.exit_status == 0
+    }
+
+    /// Return the first package failed to build
+    pub fn failed(&self) -> Option<&PackageResult> {
+        self.packages.iter().find(|p| {
+            matches!(
+                p.status,
+                PackageStatus::Failed { .. } | PackageStatus::TimedOut
+            )
+        })
+    }
+
+    /// Return the first pending package of the name
+    pub fn pending_mut(&mut self, package: &str) -> Option<&mut PackageResult> {
+        self.packages
+            .iter_mut()
+            .find(|p| p.package == package && p.status == PackageStatus::Pending)
+    }
+
+    /// Set the status of all the pending packages
+    pub fn set_pending(&mut self, status: PackageStatus) {
+        for package in self
+            .packages
+            .iter_mut()
+            .filter(|p| p.status == PackageStatus::Pending)
+        {
+            package.status = status.clone();
+        }
+    }
+}
+
+/// Build the packages twice (the instance is rolled back in between), then compare the
+/// packages produced. The build fails if any package is not reproducible
+fn check_reproducible(request: &BuildRequest) -> Result<BuildResult> {
+    let request = BuildRequest {
+        skip_built: false,
+        ..request.clone()
+    };
+    let root = Path::new(&crate::get_output_dir()).join("debs");
+    info!("Building the packages for the first time...");
+    let first = actions::package_build(&request, None)?;
+    if !first.success() {
+        return Ok(first);
+    }
+    if first.packages.iter().all(|p| p.artifacts.is_empty()) {
+        warn!("No packages are recorded in the first build, nothing to compare (is the local repository enabled?).");
+        return Ok(first);
+    }
+    // the second build overwrites the packages of the same names
+    let saved = Path::new(REPRO_DIR);
+    if saved.exists() {
+        fs::remove_dir_all(saved)?;
+    }
+    for artifact in first.packages.iter().flat_map(|p| p.artifacts.iter()) {
+        let copy = saved.join(artifact);
+        if let Some(parent) = copy.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::copy(root.join(artifact), copy)?;
+    }
+    info!("Building the packages for the second time...");
+    let mut second = actions::package_build(&request, None)?;
+    if !second.success() {
+        warn!("The second build failed, while the first one succeeded.");
+        return Ok(second);
+    }
+
+    let mut unreproducible = 0;
+    for artifact in first.packages.iter().flat_map(|p| p.artifacts.iter()) {
+        let differences = if second
+            .packages
+            .iter()
+            .any(|p| p.artifacts.contains(artifact))
+        {
+            repo::diff::diff_builds(&saved.join(artifact), &root.join(artifact))?
+        } else {
+            vec!["(not produced by the second build)".to_owned()]
+        };
+        if differences.is_empty() {
+            info!("{}: reproducible.", artifact);
+            continue;
+        }
+        unreproducible += 1;
+        error!("{}: {} entries differ:", artifact, differences.len());
+        for entry in differences {
+            eprintln!("    {}", entry);
+        }
+    }
+    fs::remove_dir_all(saved).ok();
+    if unreproducible > 0 {
+        error!("{} package(s) are not reproducible.", unreproducible);
+        second.exit_status = 1;
+    }
+
+    Ok(second)
+}
+
+/// Execute the build request, optionally continuing from a checkpoint (always up to the
+/// build phase)
+pub fn execute(request: &BuildRequest, checkpoint: Option<BuildCheckPoint>) -> Result<BuildResult> {
+    if request.check_reproducible && checkpoint.is_none() {
+        return check_reproducible(request);
+    }
+    if request.phase == BuildPhase::Unpack && checkpoint.is_none() {
+        return actions::package_unpack(&request.instance, &request.packages);
+    }
+    if request.phase == BuildPhase::Fetch && checkpoint.is_none() {
+        let mut result = BuildResult::new(&request.instance, &request.packages);
+        let status = actions::package_fetch(&request.instance, &request.packages)?;
+        result.exit_status = status;
+        result.set_pending(if status == 0 {
+            PackageStatus::Fetched
+        } else {
+            PackageStatus::Failed {
+                exit_status: status,
+            }
+        });
+        return Ok(result);
+    }
+
+    let result = actions::package_build(request, checkpoint)?;
+    let built = |package: &String| {
+        result.packages.iter().any(|p| {
+            &p.package == package
+                && matches!(p.status, PackageStatus::Built | PackageStatus::Skipped)
+        })
+    };
+    if !request.changed.is_empty() && request.changed.iter().all(built) {
+        if let Err(e) = tree::record_built_revision() {
+            warn!("Unable to record the revision of the tree: {}", e);
+        }
+    }
+
+    Ok(result)
+}
+
+#[test]
+fn test_build_result() {
+    let mut result = BuildResult::new("main", &["foo".to_owned(), "bar".to_owned()]);
+    result.pending_mut("foo").unwrap().status = PackageStatus::Built;
+    assert!(result.pending_mut("foo").is_none());
+    result.set_pending(PackageStatus::Failed { exit_status: 1 });
+    assert_eq!(result.failed().unwrap().package, "bar");
+    let json = serde_json::to_value(&result).unwrap();
+    assert_eq!(json["packages"][0]["status"], "built");
+    assert_eq!(json["packages"][1]["exit-status"], 1);
+}
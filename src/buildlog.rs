@@ -0,0 +1,215 @@
+//! This module contains the records of the package builds and their logs
+
+use anyhow::{anyhow, Result};
+use console::style;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+use tabwriter::TabWriter;
+
+use crate::info;
+
+/// Where the build logs are kept
+const LOG_DIR: &str = ".ciel/logs";
+/// The records of the package builds, one JSON object per line
+const BUILD_LEDGER: &str = ".ciel/data/builds.jsonl";
+/// Number of the logs kept for each package
+const MAX_LOGS_PER_PACKAGE: usize = 10;
+
+/// A package build (each attempt, if retried)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BuildRecord {
+    pub package: String,
+    pub instance: String,
+    /// When the build started, in seconds since the epoch
+    pub started: u64,
+    /// Wall time in seconds
+    pub wall_time: f64,
+    pub exit_status: i32,
+    /// Path to the compressed log (relative to the workspace), only the latest logs of each
+    /// package are kept
+    pub log: String,
+    /// Built in the stage 2 mode
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub stage2: bool,
+    /// CPU time (user and system) in seconds, if available
+    pub cpu_time: Option<f64>,
+    /// Peak memory usage of the container in bytes, if available
+    pub peak_memory: Option<u64>,
+    /// Packages produced (relative to the repository), as staged by the build
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub debs: Vec<String>,
+    /// Total size of the packages produced
+    pub artifact_size: u64,
+}
+
+impl BuildRecord {
+    #[inline]
+    pub fn success(&self) -> bool {
+        self.exit_status == 0
+    }
+}
+
+/// Return the path for the log of the package build started at the time
+pub fn log_path(package: &str, started: u64) -> PathBuf {
+    Path::new(LOG_DIR).join(format!("{}-{}.log.zst", package.replace('/', "_"), started))
+}
+
+/// Read the records from the offset (in bytes) of the ledger (from the start if the ledger is
+/// shorter, e.g. replaced), returns the records and the offset after the last complete line
+pub fn read_records(offset: u64) -> Result<(Vec<BuildRecord>, u64)> {
+    read_records_at(Path::new(BUILD_LEDGER), offset)
+}
+
+fn read_records_at(path: &Path, offset: u64) -> Result<(Vec<BuildRecord>, u64)> {
+    let mut f = match fs::File::open(path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok((Vec::new(), 0)),
+        Err(e) => return Err(e.into()),
+    };
+    let offset = if offset > f.metadata()?.len() {
+        0
+    } else {
+        offset
+    };
+    f.seek(SeekFrom::Start(offset))?;
+    let mut content = Vec::new();
+    f.read_to_end(&mut content)?;
+    // a record may be being appended
+    let complete = content
+        .iter()
+        .rposition(|&c| c == b'\n')
+        .map_or(0, |n| n + 1);
+    let records = String::from_utf8_lossy(&content[..complete])
+        .lines()
+        .filter_map(|l| serde_json::from_str(l).ok())
+        .collect();
+
+    Ok((records, offset + complete as u64))
+}
+
+/// Return all the records, in the chronological order
+pub fn load_records() -> Vec<BuildRecord> {
+    read_records(0)
+        .map(|(records, _)| records)
+        .unwrap_or_default()
+}
+
+/// Add the build to the ledger, then remove the old logs of the package
+pub fn record(record: &BuildRecord) -> Result<()> {
+    if let Some(parent) = Path::new(BUILD_LEDGER).parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut f = fs::File::options()
+        .create(true)
+        .append(true)
+        .open(BUILD_LEDGER)?;
+    writeln!(f, "{}", serde_json::to_string(record)?)?;
+    // the records are kept, only the logs are removed
+    let logs = load_records()
+        .into_iter()
+        .filter(|r| r.package == record.package && Path::new(&r.log).is_file())
+        .map(|r| r.log)
+        .collect::<Vec<_>>();
+    for log in logs
+        .iter()
+        .take(logs.len().saturating_sub(MAX_LOGS_PER_PACKAGE))
+    {
+        fs::remove_file(log).ok();
+    }
+
+    Ok(())
+}
+
+#[inline]
+fn matches_package(record: &BuildRecord, package: &str) -> bool {
+    record.package == package || record.package.rsplit('/').next() == Some(package)
+}
+
+/// Print the latest build log of the package
+pub fn show_latest(package: &str) -> Result<()> {
+    let record = load_records()
+        .into_iter()
+        .rev()
+        .find(|r| matches_package(r, package) && Path::new(&r.log).is_file())
+        .ok_or_else(|| anyhow!("No build logs of {} found.", package))?;
+    info!(
+        "Build of {} in {} (exit status {}):",
+        record.package, record.instance, record.exit_status
+    );
+    let mut decoder = zstd::Decoder::new(fs::File::open(&record.log)?)?;
+    let mut stdout = io::stdout();
+    match io::copy(&mut decoder, &mut stdout) {
+        // e.g. piped into `head`
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        Err(e) => Err(e.into()),
+        Ok(_) => Ok(()),
+    }
+}
+
+/// Print the builds with their logs kept
+pub fn list_logs() -> Result<()> {
+    let records = load_records();
+    let mut formatter = TabWriter::new(io::stderr());
+    writeln!(
+        &mut formatter,
+        "PACKAGE\tINSTANCE\tSTATUS\tDURATION\tSTARTED"
+    )?;
+    for record in records.iter().filter(|r| Path::new(&r.log).is_file()) {
+        let started = time::OffsetDateTime::from_unix_timestamp(record.started as i64)
+            .map(|t| t.to_string())
+            .unwrap_or_else(|_| record.started.to_string());
+        writeln!(
+            &mut formatter,
+            "{}\t{}\t{}\t{}s\t{}",
+            record.package,
+            record.instance,
+            if record.success() {
+                style("ok".to_owned()).green()
+            } else {
+                style(format!("failed ({})", record.exit_status)).red()
+            },
+            record.wall_time.round() as u64,
+            started
+        )?;
+    }
+    formatter.flush()?;
+
+    Ok(())
+}
+
+#[test]
+fn test_read_records() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("builds.jsonl");
+    assert_eq!(read_records_at(&path, 0).unwrap().1, 0);
+    let record = BuildRecord {
+        package: "base/foo".to_owned(),
+        instance: "main".to_owned(),
+        started: 1700000000,
+        wall_time: 12.5,
+        exit_status: 0,
+        log: log_path("base/foo", 1700000000)
+            .to_string_lossy()
+            .to_string(),
+        stage2: false,
+        cpu_time: None,
+        peak_memory: None,
+        debs: vec!["f/foo_1.0_amd64.deb".to_owned()],
+        artifact_size: 1024,
+    };
+    let line = format!("{}\n", serde_json::to_string(&record).unwrap());
+    // the second record is still being written
+    fs::write(&path, format!("{}{}", line, &line[..10])).unwrap();
+    let (records, offset) = read_records_at(&path, 0).unwrap();
+    assert_eq!(records.len(), 1);
+    assert_eq!(offset, line.len() as u64);
+    assert!(matches_package(&records[0], "foo"));
+    fs::write(&path, format!("{}{}", line, line)).unwrap();
+    let (records, offset) = read_records_at(&path, offset).unwrap();
+    assert_eq!(records.len(), 1);
+    assert_eq!(offset, 2 * line.len() as u64);
+}
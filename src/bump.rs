@@ -0,0 +1,320 @@
+//! This module contains the helpers bumping the packages in the trees
+
+use anyhow::{anyhow, bail, Result};
+use console::style;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    config::CielConfig,
+    preflight, tree,
+    tree::{lookup, parse_assignments},
+    warn,
+};
+
+/// Commit message of bumping the release, see [commit_message] for the placeholders
+pub const BUMP_RELEASE_MESSAGE: &str = "{package}: bump REL";
+/// Commit message of updating the version
+pub const UPDATE_VERSION_MESSAGE: &str = "{package}: update to {version}";
+
+/// How the package is bumped
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Bump {
+    /// Rebuild the same version: increase the release (`REL`)
+    Release,
+    /// Update to the version, resetting the release
+    Version(String),
+}
+
+/// A package bumped
+#[derive(Debug, Clone)]
+pub struct Bumped {
+    /// The name of the package directory
+    pub package: String,
+    pub version: String,
+    /// The release after the bump (0 if not set)
+    pub release: u32,
+    pub files: Vec<PathBuf>,
+}
+
+/// Return the index of the line where the variable is (last) assigned
+fn assignment_line(lines: &[&str], key: &str) -> Option<usize> {
+    let prefix = format!("{}=", key);
+    lines
+        .iter()
+        .rposition(|line| line.trim_start().starts_with(&prefix))
+}
+
+/// Replace the value of the (last) assignment of the variable in the script, keeping the
+/// quotes and the rest of the line. Returns None if the variable is not assigned
+fn replace_assignment(script: &str, key: &str, value: &str) -> Option<String> {
+    let mut lines = script.split_inclusive('\n').collect::<Vec<_>>();
+    let index = assignment_line(&lines, key)?;
+    let line = lines[index];
+    let start = line.find('=')? + 1;
+    let rest = &line[start..];
+    let (value_start, value_end) = match rest.chars().next() {
+        Some(quote) if quote == '"' || quote == '\'' => {
+            // a multi-line value is left alone
+            let end = rest[1..].find(quote)? + 1;
+            (start + 1, start + end)
+        }
+        _ => {
+            let end = rest
+                .find(|c: char| c.is_whitespace() || c == ';')
+                .unwrap_or(rest.len());
+            (start, start + end)
+        }
+    };
+    let replaced = format!("{}{}{}", &line[..value_start], value, &line[value_end..]);
+    lines[index] = &replaced;
+
+    Some(lines.concat())
+}
+
+/// Insert the line after the (last) assignment of the variable. Returns None if the variable is
+/// not assigned
+fn insert_after(script: &str, key: &str, new_line: &str) -> Option<String> {
+    let mut lines = script.split_inclusive('\n').collect::<Vec<_>>();
+    let index = assignment_line(&lines, key)?;
+    let new_line = format!("{}\n", new_line);
+    let terminated;
+    if !lines[index].ends_with('\n') {
+        terminated = format!("{}\n", lines[index]);
+        lines[index] = &terminated;
+    }
+    lines.insert(index + 1, &new_line);
+
+    Some(lines.concat())
+}
+
+/// Remove the assignments of the variable
+fn remove_assignment(script: &str, key: &str) -> String {
+    let prefix = format!("{}=", key);
+    script
+        .split_inclusive('\n')
+        .filter(|line| !line.trim_start().starts_with(&prefix))
+        .collect()
+}
+
+/// Fill the placeholders (`{package}`, `{version}` and `{rel}`) of the commit message template
+pub fn commit_message(template: &str, bumped: &Bumped) -> String {
+    template
+        .replace("{package}", &bumped.package)
+        .replace("{version}", &bumped.version)
+        .replace("{rel}", &bumped.release.to_string())
+}
+
+/// Edit the scripts of the package for the bump, returning the new contents of the files changed
+fn edit_package(dir: &Path, bump: &Bump) -> Result<(Bumped, Vec<(PathBuf, String)>)> {
+    let spec_path = dir.join("spec");
+    let spec = fs::read_to_string(&spec_path)?;
+    let assignments = parse_assignments(&spec);
+    let package = dir
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .ok_or_else(|| anyhow!("Invalid package directory {}", dir.display()))?;
+    let version = lookup(&assignments, "VER")
+        .ok_or_else(|| anyhow!("VER is not set in {}", spec_path.display()))?
+        .to_owned();
+
+    match bump {
+        Bump::Version(new_version) => {
+            if new_version.is_empty() || new_version.contains(char::is_whitespace) {
+                bail!("Invalid version `{}`.", new_version);
+            }
+            let spec = replace_assignment(&spec, "VER", new_version)
+                .ok_or_else(|| anyhow!("Unable to edit VER in {}", spec_path.display()))?;
+            let spec = remove_assignment(&spec, "REL");
+            let mut edits = vec![(spec_path, spec)];
+            // the release of the older packages is in the defines
+            let defines_path = dir.join("autobuild/defines");
+            if let Ok(defines) = fs::read_to_string(&defines_path) {
+                if lookup(&parse_assignments(&defines), "PKGREL").is_some() {
+                    edits.push((defines_path, remove_assignment(&defines, "PKGREL")));
+                }
+            }
+            let bumped = Bumped {
+                package,
+                version: new_version.clone(),
+                release: 0,
+                files: edits.iter().map(|(path, _)| path.clone()).collect(),
+            };
+
+            Ok((bumped, edits))
+        }
+        Bump::Release => {
+            // the release is in the spec, or in the defines of the older packages
+            let defines_path = dir.join("autobuild/defines");
+            let defines = fs::read_to_string(&defines_path).unwrap_or_default();
+            let (path, script, key) = if lookup(&assignments, "REL").is_some() {
+                (spec_path, spec.as_str(), "REL")
+            } else if lookup(&parse_assignments(&defines), "PKGREL").is_some() {
+                (defines_path, defines.as_str(), "PKGREL")
+            } else {
+                (spec_path, spec.as_str(), "REL")
+            };
+            let current = lookup(&parse_assignments(script), key).unwrap_or("0");
+            let release = current.parse::<u32>().map_err(|_| {
+                anyhow!("Unable to bump {} `{}` in {}", key, current, path.display())
+            })? + 1;
+            let edited = match replace_assignment(script, key, &release.to_string()) {
+                Some(edited) => edited,
+                None => insert_after(script, "VER", &format!("REL={}", release))
+                    .ok_or_else(|| anyhow!("Unable to edit {}", path.display()))?,
+            };
+            let bumped = Bumped {
+                package,
+                version,
+                release,
+                files: vec![path.clone()],
+            };
+
+            Ok((bumped, vec![(path, edited)]))
+        }
+    }
+}
+
+/// Commit the files changed in the repository containing them
+fn commit_files(files: &[PathBuf], message: &str) -> Result<()> {
+    let parent = files
+        .first()
+        .and_then(|f| f.parent())
+        .ok_or_else(|| anyhow!("Nothing to commit."))?;
+    let repo = git2::Repository::discover(parent)?;
+    let workdir = repo
+        .workdir()
+        .ok_or_else(|| anyhow!("The tree has no working directory."))?
+        .canonicalize()?;
+    let head = repo.head()?.peel_to_commit()?;
+    // do not take the changes staged by the user into the commit
+    let staged = repo.diff_tree_to_index(Some(&head.tree()?), None, None)?;
+    if staged.stats()?.files_changed() > 0 {
+        bail!("The tree has staged changes, please commit or unstage them first.");
+    }
+    let mut index = repo.index()?;
+    for file in files {
+        let path = file.canonicalize()?;
+        index.add_path(path.strip_prefix(&workdir)?)?;
+    }
+    index.write()?;
+    let tree = repo.find_tree(index.write_tree()?)?;
+    let signature = repo
+        .signature()
+        .map_err(|e| anyhow!("Unable to determine the author of the commit: {}", e))?;
+    repo.commit(
+        Some("HEAD"),
+        &signature,
+        &signature,
+        message,
+        &tree,
+        &[&head],
+    )?;
+
+    Ok(())
+}
+
+/// Bump the package in the trees, then commit the change with the message (filled from the
+/// template, see [commit_message]) unless `commit` is false. The version updates are never
+/// committed, as the checksums of the new sources are still to be updated. The files are
+/// restored if the package fails the preflight checks after the bump
+pub fn bump_package(
+    config: &CielConfig,
+    package: &str,
+    bump: &Bump,
+    template: Option<&str>,
+    commit: bool,
+) -> Result<Bumped> {
+    let dir = tree::find_package(config, package)
+        .ok_or_else(|| anyhow!("{} is not found in the trees.", package))?;
+    let (bumped, edits) = edit_package(&dir, bump)?;
+    let mut originals = Vec::new();
+    for (path, content) in edits.iter() {
+        originals.push((path, fs::read_to_string(path)?));
+        fs::write(path, content)?;
+    }
+    let problems = preflight::check_package(&dir, &tree::package_names(config));
+    if !problems.is_empty() {
+        for (path, content) in originals {
+            fs::write(path, content)?;
+        }
+        for problem in problems {
+            warn!("{}", problem);
+        }
+        bail!(
+            "{} fails the preflight checks after the bump, nothing is changed.",
+            package
+        );
+    }
+    match bump {
+        Bump::Version(_) => {
+            warn!(
+                "Please update the checksums (CHKSUMS) in the spec of {} for the new version.",
+                package
+            );
+            if commit {
+                warn!(
+                    "The change is not committed, commit it as `{}` once the checksums are updated.",
+                    commit_message(template.unwrap_or(UPDATE_VERSION_MESSAGE), &bumped)
+                );
+            }
+        }
+        Bump::Release if commit => {
+            let template = template.unwrap_or(BUMP_RELEASE_MESSAGE);
+            commit_files(&bumped.files, &commit_message(template, &bumped))?;
+        }
+        Bump::Release => (),
+    }
+
+    Ok(bumped)
+}
+
+#[test]
+fn test_edit_assignments() {
+    let spec =
+        "VER=1.2.3\nREL=2 # rebuilt for icu\nSRCS=\"tbl::https://example.com/foo-$VER.tar.xz\"\n";
+    assert_eq!(
+        replace_assignment(spec, "REL", "3").unwrap(),
+        "VER=1.2.3\nREL=3 # rebuilt for icu\nSRCS=\"tbl::https://example.com/foo-$VER.tar.xz\"\n"
+    );
+    assert_eq!(
+        replace_assignment("VER=\"1.2.3\"\n", "VER", "1.3.0").unwrap(),
+        "VER=\"1.3.0\"\n"
+    );
+    assert!(replace_assignment(spec, "PKGREL", "1").is_none());
+    assert_eq!(
+        insert_after("VER=1.2.3", "VER", "REL=1").unwrap(),
+        "VER=1.2.3\nREL=1\n"
+    );
+    assert_eq!(remove_assignment(spec, "REL").lines().count(), 2);
+    let bumped = Bumped {
+        package: "foo".to_owned(),
+        version: "1.3.0".to_owned(),
+        release: 0,
+        files: Vec::new(),
+    };
+    assert_eq!(
+        commit_message(UPDATE_VERSION_MESSAGE, &bumped),
+        "foo: update to 1.3.0"
+    );
+}
+
+#[test]
+fn test_edit_version() {
+    let dir = tempfile::tempdir().unwrap();
+    let package = dir.path().join("foo");
+    fs::create_dir_all(package.join("autobuild")).unwrap();
+    fs::write(
+        package.join("spec"),
+        "VER=1.2.3\nSRCS=\"tbl::https://example.com/foo-$VER.tar.xz\"\n",
+    )
+    .unwrap();
+    fs::write(package.join("autobuild/defines"), "PKGNAME=foo\nPKGREL=2\n").unwrap();
+    let (bumped, edits) = edit_package(&package, &Bump::Version("1.3.0".to_owned())).unwrap();
+    assert_eq!(bumped.release, 0);
+    assert_eq!(bumped.files.len(), 2);
+    assert!(edits[0].1.starts_with("VER=1.3.0\n"));
+    assert_eq!(edits[1].1, "PKGNAME=foo\n");
+}
@@ -0,0 +1,288 @@
+//! This module contains the tarball cache related APIs
+
+use anyhow::{anyhow, bail, Result};
+use console::style;
+use indicatif::HumanBytes;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+use tabwriter::TabWriter;
+
+use crate::{common::sha256sum, info, reflink, warn};
+
+/// Return the location of the tarball cache (`CIEL_CACHE_DIR`, `$XDG_CACHE_HOME/ciel/tarballs`
+/// or `~/.cache/ciel/tarballs`)
+pub fn cache_dir() -> Result<PathBuf> {
+    if let Some(dir) = std::env::var_os("CIEL_CACHE_DIR") {
+        return Ok(PathBuf::from(dir));
+    }
+    let base = match std::env::var_os("XDG_CACHE_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(
+            std::env::var_os("HOME").ok_or_else(|| anyhow!("Unable to determine $HOME"))?,
+        )
+        .join(".cache"),
+    };
+
+    Ok(base.join("ciel/tarballs"))
+}
+
+#[inline]
+fn is_checksum(name: &str) -> bool {
+    name.len() == 64 && name.bytes().all(|c| c.is_ascii_hexdigit())
+}
+
+#[inline]
+fn metadata_path(tarball: &Path) -> PathBuf {
+    tarball.with_extension("json")
+}
+
+/// Metadata of a cached tarball, stored next to it as `<sha256>.json`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TarballMetadata {
+    /// File name of the tarball when it was downloaded
+    pub name: String,
+    /// Whether the signature of the tarball was verified
+    pub verified: bool,
+}
+
+/// A tarball in the cache
+pub struct CacheEntry {
+    pub sha256: String,
+    pub path: PathBuf,
+    pub size: u64,
+    /// When the tarball is last used
+    pub used: SystemTime,
+    pub metadata: TarballMetadata,
+}
+
+/// Return the tarballs in the cache, the least recently used first
+pub fn list_entries() -> Result<Vec<CacheEntry>> {
+    let dir = cache_dir()?;
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        // skip the incomplete copies
+        if !is_checksum(&name) || !entry.file_type()?.is_file() {
+            continue;
+        }
+        let meta = entry.metadata()?;
+        // tarballs cached by the older versions have no metadata
+        let metadata = fs::read_to_string(metadata_path(&entry.path()))
+            .ok()
+            .and_then(|m| serde_json::from_str(&m).ok())
+            .unwrap_or_default();
+        entries.push(CacheEntry {
+            sha256: name,
+            path: entry.path(),
+            size: meta.len(),
+            used: meta.modified()?,
+            metadata,
+        });
+    }
+    entries.sort_by_key(|e| e.used);
+
+    Ok(entries)
+}
+
+/// Return the cached tarball with the checksum, if any.
+/// The tarball is verified before use and removed if it is corrupted
+pub fn lookup(sha256: &str) -> Option<PathBuf> {
+    let path = cache_dir().ok()?.join(sha256.to_ascii_lowercase());
+    if !is_checksum(sha256) || !path.is_file() {
+        return None;
+    }
+    info!("Verifying cached tarball...");
+    let checksum = fs::File::open(&path)
+        .map_err(anyhow::Error::from)
+        .and_then(sha256sum);
+    match checksum {
+        Ok(checksum) if checksum.eq_ignore_ascii_case(sha256) => (),
+        _ => {
+            warn!("The cached tarball is corrupted, downloading it again.");
+            fs::remove_file(&path).ok();
+            fs::remove_file(metadata_path(&path)).ok();
+            return None;
+        }
+    }
+    // the modification time records the last use for pruning
+    if let Ok(f) = fs::File::options().write(true).open(&path) {
+        f.set_modified(SystemTime::now()).ok();
+    }
+
+    Some(path)
+}
+
+/// Return the metadata of the cached tarball
+pub fn metadata(tarball: &Path) -> Option<TarballMetadata> {
+    let metadata = fs::read_to_string(metadata_path(tarball)).ok()?;
+
+    serde_json::from_str(&metadata).ok()
+}
+
+/// Return the most recent cached tarball for the architecture, for use without network access.
+/// Unless `verified_only` is false, only the tarballs with verified signatures are considered
+pub fn latest_for_arch(arch: &str, verified_only: bool) -> Result<CacheEntry> {
+    let pattern = format!("_{}.", arch);
+    // the release dates are part of the names, so the latest tarball sorts last
+    let entry = list_entries()?
+        .into_iter()
+        .filter(|e| e.metadata.name.contains(&pattern))
+        .filter(|e| !verified_only || e.metadata.verified)
+        .max_by(|a, b| a.metadata.name.cmp(&b.metadata.name));
+    match entry {
+        Some(entry) => Ok(entry),
+        None if verified_only => bail!(
+            "No verified tarball for {} in the cache ({}), please load the OS once with network access or pass --no-verify",
+            arch,
+            cache_dir()?.display()
+        ),
+        None => bail!(
+            "No tarball for {} in the cache ({}), please load the OS once with network access",
+            arch,
+            cache_dir()?.display()
+        ),
+    }
+}
+
+/// Move the downloaded tarball into the cache, returns the cached path
+pub fn store(path: &Path, sha256: &str, verified: bool) -> Result<PathBuf> {
+    let dir = cache_dir()?;
+    fs::create_dir_all(&dir)?;
+    let target = dir.join(sha256.to_ascii_lowercase());
+    if fs::rename(path, &target).is_err() {
+        // the cache is on a different filesystem
+        let tmp = target.with_extension("tmp");
+        reflink::copy_file(path, &tmp)?;
+        fs::rename(&tmp, &target)?;
+        fs::remove_file(path)?;
+    }
+    let metadata = TarballMetadata {
+        name: path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default(),
+        verified,
+    };
+    fs::write(metadata_path(&target), serde_json::to_string(&metadata)?)?;
+
+    Ok(target)
+}
+
+/// Select the entries (sorted by the last use) to remove: the ones not used within `max_age`,
+/// then the least recently used ones until the cache fits within `max_size`
+fn select_evictions(
+    entries: &[CacheEntry],
+    now: SystemTime,
+    max_age: Option<Duration>,
+    max_size: Option<u64>,
+) -> Vec<usize> {
+    let mut evicted = Vec::new();
+    let mut total: u64 = entries.iter().map(|e| e.size).sum();
+    for (i, entry) in entries.iter().enumerate() {
+        let expired = match (max_age, now.duration_since(entry.used)) {
+            (Some(max_age), Ok(age)) => age > max_age,
+            _ => false,
+        };
+        let oversized = max_size.map(|max| total > max).unwrap_or(false);
+        if expired || oversized {
+            evicted.push(i);
+            total -= entry.size;
+        }
+    }
+
+    evicted
+}
+
+/// Remove the cached tarballs not used within `max_age` or exceeding `max_size` in total
+pub fn prune(max_age: Option<Duration>, max_size: Option<u64>) -> Result<()> {
+    let entries = list_entries()?;
+    let evicted = select_evictions(&entries, SystemTime::now(), max_age, max_size);
+    let mut reclaimed = 0;
+    for i in evicted.iter() {
+        let entry = &entries[*i];
+        fs::remove_file(&entry.path)?;
+        fs::remove_file(metadata_path(&entry.path)).ok();
+        reclaimed += entry.size;
+    }
+    info!(
+        "{} tarball(s) removed, {} reclaimed.",
+        evicted.len(),
+        HumanBytes(reclaimed)
+    );
+
+    Ok(())
+}
+
+/// Remove all the cached tarballs
+pub fn clear() -> Result<()> {
+    prune(None, Some(0))
+}
+
+/// Print the cached tarballs
+pub fn show_cache() -> Result<()> {
+    let entries = list_entries()?;
+    let mut formatter = TabWriter::new(std::io::stderr());
+    writeln!(&mut formatter, "SHA256\tNAME\tVERIFIED\tSIZE\tLAST USED")?;
+    for entry in entries.iter() {
+        let days = SystemTime::now()
+            .duration_since(entry.used)
+            .unwrap_or_default()
+            .as_secs()
+            / 86400;
+        writeln!(
+            &mut formatter,
+            "{}\t{}\t{}\t{}\t{} day(s) ago",
+            entry.sha256,
+            if entry.metadata.name.is_empty() {
+                "-"
+            } else {
+                &entry.metadata.name
+            },
+            if entry.metadata.verified { "yes" } else { "no" },
+            HumanBytes(entry.size),
+            days
+        )?;
+    }
+    formatter.flush()?;
+    info!(
+        "{} in total.",
+        HumanBytes(entries.iter().map(|e| e.size).sum())
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_select_evictions() {
+    let now = SystemTime::UNIX_EPOCH + Duration::from_secs(100 * 86400);
+    let entry = |days: u64, size: u64| CacheEntry {
+        sha256: String::new(),
+        path: PathBuf::new(),
+        size,
+        used: now - Duration::from_secs(days * 86400),
+        metadata: TarballMetadata::default(),
+    };
+    // sorted by the last use
+    let entries = vec![entry(30, 300), entry(10, 200), entry(1, 100)];
+    let day = Duration::from_secs(86400);
+    assert_eq!(
+        select_evictions(&entries, now, None, None),
+        Vec::<usize>::new()
+    );
+    assert_eq!(select_evictions(&entries, now, Some(day * 7), None), [0, 1]);
+    assert_eq!(select_evictions(&entries, now, None, Some(300)), [0]);
+    assert_eq!(select_evictions(&entries, now, None, Some(0)), [0, 1, 2]);
+    assert_eq!(
+        select_evictions(&entries, now, Some(day * 20), Some(100)),
+        [0, 1]
+    );
+}
@@ -0,0 +1,192 @@
+//! This module contains the capability sets of the instances
+
+use anyhow::{bail, Result};
+
+use crate::{config::CapabilityConfig, preset};
+
+/// The capabilities known to Linux (see capabilities(7))
+const CAPABILITIES: &[&str] = &[
+    "CAP_AUDIT_CONTROL",
+    "CAP_AUDIT_READ",
+    "CAP_AUDIT_WRITE",
+    "CAP_BLOCK_SUSPEND",
+    "CAP_BPF",
+    "CAP_CHECKPOINT_RESTORE",
+    "CAP_CHOWN",
+    "CAP_DAC_OVERRIDE",
+    "CAP_DAC_READ_SEARCH",
+    "CAP_FOWNER",
+    "CAP_FSETID",
+    "CAP_IPC_LOCK",
+    "CAP_IPC_OWNER",
+    "CAP_KILL",
+    "CAP_LEASE",
+    "CAP_LINUX_IMMUTABLE",
+    "CAP_MAC_ADMIN",
+    "CAP_MAC_OVERRIDE",
+    "CAP_MKNOD",
+    "CAP_NET_ADMIN",
+    "CAP_NET_BIND_SERVICE",
+    "CAP_NET_BROADCAST",
+    "CAP_NET_RAW",
+    "CAP_PERFMON",
+    "CAP_SETFCAP",
+    "CAP_SETGID",
+    "CAP_SETPCAP",
+    "CAP_SETUID",
+    "CAP_SYS_ADMIN",
+    "CAP_SYS_BOOT",
+    "CAP_SYS_CHROOT",
+    "CAP_SYS_MODULE",
+    "CAP_SYS_NICE",
+    "CAP_SYS_PACCT",
+    "CAP_SYS_PTRACE",
+    "CAP_SYS_RAWIO",
+    "CAP_SYS_RESOURCE",
+    "CAP_SYS_TIME",
+    "CAP_SYS_TTY_CONFIG",
+    "CAP_SYSLOG",
+    "CAP_WAKE_ALARM",
+];
+
+/// A preset of the capability set, added to or dropped from the capabilities systemd-nspawn
+/// grants by default
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Preset {
+    /// Adds `CAP_IPC_LOCK` (for the builds locking memory, e.g. GnuPG)
+    Default,
+    /// Also adds the capabilities some test suites need (e.g. creating network namespaces or
+    /// tracing the processes)
+    TestSuites,
+    /// Adds nothing and drops the capabilities the builds should not need
+    Minimal,
+}
+
+impl Preset {
+    pub const NAMES: &'static [&'static str] = crate::cli::CAPABILITY_PRESETS;
+
+    pub fn parse(name: &str) -> Result<Self> {
+        preset::parse(
+            "capability",
+            &[
+                (Preset::NAMES[0], Preset::Default),
+                (Preset::NAMES[1], Preset::TestSuites),
+                (Preset::NAMES[2], Preset::Minimal),
+            ],
+            name,
+        )
+    }
+
+    fn added(&self) -> &'static [&'static str] {
+        match self {
+            Preset::Default => &["CAP_IPC_LOCK"],
+            Preset::TestSuites => &[
+                "CAP_IPC_LOCK",
+                "CAP_NET_ADMIN",
+                "CAP_SYS_ADMIN",
+                "CAP_SYS_PTRACE",
+                "CAP_SYS_TIME",
+            ],
+            Preset::Minimal => &[],
+        }
+    }
+
+    fn dropped(&self) -> &'static [&'static str] {
+        match self {
+            Preset::Default | Preset::TestSuites => &[],
+            Preset::Minimal => &[
+                "CAP_AUDIT_CONTROL",
+                "CAP_LINUX_IMMUTABLE",
+                "CAP_MKNOD",
+                "CAP_NET_RAW",
+                "CAP_SYS_PTRACE",
+                "CAP_SYS_TTY_CONFIG",
+            ],
+        }
+    }
+}
+
+/// Return the canonical name of the capability (e.g. `CAP_SYS_ADMIN` for `sys_admin`), the
+/// names are case-insensitive and the `CAP_` prefix is optional
+fn normalize(name: &str) -> Result<String> {
+    let upper = name.to_ascii_uppercase();
+    let name = if upper.starts_with("CAP_") {
+        upper
+    } else {
+        format!("CAP_{}", upper)
+    };
+    if !CAPABILITIES.contains(&name.as_str()) {
+        bail!("unknown capability `{}`", name);
+    }
+
+    Ok(name)
+}
+
+/// Merge the lists of the configuration into the preset, returns the added and the dropped
+/// capabilities
+fn merge(config: &CapabilityConfig) -> Result<(Vec<String>, Vec<String>)> {
+    let base = Preset::parse(config.preset.as_deref().unwrap_or("default"))?;
+    let add = config
+        .add
+        .iter()
+        .map(|n| normalize(n))
+        .collect::<Result<Vec<_>>>()?;
+    let drop = config
+        .drop
+        .iter()
+        .map(|n| normalize(n))
+        .collect::<Result<Vec<_>>>()?;
+
+    preset::merge(
+        (base.added(), base.dropped()),
+        &add,
+        &drop,
+        ("added", "dropped"),
+    )
+}
+
+/// Return the options of systemd-nspawn setting the capabilities of the instance
+pub fn nspawn_options(config: &CapabilityConfig) -> Result<Vec<String>> {
+    let (added, dropped) = merge(config)?;
+    let mut options = Vec::new();
+    if !added.is_empty() {
+        options.push(format!("--capability={}", added.join(",")));
+    }
+    if !dropped.is_empty() {
+        options.push(format!("--drop-capability={}", dropped.join(",")));
+    }
+
+    Ok(options)
+}
+
+#[test]
+fn test_nspawn_options() {
+    assert_eq!(
+        nspawn_options(&CapabilityConfig::default()).unwrap(),
+        vec!["--capability=CAP_IPC_LOCK"]
+    );
+    let config = CapabilityConfig {
+        preset: Some("minimal".to_owned()),
+        add: vec!["sys_ptrace".to_owned(), "CAP_SYS_ADMIN".to_owned()],
+        drop: vec!["cap_net_raw".to_owned(), "CAP_SYS_BOOT".to_owned()],
+    };
+    assert_eq!(
+        nspawn_options(&config).unwrap(),
+        vec![
+            "--capability=CAP_SYS_PTRACE,CAP_SYS_ADMIN",
+            "--drop-capability=CAP_AUDIT_CONTROL,CAP_LINUX_IMMUTABLE,CAP_MKNOD,CAP_NET_RAW,CAP_SYS_TTY_CONFIG,CAP_SYS_BOOT"
+        ]
+    );
+    let invalid = |preset: &str, add: &str, drop: &str| {
+        nspawn_options(&CapabilityConfig {
+            preset: Some(preset.to_owned()),
+            add: vec![add.to_owned()],
+            drop: vec![drop.to_owned()],
+        })
+        .is_err()
+    };
+    assert!(invalid("root", "CAP_SYS_ADMIN", "CAP_MKNOD"));
+    assert!(invalid("default", "CAP_SYS_SUPERUSER", "CAP_MKNOD"));
+    assert!(invalid("default", "sys_admin", "CAP_SYS_ADMIN"));
+    assert!(!invalid("test-suites", "CAP_SYS_ADMIN", "CAP_MKNOD"));
+}
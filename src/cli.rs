@@ -3,6 +3,10 @@ use clap::{Arg, Command};
 use std::ffi::OsStr;
 
 pub const GIT_TREE_URL: &str = "https://github.com/AOSC-Dev/aosc-os-abbs.git";
+/// Names of the system call filter presets (see [crate::seccomp::Preset])
+pub const SECCOMP_PRESETS: &[&str] = &["default", "permissive-for-qemu", "hardened"];
+/// Names of the capability set presets (see [crate::capability::Preset])
+pub const CAPABILITY_PRESETS: &[&str] = &["default", "test-suites", "minimal"];
 
 /// List all the available plugins/helper scripts
 fn list_helpers() -> Result<Vec<String>> {
@@ -28,6 +32,28 @@ fn list_helpers() -> Result<Vec<String>> {
     Ok(plugins)
 }
 
+/// Options of cloning the ABBS tree, shared by `load-tree` and `new`
+fn tree_clone_args() -> [Arg; 3] {
+    [
+        Arg::new("depth")
+            .long("depth")
+            .num_args(1)
+            .env("CIEL_TREE_DEPTH")
+            .value_parser(clap::value_parser!(u32).range(1..))
+            .help("Only fetch the given number of the latest commits of the tree"),
+        Arg::new("single-branch")
+            .long("single-branch")
+            .env("CIEL_TREE_SINGLE_BRANCH")
+            .action(clap::ArgAction::SetTrue)
+            .help("Only fetch the branch to check out"),
+        Arg::new("blobless")
+            .long("blobless")
+            .env("CIEL_TREE_BLOBLESS")
+            .action(clap::ArgAction::SetTrue)
+            .help("Fetch the file contents of the tree on demand (partial clone)"),
+    ]
+}
+
 /// Build the CLI instance
 pub fn build_cli() -> Command {
     let instance_arg = Arg::new("INSTANCE")
@@ -40,6 +66,10 @@ pub fn build_cli() -> Command {
         .about("CIEL! is a nspawn container manager")
         .allow_external_subcommands(true)
         .subcommand(Command::new("version").about("Display the version of CIEL!"))
+        .subcommand(Command::new("use")
+            .arg(Arg::new("INSTANCE").help("Instance to be used when -i is not specified"))
+            .arg(Arg::new("unset").long("unset").action(clap::ArgAction::SetTrue).conflicts_with("INSTANCE").help("Clear the default instance"))
+            .about("Set (or show) the default instance of the workspace"))
         .subcommand(Command::new("init")
             .arg(Arg::new("upgrade").long("upgrade").action(clap::ArgAction::SetTrue).help("Upgrade Ciel workspace from an older version"))
             .about("Initialize the work directory"))
@@ -47,85 +77,180 @@ pub fn build_cli() -> Command {
             Command::new("load-os")
                 .arg(Arg::new("url").help("URL or path to the tarball"))
                 .arg(Arg::new("arch").short('a').long("arch").help("Specify the target architecture for fetching OS tarball"))
+                .arg(Arg::new("segments").short('j').long("segments").num_args(1).env("CIEL_DOWNLOAD_SEGMENTS").value_parser(clap::value_parser!(usize)).default_value("1").help("Download the tarball using multiple connections"))
+                .arg(Arg::new("no-verify").long("no-verify").env("CIEL_NO_VERIFY").action(clap::ArgAction::SetTrue).help("Do not verify the signature of the downloaded tarball (dangerous)"))
+                .arg(Arg::new("p2p").long("p2p").env("CIEL_P2P").action(clap::ArgAction::SetTrue).help("Download the tarball from its metalink or torrent descriptor with aria2c if available (falls back to HTTPS)"))
+                .arg(Arg::new("mirror").long("mirror").num_args(1).env("CIEL_MIRROR").conflicts_with("url").help("Mirror of releases.aosc.io to fetch the OS tarball from"))
+                .arg(Arg::new("image").long("from-image").num_args(1).conflicts_with_all(["url", "arch"]).help("Import a previously exported Ciel image"))
                 .about("Unpack OS tarball or fetch the latest BuildKit from the repository"),
         )
         .subcommand(Command::new("update-os").about("Update the OS in the container"))
+        .subcommand(Command::new("update-all")
+            .arg(Arg::new("keep-modified").long("keep-modified").action(clap::ArgAction::SetTrue).help("Keep the instances with changes in their upper layers (except for the junk files) instead of rolling them back"))
+            .about("Update the OS in the container, then roll back all the instances onto it"))
         .subcommand(
             Command::new("load-tree")
-                .arg(Arg::new("url").default_value(GIT_TREE_URL).help("URL to the git repository"))
+                .arg(Arg::new("url").help("URL to the git repository (tree.url in the configuration or the AOSC OS ABBS main repository by default)"))
+                .arg(Arg::new("branch").long("branch").num_args(1).help("Branch to check out"))
+                .args(tree_clone_args())
                 .about("Clone package tree from the link provided or AOSC OS ABBS main repository"),
         )
         .subcommand(
             Command::new("update-tree")
                 .arg(Arg::new("rebase").num_args(1).short('r').long("rebase").help("Rebase the specified branch from the updated upstream"))
                 .arg(Arg::new("branch").num_args(1).help("Branch to switch to"))
-                .about("Update the existing ABBS tree (fetch only) and optionally switch to a different branch")
+                .arg(Arg::new("fetch-only").long("fetch-only").action(clap::ArgAction::SetTrue).conflicts_with("branch").help("Do not fast-forward the current branch"))
+                .about("Update the existing ABBS tree (fetch and fast-forward) and optionally switch to a different branch")
         )
         .subcommand(
             Command::new("new")
             .arg(Arg::new("tarball").num_args(1).long("from-tarball").help("Create a new workspace from the specified tarball"))
-            .arg(Arg::new("arch").num_args(1).short('a').long("arch").help("Create a new workspace for specified architecture"))
+            .arg(Arg::new("arch").num_args(1).short('a').long("arch").env("CIEL_ARCH").help("Create a new workspace for specified architecture"))
+            .arg(Arg::new("non-interactive").long("non-interactive").env("CIEL_NON_INTERACTIVE").action(clap::ArgAction::SetTrue).help("Do not ask any questions, fail if a required value is missing (the values can also be given with the CIEL_* variables, e.g. CIEL_MAINTAINER)"))
+            .arg(Arg::new("maintainer").long("maintainer").num_args(1).help("Maintainer information (e.g. \"Bot <null@aosc.io>\")"))
+            .arg(Arg::new("dnssec").long("dnssec").action(clap::ArgAction::SetTrue).help("Enable DNSSEC"))
+            .arg(Arg::new("apt-source").long("apt-source").num_args(1).action(clap::ArgAction::Append).help("Line of sources.list (can be specified multiple times)"))
+            .arg(Arg::new("no-local-repo").long("no-local-repo").action(clap::ArgAction::SetTrue).help("Disable the local packages repository"))
+            .arg(Arg::new("no-local-sources").long("no-local-sources").action(clap::ArgAction::SetTrue).help("Disable local sources caching"))
+            .arg(Arg::new("no-branch-output").long("no-branch-output").action(clap::ArgAction::SetTrue).help("Use the same OUTPUT directory for all the branches"))
+            .arg(Arg::new("proxy").long("proxy").num_args(1).env("CIEL_PROXY").help("Proxy for the downloads and Git operations (saved as network.proxy)"))
+            .arg(Arg::new("volatile-mount").long("volatile-mount").action(clap::ArgAction::SetTrue).help("Use volatile mode for filesystem operations"))
+            .arg(Arg::new("profile").long("profile").num_args(1).env("CIEL_PROFILE").help("Base the configuration on a profile (e.g. mainline, stable-proposed or retro)"))
+            .arg(Arg::new("template").long("template").num_args(1).env("CIEL_TEMPLATE").help("Initialize the workspace from a template (Git repository URL)"))
+            .arg(Arg::new("trust").long("trust").action(clap::ArgAction::SetTrue).requires("template").help("Install the hooks of the template without asking (they run on this machine as root)"))
+            .arg(Arg::new("mirror").long("mirror").num_args(1).env("CIEL_MIRROR").help("Mirror of releases.aosc.io to fetch the OS tarball from"))
+            .arg(Arg::new("no-verify").long("no-verify").env("CIEL_NO_VERIFY").action(clap::ArgAction::SetTrue).help("Do not verify the signature of the downloaded tarball (dangerous)"))
+            .arg(Arg::new("p2p").long("p2p").env("CIEL_P2P").action(clap::ArgAction::SetTrue).help("Download the tarball from its metalink or torrent descriptor with aria2c if available (falls back to HTTPS)"))
+            .arg(Arg::new("branch").long("branch").num_args(1).env("CIEL_BRANCH").help("Branch of the ABBS tree to check out"))
+            .arg(Arg::new("instance").long("instance").num_args(1).env("CIEL_INSTANCE").help("Name of the instance to add after initialization"))
+            .args(tree_clone_args())
             .about("Create a new CIEL workspace")
         )
         .subcommand(
             Command::new("list")
                 .alias("ls")
+                .arg(Arg::new("watch").long("watch").short('w').action(clap::ArgAction::SetTrue).help("Keep the table refreshed on the changes of the instances, until Ctrl-C"))
+                .arg(Arg::new("interval").long("interval").num_args(1).value_name("SECONDS").default_value("2").value_parser(clap::value_parser!(u64)).requires("watch").help("How often the changes are checked"))
                 .about("List all the instances under the specified working directory"),
         )
+        .subcommand(
+            Command::new("du")
+                .about("Show the disk usage of the base system and each instance"),
+        )
         .subcommand(
             Command::new("add")
                 .arg(Arg::new("INSTANCE").required(true))
+                .arg(Arg::new("tmpfs").long("tmpfs").num_args(1).value_name("SIZE").help("Keep the changes of this instance in memory (tmpfs of the specified size, e.g. 4G)"))
+                .arg(Arg::new("quota").long("quota").num_args(1).value_name("SIZE").help("Limit the disk space this instance can use (e.g. 20G)"))
+                .arg(Arg::new("bootstrap").long("bootstrap").action(clap::ArgAction::SetTrue).help("Use this instance for the stage 2 (bootstrap) builds only"))
+                .arg(Arg::new("seccomp").long("seccomp").num_args(1).value_name("PRESET").value_parser(SECCOMP_PRESETS).help("System call filter of this instance"))
+                .arg(Arg::new("capabilities").long("capabilities").num_args(1).value_name("PRESET").value_parser(CAPABILITY_PRESETS).help("Capability set of this instance"))
+                .arg(Arg::new("no-provision").long("no-provision").action(clap::ArgAction::SetTrue).help("Do not install the packages of `provision` in the configuration"))
                 .about("Add a new instance"),
         )
         .subcommand(
             Command::new("del")
                 .alias("rm")
                 .arg(Arg::new("INSTANCE").required(true))
+                .arg(Arg::new("dry-run").long("dry-run").action(clap::ArgAction::SetTrue).help("Only show the machines to be terminated and the directories to be deleted"))
                 .about("Remove an instance"),
         )
         .subcommand(
             Command::new("shell")
                 .alias("sh")
                 .arg(instance_arg.clone().help("Instance to be used"))
+                .arg(Arg::new("no-autostart").long("no-autostart").action(clap::ArgAction::SetTrue).env("CIEL_NO_AUTOSTART").help("Fail if the instance is not running, instead of booting it"))
                 .arg(Arg::new("COMMANDS").required(false).num_args(1..))
-                .about("Start an interactive shell"),
+                .about("Start an interactive shell (the local repository is at /var/lib/ciel/debs, the packages built are staged in /debs)"),
         )
         .subcommand(
             Command::new("run")
                 .alias("exec")
                 .arg(instance_arg.clone().help("Instance to run command in"))
+                .arg(Arg::new("no-autostart").long("no-autostart").action(clap::ArgAction::SetTrue).env("CIEL_NO_AUTOSTART").help("Fail if the instance is not running, instead of booting it"))
+                .arg(Arg::new("ephemeral").long("ephemeral").action(clap::ArgAction::SetTrue).conflicts_with_all(["INSTANCE", "no-autostart"]).help("Run the command in a temporary instance, removed afterwards (even on failure)"))
+                .arg(Arg::new("collect").long("collect").num_args(1).action(clap::ArgAction::Append).value_name("PATH").requires("ephemeral").help("Copy the path in the temporary instance into the current directory before removing the instance"))
                 .arg(Arg::new("COMMANDS").required(true).num_args(1..))
                 .about("Lower-level version of 'shell', without login environment, without sourcing ~/.bash_profile"),
         )
+        .subcommand(
+            Command::new("cp")
+                .arg(Arg::new("SRC").required(true).help("Source path (use <INSTANCE>:<PATH> for paths in an instance)"))
+                .arg(Arg::new("DST").required(true).help("Destination path (use <INSTANCE>:<PATH> for paths in an instance)"))
+                .about("Copy files between the host and an instance"),
+        )
         .subcommand(
             Command::new("config")
                 .arg(instance_arg.clone().help("Instance to be configured"))
                 .arg(Arg::new("g").short('g').action(clap::ArgAction::SetTrue).conflicts_with("INSTANCE").help("Configure base system instead of an instance"))
+                .subcommand(Command::new("migrate").about("Migrate the workspace configuration file to the current format"))
+                .subcommand(Command::new("lint").about("Check the workspace configuration for problems"))
+                .subcommand(Command::new("show").about("Show the configuration of the workspace (with the environment overrides)"))
+                .subcommand(
+                    Command::new("repo")
+                        .arg_required_else_help(true)
+                        .subcommand(Command::new("add")
+                            .arg(Arg::new("NAME").required(true).help("Name of the repository"))
+                            .arg(Arg::new("URL").required(true).help("URL of the repository"))
+                            .arg(Arg::new("SUITE").required(true).help("Suite of the repository (e.g. stable)"))
+                            .arg(Arg::new("COMPONENTS").num_args(0..).help("Components of the repository (e.g. main)"))
+                            .arg(Arg::new("priority").long("priority").num_args(1).value_parser(clap::value_parser!(i32)).help("Pin priority of the packages from the repository"))
+                            .arg(Arg::new("key").long("key").num_args(1).help("Path to the signing key of the repository"))
+                            .about("Add an extra APT repository to the base system"))
+                        .subcommand(Command::new("remove").arg(Arg::new("NAME").required(true)).about("Remove an extra APT repository"))
+                        .subcommand(Command::new("list").about("List the extra APT repositories"))
+                        .about("Manage the extra APT repositories"),
+                )
+                .subcommand(Command::new("set").arg(Arg::new("ASSIGNMENTS").required(true).num_args(1..).help("Options to set (e.g. local_repo=false)")).about("Set configuration options non-interactively"))
+                .subcommand(Command::new("use-profile").arg(Arg::new("PROFILE").required(true).help("Name of the profile (e.g. mainline, stable-proposed or retro)")).about("Switch the workspace to a configuration profile"))
                 .about("Configure system and toolchain for building interactively"),
         )
         .subcommand(
             Command::new("commit")
                 .arg(instance_arg.clone().help("Instance to be committed"))
+                .arg(Arg::new("dry-run").long("dry-run").action(clap::ArgAction::SetTrue).help("Only show the instances to be un-mounted and the layer to be committed"))
                 .about("Commit changes onto the shared underlying OS"),
         )
+        .subcommand(Command::new("status").about("Show the overview of the workspace"))
         .subcommand(
             Command::new("doctor")
+                .arg(Arg::new("mounts").long("mounts").action(clap::ArgAction::SetTrue).help("Also verify (and optionally repair) the mount states of the instances"))
                 .about("Diagnose problems (hopefully)"),
         )
         .subcommand(
             Command::new("build")
-                .arg(Arg::new("FETCH").short('g').action(clap::ArgAction::SetTrue).help("Fetch source packages only"))
-                .arg(Arg::new("OFFLINE").short('x').long("offline").action(clap::ArgAction::SetTrue).env("CIEL_OFFLINE").help("Disable network in the container during the build"))
+                .arg(Arg::new("FETCH").short('g').long("fetch-only").action(clap::ArgAction::SetTrue).help("Fetch source packages only"))
+                .arg(Arg::new("UNPACK").long("unpack-only").conflicts_with_all(["FETCH", "CONTINUE", "SELECT"]).action(clap::ArgAction::SetTrue).help("Fetch and unpack the sources into the build directories without building"))
                 .arg(instance_arg.clone().help("Instance to build in"))
-                .arg(Arg::new("STAGE2").long("stage2").short('2').action(clap::ArgAction::SetTrue).env("CIEL_STAGE2").help("Use stage 2 mode instead of the regular build mode"))
-                .arg(Arg::new("CONTINUE").conflicts_with("SELECT").short('c').long("resume").alias("continue").num_args(1).help("Continue from a Ciel checkpoint"))
-                .arg(Arg::new("SELECT").num_args(0..=1).long("stage-select").help("Select the starting point for a build"))
-                .arg(Arg::new("PACKAGES").conflicts_with("CONTINUE").num_args(1..))
+                .arg(Arg::new("EPHEMERAL").long("ephemeral").action(clap::ArgAction::SetTrue).conflicts_with_all(["INSTANCE", "CONTINUE", "SELECT", "via-daemon"]).help("Build in a temporary instance, removed afterwards (even on failure)"))
+                .arg(Arg::new("STAGE2").long("stage2").short('2').action(clap::ArgAction::SetTrue).env("CIEL_STAGE2").help("Use stage 2 mode instead of the regular build mode (same as --stage 2)"))
+                .arg(Arg::new("STAGE").long("stage").num_args(1).value_parser(["1", "2"]).conflicts_with("STAGE2").help("Build stage: 2 for bootstrapping (in a bootstrap instance, into a separate OUTPUT component)"))
+                .arg(Arg::new("CONTINUE").conflicts_with_all(["SELECT", "via-daemon"]).short('c').long("resume").alias("continue").num_args(0..=1).default_missing_value(".ciel/data/build-state.toml").help("Continue the last build from the failed package, or from a Ciel checkpoint"))
+                .arg(Arg::new("SKIP_FAILED").long("skip-failed").requires("CONTINUE").action(clap::ArgAction::SetTrue).help("Skip the failed package instead of retrying it when continuing"))
+                .arg(Arg::new("SELECT").num_args(0..=1).long("stage-select").conflicts_with("via-daemon").help("Select the starting point for a build"))
+                .arg(Arg::new("WITH_DEPS").long("with-deps").action(clap::ArgAction::SetTrue).help("Build the packages in the order of their dependencies, including the dependencies newer in the tree than in the repositories"))
+                .arg(Arg::new("RETRY").long("retry").num_args(1).value_name("N").value_parser(clap::value_parser!(usize)).help("Retry a failed package build up to N times, and tell whether the failure is reproducible"))
+                .arg(Arg::new("RESET_ON_RETRY").long("reset-on-retry").requires("RETRY").action(clap::ArgAction::SetTrue).help("Roll back the instance before each retry"))
+                .arg(Arg::new("ON_FAIL").long("on-fail").num_args(1).value_parser(["ask", "shell", "stop"]).help("What to do when a package fails to build: offer a shell in the build directory (default if at a terminal), open it, or stop"))
+                .arg(Arg::new("PACKAGE_TIMEOUT").long("package-timeout").num_args(1).value_name("DURATION").help("Terminate a package build running longer than the duration (e.g. `2h`)"))
+                .arg(Arg::new("TIMEOUT").long("timeout").num_args(1).value_name("DURATION").help("Terminate the build running longer than the duration (e.g. `12h`)"))
+                .arg(Arg::new("REPORT").long("report").num_args(1).value_name("FILE").help("Write the result into the file as JUnit XML (or JSON if it ends with .json) for the CI"))
+                .arg(Arg::new("PRIORITY").long("priority").num_args(1).value_parser(["normal", "low", "idle"]).help("CPU and IO priority of the builds, `low` or `idle` to keep the machine responsive"))
+                .arg(Arg::new("OFFLINE").short('x').long("offline").action(clap::ArgAction::SetTrue).env("CIEL_OFFLINE").help("Fetch the sources first, then disable the network in the container during the build (same as `[network] offline`)"))
+                .arg(Arg::new("BLOCK_NETWORK").long("block-network").action(clap::ArgAction::SetTrue).help("Fetch the sources first, then block the network access of the builds (except for installing the dependencies)"))
+                .arg(Arg::new("CHECK_REPRODUCIBLE").long("check-reproducible").conflicts_with_all(["FETCH", "UNPACK", "CONTINUE", "SELECT"]).action(clap::ArgAction::SetTrue).help("Build the packages twice and report the files differing in the packages produced"))
+                .arg(Arg::new("NO_PREFLIGHT").long("no-preflight").action(clap::ArgAction::SetTrue).help("Do not check the spec and defines of the packages before building"))
+                .arg(Arg::new("SKIP_BUILT").long("skip-built").action(clap::ArgAction::SetTrue).help("Skip the packages already in the local repository at the same or a newer version"))
+                .arg(Arg::new("CHANGED").long("changed").num_args(0..=1).value_name("REV").conflicts_with("CONTINUE").help("Build the packages changed in the tree since the revision (by default, since the last successful build)"))
+                .arg(Arg::new("DEDUP").long("dedup").action(clap::ArgAction::SetTrue).help("Build each package only once, even if it is listed several times (e.g. in several groups)"))
+                .arg(Arg::new("PACKAGES").conflicts_with("CONTINUE").num_args(1..).help("Packages to build, `groups/<name>` for a group in the tree, `@<file>` for a list in the file, or `-` for a list in the standard input"))
                 .about("Build the packages using the specified instance"),
         )
         .subcommand(
             Command::new("rollback")
                 .arg(instance_arg.clone().help("Instance to be rolled back"))
+                .arg(Arg::new("keep").long("keep").num_args(1).action(clap::ArgAction::Append).value_name("GLOB").help("Preserve the paths in the instance matching the glob (e.g. `/root/.cargo`), in addition to rollback-keep in the configuration"))
+                .arg(Arg::new("dry-run").long("dry-run").action(clap::ArgAction::SetTrue).help("Only show the machines to be terminated and the directories to be deleted"))
                 .about("Rollback all or specified instance"),
         )
         .subcommand(
@@ -139,6 +264,12 @@ pub fn build_cli() -> Command {
                 .arg(instance_arg.clone().help("Instance to be stopped"))
                 .about("Shuts down an instance"),
         )
+        .subcommand(
+            Command::new("autostop")
+                .arg(Arg::new("idle").long("idle").num_args(1).value_name("DURATION").help("Stop the instances idle for this long (e.g. `2h`), `auto-stop` in the configuration by default"))
+                .arg(Arg::new("dry-run").long("dry-run").action(clap::ArgAction::SetTrue).help("Only report the instances which would be stopped"))
+                .about("Stop the instances which have been idle for a while"),
+        )
         .subcommand(
             Command::new("mount")
                 .arg(instance_arg.help("Instance to be mounted"))
@@ -147,18 +278,183 @@ pub fn build_cli() -> Command {
         .subcommand(
             Command::new("farewell")
                 .alias("harakiri")
-                .about("Remove everything related to CIEL!"),
+                .arg(Arg::new("dry-run").long("dry-run").action(clap::ArgAction::SetTrue).help("Only show the machines to be terminated and the directories to be deleted"))
+                .arg(Arg::new("permanent").long("permanent").action(clap::ArgAction::SetTrue).help("Delete the workspace right away instead of moving it into the trash"))
+                .arg(Arg::new("restore").long("restore").num_args(0..=1).value_name("ID").default_missing_value("latest").conflicts_with_all(["dry-run", "permanent"]).help("Restore the workspace removed last (or the specified one) from the trash"))
+                .arg(Arg::new("purge").long("purge").num_args(0..=1).value_name("ID").default_missing_value("expired").conflicts_with_all(["restore", "permanent"]).help("Delete the expired workspaces (or the specified one) in the trash permanently"))
+                .about("Remove everything related to CIEL! (restorable from the trash for a while)"),
         )
         .subcommand(
             Command::new("repo")
                 .arg_required_else_help(true)
                 .subcommands(vec![Command::new("refresh").about("Refresh the repository"), Command::new("init").arg(Arg::new("INSTANCE").required(true)).about("Initialize the repository"), Command::new("deinit").about("Uninitialize the repository")])
+                .subcommand(
+                    Command::new("prune")
+                        .arg(Arg::new("keep").long("keep").num_args(1).value_parser(clap::value_parser!(usize)).help("Keep the last N versions of each package"))
+                        .arg(Arg::new("max-age").long("max-age").num_args(1).value_parser(clap::value_parser!(u64)).help("Remove the packages older than DAYS (the latest version of each package is kept)"))
+                        .arg(Arg::new("superseded-noarch").long("superseded-noarch").action(clap::ArgAction::SetTrue).help("Remove the `all` packages superseded by a newer architecture-specific version"))
+                        .arg(Arg::new("dry-run").long("dry-run").action(clap::ArgAction::SetTrue).help("Only list the packages to remove"))
+                        .about("Remove the old packages from the repository"),
+                )
+                .subcommand(
+                    Command::new("push")
+                        .arg(Arg::new("TARGET").required(true).env("CIEL_PUSH_TARGET").help("Where to upload to (host:/path, rsync://, sftp://host/path or s3://bucket/prefix)"))
+                        .arg(Arg::new("dry-run").long("dry-run").action(clap::ArgAction::SetTrue).help("Only list the files to upload"))
+                        .about("Upload the repository to a remote server, then run the post-push hook"),
+                )
+                .subcommand(
+                    Command::new("list")
+                        .about("List the packages in the repository"),
+                )
+                .subcommand(
+                    Command::new("show")
+                        .arg(Arg::new("PACKAGE").required(true))
+                        .about("Show the versions, dependencies and builds of a package in the repository"),
+                )
+                .subcommand(
+                    Command::new("dedupe")
+                        .arg(Arg::new("dry-run").long("dry-run").action(clap::ArgAction::SetTrue).help("Only list the packages to share"))
+                        .about("Make the identical packages in the output directories share their data (needs reflinks, e.g. on XFS or btrfs)"),
+                )
+                .subcommand(
+                    Command::new("diff")
+                        .arg(Arg::new("PACKAGE").required(true))
+                        .about("Compare the two latest versions of a package in the repository"),
+                )
+                .subcommand(
+                    Command::new("key")
+                        .arg_required_else_help(true)
+                        .subcommand(Command::new("generate").about("Generate the signing key of the workspace, the repository is signed when refreshed"))
+                        .subcommand(Command::new("remove").about("Remove the signing key, the repository is trusted without signatures again"))
+                        .subcommand(Command::new("export").about("Print the public key (armored) for trusting the repository on the other machines"))
+                        .about("Manage the signing key of the local repository"),
+                )
                 .alias("localrepo")
                 .about("Local repository operations")
         )
+        .subcommand(
+            Command::new("workspace")
+                .arg_required_else_help(true)
+                .subcommand(Command::new("relocate").about("Update the workspace after it has been moved to a different directory"))
+                .about("Workspace maintenance operations")
+        )
+        .subcommand(
+            Command::new("dist")
+                .arg_required_else_help(true)
+                .subcommand(Command::new("unlock").about("Make the base system writable for maintenance"))
+                .subcommand(Command::new("lock").about("Write-protect the base system again"))
+                .about("Base system write protection")
+        )
+        .subcommand(
+            Command::new("secret")
+                .arg_required_else_help(true)
+                .subcommand(Command::new("set").arg(Arg::new("NAME").required(true).help("Name of the secret")).arg(Arg::new("VALUE").help("Value of the secret (read from the terminal or the standard input if omitted)")).about("Store a secret, referred to as ${secret:NAME} in the configuration"))
+                .subcommand(Command::new("remove").arg(Arg::new("NAME").required(true)).about("Remove a secret"))
+                .subcommand(Command::new("list").about("List the names of the stored secrets"))
+                .about("Manage the credentials used in the configuration"),
+        )
+        .subcommand(
+            Command::new("tree")
+                .arg_required_else_help(true)
+                .subcommand(Command::new("add-remote").arg(Arg::new("NAME").required(true).help("Name of the overlay tree")).arg(Arg::new("URL").required(true).help("URL to the git repository")).arg(Arg::new("branch").long("branch").num_args(1).help("Branch to check out")).arg(Arg::new("priority").long("priority").num_args(1).allow_negative_numbers(true).value_parser(clap::value_parser!(i32)).default_value("0").help("Trees with higher priorities take precedence")).about("Add an overlay tree merged on top of the ABBS tree when building"))
+                .subcommand(Command::new("remove-remote").arg(Arg::new("NAME").required(true)).about("Remove an overlay tree"))
+                .subcommand(Command::new("list-remotes").about("List the ABBS tree and the overlay trees"))
+                .about("Manage the sources of the ABBS tree"),
+        )
+        .subcommand(
+            Command::new("schedule")
+                .arg_required_else_help(true)
+                .subcommand(
+                    Command::new("add")
+                        .arg(Arg::new("JOB").required(true).value_parser(["update-os", "gc", "build"]).help("Job to run"))
+                        .arg(Arg::new("name").long("name").num_args(1).help("Name of the job (the job by default), for scheduling several builds"))
+                        .arg(Arg::new("on-calendar").long("on-calendar").num_args(1).value_name("EVENT").help("When to run the job, as in systemd.time(7) (daily for update-os and build, weekly for gc by default)"))
+                        .arg(Arg::new("INSTANCE").short('i').num_args(1).help("Instance to build in"))
+                        .arg(Arg::new("PACKAGES").num_args(1..).help("Packages to build, as given to `ciel build`"))
+                        .about("Schedule a recurring job with a systemd timer (of the system instance, as Ciel needs root)"),
+                )
+                .subcommand(Command::new("remove").arg(Arg::new("NAME").required(true)).about("Remove a scheduled job"))
+                .subcommand(Command::new("list").about("List the jobs scheduled in the workspace"))
+                .about("Manage the recurring jobs of the workspace (e.g. nightly update-os)"),
+        )
+        .subcommand(
+            Command::new("backup")
+                .arg(Arg::new("FILE").required(true).help("Path to the backup"))
+                .arg(Arg::new("layers").long("layers").action(clap::ArgAction::SetTrue).help("Include the upper layers of the instances (the instances are stopped)"))
+                .about("Back up the configuration, the instances and the revision of the tree into a single archive"),
+        )
+        .subcommand(
+            Command::new("restore")
+                .arg(Arg::new("FILE").required(true).help("Path to the backup"))
+                .about("Reconstruct the workspace in the current directory from a backup"),
+        )
+        .subcommand(
+            Command::new("bump")
+                .arg(Arg::new("PACKAGE").required(true).help("Package to bump"))
+                .arg(Arg::new("rel").long("rel").action(clap::ArgAction::SetTrue).conflicts_with("version").help("Increase the release to rebuild the same version (default)"))
+                .arg(Arg::new("version").long("version").num_args(1).value_name("VERSION").help("Update the package to the version, resetting the release (not committed, as the checksums are to be updated)"))
+                .arg(Arg::new("message").short('m').long("message").num_args(1).value_name("TEMPLATE").help("Commit message, `{package}`, `{version}` and `{rel}` are replaced"))
+                .arg(Arg::new("no-commit").long("no-commit").action(clap::ArgAction::SetTrue).help("Only edit the spec without committing"))
+                .arg(Arg::new("build").long("build").action(clap::ArgAction::SetTrue).help("Build the package afterwards"))
+                .arg(instance_arg.clone().help("Instance to build in"))
+                .about("Bump the release (or the version) of a package in the tree, and commit the change"),
+        )
+        .subcommand(
+            Command::new("topic")
+                .arg(Arg::new("BRANCH").conflicts_with("finish").help("Topic branch to work on"))
+                .arg(Arg::new("finish").long("finish").action(clap::ArgAction::SetTrue).help("Finish the topic, restoring the branch of the tree, the repositories and the OUTPUT component"))
+                .about("Work on a topic: switch the tree to the topic branch, install from the topic repository and build into a separate OUTPUT component"),
+        )
+        .subcommand(
+            Command::new("cache")
+                .arg_required_else_help(true)
+                .subcommand(Command::new("list").about("List the cached OS tarballs"))
+                .subcommand(Command::new("prune").arg(Arg::new("max-age").long("max-age").num_args(1).value_parser(clap::value_parser!(u64)).help("Remove the tarballs not used in the given number of days")).arg(Arg::new("max-size").long("max-size").num_args(1).help("Remove the least recently used tarballs until the cache fits in the size (e.g. 4G)")).about("Remove the old tarballs from the cache"))
+                .subcommand(Command::new("clear").about("Remove all the cached tarballs"))
+                .about("Manage the OS tarball cache shared by the workspaces"),
+        )
+        .subcommand(
+            Command::new("apply")
+                .arg(Arg::new("yes").short('y').long("yes").action(clap::ArgAction::SetTrue).help("Remove the instances not in the manifest without asking"))
+                .arg(Arg::new("dry-run").long("dry-run").action(clap::ArgAction::SetTrue).help("Only show the changes to be made"))
+                .about("Reconcile the instances with the workspace manifest (ciel.toml)"),
+        )
+        .subcommand(
+            Command::new("gc")
+                .arg(Arg::new("dry-run").long("dry-run").action(clap::ArgAction::SetTrue).help("Only report what would be removed"))
+                .about("Reclaim the resources no longer used by the workspace")
+        )
+        .subcommand(
+            Command::new("qa")
+                .arg_required_else_help(true)
+                .subcommand(
+                    Command::new("conflicts")
+                        .arg(Arg::new("remote").long("remote").num_args(1).value_name("URL").help("Also check against the Contents index of the remote repository (plain, .gz or .xz)"))
+                        .about("Find the files shared by the packages without Replaces or Conflicts"),
+                )
+                .about("Quality checks of the packages in the repository")
+        )
+        .subcommand(
+            Command::new("daemon")
+                .arg(Arg::new("metrics").long("metrics").num_args(1).value_name("ADDRESS").help("Also serve the Prometheus metrics at http://ADDRESS/metrics (e.g. 127.0.0.1:9100)"))
+                .about("Serve the workspace over a control socket (same as running `cield`)"),
+        )
+        .subcommand(
+            Command::new("stats")
+                .arg(Arg::new("top").long("top").short('n').num_args(1).default_value("20").value_parser(clap::value_parser!(usize)).help("Number of the packages to show"))
+                .about("Show the slowest packages and the trends of the build times")
+        )
+        .subcommand(
+            Command::new("log")
+                .arg(Arg::new("build").long("build").num_args(1).value_name("PACKAGE").help("Print the latest build log of the package"))
+                .about("List the build logs, or print the latest build log of a package")
+        )
         .subcommand(
             Command::new("clean")
-                .about("Clean all the output directories and source cache directories")
+                .arg(Arg::new("instances").long("instances").action(clap::ArgAction::SetTrue).help("Also prune junk files from the instance layers"))
+                .arg(Arg::new("sources").long("sources").conflicts_with("instances").action(clap::ArgAction::SetTrue).help("Only clean the source cache shared by the instances"))
+                .arg(Arg::new("dry-run").long("dry-run").action(clap::ArgAction::SetTrue).help("Only show the directories to be deleted"))
+                .about("Clean all the output directories and source cache directories, then de-duplicate the remaining packages")
         )
         .subcommands({
             let plugins = list_helpers();
@@ -180,12 +476,44 @@ pub fn build_cli() -> Command {
                     .value_name("DIR")
                     .default_value(".")
                     .num_args(1..)
-                    .help("Set the CIEL! working directory"),
+                    .help("Run as if Ciel was started in DIR (the workspace is searched from DIR upwards)"),
+                Arg::new("workspace")
+                    .long("workspace")
+                    .value_name("DIR")
+                    .num_args(1)
+                    .env("CIEL_WORKSPACE")
+                    .global(true)
+                    .help("Use the workspace at DIR instead of searching for it"),
                 Arg::new("batch")
                     .short('b')
                     .long("batch")
                     .action(clap::ArgAction::SetTrue)
                     .help("Batch mode, no input required"),
+                Arg::new("component")
+                    .long("component")
+                    .num_args(1)
+                    .env("CIEL_COMPONENT")
+                    .global(true)
+                    .help("OUTPUT component to build into and use as the local repository (e.g. main or bsp)"),
+                Arg::new("progress")
+                    .long("progress")
+                    .num_args(1)
+                    .env("CIEL_PROGRESS")
+                    .value_parser(["terminal", "json"])
+                    .default_value("terminal")
+                    .global(true)
+                    .help("How to report the progress of downloads (json: JSON lines on stderr)"),
+                Arg::new("via-daemon")
+                    .long("via-daemon")
+                    .action(clap::ArgAction::SetTrue)
+                    .global(true)
+                    .help("Ask the daemon of the workspace (`cield`) to run the command, only for `list` and `build` (the results are printed as JSON)"),
+                Arg::new("json")
+                    .long("json")
+                    .action(clap::ArgAction::SetTrue)
+                    .env("CIEL_JSON")
+                    .global(true)
+                    .help("Print the results as JSON on stdout (the messages still go to stderr)"),
             ]
         )
 }
@@ -1,17 +1,28 @@
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Result};
 use console::user_attended;
 use dialoguer::{theme::ColorfulTheme, FuzzySelect};
 use lazy_static::lazy_static;
+use nix::fcntl::{flock, FlockArg};
+use nix::sys::signal::{signal, SigHandler, Signal};
 use sha2::{Digest, Sha256};
 use std::env::consts::ARCH;
+use std::ffi::OsStr;
 use std::fs::{self, File};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
 use std::os::unix::prelude::MetadataExt;
 use std::{
-    io::{Read, Write},
+    io::{BufRead, BufReader, Read, Write},
     path::{Path, PathBuf},
-    time::Duration,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
 };
 
+use crate::errors::CielError;
+use crate::progress::{Progress, Unit};
+
 pub const CIEL_MAINLINE_ARCHS: &[&str] = &["amd64", "arm64", "ppc64el", "mips64r6el", "riscv64"];
 pub const CIEL_RETRO_ARCHS: &[&str] = &["armv4", "armv6hf", "armv7hf", "i486", "m68k", "powerpc"];
 pub const CURRENT_CIEL_VERSION: usize = 3;
@@ -19,14 +30,15 @@ const CURRENT_CIEL_VERSION_STR: &str = "3";
 pub const CIEL_DIST_DIR: &str = ".ciel/container/dist";
 pub const CIEL_INST_DIR: &str = ".ciel/container/instances";
 pub const CIEL_DATA_DIR: &str = ".ciel/data";
+const CIEL_LOCATION_FILE: &str = ".ciel/data/location";
+const CIEL_DEFAULT_INSTANCE_FILE: &str = ".ciel/data/default-instance";
 const SKELETON_DIRS: &[&str] = &[CIEL_DIST_DIR, CIEL_INST_DIR, CIEL_DATA_DIR];
+/// Selects the workspace, instead of searching from the current directory
+pub const CIEL_WORKSPACE_ENV: &str = "CIEL_WORKSPACE";
 
 lazy_static! {
-    static ref SPINNER_STYLE: indicatif::ProgressStyle =
-        indicatif::ProgressStyle::default_spinner()
-            .tick_chars("⠋⠙⠸⠴⠦⠇ ")
-            .template("{spinner:.green} {wide_msg}")
-            .unwrap();
+    /// The directory Ciel is invoked from, if it is not the root of the workspace
+    static ref INVOCATION_DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
 }
 
 #[macro_export]
@@ -40,13 +52,86 @@ macro_rules! make_progress_bar {
     };
 }
 
-#[inline]
-pub fn create_spinner(msg: &'static str, tick_rate: u64) -> indicatif::ProgressBar {
-    let spinner = indicatif::ProgressBar::new_spinner().with_style(SPINNER_STYLE.clone());
-    spinner.set_message(msg);
-    spinner.enable_steady_tick(Duration::from_millis(tick_rate));
+/// Parse a human readable size with binary suffixes (e.g. `512M` or `20G`) into bytes
+pub fn parse_size(size: &str) -> Option<u64> {
+    let size = size.trim();
+    let (digits, shift) = match size.chars().last()? {
+        'k' | 'K' => (&size[..size.len() - 1], 10),
+        'm' | 'M' => (&size[..size.len() - 1], 20),
+        'g' | 'G' => (&size[..size.len() - 1], 30),
+        't' | 'T' => (&size[..size.len() - 1], 40),
+        _ => (size, 0),
+    };
+
+    digits.parse::<u64>().ok()?.checked_mul(1u64 << shift)
+}
+
+/// Match the name against the wildcard pattern (`*` matches any characters, `?` matches one)
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let name = name.chars().collect::<Vec<_>>();
+    let (mut p, mut n) = (0, 0);
+    // the last `*` seen, and where its match ends in the name
+    let mut star = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = star {
+            // let the `*` match one more character
+            p = star_p + 1;
+            n = star_n + 1;
+            star = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+fn glob_match_components(pattern: &[&str], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((&"**", rest)) => (0..=path.len()).any(|i| glob_match_components(rest, &path[i..])),
+        Some((first, rest)) => match path.split_first() {
+            Some((name, path)) => wildcard_match(first, name) && glob_match_components(rest, path),
+            None => false,
+        },
+    }
+}
 
-    spinner
+/// Match the path against the glob pattern: `*` and `?` match within a path component, `**`
+/// matches any number of components
+pub fn glob_match(pattern: &str, path: &str) -> bool {
+    let pattern = pattern
+        .split('/')
+        .filter(|c| !c.is_empty())
+        .collect::<Vec<_>>();
+    let path = path
+        .split('/')
+        .filter(|c| !c.is_empty())
+        .collect::<Vec<_>>();
+
+    glob_match_components(&pattern, &path)
+}
+
+/// Parse a human readable duration (e.g. `12h` or `7d`) into seconds
+pub fn parse_duration(duration: &str) -> Option<u64> {
+    let duration = duration.trim();
+    let (digits, unit) = match duration.chars().last()? {
+        's' => (&duration[..duration.len() - 1], 1),
+        'm' => (&duration[..duration.len() - 1], 60),
+        'h' => (&duration[..duration.len() - 1], 3600),
+        'd' => (&duration[..duration.len() - 1], 86400),
+        'w' => (&duration[..duration.len() - 1], 7 * 86400),
+        _ => (duration, 1),
+    };
+
+    digits.parse::<u64>().ok()?.checked_mul(unit)
 }
 
 #[inline]
@@ -96,65 +181,221 @@ pub fn sha256sum<R: Read>(mut reader: R) -> Result<String> {
     Ok(format!("{:x}", hasher.finalize()))
 }
 
-/// Extract the given .tar.xz stream and preserve all the file attributes
-pub fn extract_tar_xz<R: Read>(reader: R, path: &Path) -> Result<()> {
-    let decompress = xz2::read::XzDecoder::new(reader);
-    let mut tar_processor = tar::Archive::new(decompress);
-    tar_processor.set_unpack_xattrs(true);
-    tar_processor.set_preserve_permissions(true);
-    tar_processor.unpack(path)?;
+/// Decompress the tarball stream, the compression format (xz, zstd, gzip or none) is detected
+/// from the magic number
+pub fn decompress_tarball<'a, R: Read + 'a>(reader: R) -> Result<Box<dyn Read + 'a>> {
+    let mut reader = BufReader::new(reader);
+    let magic = reader.fill_buf()?;
+    let (xz, zstd, gzip) = (
+        magic.starts_with(b"\xfd7zXZ\x00"),
+        magic.starts_with(b"\x28\xb5\x2f\xfd"),
+        magic.starts_with(b"\x1f\x8b"),
+    );
+
+    Ok(if xz {
+        Box::new(xz2::read::XzDecoder::new_multi_decoder(reader))
+    } else if zstd {
+        Box::new(zstd::Decoder::with_buffer(reader)?)
+    } else if gzip {
+        Box::new(flate2::read::GzDecoder::new(reader))
+    } else {
+        Box::new(reader)
+    })
+}
+
+/// Extract the given (uncompressed) tar stream and preserve all the file attributes
+/// (see [crate::extract])
+pub fn extract_tar<R: Read>(reader: R, path: &Path) -> Result<()> {
+    crate::extract::extract(reader, path, |_| Ok(()))?;
 
     Ok(())
 }
 
-pub fn extract_system_tarball(path: &Path, total: u64) -> Result<()> {
-    let f = File::open(path)?;
-    let progress_bar = indicatif::ProgressBar::new(total);
-    progress_bar.set_style(
-        indicatif::ProgressStyle::default_bar()
-            .template(make_progress_bar!("Extracting tarball..."))
-            .unwrap(),
-    );
-    progress_bar.set_draw_target(indicatif::ProgressDrawTarget::stderr_with_hz(5));
-    let reader = progress_bar.wrap_read(f);
+static EXTRACTION_CANCELLED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn cancel_extraction(_: libc::c_int) {
+    EXTRACTION_CANCELLED.store(true, Ordering::SeqCst);
+}
+
+/// Unpack the tar stream entry by entry, stops when the extraction is cancelled
+fn unpack_tar_entries<R: Read>(reader: R, dest: &Path, progress: &Progress) -> Result<()> {
+    crate::extract::extract(reader, dest, |files| {
+        if EXTRACTION_CANCELLED.load(Ordering::SeqCst) {
+            bail!("Extraction cancelled");
+        }
+        progress.set_message(&format!("Extracting tarball... ({} files)", files));
+
+        Ok(())
+    })?;
+
+    Ok(())
+}
+
+/// Extract the (compressed) OS tarball stream to the dist layer while it is being read,
+/// `total` is the size of the stream for reporting the progress (0 if unknown).
+/// The extraction can be cancelled by Ctrl-C, and the partially extracted files are removed
+pub fn extract_system_stream<R: Read>(reader: R, total: u64) -> Result<()> {
     let dist_dir = PathBuf::from(CIEL_DIST_DIR);
     if dist_dir.exists() {
         fs::remove_dir_all(&dist_dir).ok();
+    }
+    fs::create_dir_all(&dist_dir)?;
+    let progress = Progress::new(
+        "Extracting tarball...",
+        Unit::Bytes,
+        Some(total).filter(|t| *t > 0),
+    );
+    EXTRACTION_CANCELLED.store(false, Ordering::SeqCst);
+    // unsafe: the handler only stores to an atomic
+    let previous = unsafe { signal(Signal::SIGINT, SigHandler::Handler(cancel_extraction)) }?;
+    let result = decompress_tarball(progress.wrap_read(reader))
+        .and_then(|reader| unpack_tar_entries(reader, &dist_dir, &progress));
+    unsafe { signal(Signal::SIGINT, previous) }?;
+    progress.finish();
+    if result.is_err() {
+        // never leave a half-extracted dist layer around
+        fs::remove_dir_all(&dist_dir).ok();
         fs::create_dir_all(&dist_dir)?;
     }
-    extract_tar_xz(reader, &dist_dir)?;
-    progress_bar.finish_and_clear();
 
-    Ok(())
+    result
+}
+
+pub fn extract_system_tarball(path: &Path, total: u64) -> Result<()> {
+    crate::compat::check_tarball_arch(path)?;
+    let dist_dir = PathBuf::from(CIEL_DIST_DIR);
+    // only for the uncompressed tarballs, the released ones are compressed
+    if crate::reflink::is_plain_tarball(path)? {
+        let spinner = Progress::spinner("Extracting tarball...");
+        if dist_dir.exists() {
+            fs::remove_dir_all(&dist_dir).ok();
+        }
+        fs::create_dir_all(&dist_dir)?;
+        // the blocks are shared file by file, Ctrl-C is ignored until the extraction is done
+        let previous = unsafe { signal(Signal::SIGINT, SigHandler::SigIgn) }?;
+        let result = crate::reflink::extract_tarball(path, &dist_dir);
+        unsafe { signal(Signal::SIGINT, previous) }?;
+        spinner.finish();
+        if result.is_err() {
+            fs::remove_dir_all(&dist_dir).ok();
+            fs::create_dir_all(&dist_dir)?;
+        }
+        return result;
+    }
+
+    extract_system_stream(File::open(path)?, total)
 }
 
 pub fn ciel_init() -> Result<()> {
     for dir in SKELETON_DIRS {
         fs::create_dir_all(dir)?;
     }
+    // use ZFS datasets for the layers when the workspace is located on ZFS
+    crate::zfs::create_workspace_datasets()?;
     let mut f = File::create(".ciel/version")?;
     f.write_all(CURRENT_CIEL_VERSION_STR.as_bytes())?;
+    record_workspace_location()?;
+
+    Ok(())
+}
+
+/// Record the current location of the workspace, the container names are derived from it
+pub fn record_workspace_location() -> Result<()> {
+    let cwd = std::env::current_dir()?;
+    fs::write(CIEL_LOCATION_FILE, cwd.as_os_str().as_bytes())?;
 
     Ok(())
 }
 
-/// Find the ciel directory
+/// Return the location of the workspace when it was last used
+pub fn recorded_workspace_location() -> Option<PathBuf> {
+    let location = fs::read(CIEL_LOCATION_FILE).ok()?;
+
+    Some(PathBuf::from(OsStr::from_bytes(&location)))
+}
+
+/// Return the default instance of the workspace (set by `ciel use`), if any
+pub fn default_instance() -> Option<String> {
+    let instance = fs::read_to_string(CIEL_DEFAULT_INSTANCE_FILE).ok()?;
+    let instance = instance.trim();
+
+    (!instance.is_empty()).then(|| instance.to_owned())
+}
+
+/// Set the default instance of the workspace, or clear it if `instance` is `None`
+pub fn set_default_instance(instance: Option<&str>) -> Result<()> {
+    match instance {
+        Some(instance) => fs::write(CIEL_DEFAULT_INSTANCE_FILE, instance)?,
+        None if Path::new(CIEL_DEFAULT_INSTANCE_FILE).exists() => {
+            fs::remove_file(CIEL_DEFAULT_INSTANCE_FILE)?
+        }
+        None => (),
+    }
+
+    Ok(())
+}
+
+/// Check if the workspace has been moved since it was last used
+pub fn check_workspace_location() -> Result<()> {
+    let cwd = std::env::current_dir()?;
+    match recorded_workspace_location() {
+        Some(location) if location != cwd => Err(CielError::WorkspaceMoved(location).into()),
+        Some(_) => Ok(()),
+        // workspaces created by older versions of Ciel
+        None => record_workspace_location(),
+    }
+}
+
+/// Find the workspace containing the directory: walk up the parent directories until one
+/// containing `.ciel` is found, without crossing the filesystem boundary
 pub fn find_ciel_dir<P: AsRef<Path>>(start: P) -> Result<PathBuf> {
-    let start_path = fs::metadata(start.as_ref())?;
-    let start_dev = start_path.dev();
-    let mut current_dir = start.as_ref().to_path_buf();
+    let start = start.as_ref().canonicalize()?;
+    let start_dev = fs::metadata(&start)?.dev();
+    let mut current_dir = start.as_path();
     loop {
-        if !current_dir.exists() {
-            return Err(anyhow!("Hit filesystem ceiling!"));
-        }
-        let current_dev = current_dir.metadata()?.dev();
-        if current_dev != start_dev {
-            return Err(anyhow!("Hit filesystem boundary!"));
-        }
         if current_dir.join(".ciel").is_dir() {
-            return Ok(current_dir);
+            return Ok(current_dir.to_owned());
         }
-        current_dir = current_dir.join("..");
+        current_dir = match current_dir.parent() {
+            Some(parent) if parent.metadata()?.dev() == start_dev => parent,
+            _ => return Err(CielError::NotAWorkspace.into()),
+        };
+    }
+}
+
+/// Return the root of the workspace: `$CIEL_WORKSPACE` if set, otherwise the workspace
+/// containing the current directory
+pub fn workspace_root() -> Result<PathBuf> {
+    if let Some(dir) = std::env::var_os(CIEL_WORKSPACE_ENV) {
+        let dir = PathBuf::from(dir);
+        if !dir.join(".ciel").is_dir() {
+            return Err(CielError::NotAWorkspace.into());
+        }
+        return Ok(dir.canonicalize()?);
+    }
+
+    find_ciel_dir(std::env::current_dir()?)
+}
+
+/// Switch to the root of the workspace, as the paths in the workspace (`.ciel`, `TREE`, the
+/// instances) are relative to it. Returns the root
+pub fn enter_workspace() -> Result<PathBuf> {
+    let root = workspace_root()?;
+    let cwd = std::env::current_dir()?;
+    if cwd != root {
+        std::env::set_current_dir(&root)?;
+        *INVOCATION_DIR.lock().unwrap() = Some(cwd);
+    }
+
+    Ok(root)
+}
+
+/// Resolve the path given by the user against the directory Ciel is invoked from
+pub fn user_path<P: AsRef<Path>>(path: P) -> PathBuf {
+    let path = path.as_ref();
+    match &*INVOCATION_DIR.lock().unwrap() {
+        Some(dir) if path.is_relative() => dir.join(path),
+        _ => path.to_owned(),
     }
 }
 
@@ -201,3 +442,82 @@ pub fn ask_for_target_arch() -> Result<&'static str> {
 
     Ok(all_archs[chosen_index])
 }
+
+/// An advisory lock (flock(2)) on the file, created if missing, released when dropped
+pub struct FileLock {
+    _file: File,
+}
+
+impl FileLock {
+    fn acquire(path: &Path, arg: FlockArg) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = File::options().create(true).write(true).open(path)?;
+        flock(file.as_raw_fd(), arg)?;
+
+        Ok(FileLock { _file: file })
+    }
+
+    /// Take the shared lock, blocks while the exclusive lock is held
+    pub fn shared(path: &Path) -> Result<Self> {
+        FileLock::acquire(path, FlockArg::LockShared)
+    }
+
+    /// Take the exclusive lock, blocks while any lock is held
+    pub fn exclusive(path: &Path) -> Result<Self> {
+        FileLock::acquire(path, FlockArg::LockExclusive)
+    }
+
+    /// Try to take the exclusive lock, returns None if any lock is held
+    pub fn try_exclusive(path: &Path) -> Result<Option<Self>> {
+        match FileLock::acquire(path, FlockArg::LockExclusiveNonblock) {
+            Ok(lock) => Ok(Some(lock)),
+            Err(e) => match e.downcast_ref::<nix::Error>() {
+                Some(nix::Error::EWOULDBLOCK) => Ok(None),
+                _ => Err(e),
+            },
+        }
+    }
+}
+
+#[test]
+fn test_parse_size() {
+    assert_eq!(parse_size("1024"), Some(1024));
+    assert_eq!(parse_size("4k"), Some(4096));
+    assert_eq!(parse_size("20G"), Some(20 * 1024 * 1024 * 1024));
+    assert_eq!(parse_size("G"), None);
+    assert_eq!(parse_size("1.5G"), None);
+}
+
+#[test]
+fn test_parse_duration() {
+    assert_eq!(parse_duration("30"), Some(30));
+    assert_eq!(parse_duration("12h"), Some(12 * 3600));
+    assert_eq!(parse_duration("7d"), Some(7 * 86400));
+    assert_eq!(parse_duration("d"), None);
+    assert_eq!(parse_duration("1.5d"), None);
+}
+
+#[test]
+fn test_glob_match() {
+    assert!(glob_match("/root/.cargo", "/root/.cargo"));
+    assert!(!glob_match("/root/.cargo", "/root/.cargo/registry"));
+    assert!(glob_match("/var/cache/*", "/var/cache/acbs"));
+    assert!(!glob_match("/var/cache/*", "/var/cache/acbs/tarballs"));
+    assert!(glob_match("/var/**/acbs", "/var/cache/acbs"));
+    assert!(glob_match("/root/.*rc", "/root/.bashrc"));
+    assert!(glob_match("/srv/build-?", "/srv/build-1"));
+    assert!(!glob_match("/srv/build-?", "/srv/build-10"));
+}
+
+#[test]
+fn test_find_ciel_dir() {
+    let workspace = tempfile::tempdir().unwrap();
+    let tree = workspace.path().join("TREE/app-admin");
+    fs::create_dir_all(workspace.path().join(".ciel")).unwrap();
+    fs::create_dir_all(&tree).unwrap();
+    let root = workspace.path().canonicalize().unwrap();
+    assert_eq!(find_ciel_dir(&tree).unwrap(), root);
+    assert_eq!(find_ciel_dir(&root).unwrap(), root);
+}
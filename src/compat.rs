@@ -0,0 +1,252 @@
+//! This module contains the compatibility checks of the OS tarballs
+
+use anyhow::{anyhow, bail, Result};
+use console::style;
+use std::{
+    fs::{self, File},
+    io::Read,
+    path::Path,
+};
+
+use crate::warn;
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const EM_386: u16 = 3;
+const EM_68K: u16 = 4;
+const EM_MIPS: u16 = 8;
+const EM_PPC: u16 = 20;
+const EM_PPC64: u16 = 21;
+const EM_ARM: u16 = 40;
+const EM_X86_64: u16 = 62;
+const EM_AARCH64: u16 = 183;
+const EM_RISCV: u16 = 243;
+const EM_LOONGARCH: u16 = 258;
+const BINFMT_MISC_DIR: &str = "/proc/sys/fs/binfmt_misc";
+
+/// Architecture of an ELF executable
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ElfArch {
+    pub machine: u16,
+    pub is_64: bool,
+    pub little_endian: bool,
+}
+
+impl ElfArch {
+    /// Parse the ELF header (at least the first 20 bytes)
+    pub fn parse(header: &[u8]) -> Option<Self> {
+        if header.len() < 20 || !header.starts_with(ELF_MAGIC) {
+            return None;
+        }
+        let little_endian = header[5] == 1;
+        let machine = [header[18], header[19]];
+
+        Some(ElfArch {
+            machine: if little_endian {
+                u16::from_le_bytes(machine)
+            } else {
+                u16::from_be_bytes(machine)
+            },
+            is_64: header[4] == 2,
+            little_endian,
+        })
+    }
+
+    /// Name of the architecture for the humans
+    pub fn name(&self) -> &'static str {
+        match (self.machine, self.is_64, self.little_endian) {
+            (EM_X86_64, ..) => "amd64",
+            (EM_386, ..) => "i486",
+            (EM_AARCH64, ..) => "arm64",
+            (EM_ARM, ..) => "arm",
+            (EM_PPC64, _, true) => "ppc64el",
+            (EM_PPC64, _, false) => "ppc64",
+            (EM_PPC, ..) => "powerpc",
+            (EM_MIPS, true, true) => "mips64el",
+            (EM_MIPS, ..) => "mips",
+            (EM_RISCV, true, _) => "riscv64",
+            (EM_68K, ..) => "m68k",
+            (EM_LOONGARCH, ..) => "loongarch64",
+            _ => "unknown",
+        }
+    }
+
+    /// Name of the qemu-user emulator, as registered in binfmt_misc
+    fn qemu_name(&self) -> Option<&'static str> {
+        Some(match (self.machine, self.is_64, self.little_endian) {
+            (EM_X86_64, ..) => "x86_64",
+            (EM_386, ..) => "i386",
+            (EM_AARCH64, ..) => "aarch64",
+            (EM_ARM, ..) => "arm",
+            (EM_PPC64, _, true) => "ppc64le",
+            (EM_PPC64, _, false) => "ppc64",
+            (EM_PPC, ..) => "ppc",
+            (EM_MIPS, true, true) => "mips64el",
+            (EM_MIPS, true, false) => "mips64",
+            (EM_RISCV, true, _) => "riscv64",
+            (EM_68K, ..) => "m68k",
+            (EM_LOONGARCH, ..) => "loongarch64",
+            _ => return None,
+        })
+    }
+
+    /// Check if executables of the architecture run natively on this (host) architecture
+    pub fn runs_natively(&self, target: &ElfArch) -> bool {
+        if self == target {
+            return true;
+        }
+        // 32-bit compatibility modes
+        matches!(
+            (self.machine, target.machine),
+            (EM_X86_64, EM_386) | (EM_AARCH64, EM_ARM) | (EM_PPC64, EM_PPC)
+        ) && self.little_endian == target.little_endian
+    }
+}
+
+/// Return the architecture of the host (i.e. of the running Ciel executable)
+pub fn host_arch() -> Result<ElfArch> {
+    let mut header = [0u8; 20];
+    File::open("/proc/self/exe")?.read_exact(&mut header)?;
+
+    ElfArch::parse(&header).ok_or_else(|| anyhow!("Unable to determine the host architecture"))
+}
+
+/// Check if an emulator for the architecture is registered in binfmt_misc
+pub fn emulation_enabled(arch: &ElfArch) -> bool {
+    let name = match arch.qemu_name() {
+        Some(name) => name,
+        None => return false,
+    };
+    // the first line of the handler is either `enabled` or `disabled`
+    fs::read_to_string(Path::new(BINFMT_MISC_DIR).join(format!("qemu-{}", name)))
+        .map(|handler| handler.lines().next() == Some("enabled"))
+        .unwrap_or(false)
+}
+
+/// Return the architecture of the first executable in the tarball (the tarball is scanned until
+/// an executable is found), or None if there are no executables in it
+pub fn tarball_arch(path: &Path) -> Result<Option<ElfArch>> {
+    let reader = crate::common::decompress_tarball(File::open(path)?)?;
+    let mut tarball = tar::Archive::new(reader);
+    for entry in tarball.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let entry_path = entry.path()?;
+        let entry_path = entry_path.strip_prefix(".").unwrap_or(&entry_path);
+        if !entry_path.starts_with("usr/bin") && !entry_path.starts_with("bin") {
+            continue;
+        }
+        let mut header = Vec::with_capacity(20);
+        (&mut entry).take(20).read_to_end(&mut header)?;
+        if let Some(arch) = ElfArch::parse(&header) {
+            return Ok(Some(arch));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Return the architecture of the extracted OS in the directory
+pub fn extracted_arch(dir: &Path) -> Result<Option<ElfArch>> {
+    for candidate in ["usr/bin/bash", "usr/bin/env"] {
+        let mut header = Vec::with_capacity(20);
+        match File::open(dir.join(candidate)) {
+            Ok(f) => f.take(20).read_to_end(&mut header)?,
+            Err(_) => continue,
+        };
+        if let Some(arch) = ElfArch::parse(&header) {
+            return Ok(Some(arch));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Refuse to load a tarball the host can not run, unless an emulator is registered
+/// for its architecture
+pub fn check_tarball_arch(path: &Path) -> Result<()> {
+    check_arch(tarball_arch(path)?)
+}
+
+/// Refuse to use an OS the host can not run, unless an emulator is registered
+/// for its architecture
+pub fn check_arch(target: Option<ElfArch>) -> Result<()> {
+    let target = match target {
+        Some(target) => target,
+        None => {
+            warn!("No executables found in the tarball, is it an OS tarball?");
+            return Ok(());
+        }
+    };
+    let host = host_arch()?;
+    if host.runs_natively(&target) {
+        return Ok(());
+    }
+    if emulation_enabled(&target) {
+        warn!(
+            "The tarball is built for {}, it will run under emulation.",
+            target.name()
+        );
+        return Ok(());
+    }
+
+    bail!(
+        "The tarball is built for {}, which can not run on this {} host. Please register a qemu-user emulator for it (e.g. install qemu-user-static with binfmt support) to enable emulation.",
+        target.name(),
+        host.name()
+    )
+}
+
+/// Check if this version of Ciel is at least the specified version (e.g. `3.1`)
+pub fn version_satisfies(min_version: &str) -> bool {
+    version_at_least(env!("CARGO_PKG_VERSION"), min_version)
+}
+
+fn version_at_least(version: &str, min_version: &str) -> bool {
+    let parse = |v: &str| -> Vec<u64> {
+        v.trim()
+            .split('.')
+            .map(|c| {
+                c.chars()
+                    .take_while(|c| c.is_ascii_digit())
+                    .collect::<String>()
+                    .parse()
+                    .unwrap_or(0)
+            })
+            .collect()
+    };
+    let (mut version, mut min_version) = (parse(version), parse(min_version));
+    let len = version.len().max(min_version.len());
+    version.resize(len, 0);
+    min_version.resize(len, 0);
+
+    version >= min_version
+}
+
+#[test]
+fn test_elf_arch() {
+    let mut header = [0u8; 20];
+    header[..4].copy_from_slice(ELF_MAGIC);
+    header[4] = 2;
+    header[5] = 1;
+    header[18..20].copy_from_slice(&EM_AARCH64.to_le_bytes());
+    let arm64 = ElfArch::parse(&header).unwrap();
+    assert_eq!(arm64.name(), "arm64");
+    header[18..20].copy_from_slice(&EM_X86_64.to_le_bytes());
+    let amd64 = ElfArch::parse(&header).unwrap();
+    assert!(!amd64.runs_natively(&arm64));
+    header[4] = 1;
+    header[18..20].copy_from_slice(&EM_386.to_le_bytes());
+    assert!(amd64.runs_natively(&ElfArch::parse(&header).unwrap()));
+    assert_eq!(ElfArch::parse(b"#!/bin/sh\n"), None);
+}
+
+#[test]
+fn test_version_at_least() {
+    assert!(version_at_least("3.1.4", "3.1"));
+    assert!(version_at_least("3.1.4", "3.1.4"));
+    assert!(!version_at_least("3.1.4", "3.2.0"));
+    assert!(version_at_least("3.10.0", "3.9"));
+    assert!(!version_at_least("3.1.4", "4"));
+}
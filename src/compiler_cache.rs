@@ -0,0 +1,158 @@
+//! This module contains the compiler cache (ccache or sccache) related APIs
+
+use anyhow::{bail, Result};
+use console::style;
+use std::{fs, path::Path};
+
+use crate::{info, warn};
+
+/// The cache directory in the workspace
+pub const CACHE_DIR: &str = ".ciel/data/compiler-cache";
+/// Where the cache directory is mounted in the instances
+pub const CACHE_MOUNT: &str = "/var/cache/ciel-compiler-cache";
+/// Set while building with the compiler cache, to the name of the tool
+const CACHE_ENV: &str = "CIEL_COMPILER_CACHE";
+/// ccache logs the statistics of each compilation here (relative to the cache directory)
+const CCACHE_STATS_LOG: &str = "ccache/stats.log";
+
+/// The supported compiler caches
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompilerCache {
+    Ccache,
+    Sccache,
+}
+
+impl CompilerCache {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "ccache" => Ok(CompilerCache::Ccache),
+            "sccache" => Ok(CompilerCache::Sccache),
+            _ => bail!(
+                "Unsupported compiler cache `{}`: expected ccache or sccache",
+                name
+            ),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            CompilerCache::Ccache => "ccache",
+            CompilerCache::Sccache => "sccache",
+        }
+    }
+
+    /// Return the environment variables of the builds using the tool
+    fn environment(&self) -> Vec<String> {
+        match self {
+            CompilerCache::Ccache => vec![
+                format!("CCACHE_DIR={}/ccache", CACHE_MOUNT),
+                format!("CCACHE_STATSLOG={}/{}", CACHE_MOUNT, CCACHE_STATS_LOG),
+                // the compiler wrappers shipped with ccache
+                "PATH=/usr/lib/ccache:/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin"
+                    .to_owned(),
+            ],
+            CompilerCache::Sccache => vec![
+                format!("SCCACHE_DIR={}/sccache", CACHE_MOUNT),
+                "RUSTC_WRAPPER=sccache".to_owned(),
+                "CMAKE_C_COMPILER_LAUNCHER=sccache".to_owned(),
+                "CMAKE_CXX_COMPILER_LAUNCHER=sccache".to_owned(),
+            ],
+        }
+    }
+}
+
+/// Enable the compiler cache for the following builds in the instance, if the tool is installed
+/// in it. Returns the tool enabled
+pub fn enable(name: &str, instance: &str) -> Result<Option<CompilerCache>> {
+    let cache = CompilerCache::parse(name)?;
+    if !Path::new(instance)
+        .join("usr/bin")
+        .join(cache.name())
+        .exists()
+    {
+        warn!(
+            "{} is not installed in {}, the compiler cache is not used.",
+            cache.name(),
+            instance
+        );
+        disable();
+        return Ok(None);
+    }
+    fs::create_dir_all(Path::new(CACHE_DIR).join(cache.name()))?;
+    std::env::set_var(CACHE_ENV, cache.name());
+
+    Ok(Some(cache))
+}
+
+/// Disable the compiler cache for the following builds
+pub fn disable() {
+    std::env::remove_var(CACHE_ENV);
+}
+
+/// Return the extra `systemd-run` options of the commands in the containers
+pub fn run_options() -> Vec<String> {
+    std::env::var(CACHE_ENV)
+        .ok()
+        .and_then(|name| CompilerCache::parse(&name).ok())
+        .map(|cache| {
+            cache
+                .environment()
+                .into_iter()
+                .map(|env| format!("--setenv={}", env))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Clear the statistics log of ccache, before a build
+pub fn reset_stats(cache: CompilerCache) {
+    if cache == CompilerCache::Ccache {
+        fs::remove_file(Path::new(CACHE_DIR).join(CCACHE_STATS_LOG)).ok();
+    }
+}
+
+/// Count the (hits, misses) in the statistics log of ccache
+fn count_stats(log: &str) -> (usize, usize) {
+    let mut counts = (0, 0);
+    for line in log.lines().filter(|l| !l.starts_with('#')) {
+        match line.trim() {
+            "direct_cache_hit" | "preprocessed_cache_hit" => counts.0 += 1,
+            "cache_miss" => counts.1 += 1,
+            _ => (),
+        }
+    }
+
+    counts
+}
+
+/// Report the hit rate of the compiler cache since the last reset
+pub fn report(cache: CompilerCache, instance: &str) {
+    match cache {
+        CompilerCache::Ccache => {
+            let log =
+                fs::read_to_string(Path::new(CACHE_DIR).join(CCACHE_STATS_LOG)).unwrap_or_default();
+            let (hits, misses) = count_stats(&log);
+            if hits + misses == 0 {
+                info!("ccache: no cacheable compilations.");
+                return;
+            }
+            info!(
+                "ccache: {} hits, {} misses ({:.1}% hit rate).",
+                hits,
+                misses,
+                hits as f64 * 100.0 / (hits + misses) as f64
+            );
+        }
+        // sccache keeps the statistics in its server
+        CompilerCache::Sccache => {
+            info!("sccache statistics:");
+            crate::actions::run_in_container(instance, &["sccache", "--show-stats"]).ok();
+        }
+    }
+}
+
+#[test]
+fn test_count_stats() {
+    let log = "# /build/foo.c\ndirect_cache_hit\n# /build/bar.c\ncache_miss\n# /build/baz.c\npreprocessed_cache_hit\ncompiler_check_failed\n";
+    assert_eq!(count_stats(log), (2, 1));
+}
@@ -0,0 +1,367 @@
+//! This module contains the daemon mode (`cield`) related APIs
+
+use anyhow::{anyhow, bail, Result};
+use console::style;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    fs,
+    io::{BufRead, BufReader, Write},
+    os::unix::{
+        fs::PermissionsExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::Path,
+    sync::Mutex,
+    time::Duration,
+};
+
+use crate::{actions, build, config, error, info, machine, warn};
+
+/// The control socket in the workspace
+pub const SOCKET_PATH: &str = ".ciel/data/cield.sock";
+
+const PARSE_ERROR: i32 = -32700;
+const METHOD_NOT_FOUND: i32 = -32601;
+const INVALID_PARAMS: i32 = -32602;
+/// The operation itself failed
+const OPERATION_FAILED: i32 = -32000;
+/// How often the daemon looks for the idle instances
+const AUTOSTOP_INTERVAL: Duration = Duration::from_secs(60);
+
+lazy_static! {
+    /// Held while an operation changing the workspace runs
+    static ref WORKSPACE: Mutex<()> = Mutex::new(());
+    /// The operation running in the workspace
+    static ref OPERATION: Mutex<Option<String>> = Mutex::new(None);
+}
+
+#[derive(Debug, Deserialize)]
+struct RpcRequest {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RpcError {
+    code: i32,
+    message: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RpcResponse {
+    jsonrpc: String,
+    id: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+}
+
+impl RpcResponse {
+    fn new(id: Value, result: std::result::Result<Value, RpcError>) -> Self {
+        let (result, error) = match result {
+            Ok(result) => (Some(result), None),
+            Err(error) => (None, Some(error)),
+        };
+
+        RpcResponse {
+            jsonrpc: "2.0".to_owned(),
+            id,
+            result,
+            error,
+        }
+    }
+}
+
+/// Parameters of the `build` method
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct BuildParams {
+    pub instance: String,
+    pub packages: Vec<String>,
+    pub changed: Vec<String>,
+    pub offline: bool,
+    pub stage2: bool,
+    pub skip_built: bool,
+    pub with_deps: bool,
+    pub block_network: bool,
+    pub phase: build::BuildPhase,
+    pub skip_preflight: bool,
+    pub retry: usize,
+    pub reset_on_retry: bool,
+    pub check_reproducible: bool,
+    /// Time limit of each package build in seconds
+    pub package_timeout: Option<u64>,
+    /// Time limit of the whole build in seconds
+    pub timeout: Option<u64>,
+    pub priority: Option<config::BuildPriority>,
+}
+
+impl From<&build::BuildRequest> for BuildParams {
+    fn from(request: &build::BuildRequest) -> Self {
+        BuildParams {
+            instance: request.instance.clone(),
+            packages: request.packages.clone(),
+            changed: request.changed.clone(),
+            offline: request.offline,
+            stage2: request.stage2,
+            skip_built: request.skip_built,
+            with_deps: request.with_deps,
+            block_network: request.block_network,
+            phase: request.phase,
+            skip_preflight: request.skip_preflight,
+            retry: request.retry,
+            reset_on_retry: request.reset_on_retry,
+            check_reproducible: request.check_reproducible,
+            package_timeout: request.package_timeout,
+            timeout: request.timeout,
+            priority: request.priority,
+        }
+    }
+}
+
+impl BuildParams {
+    fn into_request(self) -> build::BuildRequest {
+        build::BuildRequest {
+            instance: self.instance,
+            packages: self.packages,
+            changed: self.changed,
+            offline: self.offline,
+            stage2: self.stage2,
+            skip_built: self.skip_built,
+            with_deps: self.with_deps,
+            block_network: self.block_network,
+            phase: self.phase,
+            skip_preflight: self.skip_preflight,
+            retry: self.retry,
+            reset_on_retry: self.reset_on_retry,
+            check_reproducible: self.check_reproducible,
+            package_timeout: self.package_timeout,
+            timeout: self.timeout,
+            priority: self.priority,
+            // nobody is at the terminal of the daemon
+            on_fail: build::FailureAction::Stop,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct SpawnParams {
+    instance: String,
+}
+
+/// Run the operation changing the workspace, after the one running (if any)
+fn exclusive<T, F: FnOnce() -> Result<T>>(name: String, f: F) -> Result<T> {
+    // a panicked operation leaves nothing to protect
+    let _guard = WORKSPACE.lock().unwrap_or_else(|e| e.into_inner());
+    *OPERATION.lock().unwrap_or_else(|e| e.into_inner()) = Some(name);
+    let result = f();
+    *OPERATION.lock().unwrap_or_else(|e| e.into_inner()) = None;
+
+    result
+}
+
+/// Return the operation running in the workspace, if any
+pub fn current_operation() -> Option<String> {
+    OPERATION.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+fn parse_params<T: serde::de::DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|e| RpcError {
+        code: INVALID_PARAMS,
+        message: e.to_string(),
+    })
+}
+
+fn operation_failed(e: anyhow::Error) -> RpcError {
+    RpcError {
+        code: OPERATION_FAILED,
+        message: format!("{:#}", e),
+    }
+}
+
+fn dispatch(method: &str, params: Value) -> std::result::Result<Value, RpcError> {
+    match method {
+        "status" => {
+            let instances = machine::list_instances().map_err(operation_failed)?;
+            Ok(json!({ "instances": instances, "operation": current_operation() }))
+        }
+        "spawn" => {
+            let params: SpawnParams = parse_params(params)?;
+            let ns_name = exclusive(format!("spawn {}", params.instance), || {
+                actions::start_container(&params.instance)
+            })
+            .map_err(operation_failed)?;
+            Ok(json!({ "instance": params.instance, "container": ns_name }))
+        }
+        "build" => {
+            let params: BuildParams = parse_params(params)?;
+            if params.packages.is_empty() {
+                return Err(RpcError {
+                    code: INVALID_PARAMS,
+                    message: "no packages to build".to_owned(),
+                });
+            }
+            let request = params.into_request();
+            let result = exclusive(
+                format!(
+                    "build {} in {}",
+                    request.packages.join(" "),
+                    request.instance
+                ),
+                || build::execute(&request, None),
+            )
+            .map_err(operation_failed)?;
+            serde_json::to_value(result).map_err(|e| operation_failed(e.into()))
+        }
+        _ => Err(RpcError {
+            code: METHOD_NOT_FOUND,
+            message: format!("unknown method `{}`", method),
+        }),
+    }
+}
+
+fn handle_client(stream: UnixStream) -> Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str::<RpcRequest>(&line) {
+            Ok(request) => {
+                info!("Request: {}", request.method);
+                RpcResponse::new(request.id, dispatch(&request.method, request.params))
+            }
+            Err(e) => RpcResponse::new(
+                Value::Null,
+                Err(RpcError {
+                    code: PARSE_ERROR,
+                    message: e.to_string(),
+                }),
+            ),
+        };
+        writeln!(writer, "{}", serde_json::to_string(&response)?)?;
+    }
+
+    Ok(())
+}
+
+/// Serve the workspace over the control socket (and the metrics at the address), until killed
+pub fn serve(metrics: Option<&str>) -> Result<()> {
+    let path = Path::new(SOCKET_PATH);
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            bail!("The daemon is already running in this workspace.");
+        }
+        // left by a daemon not exited cleanly
+        fs::remove_file(path)?;
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    // the daemon runs as root, so are the clients allowed
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    info!("Listening on {} ...", path.display());
+    if let Some(address) = metrics {
+        let address = address.to_owned();
+        info!("Serving the metrics at http://{}/metrics ...", address);
+        std::thread::spawn(move || {
+            if let Err(e) = crate::metrics::serve(&address) {
+                error!("Unable to serve the metrics: {}", e);
+            }
+        });
+    }
+    if let Some(idle) = config::read_config().ok().and_then(|c| c.auto_stop()) {
+        info!(
+            "Stopping the instances idle for {} ...",
+            indicatif::HumanDuration(Duration::from_secs(idle))
+        );
+        std::thread::spawn(move || loop {
+            std::thread::sleep(AUTOSTOP_INTERVAL);
+            // the instances are not stopped in the middle of an operation of the daemon
+            if let Err(e) = exclusive("autostop".to_owned(), || actions::autostop(idle, false)) {
+                warn!("Unable to stop the idle instances: {}", e);
+            }
+        });
+    }
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                std::thread::spawn(move || {
+                    if let Err(e) = handle_client(stream) {
+                        warn!("Client disconnected: {}", e);
+                    }
+                });
+            }
+            Err(e) => error!("Unable to accept the connection: {}", e),
+        }
+    }
+
+    Ok(())
+}
+
+/// Call the method of the daemon running in the workspace, returns the result
+pub fn call(method: &str, params: Value) -> Result<Value> {
+    let stream = UnixStream::connect(SOCKET_PATH).map_err(|e| {
+        anyhow!(
+            "Unable to connect to the daemon (is `cield` running?): {}",
+            e
+        )
+    })?;
+    let mut writer = stream.try_clone()?;
+    let request = json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params });
+    writeln!(writer, "{}", request)?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    let response: RpcResponse = serde_json::from_str(&line)
+        .map_err(|e| anyhow!("Invalid response from the daemon: {}", e))?;
+    if let Some(error) = response.error {
+        bail!("{} (error {})", error.message, error.code);
+    }
+
+    Ok(response.result.unwrap_or(Value::Null))
+}
+
+#[test]
+fn test_rpc_response() {
+    let response = RpcResponse::new(json!(1), Ok(json!({ "ok": true })));
+    assert_eq!(
+        serde_json::to_string(&response).unwrap(),
+        r#"{"jsonrpc":"2.0","id":1,"result":{"ok":true}}"#
+    );
+    let response = RpcResponse::new(
+        json!(2),
+        dispatch("frobnicate", Value::Null).map_err(|mut e| {
+            e.message.clear();
+            e
+        }),
+    );
+    assert_eq!(
+        serde_json::to_string(&response).unwrap(),
+        r#"{"jsonrpc":"2.0","id":2,"error":{"code":-32601,"message":""}}"#
+    );
+}
+
+#[test]
+fn test_build_params() {
+    let request = build::BuildRequest {
+        packages: vec!["foo".to_owned()],
+        phase: build::BuildPhase::Fetch,
+        check_reproducible: true,
+        priority: Some(config::BuildPriority::Idle),
+        ..Default::default()
+    };
+    let params = serde_json::to_value(BuildParams::from(&request)).unwrap();
+    assert_eq!(params["phase"], "fetch");
+    let request = parse_params::<BuildParams>(params).unwrap().into_request();
+    assert_eq!(request.phase, build::BuildPhase::Fetch);
+    assert!(request.check_reproducible);
+    assert_eq!(request.priority, Some(config::BuildPriority::Idle));
+}
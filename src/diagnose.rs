@@ -1,7 +1,9 @@
 use anyhow::{anyhow, Result};
-use console::style;
+use console::{style, user_attended};
+use dialoguer::{theme::ColorfulTheme, Confirm};
 use fs3::statvfs;
 use indicatif::HumanBytes;
+use serde::Serialize;
 use std::sync::mpsc::channel;
 use std::{fs::File, io::BufRead, time::Duration};
 use std::{
@@ -13,7 +15,7 @@ use which::which;
 use zbus::blocking::Connection;
 use zbus::dbus_proxy;
 
-use crate::error;
+use crate::{actions, error, info, lsm, output, overlayfs};
 
 const TEST_TEXT: &[u8] = b"An-An was born a rabbit, but found herself a girl with bunny ears and tails when she woke up one day. She couldn't seem to remember why.";
 const TEST_PROGRAMS: &[&str] = &["systemd-nspawn", "systemd-run"];
@@ -25,6 +27,7 @@ const TEST_CASES: &[&dyn Fn() -> Result<String>] = &[
     &test_vm_container,
     &test_disk_io,
     &test_disk_space,
+    &test_security_modules,
 ];
 
 #[dbus_proxy(
@@ -133,41 +136,174 @@ fn test_disk_space() -> Result<String> {
     }
 }
 
-/// Carry out the diagnostic tests
-pub fn run_diagnose() -> Result<()> {
-    let mut lines = vec![];
-    let mut has_error = false;
+fn test_security_modules() -> Result<String> {
+    let modules = lsm::detect();
+    if modules.is_empty() {
+        return Ok("No Linux security modules are active".to_string());
+    }
+    if let Some(hint) = lsm::problems().into_iter().next() {
+        return Ok(format!("!{}", hint));
+    }
+    let names = modules
+        .iter()
+        .map(|m| match m {
+            lsm::SecurityModule::SELinux { enforcing: true } => "SELinux (enforcing)",
+            lsm::SecurityModule::SELinux { enforcing: false } => "SELinux (permissive)",
+            lsm::SecurityModule::AppArmor { .. } => "AppArmor",
+        })
+        .collect::<Vec<_>>();
+
+    Ok(format!(
+        "Security modules are set up for the containers: {}",
+        names.join(", ")
+    ))
+}
+
+/// Result of a diagnostic test
+#[derive(Debug, Serialize)]
+struct CheckResult {
+    status: CheckStatus,
+    message: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum CheckStatus {
+    Ok,
+    Warning,
+    Error,
+}
+
+/// Run the diagnostic tests, until the first error
+fn run_checks() -> Vec<CheckResult> {
+    let mut results = vec![];
     for test in TEST_CASES {
         match test() {
             Ok(msg) => {
-                if msg.starts_with('!') {
-                    lines.push(format!(
-                        "{} {}",
-                        style("!").yellow(),
-                        style(msg.strip_prefix('!').unwrap()).yellow().bold()
-                    ));
+                if let Some(msg) = msg.strip_prefix('!') {
+                    results.push(CheckResult {
+                        status: CheckStatus::Warning,
+                        message: msg.to_owned(),
+                    });
                     continue;
                 }
-                lines.push(format!(
-                    "{} {}",
-                    style("✓").green(),
-                    style(msg).green().bold()
-                ))
+                results.push(CheckResult {
+                    status: CheckStatus::Ok,
+                    message: msg,
+                });
             }
             Err(err) => {
-                has_error = true;
-                lines.push(format!("{} {}", style("x").red(), style(err).red().bold()));
+                results.push(CheckResult {
+                    status: CheckStatus::Error,
+                    message: err.to_string(),
+                });
                 break;
             }
         }
     }
 
-    for line in lines {
-        println!("{}", line);
+    results
+}
+
+/// Return the problems found by the diagnostic tests and the mount checks (for `ciel status`)
+pub fn pending_issues() -> Vec<String> {
+    let mut issues = run_checks()
+        .into_iter()
+        .filter(|r| r.status != CheckStatus::Ok)
+        .map(|r| r.message)
+        .collect::<Vec<_>>();
+    match overlayfs::verify() {
+        Ok(mounts) => issues.extend(mounts.iter().map(|i| i.to_string())),
+        Err(e) => issues.push(format!("Unable to verify the mounts: {}", e)),
+    }
+
+    issues
+}
+
+/// Carry out the diagnostic tests
+pub fn run_diagnose() -> Result<()> {
+    let results = run_checks();
+    for result in results.iter() {
+        match result.status {
+            CheckStatus::Ok => println!(
+                "{} {}",
+                style("✓").green(),
+                style(&result.message).green().bold()
+            ),
+            CheckStatus::Warning => println!(
+                "{} {}",
+                style("!").yellow(),
+                style(&result.message).yellow().bold()
+            ),
+            CheckStatus::Error => println!(
+                "{} {}",
+                style("x").red(),
+                style(&result.message).red().bold()
+            ),
+        }
+    }
+    if results.iter().any(|r| r.status == CheckStatus::Error) {
+        return Err(anyhow!("Test error detected"));
+    }
+
+    Ok(())
+}
+
+/// Carry out the diagnostic tests (and check the mounts), printing the results as JSON.
+/// The mounts are never repaired in this mode
+pub fn report_json(mounts: bool) -> Result<()> {
+    #[derive(Serialize)]
+    struct Report {
+        checks: Vec<CheckResult>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        mounts: Option<Vec<String>>,
     }
-    if has_error {
+
+    let report = Report {
+        checks: run_checks(),
+        mounts: if mounts {
+            Some(overlayfs::verify()?.iter().map(|i| i.to_string()).collect())
+        } else {
+            None
+        },
+    };
+    output::print_json(&report)?;
+    if report.checks.iter().any(|r| r.status == CheckStatus::Error) {
         return Err(anyhow!("Test error detected"));
     }
+    if report.mounts.map_or(false, |m| !m.is_empty()) {
+        return Err(anyhow!("Inconsistent mount states detected"));
+    }
+
+    Ok(())
+}
+
+/// Check the overlay mounts of the workspace and offer to repair the problems found
+pub fn diagnose_mounts() -> Result<()> {
+    let issues = overlayfs::verify()?;
+    if issues.is_empty() {
+        println!(
+            "{} {}",
+            style("✓").green(),
+            style("Mount states seem to be consistent").green().bold()
+        );
+        return Ok(());
+    }
+    for issue in issues.iter() {
+        println!("{} {}", style("x").red(), style(issue).red().bold());
+    }
+    if !user_attended()
+        || !Confirm::with_theme(&ColorfulTheme::default())
+            .with_prompt("Repair the mounts now?")
+            .default(true)
+            .interact()?
+    {
+        return Err(anyhow!("Inconsistent mount states detected"));
+    }
+    for issue in issues.iter() {
+        actions::repair_mount_issue(issue)?;
+    }
+    info!("Mounts repaired.");
 
     Ok(())
 }
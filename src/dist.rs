@@ -0,0 +1,135 @@
+//! This module contains the write protection APIs for the dist (base) layer
+
+use crate::common::CIEL_DIST_DIR;
+use crate::{info, warn, zfs};
+use anyhow::{anyhow, Result};
+use console::style;
+use libmount::mountinfo::Parser;
+use nix::mount::{mount, umount2, MntFlags, MsFlags};
+use nix::sys::statvfs::{statvfs, FsFlags};
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Exists if the dist layer has been unlocked for maintenance (by `ciel dist unlock`)
+const UNLOCK_MARKER: &str = ".ciel/data/dist-unlocked";
+
+#[inline]
+fn dist_path() -> Result<PathBuf> {
+    Ok(std::env::current_dir()?.join(CIEL_DIST_DIR))
+}
+
+/// Check if the protective bind mount is in place
+fn is_bind_mounted(dist: &Path) -> Result<bool> {
+    let mountinfo_content: Vec<u8> = fs::read("/proc/self/mountinfo")?;
+    let mut mounts = Vec::new();
+    for mount in Parser::new(&mountinfo_content) {
+        let mount = mount?;
+        if mount.mount_point == dist.as_os_str() {
+            mounts.push(mount.root.into_owned());
+        }
+    }
+
+    // the dist layer may be a filesystem of its own (e.g. a ZFS dataset),
+    // otherwise the bind mount is the only mount with a sub-directory as its root
+    Ok(mounts.len() > 1 || mounts.iter().any(|root| root != OsStr::new("/")))
+}
+
+/// Return if the dist layer is currently write-protected
+pub fn is_locked() -> Result<bool> {
+    let dist = dist_path()?;
+    if !dist.is_dir() {
+        return Ok(false);
+    }
+
+    Ok(statvfs(&dist)?.flags().contains(FsFlags::ST_RDONLY))
+}
+
+/// Write-protect the dist layer. For overlay based workspaces, the dist layer is bind-mounted
+/// read-only onto itself; for ZFS based workspaces, the dataset is set to read-only
+pub fn lock() -> Result<()> {
+    let dist = dist_path()?;
+    if !dist.is_dir() || is_locked()? {
+        return Ok(());
+    }
+    if zfs::is_zfs_workspace() {
+        return zfs::set_dist_readonly(true);
+    }
+    if !is_bind_mounted(&dist)? {
+        mount(
+            Some(&dist),
+            &dist,
+            None::<&str>,
+            MsFlags::MS_BIND,
+            None::<&str>,
+        )
+        .map_err(|e| anyhow!("Unable to bind-mount the dist layer: {}", e))?;
+    }
+    mount(
+        None::<&str>,
+        &dist,
+        None::<&str>,
+        MsFlags::MS_REMOUNT | MsFlags::MS_BIND | MsFlags::MS_RDONLY,
+        None::<&str>,
+    )
+    .map_err(|e| anyhow!("Unable to write-protect the dist layer: {}", e))?;
+
+    Ok(())
+}
+
+/// Remove the write protection of the dist layer
+pub fn unlock() -> Result<()> {
+    let dist = dist_path()?;
+    if zfs::is_zfs_workspace() {
+        return zfs::set_dist_readonly(false);
+    }
+    // the bind mount is removed entirely: renaming files from the upper layers into the dist layer
+    // does not work across mount points, even if they are on the same filesystem
+    while is_bind_mounted(&dist)? {
+        umount2(&dist, MntFlags::MNT_DETACH)
+            .map_err(|e| anyhow!("Unable to remove the write protection: {}", e))?;
+    }
+
+    Ok(())
+}
+
+/// Temporarily remove the write protection of the dist layer while running the function
+pub fn with_unlocked<T, F: FnOnce() -> Result<T>>(func: F) -> Result<T> {
+    unlock()?;
+    let result = func();
+    ensure_locked();
+
+    result
+}
+
+/// Unlock the dist layer for maintenance, until `end_maintenance` is called
+pub fn begin_maintenance() -> Result<()> {
+    fs::write(UNLOCK_MARKER, "")?;
+    unlock()?;
+    warn!("The dist layer is now writable, please be careful.");
+    info!("Run `ciel dist lock` to write-protect the dist layer again when you are done.");
+
+    Ok(())
+}
+
+/// Write-protect the dist layer again after maintenance
+pub fn end_maintenance() -> Result<()> {
+    if Path::new(UNLOCK_MARKER).exists() {
+        fs::remove_file(UNLOCK_MARKER)?;
+    }
+    lock()?;
+    info!("The dist layer is now write-protected.");
+
+    Ok(())
+}
+
+/// Write-protect the dist layer unless it has been unlocked for maintenance
+pub fn ensure_locked() {
+    if Path::new(UNLOCK_MARKER).exists() {
+        return;
+    }
+    if let Err(e) = lock() {
+        warn!("Unable to write-protect the dist layer: {}", e);
+        info!("The base system may be changed accidentally.");
+    }
+}
@@ -0,0 +1,167 @@
+//! This module contains the kinds of the errors of Ciel, with the exit codes they map to
+//! (listed in the README)
+
+use serde_json::Value;
+use std::{fmt, path::PathBuf};
+
+use crate::build::{BuildResult, PackageStatus};
+
+pub const EXIT_FAILURE: i32 = 1;
+pub const EXIT_NOT_A_WORKSPACE: i32 = 3;
+pub const EXIT_WORKSPACE_MOVED: i32 = 4;
+pub const EXIT_INSTANCE_NOT_FOUND: i32 = 5;
+pub const EXIT_CONTAINER_BOOT_TIMEOUT: i32 = 6;
+pub const EXIT_NETWORK_ERROR: i32 = 7;
+pub const EXIT_INVALID_CONFIG: i32 = 8;
+pub const EXIT_BUILD_FAILED: i32 = 10;
+pub const EXIT_BUILD_TIMED_OUT: i32 = 11;
+
+/// The failures the scripts may want to tell apart
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CielError {
+    NotAWorkspace,
+    /// The workspace has been moved from the location
+    WorkspaceMoved(PathBuf),
+    InstanceNotFound(String),
+    /// The container (by its name) did not start in time
+    ContainerBootTimeout(String),
+    NetworkError(String),
+    InvalidConfig(String),
+    BuildFailed {
+        package: String,
+    },
+    BuildTimedOut {
+        package: String,
+    },
+}
+
+impl CielError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            CielError::NotAWorkspace => EXIT_NOT_A_WORKSPACE,
+            CielError::WorkspaceMoved(_) => EXIT_WORKSPACE_MOVED,
+            CielError::InstanceNotFound(_) => EXIT_INSTANCE_NOT_FOUND,
+            CielError::ContainerBootTimeout(_) => EXIT_CONTAINER_BOOT_TIMEOUT,
+            CielError::NetworkError(_) => EXIT_NETWORK_ERROR,
+            CielError::InvalidConfig(_) => EXIT_INVALID_CONFIG,
+            CielError::BuildFailed { .. } => EXIT_BUILD_FAILED,
+            CielError::BuildTimedOut { .. } => EXIT_BUILD_TIMED_OUT,
+        }
+    }
+
+    /// Return the error of the build, if any package failed
+    pub fn from_build(result: &BuildResult) -> Option<Self> {
+        let failed = result.failed()?;
+        let package = failed.package.clone();
+
+        Some(match failed.status {
+            PackageStatus::TimedOut => CielError::BuildTimedOut { package },
+            _ => CielError::BuildFailed { package },
+        })
+    }
+
+    /// Return the error of the build from its result in JSON (as returned by the daemon)
+    pub fn from_build_json(result: &Value) -> Option<Self> {
+        result["packages"].as_array()?.iter().find_map(|p| {
+            let package = p["package"].as_str().unwrap_or_default().to_owned();
+            match p["status"].as_str() {
+                Some("failed") => Some(CielError::BuildFailed { package }),
+                Some("timed-out") => Some(CielError::BuildTimedOut { package }),
+                _ => None,
+            }
+        })
+    }
+}
+
+impl fmt::Display for CielError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CielError::NotAWorkspace => {
+                write!(f, "This directory does not look like a Ciel workspace")
+            }
+            CielError::WorkspaceMoved(location) => {
+                write!(
+                    f,
+                    "This workspace has been moved from {}",
+                    location.display()
+                )
+            }
+            CielError::InstanceNotFound(instance) => {
+                write!(f, "Instance `{}` does not exist.", instance)
+            }
+            CielError::ContainerBootTimeout(ns_name) => {
+                write!(f, "Timeout waiting for container {}", ns_name)
+            }
+            CielError::NetworkError(message) => write!(f, "{}", message),
+            CielError::InvalidConfig(message) => write!(f, "Invalid configuration: {}", message),
+            CielError::BuildFailed { package } => write!(f, "{} failed to build", package),
+            CielError::BuildTimedOut { package } => {
+                write!(f, "{} ran out of the time limit", package)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CielError {}
+
+/// Return the exit code for the error, by the first kind of error found in its chain
+pub fn exit_code(error: &anyhow::Error) -> i32 {
+    for cause in error.chain() {
+        if let Some(e) = cause.downcast_ref::<CielError>() {
+            return e.exit_code();
+        }
+        if cause.downcast_ref::<reqwest::Error>().is_some() {
+            return EXIT_NETWORK_ERROR;
+        }
+        if let Some(e) = cause.downcast_ref::<git2::Error>() {
+            if matches!(
+                e.class(),
+                git2::ErrorClass::Net | git2::ErrorClass::Http | git2::ErrorClass::Ssl
+            ) {
+                return EXIT_NETWORK_ERROR;
+            }
+        }
+    }
+
+    EXIT_FAILURE
+}
+
+/// Return the exit code for the result of the build
+pub fn build_exit_code(result: &BuildResult) -> i32 {
+    if result.success() {
+        return 0;
+    }
+
+    CielError::from_build(result).map_or(EXIT_FAILURE, |e| e.exit_code())
+}
+
+#[test]
+fn test_exit_code() {
+    use anyhow::Context;
+
+    let e = anyhow::Error::from(CielError::InstanceNotFound("main".to_owned()));
+    assert_eq!(exit_code(&e), EXIT_INSTANCE_NOT_FOUND);
+    let e: anyhow::Error = Err::<(), _>(CielError::NotAWorkspace)
+        .context("Unable to run the command")
+        .unwrap_err();
+    assert_eq!(exit_code(&e), EXIT_NOT_A_WORKSPACE);
+    assert_eq!(
+        exit_code(&anyhow::anyhow!("Something went wrong")),
+        EXIT_FAILURE
+    );
+
+    let mut result = BuildResult::new("main", &["foo".to_owned(), "bar".to_owned()]);
+    assert_eq!(build_exit_code(&result), 0);
+    result.exit_status = 1;
+    result.packages[0].status = PackageStatus::Built;
+    result.packages[1].status = PackageStatus::TimedOut;
+    assert_eq!(build_exit_code(&result), EXIT_BUILD_TIMED_OUT);
+    assert_eq!(
+        CielError::from_build_json(&serde_json::json!({
+            "packages": [{ "package": "foo", "status": "failed", "exit-status": 2 }]
+        })),
+        Some(CielError::BuildFailed {
+            package: "foo".to_owned()
+        })
+    );
+}
@@ -0,0 +1,427 @@
+//! This module contains the native extraction of the OS tarballs
+
+use anyhow::{anyhow, bail, Result};
+use nix::sys::stat::{utimensat, UtimensatFlags};
+use nix::sys::time::{TimeSpec, TimeValLike};
+use nix::unistd::{fchownat, FchownatFlags, Gid, Uid};
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    fs,
+    io::Read,
+    mem,
+    os::unix::{ffi::OsStrExt, fs::PermissionsExt},
+    path::{Component, Path, PathBuf},
+};
+
+const XATTR_PAX_PREFIX: &[u8] = b"SCHILY.xattr.";
+const CAPABILITY_XATTR: &str = "security.capability";
+const ACL_ACCESS_XATTR: &str = "system.posix_acl_access";
+const ACL_DEFAULT_XATTR: &str = "system.posix_acl_default";
+/// The ACLs in the text form (see acl(5)) and the extended attributes they are stored in
+const ACL_PAX_KEYS: &[(&[u8], &str)] = &[
+    (b"SCHILY.acl.access", ACL_ACCESS_XATTR),
+    (b"SCHILY.acl.default", ACL_DEFAULT_XATTR),
+];
+
+// the binary form of the ACLs, from <linux/posix_acl_xattr.h>
+const ACL_XATTR_VERSION: u32 = 2;
+const ACL_UNDEFINED_ID: u32 = u32::MAX;
+const ACL_USER_OBJ: u16 = 0x01;
+const ACL_USER: u16 = 0x02;
+const ACL_GROUP_OBJ: u16 = 0x04;
+const ACL_GROUP: u16 = 0x08;
+const ACL_MASK: u16 = 0x10;
+const ACL_OTHER: u16 = 0x20;
+
+/// What has been extracted
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ExtractStats {
+    pub entries: usize,
+    /// Files with capabilities
+    pub capabilities: usize,
+    /// Files with ACLs
+    pub acls: usize,
+}
+
+/// The metadata of an entry, applied after the entry is unpacked
+struct EntryMeta {
+    uid: u64,
+    gid: u64,
+    mode: u32,
+    mtime: u64,
+    symlink: bool,
+    xattrs: Vec<(Vec<u8>, Vec<u8>)>,
+    /// The ACLs in the text form, with the names of their extended attributes
+    acls: Vec<(&'static str, String)>,
+}
+
+fn read_meta<R: Read>(entry: &mut tar::Entry<R>) -> Result<EntryMeta> {
+    let header = entry.header();
+    let mut meta = EntryMeta {
+        uid: header.uid()?,
+        gid: header.gid()?,
+        mode: header.mode()?,
+        mtime: header.mtime()?,
+        symlink: header.entry_type().is_symlink(),
+        xattrs: Vec::new(),
+        acls: Vec::new(),
+    };
+    if let Some(extensions) = entry.pax_extensions()? {
+        for extension in extensions {
+            let extension = extension?;
+            let key = extension.key_bytes();
+            if let Some(name) = key.strip_prefix(XATTR_PAX_PREFIX) {
+                meta.xattrs
+                    .push((name.to_owned(), extension.value_bytes().to_owned()));
+            } else if let Some((_, name)) = ACL_PAX_KEYS.iter().find(|(k, _)| *k == key) {
+                meta.acls.push((*name, extension.value()?.to_owned()));
+            }
+        }
+    }
+
+    Ok(meta)
+}
+
+/// Return the relative path of the tarball entry, rejecting paths escaping the destination
+pub(crate) fn entry_path(path: &Path) -> Result<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::RootDir | Component::CurDir => continue,
+            Component::Normal(name) => normalized.push(name),
+            _ => bail!("Invalid path in the tarball: {}", path.display()),
+        }
+    }
+
+    Ok(normalized)
+}
+
+/// Read the names and the IDs in /etc/passwd or /etc/group of the extracted tree
+fn read_ids(path: &Path) -> HashMap<String, u32> {
+    fs::read_to_string(path)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(':');
+            let name = fields.next()?;
+            let id = fields.nth(1)?.parse().ok()?;
+            Some((name.to_owned(), id))
+        })
+        .collect()
+}
+
+/// Encode the ACL in the text form (e.g. `user::rwx,user:builder:r-x,group::r-x,mask::r-x,
+/// other::r-x`, the named entries may carry the ID as the fourth field) into the binary form
+/// of the extended attributes. The names are looked up in `users` and `groups`
+fn encode_acl(
+    text: &str,
+    users: &HashMap<String, u32>,
+    groups: &HashMap<String, u32>,
+) -> Result<Vec<u8>> {
+    let mut entries = Vec::new();
+    for item in text.split(|c: char| c == ',' || c == '\n') {
+        let item = item.split('#').next().unwrap_or_default().trim();
+        if item.is_empty() {
+            continue;
+        }
+        let fields = item.split(':').collect::<Vec<_>>();
+        if fields.len() < 3 {
+            bail!("Invalid ACL entry `{}`", item);
+        }
+        let (kind, qualifier) = (fields[0], fields[1]);
+        let perm = fields[2]
+            .chars()
+            .try_fold(0u16, |perm, c| match c {
+                'r' => Some(perm | 4),
+                'w' => Some(perm | 2),
+                'x' => Some(perm | 1),
+                '-' => Some(perm),
+                _ => None,
+            })
+            .ok_or_else(|| anyhow!("Invalid permissions in ACL entry `{}`", item))?;
+        let (tag, names) = match (kind, qualifier.is_empty()) {
+            ("user" | "u", true) => (ACL_USER_OBJ, None),
+            ("user" | "u", false) => (ACL_USER, Some(users)),
+            ("group" | "g", true) => (ACL_GROUP_OBJ, None),
+            ("group" | "g", false) => (ACL_GROUP, Some(groups)),
+            ("mask" | "m", _) => (ACL_MASK, None),
+            ("other" | "o", _) => (ACL_OTHER, None),
+            _ => bail!("Invalid ACL entry `{}`", item),
+        };
+        let id = match names {
+            None => ACL_UNDEFINED_ID,
+            Some(names) => fields
+                .get(3)
+                .and_then(|id| id.parse().ok())
+                .or_else(|| qualifier.parse().ok())
+                .or_else(|| names.get(qualifier).copied())
+                .ok_or_else(|| anyhow!("Unknown {} `{}` in the ACL", kind, qualifier))?,
+        };
+        entries.push((tag, id, perm));
+    }
+    // the kernel only accepts the entries sorted by the tag and the ID
+    entries.sort_unstable();
+    let mut value = ACL_XATTR_VERSION.to_le_bytes().to_vec();
+    for (tag, id, perm) in entries {
+        value.extend_from_slice(&tag.to_le_bytes());
+        value.extend_from_slice(&perm.to_le_bytes());
+        value.extend_from_slice(&id.to_le_bytes());
+    }
+
+    Ok(value)
+}
+
+#[inline]
+fn lacks_xattr(target: &Path, name: &str) -> bool {
+    !matches!(xattr::get(target, name), Ok(Some(_)))
+}
+
+fn set_xattr(target: &Path, name: &OsStr, value: &[u8]) -> Result<()> {
+    xattr::set(target, name, value).map_err(|e| {
+        anyhow!(
+            "Unable to set {} on {}: {} (does the filesystem support it?)",
+            name.to_string_lossy(),
+            target.display(),
+            e
+        )
+    })
+}
+
+/// Unpacks the entries of a tarball into the destination with all their metadata
+pub struct Extractor {
+    dest: PathBuf,
+    /// The paths unpacked (relative to the destination)
+    paths: Vec<PathBuf>,
+    /// The directories, whose metadata is applied after their contents are unpacked
+    dirs: Vec<(PathBuf, EntryMeta)>,
+    /// The ACLs, applied last as the names in them are looked up in the extracted tree
+    acls: Vec<(PathBuf, Vec<(&'static str, String)>)>,
+    capabilities: Vec<PathBuf>,
+}
+
+impl Extractor {
+    pub fn new(dest: &Path) -> Result<Self> {
+        Ok(Extractor {
+            dest: fs::canonicalize(dest)?,
+            paths: Vec::new(),
+            dirs: Vec::new(),
+            acls: Vec::new(),
+            capabilities: Vec::new(),
+        })
+    }
+
+    /// Return the archive configured for the extractor, which applies the metadata itself
+    pub fn archive<R: Read>(reader: R) -> tar::Archive<R> {
+        let mut archive = tar::Archive::new(reader);
+        archive.set_unpack_xattrs(false);
+        archive.set_preserve_permissions(false);
+
+        archive
+    }
+
+    /// Return the destination directory (canonicalized)
+    pub fn dest(&self) -> &Path {
+        &self.dest
+    }
+
+    /// Unpack the entry
+    pub fn unpack<R: Read>(&mut self, entry: &mut tar::Entry<R>) -> Result<()> {
+        let path = entry_path(&entry.path()?)?;
+        let entry_type = entry.header().entry_type();
+        // the parents are checked not to leave the destination (e.g. through the symlinks
+        // unpacked before)
+        if !entry.unpack_in(&self.dest)? {
+            bail!("Invalid path in the tarball: {}", path.display());
+        }
+        if entry_type.is_dir() {
+            let target = self.dest.join(&path);
+            let meta = read_meta(entry)?;
+            if !path.as_os_str().is_empty() {
+                self.paths.push(path);
+            }
+            self.dirs.push((target, meta));
+            return Ok(());
+        }
+        if entry_type.is_hard_link() {
+            // shares the metadata with the file linked to
+            self.paths.push(path);
+            return Ok(());
+        }
+        let target = self.dest.join(&path);
+        self.unpacked(entry, &target)
+    }
+
+    /// Apply the metadata of the entry to the target, which has been unpacked by the caller
+    pub fn unpacked<R: Read>(&mut self, entry: &mut tar::Entry<R>, target: &Path) -> Result<()> {
+        let meta = read_meta(entry)?;
+        self.apply(target, meta)?;
+        if let Ok(path) = target.strip_prefix(&self.dest) {
+            self.paths.push(path.to_owned());
+        }
+
+        Ok(())
+    }
+
+    fn apply(&mut self, target: &Path, meta: EntryMeta) -> Result<()> {
+        fchownat(
+            None,
+            target,
+            Some(Uid::from_raw(meta.uid as u32)),
+            Some(Gid::from_raw(meta.gid as u32)),
+            FchownatFlags::NoFollowSymlink,
+        )?;
+        if !meta.symlink {
+            // chown(2) clears the setuid bits and the capabilities, so they are applied afterwards
+            fs::set_permissions(target, fs::Permissions::from_mode(meta.mode))?;
+            for (name, value) in meta.xattrs.iter() {
+                let name = OsStr::from_bytes(name);
+                set_xattr(target, name, value)?;
+                if name == CAPABILITY_XATTR {
+                    self.capabilities.push(target.to_owned());
+                }
+            }
+            if !meta.acls.is_empty() {
+                self.acls.push((target.to_owned(), meta.acls));
+            }
+        }
+        let mtime = TimeSpec::seconds(meta.mtime as i64);
+        utimensat(
+            None,
+            target,
+            &mtime,
+            &mtime,
+            UtimensatFlags::NoFollowSymlink,
+        )?;
+
+        Ok(())
+    }
+
+    /// Apply the metadata of the directories and the ACLs, then verify the extracted tree:
+    /// all the entries must exist, with their capabilities and ACLs
+    pub fn finish(mut self) -> Result<ExtractStats> {
+        // the innermost first, the directories may be read-only
+        for (target, meta) in mem::take(&mut self.dirs).into_iter().rev() {
+            self.apply(&target, meta)?;
+        }
+        let users = read_ids(&self.dest.join("etc/passwd"));
+        let groups = read_ids(&self.dest.join("etc/group"));
+        let mut acls = 0;
+        for (target, entries) in self.acls.iter() {
+            for (name, text) in entries {
+                let value = encode_acl(text, &users, &groups)
+                    .map_err(|e| anyhow!("{}: {}", target.display(), e))?;
+                set_xattr(target, OsStr::new(name), &value)?;
+            }
+            acls += 1;
+        }
+
+        let missing = self
+            .paths
+            .iter()
+            .filter(|path| fs::symlink_metadata(self.dest.join(path)).is_err())
+            .collect::<Vec<_>>();
+        if let Some(path) = missing.first() {
+            bail!(
+                "Extraction incomplete: {} of {} entries are missing (e.g. {})",
+                missing.len(),
+                self.paths.len(),
+                path.display()
+            );
+        }
+        let lost_capabilities = self
+            .capabilities
+            .iter()
+            .filter(|target| lacks_xattr(target, CAPABILITY_XATTR))
+            .count();
+        let lost_acls = self
+            .acls
+            .iter()
+            .filter(|(target, entries)| entries.iter().any(|(name, _)| lacks_xattr(target, name)))
+            .count();
+        if lost_capabilities > 0 || lost_acls > 0 {
+            bail!(
+                "The filesystem dropped the capabilities of {} file(s) and the ACLs of {} file(s)",
+                lost_capabilities,
+                lost_acls
+            );
+        }
+
+        Ok(ExtractStats {
+            entries: self.paths.len(),
+            capabilities: self.capabilities.len(),
+            acls,
+        })
+    }
+}
+
+/// Extract the (uncompressed) tar stream into the directory with all the metadata, `on_entry`
+/// is called before each entry (with the number of entries extracted) and may stop the
+/// extraction by returning an error
+pub fn extract<R: Read, F: FnMut(usize) -> Result<()>>(
+    reader: R,
+    dest: &Path,
+    mut on_entry: F,
+) -> Result<ExtractStats> {
+    let mut archive = Extractor::archive(reader);
+    let mut extractor = Extractor::new(dest)?;
+    for (n, entry) in archive.entries()?.enumerate() {
+        on_entry(n)?;
+        extractor.unpack(&mut entry?)?;
+    }
+
+    extractor.finish()
+}
+
+#[test]
+fn test_encode_acl() {
+    let users = vec![("builder".to_owned(), 1000)]
+        .into_iter()
+        .collect::<HashMap<_, _>>();
+    let groups = HashMap::new();
+    let value = encode_acl(
+        "user::rwx\nuser:builder:r-x\ngroup::r-x\nmask::r-x\nother::---\n",
+        &users,
+        &groups,
+    )
+    .unwrap();
+    assert_eq!(value.len(), 4 + 5 * 8);
+    assert_eq!(&value[..4], &[2, 0, 0, 0]);
+    // user:builder (tag 0x02, r-x, uid 1000)
+    assert_eq!(&value[12..20], &[0x02, 0, 5, 0, 0xe8, 0x03, 0, 0]);
+    // the entries are sorted, the same as in the star format with the IDs
+    assert_eq!(
+        encode_acl(
+            "other::---,mask::r-x,group::r-x,user:builder:r-x:1000,user::rwx",
+            &HashMap::new(),
+            &groups
+        )
+        .unwrap(),
+        value
+    );
+    assert!(encode_acl("group:nobody:r-x", &users, &groups).is_err());
+    assert!(encode_acl("user::rwz", &users, &groups).is_err());
+}
+
+#[test]
+fn test_extract_escape() {
+    let dest = tempfile::tempdir().unwrap();
+    let outside = tempfile::tempdir().unwrap();
+    let mut builder = tar::Builder::new(Vec::new());
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Symlink);
+    header.set_size(0);
+    builder
+        .append_link(&mut header, "x", outside.path())
+        .unwrap();
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Directory);
+    header.set_mode(0o755);
+    header.set_size(0);
+    builder
+        .append_data(&mut header, "x/escaped", std::io::empty())
+        .unwrap();
+    let tarball = builder.into_inner().unwrap();
+    assert!(extract(tarball.as_slice(), dest.path(), |_| Ok(())).is_err());
+    assert!(!outside.path().join("escaped").exists());
+}
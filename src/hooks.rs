@@ -0,0 +1,165 @@
+//! This module contains the hooks subsystem (documented in the README)
+
+use anyhow::{anyhow, Result};
+use console::style;
+use std::{
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::{info, warn};
+
+/// Location of the hooks in the workspace
+pub const HOOKS_LOCATION: &str = ".ciel/hooks";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hook {
+    PreBuild,
+    PostBuild,
+    PreStart,
+    PostStart,
+    PreCommit,
+    PostCommit,
+    PostLoadOs,
+    PostInit,
+    PostPush,
+}
+
+impl Hook {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Hook::PreBuild => "pre-build",
+            Hook::PostBuild => "post-build",
+            Hook::PreStart => "pre-start",
+            Hook::PostStart => "post-start",
+            Hook::PreCommit => "pre-commit",
+            Hook::PostCommit => "post-commit",
+            Hook::PostLoadOs => "post-load-os",
+            Hook::PostInit => "post-init",
+            Hook::PostPush => "post-push",
+        }
+    }
+
+    #[inline]
+    fn can_abort(&self) -> bool {
+        self.name().starts_with("pre-")
+    }
+}
+
+/// Information about the operation passed to the hooks
+#[derive(Debug, Default, Clone, Copy)]
+pub struct HookContext<'a> {
+    pub instance: Option<&'a str>,
+    pub packages: &'a [String],
+    pub exit_status: Option<i32>,
+    pub target: Option<&'a str>,
+}
+
+impl<'a> HookContext<'a> {
+    pub fn instance(instance: &'a str) -> Self {
+        HookContext {
+            instance: Some(instance),
+            ..Default::default()
+        }
+    }
+}
+
+#[inline]
+fn is_executable(path: &Path) -> bool {
+    fs::metadata(path)
+        .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+/// Find the executables of the hook
+fn find_hook_scripts(hooks_dir: &Path, hook: Hook) -> Result<Vec<PathBuf>> {
+    let mut scripts = Vec::new();
+    let script = hooks_dir.join(hook.name());
+    if is_executable(&script) {
+        scripts.push(script);
+    }
+    let dir = hooks_dir.join(format!("{}.d", hook.name()));
+    if dir.is_dir() {
+        let mut entries = fs::read_dir(dir)?
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| is_executable(p))
+            .collect::<Vec<_>>();
+        entries.sort();
+        scripts.extend(entries);
+    }
+
+    Ok(scripts)
+}
+
+fn run_script(script: &Path, hook: Hook, context: &HookContext) -> Result<()> {
+    let workspace = std::env::current_dir()?;
+    let mut command = Command::new(script);
+    command
+        .current_dir(&workspace)
+        .env("CIEL_HOOK", hook.name())
+        .env("CIEL_WORKSPACE", &workspace);
+    if let Some(instance) = context.instance {
+        command.env("CIEL_INSTANCE", instance);
+    }
+    if !context.packages.is_empty() {
+        command.env("CIEL_PACKAGES", context.packages.join(" "));
+    }
+    if let Some(status) = context.exit_status {
+        command.env("CIEL_EXIT_STATUS", status.to_string());
+    }
+    if let Some(target) = context.target {
+        command.env("CIEL_PUSH_TARGET", target);
+    }
+    let status = command
+        .status()
+        .map_err(|e| anyhow!("Unable to execute {}: {}", script.display(), e))?;
+    if !status.success() {
+        return Err(anyhow!("{} failed: {}", script.display(), status));
+    }
+
+    Ok(())
+}
+
+/// Run the scripts of the hook. Returns an error if a `pre-*` hook fails
+pub fn run_hook(hook: Hook, context: &HookContext) -> Result<()> {
+    for script in find_hook_scripts(Path::new(HOOKS_LOCATION), hook)? {
+        info!("Running {} hook: {}", hook.name(), script.display());
+        if let Err(e) = run_script(&script, hook, context) {
+            if hook.can_abort() {
+                return Err(anyhow!("Aborted by the {} hook: {}", hook.name(), e));
+            }
+            warn!("{}", e);
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_find_hook_scripts() {
+    let dir = tempfile::tempdir().unwrap();
+    let write_script = |path: &Path, mode: u32| {
+        fs::write(path, "#!/bin/sh\n").unwrap();
+        fs::set_permissions(path, fs::Permissions::from_mode(mode)).unwrap();
+    };
+    write_script(&dir.path().join("pre-build"), 0o755);
+    fs::create_dir(dir.path().join("pre-build.d")).unwrap();
+    write_script(&dir.path().join("pre-build.d/20-b"), 0o755);
+    write_script(&dir.path().join("pre-build.d/10-a"), 0o755);
+    write_script(&dir.path().join("pre-build.d/30-disabled"), 0o644);
+    let scripts = find_hook_scripts(dir.path(), Hook::PreBuild).unwrap();
+    assert_eq!(
+        scripts,
+        vec![
+            dir.path().join("pre-build"),
+            dir.path().join("pre-build.d/10-a"),
+            dir.path().join("pre-build.d/20-b"),
+        ]
+    );
+    assert!(find_hook_scripts(dir.path(), Hook::PostBuild)
+        .unwrap()
+        .is_empty());
+}
@@ -0,0 +1,129 @@
+//! This module contains ciel image (exported dist layer) related APIs
+
+use crate::common::{extract_tar, CIEL_DIST_DIR};
+use crate::{compat, dist, info, progress::Progress};
+use anyhow::{anyhow, bail, Result};
+use console::style;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{
+    fs::{self, File},
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+const IMAGE_FORMAT_VERSION: usize = 1;
+const IMAGE_MANIFEST_NAME: &str = "ciel-image.toml";
+const IMAGE_ROOTFS_NAME: &str = "rootfs.tar";
+
+/// Manifest embedded in an exported ciel image
+#[derive(Deserialize, Debug, Clone)]
+pub struct ImageManifest {
+    pub version: usize,
+    pub arch: String,
+    pub date: String,
+    pub sha256sum: String,
+}
+
+// The image is a zstd compressed tarball with the following layout:
+// |- ciel-image.toml: the manifest
+// |- rootfs.tar: (uncompressed) tarball of the dist layer
+fn open_image(path: &Path) -> Result<tar::Archive<impl Read>> {
+    let f = File::open(path)?;
+
+    Ok(tar::Archive::new(zstd::Decoder::new(f)?))
+}
+
+#[inline]
+fn entry_name<R: Read>(entry: &tar::Entry<R>) -> Result<PathBuf> {
+    let path = entry.path()?.into_owned();
+
+    Ok(path.strip_prefix(".").map(|p| p.to_owned()).unwrap_or(path))
+}
+
+/// Computes the checksum of the data read
+struct HashReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> Read for HashReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+
+        Ok(n)
+    }
+}
+
+/// Unpack the root filesystem to the dist layer while calculating its checksum, then verify
+/// the embedded manifest against it
+fn unpack_image(path: &Path, dist_dir: &Path) -> Result<ImageManifest> {
+    let mut archive = open_image(path)?;
+    let mut manifest = None;
+    let mut checksum = None;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = entry_name(&entry)?;
+        if name == Path::new(IMAGE_MANIFEST_NAME) {
+            let mut data = String::new();
+            entry.read_to_string(&mut data)?;
+            manifest = Some(toml::from_str::<ImageManifest>(&data)?);
+        } else if name == Path::new(IMAGE_ROOTFS_NAME) {
+            let mut reader = HashReader {
+                inner: &mut entry,
+                hasher: Sha256::new(),
+            };
+            extract_tar(&mut reader, dist_dir)?;
+            // the rest of the tarball (e.g. the padding) is still part of the checksum
+            io::copy(&mut reader, &mut io::sink())?;
+            checksum = Some(format!("{:x}", reader.hasher.finalize()));
+        }
+    }
+    let manifest = manifest.ok_or_else(|| anyhow!("Image does not contain a manifest"))?;
+    let checksum = checksum.ok_or_else(|| anyhow!("Image does not contain a root filesystem"))?;
+    if manifest.version > IMAGE_FORMAT_VERSION {
+        bail!(
+            "Image format version {} is not supported by this version of Ciel",
+            manifest.version
+        );
+    }
+    if manifest.sha256sum != checksum {
+        bail!(
+            "Checksum mismatch: expected {} but got {}",
+            manifest.sha256sum,
+            checksum
+        );
+    }
+    compat::check_arch(compat::extracted_arch(dist_dir)?)?;
+
+    Ok(manifest)
+}
+
+/// Unpack the image to the dist layer, the unpacked files are removed if the verification fails
+pub fn import_image(path: &Path) -> Result<()> {
+    let dist_dir = PathBuf::from(CIEL_DIST_DIR);
+    let manifest = dist::with_unlocked(|| {
+        let spinner = Progress::spinner("Importing image...");
+        if dist_dir.exists() {
+            fs::remove_dir_all(&dist_dir).ok();
+        }
+        fs::create_dir_all(&dist_dir)?;
+        let result = unpack_image(path, &dist_dir);
+        spinner.finish();
+        if result.is_err() {
+            fs::remove_dir_all(&dist_dir).ok();
+            fs::create_dir_all(&dist_dir)?;
+        }
+
+        result
+    })?;
+    info!(
+        "Image imported: {} ({}), created on {}.",
+        path.display(),
+        manifest.arch,
+        manifest.date
+    );
+
+    Ok(())
+}
@@ -0,0 +1,215 @@
+//! This module contains the Linux security module (SELinux and AppArmor) related APIs
+
+use anyhow::{bail, Result};
+use console::style;
+use std::{fs, path::Path, process::Command};
+
+use crate::{config, info, warn};
+
+/// The domain of the containers
+const SELINUX_PROCESS_CONTEXT: &str = "system_u:system_r:container_t:s0";
+/// The label of the container trees and the API filesystems in the containers
+const SELINUX_FILE_CONTEXT: &str = "system_u:object_r:container_file_t:s0";
+const SELINUX_FILE_TYPE: &str = "container_file_t";
+const SELINUX_XATTR: &str = "security.selinux";
+const SELINUX_ENFORCE: &str = "/sys/fs/selinux/enforce";
+/// Writing a context into it checks if the context is valid in the loaded policy
+const SELINUX_CHECK_CONTEXT: &str = "/sys/fs/selinux/context";
+const APPARMOR_ENABLED: &str = "/sys/module/apparmor/parameters/enabled";
+const APPARMOR_PROFILES: &str = "/sys/kernel/security/apparmor/profiles";
+
+/// A security module active on the host
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SecurityModule {
+    SELinux {
+        enforcing: bool,
+    },
+    /// AppArmor, with the profile confining systemd-nspawn and its mode (e.g. `enforce`)
+    AppArmor {
+        nspawn_profile: Option<(String, String)>,
+    },
+}
+
+/// Return the profile confining systemd-nspawn and its mode, from the list of the profiles
+/// loaded (e.g. `/usr/bin/systemd-nspawn (enforce)`)
+fn find_nspawn_profile(profiles: &str) -> Option<(String, String)> {
+    profiles.lines().find_map(|line| {
+        let (name, mode) = line.trim().rsplit_once(' ')?;
+        let mode = mode.strip_prefix('(')?.strip_suffix(')')?;
+        if name == "systemd-nspawn" || name.ends_with("/systemd-nspawn") {
+            Some((name.to_owned(), mode.to_owned()))
+        } else {
+            None
+        }
+    })
+}
+
+/// Detect the security modules active on the host
+pub fn detect() -> Vec<SecurityModule> {
+    let mut modules = Vec::new();
+    if let Ok(enforce) = fs::read_to_string(SELINUX_ENFORCE) {
+        modules.push(SecurityModule::SELinux {
+            enforcing: enforce.trim() == "1",
+        });
+    }
+    if fs::read_to_string(APPARMOR_ENABLED).map_or(false, |e| e.trim() == "Y") {
+        modules.push(SecurityModule::AppArmor {
+            nspawn_profile: fs::read_to_string(APPARMOR_PROFILES)
+                .ok()
+                .and_then(|profiles| find_nspawn_profile(&profiles)),
+        });
+    }
+
+    modules
+}
+
+#[inline]
+fn selinux_enabled() -> bool {
+    Path::new(SELINUX_ENFORCE).exists()
+}
+
+/// Whether the loaded SELinux policy has the container types (from container-selinux)
+pub fn has_container_policy() -> bool {
+    [SELINUX_PROCESS_CONTEXT, SELINUX_FILE_CONTEXT]
+        .iter()
+        .all(|context| fs::write(SELINUX_CHECK_CONTEXT, context).is_ok())
+}
+
+/// Return the options of systemd-nspawn for the security modules
+pub fn nspawn_options() -> Vec<String> {
+    if !selinux_enabled() || !has_container_policy() {
+        return Vec::new();
+    }
+
+    vec![
+        format!("--selinux-context={}", SELINUX_PROCESS_CONTEXT),
+        format!("--selinux-apifs-context={}", SELINUX_FILE_CONTEXT),
+    ]
+}
+
+/// Return the command running systemd-nspawn, under the AppArmor profile if configured
+pub fn nspawn_command() -> Command {
+    match config::read_config().ok().and_then(|c| c.apparmor_profile) {
+        Some(profile) => {
+            let mut command = Command::new("aa-exec");
+            command.args(["-p", &profile, "--", "systemd-nspawn"]);
+            command
+        }
+        None => Command::new("systemd-nspawn"),
+    }
+}
+
+/// Whether the context (e.g. `system_u:object_r:container_file_t:s0`) has the type
+#[inline]
+fn has_type(context: &[u8], file_type: &str) -> bool {
+    String::from_utf8_lossy(context)
+        .trim_end_matches('\0')
+        .split(':')
+        .nth(2)
+        == Some(file_type)
+}
+
+/// Label the trees used by the containers for SELinux, unless they are already labeled.
+/// Only the trees in the workspace are relabeled, the labels of the host directories
+/// (e.g. the extra mounts of the instances) are left to the user
+pub fn label_trees<P: AsRef<Path>>(workspace: &Path, paths: &[P]) -> Result<()> {
+    if !selinux_enabled() || !has_container_policy() {
+        return Ok(());
+    }
+    let workspace = fs::canonicalize(workspace)?;
+    for path in paths {
+        let path = path.as_ref();
+        match xattr::get(path, SELINUX_XATTR) {
+            Ok(Some(context)) if has_type(&context, SELINUX_FILE_TYPE) => continue,
+            Err(_) if !path.exists() => continue,
+            _ => (),
+        }
+        if !fs::canonicalize(path).map_or(false, |p| p.starts_with(&workspace)) {
+            warn!(
+                "{} is outside of the workspace and not labeled for SELinux, the instances may be denied access to it.",
+                path.display()
+            );
+            warn!(
+                "To allow the access, label it with `chcon -R -t {} {}`.",
+                SELINUX_FILE_TYPE,
+                path.display()
+            );
+            continue;
+        }
+        info!("Labeling {} for SELinux ...", path.display());
+        let output = Command::new("chcon")
+            .args(["-R", "-t", SELINUX_FILE_TYPE])
+            .arg(path)
+            .output()?;
+        if !output.status.success() {
+            bail!(
+                "Unable to label {} for SELinux: {}\nPlease label it with `chcon -R -t {} {}`, or put SELinux into permissive mode with `setenforce 0`.",
+                path.display(),
+                String::from_utf8_lossy(&output.stderr).trim(),
+                SELINUX_FILE_TYPE,
+                path.display()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Return the problems of the security modules which are likely to prevent the containers
+/// from working, with how to solve them
+pub fn problems() -> Vec<String> {
+    let profile = config::read_config().ok().and_then(|c| c.apparmor_profile);
+    let mut hints = Vec::new();
+    for module in detect() {
+        match module {
+            SecurityModule::SELinux { enforcing: true } if !has_container_policy() => {
+                hints.push(format!(
+                    "SELinux is enforcing, but the policy has no `{}` type: please install the container policy (e.g. container-selinux).",
+                    SELINUX_FILE_TYPE
+                ));
+            }
+            SecurityModule::AppArmor {
+                nspawn_profile: Some((name, mode)),
+            } if mode == "enforce" && profile.is_none() => {
+                hints.push(format!(
+                    "systemd-nspawn is confined by AppArmor profile `{}`: check the denials with `journalctl -k -g apparmor=.DENIED`, then set `apparmor-profile` in the configuration or run `aa-complain {}`.",
+                    name, name
+                ));
+            }
+            _ => (),
+        }
+    }
+
+    hints
+}
+
+/// Explain how the security modules may have prevented the container from working, if any
+pub fn explain_denials() -> Vec<String> {
+    let mut hints = problems();
+    if hints.is_empty() && detect().contains(&SecurityModule::SELinux { enforcing: true }) {
+        hints.push(
+            "SELinux is enforcing: check the denials with `ausearch -m avc -ts recent`.".to_owned(),
+        );
+    }
+
+    hints
+}
+
+#[test]
+fn test_lsm_helpers() {
+    let profiles =
+        "lsb_release (enforce)\n/usr/bin/systemd-nspawn (enforce)\nnvidia_modprobe (complain)\n";
+    assert_eq!(
+        find_nspawn_profile(profiles),
+        Some(("/usr/bin/systemd-nspawn".to_owned(), "enforce".to_owned()))
+    );
+    assert_eq!(find_nspawn_profile("lsb_release (enforce)\n"), None);
+    assert!(has_type(
+        b"system_u:object_r:container_file_t:s0:c1,c2\0",
+        SELINUX_FILE_TYPE
+    ));
+    assert!(!has_type(
+        b"unconfined_u:object_r:user_home_t:s0",
+        SELINUX_FILE_TYPE
+    ));
+}
@@ -3,37 +3,48 @@
 use crate::common::{is_legacy_workspace, CIEL_INST_DIR};
 use crate::dbus_machine1::ManagerProxyBlocking;
 use crate::dbus_machine1_machine::MachineProxyBlocking;
-use crate::overlayfs::is_mounted;
-use crate::{info, overlayfs::LayerManager, warn};
+use crate::overlayfs::get_layer_manager;
+use crate::{errors::CielError, info, overlayfs::LayerManager, warn};
 use adler32::adler32;
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Result};
 use console::style;
 use libc::{c_char, ftok, waitpid, WNOHANG};
 use libsystemd_sys::bus::{sd_bus_flush_close_unref, sd_bus_open_system_machine};
+use nix::sys::signal::{signal, SigHandler, Signal};
+use serde::Serialize;
 use std::{
     ffi::{CString, OsStr},
+    io::{Read, Write},
     mem::MaybeUninit,
     process::Command,
 };
-use std::{fs, time::Duration};
+use std::{
+    fs,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc,
+    },
+    time::{Duration, Instant},
+};
 use std::{os::unix::ffi::OsStrExt, process::Child};
-use std::{path::Path, process::Stdio, thread::sleep};
+use std::{
+    path::{Path, PathBuf},
+    process::Stdio,
+    thread::sleep,
+};
 use zbus::blocking::Connection;
 
-const DEFAULT_NSPAWN_OPTIONS: &[&str] = &[
-    "-qb",
-    "--capability=CAP_IPC_LOCK",
-    "--system-call-filter=swapcontext",
-];
+const DEFAULT_NSPAWN_OPTIONS: &[&str] = &["-qb"];
 
 /// Instance status information
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
 pub struct CielInstance {
-    name: String,
+    pub name: String,
     // namespace name (in the form of `$name-$id`)
     pub ns_name: String,
     pub mounted: bool,
-    running: bool,
+    pub running: bool,
     pub started: bool,
     booted: Option<bool>,
 }
@@ -64,7 +75,7 @@ fn legacy_container_name(path: &Path) -> Result<String> {
 }
 
 /// Used for getting the instance name from Ciel 3+
-fn new_container_name(path: &Path) -> Result<String> {
+pub(crate) fn new_container_name(path: &Path) -> Result<String> {
     // New container name is calculated using the following formula:
     // $name-adler32($PWD)
     let hash = adler32(path.as_os_str().as_bytes())?;
@@ -116,7 +127,7 @@ fn wait_for_container(child: &mut Child, ns_name: &str, retry: usize) -> Result<
         sleep(Duration::from_secs_f32(((i + 1) as f32).ln().ceil()));
     }
 
-    Err(anyhow!("Timeout waiting for container {}", ns_name))
+    Err(CielError::ContainerBootTimeout(ns_name.to_owned()).into())
 }
 
 /// Setting up cross-namespace bind-mounts for the container using systemd
@@ -151,6 +162,11 @@ pub fn get_container_ns_name<P: AsRef<Path>>(path: P, legacy: bool) -> Result<St
     new_container_name(&path)
 }
 
+/// Get the container name (ns_name) the instance would have if the workspace were located at `root`
+pub fn get_container_ns_name_at(root: &Path, instance: &str) -> Result<String> {
+    new_container_name(&root.join(instance))
+}
+
 /// Spawn a new container using nspawn
 pub fn spawn_container<P: AsRef<Path>>(
     ns_name: &str,
@@ -162,8 +178,9 @@ pub fn spawn_container<P: AsRef<Path>>(
         .as_ref()
         .to_str()
         .ok_or_else(|| anyhow!("Path contains invalid Unicode characters."))?;
-    let mut child = Command::new("systemd-nspawn")
+    let mut child = crate::lsm::nspawn_command()
         .args(DEFAULT_NSPAWN_OPTIONS)
+        .args(crate::lsm::nspawn_options())
         .args(extra_options)
         .args(["-D", path, "-M", ns_name, "--"])
         .env("SYSTEMD_NSPAWN_TMPFS_TMP", "0")
@@ -172,7 +189,12 @@ pub fn spawn_container<P: AsRef<Path>>(
         .spawn()?;
 
     info!("{}: waiting for container to start...", ns_name);
-    wait_for_container(&mut child, ns_name, 10)?;
+    if let Err(e) = wait_for_container(&mut child, ns_name, 10) {
+        for hint in crate::lsm::explain_denials() {
+            warn!("{}", hint);
+        }
+        return Err(e);
+    }
     info!("{}: setting up mounts...", ns_name);
     if let Err(e) = setup_bind_mounts(ns_name, mounts) {
         warn!("Failed to setup bind mounts: {:?}", e);
@@ -181,17 +203,39 @@ pub fn spawn_container<P: AsRef<Path>>(
     Ok(())
 }
 
-/// Execute a command in the container
-pub fn execute_container_command<S: AsRef<OsStr>>(ns_name: &str, args: &[S]) -> Result<i32> {
+fn container_command<S: AsRef<OsStr>>(ns_name: &str, args: &[S]) -> Command {
     let mut extra_options = vec!["--setenv=HOME=/root".to_string()];
     if std::env::var("CIEL_STAGE2").is_ok() {
         extra_options.push("--setenv=ABSTAGE2=1".to_string());
     }
+    extra_options.extend(crate::compiler_cache::run_options());
+    if let Some(priority) = std::env::var("CIEL_PRIORITY")
+        .ok()
+        .and_then(|p| crate::config::BuildPriority::parse(&p).ok())
+    {
+        if let Err(e) = set_scope_properties(ns_name, priority.scope_properties()) {
+            warn!("Unable to set the priority of {}: {}", ns_name, e);
+        }
+        extra_options.extend(
+            priority
+                .properties()
+                .iter()
+                .map(|p| format!("--property={}", p)),
+        );
+    }
     // TODO: maybe replace with systemd API cross-namespace call?
-    let exit_code = Command::new("systemd-run")
-        .args(extra_options)
+    let mut cmd = Command::new("systemd-run");
+    cmd.args(extra_options)
         .args(["-M", ns_name, "-qt", "--"])
         .args(args)
+        .stdout(crate::output::child_stdout());
+
+    cmd
+}
+
+/// Execute a command in the container
+pub fn execute_container_command<S: AsRef<OsStr>>(ns_name: &str, args: &[S]) -> Result<i32> {
+    let exit_code = container_command(ns_name, args)
         .spawn()?
         .wait()?
         .code()
@@ -200,6 +244,248 @@ pub fn execute_container_command<S: AsRef<OsStr>>(ns_name: &str, args: &[S]) ->
     Ok(exit_code)
 }
 
+/// Execute a command in the container, passing each line of the output to the watcher, which
+/// returns if the line should be shown (lines ending with `\r`, e.g. the progress bars, count)
+pub fn execute_container_command_watched<S: AsRef<OsStr>, F: FnMut(&str) -> bool>(
+    ns_name: &str,
+    args: &[S],
+    mut watch: F,
+) -> Result<i32> {
+    let mut child = container_command(ns_name, args)
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let mut output = child.stdout.take().unwrap();
+    let mut stdout: Box<dyn Write> = if crate::output::json_output() {
+        Box::new(std::io::stderr())
+    } else {
+        Box::new(std::io::stdout())
+    };
+    let mut buf = [0u8; 8192];
+    let mut line = Vec::new();
+    loop {
+        let n = match output.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        for &byte in buf[..n].iter() {
+            line.push(byte);
+            if byte == b'\n' || byte == b'\r' {
+                let text = String::from_utf8_lossy(&line[..line.len() - 1]);
+                if watch(&text) {
+                    stdout.write_all(&line)?;
+                }
+                line.clear();
+            }
+        }
+        stdout.flush()?;
+    }
+    if !line.is_empty() && watch(&String::from_utf8_lossy(&line)) {
+        stdout.write_all(&line)?;
+        stdout.flush()?;
+    }
+    let exit_code = child.wait()?.code().unwrap_or(127);
+
+    Ok(exit_code)
+}
+
+/// How a command run with a deadline ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Completion {
+    /// The command exited with the status
+    Exited(i32),
+    /// The command was still running at the deadline
+    TimedOut,
+}
+
+impl Completion {
+    /// Return the exit status to report, [crate::errors::EXIT_BUILD_TIMED_OUT] if timed out
+    pub fn exit_status(&self) -> i32 {
+        match self {
+            Completion::Exited(status) => *status,
+            Completion::TimedOut => crate::errors::EXIT_BUILD_TIMED_OUT,
+        }
+    }
+}
+
+/// Bytes of the output kept for the report of the watchdog
+const OUTPUT_TAIL_SIZE: usize = 16384;
+/// Lines of the output shown in the report of the watchdog
+const OUTPUT_TAIL_LINES: usize = 30;
+
+/// Execute a command in the container, the output is also written (compressed) into the log.
+/// If the command is still running at the deadline, the process tree and the last lines of
+/// the output are written to the log, then the container is terminated and
+/// [Completion::TimedOut] is returned
+pub fn execute_container_command_logged<S: AsRef<OsStr>>(
+    ns_name: &str,
+    args: &[S],
+    log: &Path,
+    deadline: Option<Instant>,
+) -> Result<Completion> {
+    if let Some(parent) = log.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut encoder = zstd::Encoder::new(fs::File::create(log)?, 3)?;
+    let mut child = container_command(ns_name, args)
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let mut output = child.stdout.take().unwrap();
+    let reader = std::thread::spawn(move || -> Result<_> {
+        // stdout is kept for the JSON document in the JSON mode
+        let mut stdout: Box<dyn Write> = if crate::output::json_output() {
+            Box::new(std::io::stderr())
+        } else {
+            Box::new(std::io::stdout())
+        };
+        let mut buf = [0u8; 8192];
+        let mut tail = Vec::new();
+        loop {
+            let n = match output.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            stdout.write_all(&buf[..n])?;
+            stdout.flush()?;
+            encoder.write_all(&buf[..n])?;
+            tail.extend_from_slice(&buf[..n]);
+            if tail.len() > OUTPUT_TAIL_SIZE {
+                tail.drain(..tail.len() - OUTPUT_TAIL_SIZE);
+            }
+        }
+
+        Ok((encoder, tail))
+    });
+    let mut report = None;
+    let completion = loop {
+        if let Some(status) = child.try_wait()? {
+            break Completion::Exited(status.code().unwrap_or(127));
+        }
+        if deadline.map_or(false, |d| Instant::now() >= d) {
+            let tree = container_cgroup(ns_name)
+                .and_then(|cgroup| process_tree(&cgroup))
+                .unwrap_or_else(|e| format!("(unable to read the process tree: {})\n", e));
+            report = Some(tree);
+            child.kill().ok();
+            child.wait()?;
+            if let Err(e) = terminate_container_by_name(ns_name) {
+                warn!("Unable to terminate the container: {}", e);
+            }
+            break Completion::TimedOut;
+        }
+        sleep(Duration::from_millis(200));
+    };
+    let (mut encoder, tail) = reader
+        .join()
+        .map_err(|_| anyhow!("The output of the command could not be read"))??;
+    if let Some(tree) = report {
+        let tail = String::from_utf8_lossy(&tail);
+        let lines = tail.lines().collect::<Vec<_>>();
+        let report = format!(
+            "\n===== ciel: the command timed out =====\n----- process tree -----\n{}----- last {} lines of the output -----\n{}\n",
+            tree,
+            OUTPUT_TAIL_LINES,
+            lines[lines.len().saturating_sub(OUTPUT_TAIL_LINES)..].join("\n")
+        );
+        eprint!("{}", report);
+        encoder.write_all(report.as_bytes())?;
+    }
+    encoder.finish()?;
+
+    Ok(completion)
+}
+
+/// Render the processes (PID, parent PID, command line) as a tree
+fn render_process_tree(processes: &[(u32, u32, String)]) -> String {
+    fn render(processes: &[(u32, u32, String)], parent: u32, depth: usize, out: &mut String) {
+        for (pid, _, cmdline) in processes.iter().filter(|p| p.1 == parent) {
+            out.push_str(&format!("{}{} {}\n", "  ".repeat(depth), pid, cmdline));
+            render(processes, *pid, depth + 1, out);
+        }
+    }
+    let pids = processes.iter().map(|p| p.0).collect::<Vec<_>>();
+    let mut out = String::new();
+    // the processes whose parents are not in the list are the roots
+    let mut roots = processes
+        .iter()
+        .filter(|p| !pids.contains(&p.1))
+        .map(|p| p.1)
+        .collect::<Vec<_>>();
+    roots.sort_unstable();
+    roots.dedup();
+    for root in roots {
+        render(processes, root, 0, &mut out);
+    }
+
+    out
+}
+
+/// Return the tree of the processes in the cgroup (and its sub-cgroups)
+fn process_tree(cgroup: &Path) -> Result<String> {
+    let mut processes = Vec::new();
+    for entry in walkdir::WalkDir::new(cgroup)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_name() == "cgroup.procs")
+    {
+        for pid in fs::read_to_string(entry.path())?.lines() {
+            let pid: u32 = match pid.trim().parse() {
+                Ok(pid) => pid,
+                Err(_) => continue,
+            };
+            // the command may contain spaces and parentheses, the fields follow the last `)`
+            let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).unwrap_or_default();
+            let ppid = stat
+                .rsplit_once(')')
+                .and_then(|(_, fields)| fields.split_whitespace().nth(1)?.parse().ok())
+                .unwrap_or(0);
+            let cmdline = fs::read(format!("/proc/{}/cmdline", pid)).unwrap_or_default();
+            let cmdline = String::from_utf8_lossy(&cmdline).replace('\0', " ");
+            processes.push((pid, ppid, cmdline.trim().to_owned()));
+        }
+    }
+    processes.sort_unstable();
+
+    Ok(render_process_tree(&processes))
+}
+
+/// Set the properties of the scope of the container on the host (until it stops)
+fn set_scope_properties(ns_name: &str, properties: &[&str]) -> Result<()> {
+    let conn = Connection::system()?;
+    let proxy = ManagerProxyBlocking::new(&conn)?;
+    let path = proxy.get_machine(ns_name)?;
+    let proxy = MachineProxyBlocking::builder(&conn).path(&path)?.build()?;
+    let status = Command::new("systemctl")
+        .args(["set-property", "--runtime", &proxy.unit()?])
+        .args(properties)
+        .status()?;
+    if !status.success() {
+        bail!("systemctl set-property failed with {}", status);
+    }
+
+    Ok(())
+}
+
+/// Return the cgroup (v2) of the processes in the container
+pub fn container_cgroup(ns_name: &str) -> Result<PathBuf> {
+    let conn = Connection::system()?;
+    let proxy = ManagerProxyBlocking::new(&conn)?;
+    let path = proxy.get_machine(ns_name)?;
+    let proxy = MachineProxyBlocking::builder(&conn).path(&path)?.build()?;
+    let cgroups = fs::read_to_string(format!("/proc/{}/cgroup", proxy.leader()?))?;
+    let cgroup = cgroups
+        .lines()
+        .find_map(|l| l.strip_prefix("0::"))
+        .ok_or_else(|| anyhow!("The container is not in a cgroup v2 hierarchy"))?;
+    // the init of a booted container is in a sub-cgroup of the container
+    let cgroup = cgroup.strip_suffix("/init.scope").unwrap_or(cgroup);
+
+    Ok(Path::new("/sys/fs/cgroup").join(cgroup.trim_start_matches('/')))
+}
+
 /// Reap all the exited child processes
 pub(crate) fn clean_child_process() {
     let mut status = 0;
@@ -299,10 +585,49 @@ pub fn terminate_container_by_name(ns_name: &str) -> Result<()> {
     terminate_container(&proxy)
 }
 
+/// List the machines registered by the instances of this workspace which no longer have a
+/// filesystem to run on (the instance is deleted or un-mounted), returns the container names
+pub fn list_stale_machines() -> Result<Vec<String>> {
+    let cwd = std::env::current_dir()?;
+    let conn = Connection::system()?;
+    let proxy = ManagerProxyBlocking::new(&conn)?;
+    let mut stale = Vec::new();
+    for (ns_name, _, _, path) in proxy.list_machines()? {
+        let machine = MachineProxyBlocking::builder(&conn).path(&path)?.build()?;
+        let root = match machine.root_directory() {
+            Ok(root) => Path::new(&root).to_path_buf(),
+            Err(_) => continue,
+        };
+        if root.parent() != Some(cwd.as_path()) {
+            continue;
+        }
+        let instance = match root.file_name() {
+            Some(name) => name.to_string_lossy().to_string(),
+            None => continue,
+        };
+        if !crate::common::is_instance_exists(&instance)
+            || !get_layer_manager(&instance)?.is_mounted(&root)?
+        {
+            stale.push(ns_name);
+        }
+    }
+
+    Ok(stale)
+}
+
 /// Mount the filesystem layers using the specified layer manager and the instance name
 pub fn mount_layers(manager: &mut dyn LayerManager, name: &str) -> Result<()> {
     let target = std::env::current_dir()?.join(name);
     if !manager.is_mounted(&target)? {
+        // mounting over a non-empty directory would hide its contents
+        if let Ok(mut entries) = fs::read_dir(&target) {
+            if entries.next().is_some() {
+                return Err(anyhow!(
+                    "Mount point {:?} is not empty, refusing to mount over it.",
+                    target
+                ));
+            }
+        }
         fs::create_dir_all(&target)?;
         manager.mount(&target)?;
     }
@@ -313,7 +638,7 @@ pub fn mount_layers(manager: &mut dyn LayerManager, name: &str) -> Result<()> {
 /// Get the information of the container specified
 pub fn inspect_instance(name: &str, ns_name: &str) -> Result<CielInstance> {
     let full_path = std::env::current_dir()?.join(name);
-    let mounted = is_mounted(&full_path, OsStr::new("overlay"))?;
+    let mounted = get_layer_manager(name)?.is_mounted(&full_path)?;
     let conn = Connection::system()?;
     let proxy = ManagerProxyBlocking::new(&conn)?;
     let path = proxy.get_machine(ns_name);
@@ -354,13 +679,11 @@ pub fn inspect_instance(name: &str, ns_name: &str) -> Result<CielInstance> {
 pub fn list_instances() -> Result<Vec<CielInstance>> {
     let legacy = is_legacy_workspace()?;
     let mut instances: Vec<CielInstance> = Vec::new();
-    for entry in (fs::read_dir(CIEL_INST_DIR)?).flatten() {
-        if entry.file_type().map(|e| e.is_dir())? {
-            instances.push(inspect_instance(
-                &entry.file_name().to_string_lossy(),
-                &get_container_ns_name(&entry.file_name(), legacy)?,
-            )?);
-        }
+    for name in list_instances_simple()? {
+        instances.push(inspect_instance(
+            &name,
+            &get_container_ns_name(&name, legacy)?,
+        )?);
     }
 
     Ok(instances)
@@ -378,14 +701,11 @@ pub fn list_instances_simple() -> Result<Vec<String>> {
     Ok(instances)
 }
 
-/// Print all the instances under the current directory
-pub fn print_instances() -> Result<()> {
+pub(crate) fn write_instances<W: std::io::Write>(instances: &[CielInstance], out: W) -> Result<()> {
     use crate::logging::color_bool;
-    use std::io::Write;
     use tabwriter::TabWriter;
 
-    let instances = list_instances()?;
-    let mut formatter = TabWriter::new(std::io::stderr());
+    let mut formatter = TabWriter::new(out);
     writeln!(&mut formatter, "NAME\tMOUNTED\tRUNNING\tBOOTED")?;
     for instance in instances {
         let mounted = color_bool(instance.mounted);
@@ -409,6 +729,150 @@ pub fn print_instances() -> Result<()> {
     Ok(())
 }
 
+/// Print all the instances under the current directory
+pub fn print_instances() -> Result<()> {
+    let instances = list_instances()?;
+    if crate::output::json_output() {
+        return crate::output::print_json(&instances);
+    }
+
+    write_instances(&instances, std::io::stderr())
+}
+
+static WATCH_STOPPED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn stop_watching(_: libc::c_int) {
+    WATCH_STOPPED.store(true, Ordering::SeqCst);
+}
+
+/// Forward the machine added and removed signals of machined to the channel
+fn subscribe_machine_signals(tx: mpsc::Sender<()>) -> Result<()> {
+    let conn = Connection::system()?;
+    let new_conn = conn.clone();
+    let new_tx = tx.clone();
+    std::thread::spawn(move || -> Result<()> {
+        let proxy = ManagerProxyBlocking::new(&new_conn)?;
+        for _ in proxy.receive_machine_new()? {
+            if new_tx.send(()).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    });
+    std::thread::spawn(move || -> Result<()> {
+        let proxy = ManagerProxyBlocking::new(&conn)?;
+        for _ in proxy.receive_machine_removed()? {
+            if tx.send(()).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    });
+
+    Ok(())
+}
+
+/// Return the mount states of the instances (without asking machined)
+fn mount_states(instances: &[CielInstance]) -> Vec<bool> {
+    let cwd = std::env::current_dir().unwrap_or_default();
+    instances
+        .iter()
+        .map(|i| {
+            get_layer_manager(&i.name)
+                .and_then(|man| man.is_mounted(&cwd.join(&i.name)))
+                .unwrap_or(false)
+        })
+        .collect()
+}
+
+/// Watch the instances under the current directory, `on_change` is called with the instances
+/// at first and whenever they change (checked at most once per interval), until Ctrl-C.
+/// The containers started and stopped are signaled by machined, only the mounts (and the
+/// containers still booting) are checked on each interval
+pub fn watch_instances<F: FnMut(&[CielInstance]) -> Result<()>>(
+    interval: Duration,
+    mut on_change: F,
+) -> Result<()> {
+    let (tx, rx) = mpsc::channel();
+    subscribe_machine_signals(tx)?;
+    let mut instances = list_instances()?;
+    let mut mounts = mount_states(&instances);
+    on_change(&instances)?;
+    WATCH_STOPPED.store(false, Ordering::SeqCst);
+    // unsafe: the handler only stores to an atomic
+    let previous = unsafe { signal(Signal::SIGINT, SigHandler::Handler(stop_watching)) }?;
+    let mut signaled = false;
+    let mut last_check = Instant::now();
+    let result = loop {
+        if WATCH_STOPPED.load(Ordering::SeqCst) {
+            break Ok(());
+        }
+        match rx.recv_timeout(Duration::from_millis(200)) {
+            Ok(()) => signaled = true,
+            Err(mpsc::RecvTimeoutError::Timeout) => (),
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                break Err(anyhow!("Lost the connection to systemd-machined"));
+            }
+        }
+        if last_check.elapsed() < interval {
+            continue;
+        }
+        last_check = Instant::now();
+        let booting = instances
+            .iter()
+            .any(|i| i.running && i.booted == Some(false));
+        let current_mounts = mount_states(&instances);
+        if !signaled && !booting && current_mounts == mounts {
+            continue;
+        }
+        signaled = false;
+        instances = match list_instances() {
+            Ok(instances) => instances,
+            Err(e) => break Err(e),
+        };
+        mounts = mount_states(&instances);
+        if let Err(e) = on_change(&instances) {
+            break Err(e);
+        }
+    };
+    unsafe { signal(Signal::SIGINT, previous) }?;
+
+    result
+}
+
+/// Print the instances under the current directory, refreshed on the changes until Ctrl-C
+pub fn print_instances_watch(interval: Duration) -> Result<()> {
+    let result = watch_instances(interval, |instances| {
+        let mut table = Vec::new();
+        write_instances(instances, &mut table)?;
+        // clear the screen, then draw the table from the top
+        eprint!("\x1b[2J\x1b[H{}", String::from_utf8_lossy(&table));
+        eprintln!(
+            "\n{} (every {}s, Ctrl-C to exit)",
+            style("Watching the instances").dim(),
+            interval.as_secs()
+        );
+        Ok(())
+    });
+    eprintln!();
+
+    result
+}
+
+#[test]
+fn test_render_process_tree() {
+    let processes = vec![
+        (1, 0, "/sbin/init".to_owned()),
+        (10, 1, "/bin/acbs-build -- foo".to_owned()),
+        (11, 10, "make".to_owned()),
+        (12, 1, "/usr/lib/systemd/systemd-journald".to_owned()),
+    ];
+    assert_eq!(
+        render_process_tree(&processes),
+        "1 /sbin/init\n  10 /bin/acbs-build -- foo\n    11 make\n  12 /usr/lib/systemd/systemd-journald\n"
+    );
+}
+
 #[test]
 fn test_inspect_instance() {
     println!("{:#?}", inspect_instance("alpine", "alpine"));
@@ -1,39 +1,79 @@
 mod actions;
+mod activity;
+mod backup;
+mod build;
+mod buildlog;
+mod bump;
+mod cache;
+mod capability;
 mod cli;
 mod common;
+mod compat;
+mod compiler_cache;
 mod config;
+mod daemon;
 mod dbus_machine1;
 mod dbus_machine1_machine;
 mod diagnose;
+mod dist;
+mod errors;
+mod extract;
+mod hooks;
+mod image;
 mod logging;
+mod lsm;
 mod machine;
+mod manifest;
+mod metrics;
+mod mount;
 mod network;
+mod notify;
+mod output;
 mod overlayfs;
+mod pkgmgr;
+mod preflight;
+mod preset;
+mod profile;
+mod progress;
+mod quota;
+mod reflink;
 mod repo;
+mod report;
+mod sandbox;
+mod schedule;
+mod seccomp;
+mod secrets;
+mod sources;
+mod stats;
+mod template;
+mod transfer;
+mod trash;
+mod tree;
+mod verify;
+mod zfs;
 
-use anyhow::{anyhow, bail, Context, Result};
+use anyhow::{anyhow, bail, Result};
 use clap::ArgMatches;
 use console::{style, user_attended};
 use dotenvy::dotenv;
 use std::process;
 use std::{path::Path, process::Command};
 
-use crate::actions::BuildSettings;
 use crate::common::*;
 
 macro_rules! print_error {
     ($input:block) => {
         if let Err(e) = $input {
             error!("{:?}", e);
-            process::exit(1);
+            process::exit(errors::exit_code(&e));
         }
     };
 }
 
 macro_rules! one_or_all_instance {
     ($args:ident, $func:expr) => {{
-        if let Ok(instance) = get_instance_option($args) {
-            $func(&instance)
+        if let Some(instance) = $args.get_one::<String>("INSTANCE") {
+            $func(instance)
         } else {
             actions::for_each_instance($func)
         }
@@ -52,21 +92,60 @@ fn unsupported_target_architecture(arch: &str) -> ! {
     process::exit(1);
 }
 
-fn get_output_dir() -> String {
+/// Print the result of the build (as JSON with `--json`), write the report (if asked), ring
+/// the bell, then exit with the exit code of the build (see [errors])
+fn finish_build(result: &build::BuildResult, bell: bool, report: Option<&String>) -> ! {
+    let log_url = config::read_config().ok().and_then(|c| c.build.log_url);
+    if let Some(path) = report {
+        if let Err(e) = report::write_report(result, &user_path(path), log_url.as_deref()) {
+            error!("Unable to write the report: {}", e);
+        }
+    }
+    report::print_annotations(result, log_url.as_deref());
+    if output::json_output() {
+        if let Err(e) = output::print_json(result) {
+            error!("Unable to print the result: {}", e);
+        }
+    } else if bell {
+        println!("\x07"); // bell character
+    }
+    process::exit(errors::build_exit_code(result));
+}
+
+/// Ask the daemon to build the packages, print the result then exit with the exit code of the
+/// build
+fn build_via_daemon(request: &build::BuildRequest) -> ! {
+    let result = serde_json::to_value(daemon::BuildParams::from(request))
+        .map_err(anyhow::Error::from)
+        .and_then(|params| daemon::call("build", params))
+        .and_then(|result| output::print_json(&result).map(|_| result));
+    match result {
+        Ok(result) if result["exit-status"].as_i64() == Some(0) => process::exit(0),
+        Ok(result) => process::exit(
+            errors::CielError::from_build_json(&result)
+                .map_or(errors::EXIT_FAILURE, |e| e.exit_code()),
+        ),
+        Err(e) => {
+            error!("{:?}", e);
+            process::exit(errors::exit_code(&e));
+        }
+    }
+}
+
+pub(crate) fn get_output_dir() -> String {
     if let Ok(c) = config::read_config() {
-        return actions::get_output_directory(c.sep_mount);
+        let component = actions::get_output_component(&c);
+        return actions::get_output_directory(c.sep_mount, component.as_deref());
     }
     "OUTPUT".to_string()
 }
 
+/// Return the instance specified (with `-i`), otherwise the default instance of the workspace
 #[inline]
 fn get_instance_option(args: &ArgMatches) -> Result<String> {
-    let option_instance = args.get_one::<String>("INSTANCE");
-    if option_instance.is_none() {
-        return Err(anyhow!("No instance specified!"));
-    }
+    let specified = args.try_get_one::<String>("INSTANCE").ok().flatten();
 
-    Ok(option_instance.expect("Internal error").to_string())
+    actions::select_instance(specified.map(|s| s.as_str()))
 }
 
 #[inline]
@@ -74,7 +153,12 @@ fn is_root() -> bool {
     nix::unistd::geteuid().is_root()
 }
 
-fn update_tree(path: &Path, branch: Option<&String>, rebase_from: Option<&String>) -> Result<()> {
+fn update_tree(
+    path: &Path,
+    branch: Option<&String>,
+    rebase_from: Option<&String>,
+    fetch_only: bool,
+) -> Result<()> {
     let mut repo = network::fetch_repo(path)?;
     if let Some(branch) = branch {
         if repo.state() != git2::RepositoryState::Clean {
@@ -92,46 +176,146 @@ fn update_tree(path: &Path, branch: Option<&String>, rebase_from: Option<&String
             bail!("You need to specify a branch to switch to when requesting a rebase.");
         }
         info!("Successfully fetched new changes from remote.");
+        if !fetch_only {
+            if network::git_fast_forward(&mut repo)? {
+                info!("Successfully fast-forwarded the current branch.");
+            } else {
+                info!("The current branch is already up to date.");
+            }
+            tree::update_overlays()?;
+        }
     }
 
     Ok(())
 }
 
-fn main() -> Result<()> {
+/// Read the options of cloning the ABBS tree from the command line
+fn clone_options(args: &ArgMatches) -> network::CloneOptions {
+    network::CloneOptions {
+        branch: args.get_one::<String>("branch").cloned(),
+        depth: args.get_one::<u32>("depth").copied(),
+        single_branch: args.get_flag("single-branch"),
+        blobless: args.get_flag("blobless"),
+    }
+}
+
+/// Read the value of the secret from the terminal (without echoing) or the standard input
+fn read_secret_value() -> Result<String> {
+    if user_attended() {
+        return Ok(dialoguer::Password::new()
+            .with_prompt("Value of the secret")
+            .interact()?);
+    }
+    let mut value = String::new();
+    std::io::stdin().read_line(&mut value)?;
+
+    Ok(value.trim_end_matches('\n').to_owned())
+}
+
+fn main() {
+    if let Err(e) = run() {
+        error!("{:?}", e);
+        process::exit(errors::exit_code(&e));
+    }
+}
+
+fn run() -> Result<()> {
     // source .env file, ignore errors
     dotenv().ok();
 
     let build_cli = cli::build_cli();
     let version_string = build_cli.render_version();
-    let args = build_cli.get_matches();
+    let mut argv = std::env::args_os().collect::<Vec<_>>();
+    // `cield` (a link to ciel) runs the daemon
+    if argv
+        .first()
+        .and_then(|arg0| Path::new(arg0).file_name())
+        .map_or(false, |name| name == "cield")
+    {
+        argv.insert(1, "daemon".into());
+    }
+    let args = build_cli.get_matches_from(argv);
     if !is_root() {
         println!("Please run me as root!");
         process::exit(1);
     }
-    let mut directory = Path::new(args.get_one::<String>("C").unwrap()).to_path_buf();
+    if let Some(component) = args.get_one::<String>("component") {
+        if !actions::is_valid_component_name(component) {
+            error!("Invalid component name: {}", component);
+            process::exit(1);
+        }
+        // the mounts of the containers and the repository commands check the environment variable
+        std::env::set_var("CIEL_COMPONENT", component);
+    }
+    if args.get_one::<String>("progress").map(|p| p.as_str()) == Some("json") {
+        progress::use_json_output();
+    }
+    if args.get_flag("json") {
+        output::use_json_output();
+    }
     let host_arch = get_host_arch_name();
     // Switch to the target directory
-    std::env::set_current_dir(&directory).unwrap();
+    std::env::set_current_dir(args.get_one::<String>("C").unwrap())?;
+    if let Some(workspace) = args.get_one::<String>("workspace") {
+        // the library reads the environment variable, after switching to the workspace
+        let workspace = Path::new(workspace)
+            .canonicalize()
+            .map_err(|_| errors::CielError::NotAWorkspace)?;
+        std::env::set_var(common::CIEL_WORKSPACE_ENV, workspace);
+    }
     // get subcommands from command line parser
     let subcmd = args.subcommand();
-    // check if the workspace exists, except when the command is `init` or `new`
+    // check if the workspace exists, except when the command is `init`, `new` or `cache`
     match subcmd {
-        Some(("init", _)) | Some(("new", _)) | Some(("version", _)) => (),
-        _ if !Path::new("./.ciel").is_dir() => {
-            if directory == Path::new(".") {
-                directory =
-                    common::find_ciel_dir(".").context("Error finding ciel workspace directory")?;
-                info!(
-                    "Selected Ciel directory: {}",
-                    style(directory.canonicalize()?.display()).cyan()
-                );
-                std::env::set_current_dir(&directory).unwrap();
-            } else {
-                error!("This directory does not look like a Ciel workspace");
+        Some(("init", _)) | Some(("new", _)) | Some(("version", _)) | Some(("cache", _))
+        | Some(("restore", _)) => (),
+        // the workspace is in the trash
+        // the trash is kept after the workspace is removed
+        Some(("farewell", args))
+            if (args.contains_id("restore") || args.contains_id("purge"))
+                && Path::new(trash::TRASH_DIR).is_dir() => {}
+        _ => {
+            let cwd = std::env::current_dir()?;
+            let root = common::enter_workspace()?;
+            if root != cwd {
+                info!("Selected Ciel directory: {}", style(root.display()).cyan());
+            }
+        }
+    }
+    let directory = std::env::current_dir()?;
+    // container names are derived from the workspace location, moving the workspace breaks the association
+    match subcmd {
+        Some(("init", _))
+        | Some(("new", _))
+        | Some(("version", _))
+        | Some(("cache", _))
+        | Some(("workspace", _))
+        | Some(("restore", _))
+        | Some(("farewell", _)) => (),
+        _ => {
+            if let Err(e) = common::check_workspace_location() {
+                error!("{}", e);
+                info!("Please run `ciel workspace relocate` to update this workspace.");
+                process::exit(errors::exit_code(&e));
+            }
+        }
+    }
+    if args.get_flag("via-daemon") {
+        match subcmd {
+            None | Some(("list", _)) => {
+                print_error!({
+                    daemon::call("status", serde_json::Value::Null)
+                        .and_then(|status| output::print_json(&status["instances"]))
+                });
+                return Ok(());
+            }
+            // the request is sent after parsing the options
+            Some(("build", _)) => (),
+            Some((name, _)) => {
+                error!("`ciel {}` can not be run by the daemon.", name);
                 process::exit(1);
             }
         }
-        _ => (),
     }
     // list instances if no command is specified
     if subcmd.is_none() {
@@ -141,8 +325,39 @@ fn main() -> Result<()> {
     let subcmd = subcmd.unwrap();
     // Switch table
     match subcmd {
-        ("farewell", _) => {
-            actions::farewell(&directory).unwrap();
+        ("farewell", args) => {
+            if let Some(id) = args.get_one::<String>("restore") {
+                let id = Some(id.as_str()).filter(|id| *id != "latest");
+                print_error!({
+                    trash::restore(&directory, id).map(|entry| {
+                        info!("Workspace restored from {}.", entry.path.display());
+                    })
+                });
+                return Ok(());
+            }
+            if let Some(id) = args.get_one::<String>("purge") {
+                let id = Some(id.as_str()).filter(|id| *id != "expired");
+                let dry_run = args.get_flag("dry-run");
+                print_error!({
+                    actions::purge_trash_plan(&directory, id).and_then(|plan| {
+                        if dry_run {
+                            plan.print();
+                            Ok(())
+                        } else {
+                            plan.execute()
+                        }
+                    })
+                });
+                return Ok(());
+            }
+            let permanent = args.get_flag("permanent");
+            if args.get_flag("dry-run") {
+                print_error!({
+                    actions::farewell_plan(&directory, permanent).map(|plan| plan.print())
+                });
+                return Ok(());
+            }
+            actions::farewell(&directory, permanent).unwrap();
         }
         ("init", args) => {
             if args.get_flag("upgrade") {
@@ -158,23 +373,52 @@ fn main() -> Result<()> {
         }
         ("load-tree", args) => {
             info!("Cloning abbs tree...");
-            network::download_git(args.get_one::<String>("url").unwrap(), Path::new("TREE"))?;
+            let config = config::read_config().ok();
+            let url = match args.get_one::<String>("url") {
+                Some(url) => url.as_str(),
+                None => config
+                    .as_ref()
+                    .map(tree::tree_url)
+                    .unwrap_or(cli::GIT_TREE_URL),
+            };
+            let mut options = clone_options(args);
+            if options.branch.is_none() {
+                options.branch = config.as_ref().and_then(|c| c.tree.branch.clone());
+            }
+            print_error!({ network::clone_repo(url, Path::new("TREE"), &options) });
         }
         ("update-tree", args) => {
             let tree = Path::new("TREE");
             info!("Updating tree...");
-            print_error!({ update_tree(tree, args.get_one("branch"), args.get_one("rebase")) });
+            print_error!({
+                update_tree(
+                    tree,
+                    args.get_one("branch"),
+                    args.get_one("rebase"),
+                    args.get_flag("fetch-only"),
+                )
+            });
         }
         ("load-os", args) => {
+            if let Some(image) = args.get_one::<String>("image") {
+                print_error!({ image::import_image(&user_path(image)) });
+                info!("Image imported.");
+                return Ok(());
+            }
+            let segments = *args.get_one::<usize>("segments").unwrap();
+            let verify = !args.get_flag("no-verify");
+            if args.get_flag("p2p") {
+                network::set_p2p();
+            }
             let url = args.get_one::<String>("url");
             if let Some(url) = url {
                 // load from network using specified url
                 if url.starts_with("https://") || url.starts_with("http://") {
-                    print_error!({ actions::load_os(url, None) });
+                    print_error!({ actions::load_os(&[url.clone()], None, segments, verify) });
                     return Ok(());
                 }
                 // load from file
-                let tarball = Path::new(url);
+                let tarball = &user_path(url);
                 if !tarball.is_file() {
                     error!("{:?} is not a file", url);
                     process::exit(1);
@@ -200,8 +444,36 @@ fn main() -> Result<()> {
                 ask_for_target_arch().unwrap()
             };
             info!("No URL specified. Ciel will automatically pick one.");
+            if network::is_offline() {
+                let cached = cache::latest_for_arch(arch, verify);
+                if let Err(e) = cached {
+                    error!("{}", e);
+                    process::exit(1);
+                }
+                let cached = cached.unwrap();
+                info!(
+                    "Working offline, using cached tarball {}",
+                    cached.metadata.name
+                );
+                print_error!({
+                    actions::load_os(
+                        &[cached.path.to_string_lossy().to_string()],
+                        Some(cached.sha256),
+                        segments,
+                        verify,
+                    )
+                });
+                return Ok(());
+            }
             info!("Picking OS tarball for architecture {}", arch);
-            let tarball = network::pick_latest_tarball(arch);
+            let configured = config::read_config()
+                .map(|c| c.release_mirrors)
+                .unwrap_or_default();
+            let mirrors = network::select_mirrors(
+                args.get_one::<String>("mirror").map(|m| m.as_str()),
+                &configured,
+            );
+            let tarball = network::pick_latest_tarball(arch, &mirrors);
             if let Err(e) = tarball {
                 error!("Unable to determine the latest tarball: {}", e);
                 process::exit(1);
@@ -209,15 +481,75 @@ fn main() -> Result<()> {
             let tarball = tarball.unwrap();
             print_error!({
                 actions::load_os(
-                    &format!("https://releases.aosc.io/{}", tarball.path),
+                    &network::tarball_urls(&tarball, &mirrors),
                     Some(tarball.sha256sum),
+                    segments,
+                    verify,
                 )
             });
         }
         ("update-os", _) => {
             print_error!({ actions::update_os() });
         }
+        ("update-all", args) => {
+            print_error!({ actions::update_all(args.get_flag("keep-modified")) });
+        }
         ("config", args) => {
+            match args.subcommand() {
+                Some(("migrate", _)) => {
+                    print_error!({ config::migrate_config_file() });
+                    return Ok(());
+                }
+                Some(("lint", _)) => {
+                    print_error!({ config::lint_config() });
+                    return Ok(());
+                }
+                Some(("show", _)) => {
+                    print_error!({ config::show_config() });
+                    return Ok(());
+                }
+                Some(("set", args)) => {
+                    let assignments = args
+                        .get_many::<String>("ASSIGNMENTS")
+                        .unwrap()
+                        .collect::<Vec<_>>();
+                    print_error!({ actions::set_config(&assignments) });
+                    return Ok(());
+                }
+                Some(("repo", args)) => {
+                    match args.subcommand() {
+                        Some(("add", args)) => {
+                            let name = args.get_one::<String>("NAME").unwrap();
+                            let repo = config::AptRepository {
+                                url: args.get_one::<String>("URL").unwrap().clone(),
+                                suite: args.get_one::<String>("SUITE").unwrap().clone(),
+                                components: args
+                                    .get_many::<String>("COMPONENTS")
+                                    .map(|c| c.cloned().collect())
+                                    .unwrap_or_default(),
+                                pin_priority: args.get_one::<i32>("priority").copied(),
+                                signed_by: args.get_one::<String>("key").cloned(),
+                            };
+                            print_error!({ actions::add_apt_repository(name, repo) });
+                        }
+                        Some(("remove", args)) => {
+                            let name = args.get_one::<String>("NAME").unwrap();
+                            print_error!({ actions::remove_apt_repository(name) });
+                        }
+                        Some(("list", _)) => {
+                            print_error!({ actions::list_apt_repositories() });
+                        }
+                        _ => unreachable!(),
+                    }
+                    return Ok(());
+                }
+                Some(("use-profile", args)) => {
+                    let name = args.get_one::<String>("PROFILE").unwrap();
+                    print_error!({ actions::use_profile(name) });
+                    return Ok(());
+                }
+                _ => (),
+            }
             if args.get_flag("g") {
                 print_error!({ actions::config_os(None) });
                 return Ok(());
@@ -236,30 +568,110 @@ fn main() -> Result<()> {
                 val.as_str()
             });
             let tarball = args.get_one::<String>("tarball");
-            if let Err(e) = actions::onboarding(tarball, arch) {
-                error!("{}", e);
-                process::exit(1);
+            if args.get_flag("p2p") {
+                network::set_p2p();
+            }
+            // boolean flags only override the defaults when specified
+            let flag = |name: &str, value: bool| args.get_flag(name).then_some(value);
+            let mut options = actions::OnboardingOptions {
+                non_interactive: args.get_flag("non-interactive"),
+                config: config::ConfigOverrides {
+                    maintainer: args.get_one::<String>("maintainer").cloned(),
+                    dnssec: flag("dnssec", true),
+                    apt_sources: args
+                        .get_many::<String>("apt-source")
+                        .map(|sources| sources.cloned().collect::<Vec<_>>().join("\n")),
+                    local_repo: flag("no-local-repo", false),
+                    local_sources: flag("no-local-sources", false),
+                    sep_mount: flag("no-branch-output", false),
+                    volatile_mount: flag("volatile-mount", true),
+                    proxy: args.get_one::<String>("proxy").cloned(),
+                },
+                profile: args.get_one::<String>("profile").cloned(),
+                template: args.get_one::<String>("template").cloned(),
+                trust_template: args.get_flag("trust"),
+                branch: args.get_one::<String>("branch").cloned(),
+                instance: args.get_one::<String>("instance").cloned(),
+                mirror: args.get_one::<String>("mirror").cloned(),
+                no_verify: args.get_flag("no-verify"),
+                tree: clone_options(args),
+            };
+            print_error!({
+                options
+                    .config
+                    .apply_env_overrides(|name| std::env::var(name).ok())
+            });
+            print_error!({ actions::onboarding(tarball, arch, &options) });
+        }
+        ("run", args) if args.get_flag("ephemeral") => {
+            let command = args
+                .get_many::<String>("COMMANDS")
+                .unwrap()
+                .collect::<Vec<_>>();
+            let collect = args
+                .get_many::<String>("collect")
+                .map(|paths| paths.collect::<Vec<_>>())
+                .unwrap_or_default();
+            let status = actions::with_ephemeral_instance(&Default::default(), |instance| {
+                let status = actions::run_in_container(instance, &command)?;
+                // the artifacts (e.g. the logs) are collected even if the command failed
+                let dest = user_path(".").to_string_lossy().to_string();
+                for path in collect {
+                    if let Err(e) = transfer::copy(&format!("{}:{}", instance, path), &dest) {
+                        warn!("Unable to collect {}: {}", path, e);
+                    }
+                }
+                Ok(status)
+            });
+            match status {
+                Ok(status) => process::exit(status),
+                Err(e) => {
+                    error!("{:?}", e);
+                    process::exit(errors::exit_code(&e));
+                }
             }
         }
         ("run", args) => {
             let instance = get_instance_option(args)?;
+            let autostart = !args.get_flag("no-autostart");
             let args = args.get_many::<String>("COMMANDS").unwrap();
-            let status =
-                actions::run_in_container(&instance, &args.into_iter().collect::<Vec<_>>())?;
+            let status = actions::exec_in_container(
+                &instance,
+                &args.into_iter().collect::<Vec<_>>(),
+                autostart,
+            )?;
             process::exit(status);
         }
+        ("cp", args) => {
+            let src = args.get_one::<String>("SRC").unwrap();
+            let dst = args.get_one::<String>("DST").unwrap();
+            print_error!({ transfer::copy(src, dst) });
+        }
         ("shell", args) => {
             let instance = get_instance_option(args)?;
+            let autostart = !args.get_flag("no-autostart");
             if let Some(cmd) = args.get_many::<String>("COMMANDS") {
                 let command = cmd
                     .into_iter()
                     .fold(String::with_capacity(1024), |acc, x| acc + " " + x);
-                let status = actions::run_in_container(&instance, &["/bin/bash", "-ec", &command])?;
+                let status = actions::exec_in_container(
+                    &instance,
+                    &["/bin/bash", "-ec", &command],
+                    autostart,
+                )?;
                 process::exit(status);
             }
-            let status = actions::run_in_container(&instance, &["/bin/bash"])?;
+            let status = actions::exec_in_container(&instance, &["/bin/bash"], autostart)?;
             process::exit(status);
         }
+        ("use", args) => {
+            let instance = args.get_one::<String>("INSTANCE").map(|s| s.as_str());
+            if instance.is_none() && !args.get_flag("unset") {
+                actions::show_default_instance();
+                return Ok(());
+            }
+            print_error!({ actions::use_instance(instance) });
+        }
         ("stop", args) => {
             let instance = get_instance_option(args)?;
             print_error!({ actions::stop_container(&instance) });
@@ -267,64 +679,338 @@ fn main() -> Result<()> {
         ("down", args) => {
             print_error!({ one_or_all_instance!(args, &actions::container_down) });
         }
+        ("autostop", args) => {
+            let idle = match args.get_one::<String>("idle") {
+                Some(duration) => match parse_duration(duration) {
+                    Some(idle) => idle,
+                    None => {
+                        error!("Invalid duration: {}", duration);
+                        process::exit(1);
+                    }
+                },
+                None => match config::read_config().ok().and_then(|c| c.auto_stop()) {
+                    Some(idle) => idle,
+                    None => {
+                        error!("Please specify --idle or set `auto-stop` in the configuration.");
+                        process::exit(1);
+                    }
+                },
+            };
+            print_error!({
+                actions::autostop(idle, args.get_flag("dry-run")).map(|stopped| {
+                    if stopped.is_empty() {
+                        info!("No instances have been idle for that long.");
+                    }
+                })
+            });
+        }
         ("commit", args) => {
             let instance = get_instance_option(args)?;
+            if args.get_flag("dry-run") {
+                print_error!({ actions::commit_plan(&instance).map(|plan| plan.print()) });
+                return Ok(());
+            }
             print_error!({ actions::commit_container(&instance) });
         }
         ("rollback", args) => {
-            print_error!({ one_or_all_instance!(args, &actions::rollback_container) });
+            let keep = args
+                .get_many::<String>("keep")
+                .map(|k| k.cloned().collect::<Vec<_>>())
+                .unwrap_or_default();
+            if args.get_flag("dry-run") {
+                print_error!({
+                    one_or_all_instance!(args, &|instance: &str| {
+                        actions::rollback_plan(instance, &keep).map(|plan| plan.print())
+                    })
+                });
+                return Ok(());
+            }
+            print_error!({
+                one_or_all_instance!(args, &|instance: &str| {
+                    actions::rollback_keeping(instance, &keep)
+                })
+            });
         }
         ("del", args) => {
             let instance = args.get_one::<String>("INSTANCE").unwrap();
+            if args.get_flag("dry-run") {
+                print_error!({ actions::remove_plan(instance).map(|plan| plan.print()) });
+                return Ok(());
+            }
             print_error!({ actions::remove_instance(instance) });
         }
         ("add", args) => {
             let instance = args.get_one::<String>("INSTANCE").unwrap();
-            print_error!({ actions::add_instance(instance) });
+            let tmpfs_size = args.get_one::<String>("tmpfs").cloned();
+            if let Some(size) = &tmpfs_size {
+                if !config::validate_tmpfs_size(size) {
+                    error!("Invalid tmpfs size: {}", size);
+                    process::exit(1);
+                }
+            }
+            let quota = args.get_one::<String>("quota").cloned();
+            if let Some(size) = &quota {
+                if parse_size(size).is_none() {
+                    error!("Invalid quota size: {}", size);
+                    process::exit(1);
+                }
+            }
+            let inst_config = config::InstanceConfig {
+                tmpfs_size,
+                quota,
+                bootstrap: args.get_flag("bootstrap"),
+                seccomp: config::SeccompConfig {
+                    preset: args.get_one::<String>("seccomp").cloned(),
+                    ..Default::default()
+                },
+                capabilities: config::CapabilityConfig {
+                    preset: args.get_one::<String>("capabilities").cloned(),
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            print_error!({ actions::add_instance(instance, &inst_config) });
+            if !args.get_flag("no-provision") {
+                print_error!({ actions::provision_instance(instance) });
+            }
         }
         ("build", args) => {
-            let instance = get_instance_option(args)?;
-            let settings = BuildSettings {
-                offline: args.get_flag("OFFLINE"),
-                stage2: args.get_flag("STAGE2"),
+            if args.get_flag("OFFLINE") {
+                network::set_offline();
+            }
+            let ephemeral = args.get_flag("EPHEMERAL");
+            let mut request = build::BuildRequest {
+                // the temporary instance is created right before the build
+                instance: if ephemeral {
+                    String::new()
+                } else {
+                    get_instance_option(args)?
+                },
+                offline: network::is_offline(),
+                stage2: args.get_flag("STAGE2")
+                    || args.get_one::<String>("STAGE").map(|s| s.as_str()) == Some("2"),
+                skip_built: args.get_flag("SKIP_BUILT"),
+                phase: if args.get_flag("FETCH") {
+                    build::BuildPhase::Fetch
+                } else if args.get_flag("UNPACK") {
+                    build::BuildPhase::Unpack
+                } else {
+                    build::BuildPhase::Build
+                },
+                with_deps: args.get_flag("WITH_DEPS"),
+                skip_preflight: args.get_flag("NO_PREFLIGHT"),
+                block_network: args.get_flag("BLOCK_NETWORK"),
+                check_reproducible: args.get_flag("CHECK_REPRODUCIBLE"),
+                retry: args.get_one::<usize>("RETRY").copied().unwrap_or(0),
+                reset_on_retry: args.get_flag("RESET_ON_RETRY"),
+                on_fail: match args.get_one::<String>("ON_FAIL").map(|s| s.as_str()) {
+                    Some("shell") => build::FailureAction::Shell,
+                    Some("stop") => build::FailureAction::Stop,
+                    _ => build::FailureAction::Ask,
+                },
+                priority: args
+                    .get_one::<String>("PRIORITY")
+                    .and_then(|p| config::BuildPriority::parse(p).ok()),
+                ..Default::default()
             };
-            let mut state = None;
+            for (arg, timeout) in [
+                ("PACKAGE_TIMEOUT", &mut request.package_timeout),
+                ("TIMEOUT", &mut request.timeout),
+            ] {
+                if let Some(duration) = args.get_one::<String>(arg) {
+                    match parse_duration(duration) {
+                        Some(seconds) => *timeout = Some(seconds),
+                        None => {
+                            error!("Invalid duration: {}", duration);
+                            process::exit(1);
+                        }
+                    }
+                }
+            }
             if let Some(cont) = args.get_one::<String>("CONTINUE") {
-                state = Some(actions::load_build_checkpoint(cont)?);
-                let empty: Vec<&str> = Vec::new();
-                let status = actions::package_build(&instance, empty.into_iter(), state, settings)?;
-                println!("\x07"); // bell character
-                process::exit(status);
+                let mut state = actions::load_build_checkpoint(cont)?;
+                if args.get_flag("SKIP_FAILED") {
+                    state.skip_failed();
+                }
+                let result = build::execute(&request, Some(state))?;
+                finish_build(&result, true, args.get_one::<String>("REPORT"));
             }
             let packages = args.get_many::<String>("PACKAGES");
-            if packages.is_none() {
+            if packages.is_none() && !args.contains_id("CHANGED") {
                 error!("Please specify a list of packages to build!");
                 process::exit(1);
             }
-            let packages = packages.unwrap();
+            if let Some(packages) = packages {
+                request.packages = actions::expand_package_list(packages);
+                if args.get_flag("DEDUP") {
+                    request.packages = actions::dedup_packages(request.packages);
+                }
+            }
+            if args.contains_id("CHANGED") {
+                let since = args.get_one::<String>("CHANGED").map(|s| s.as_str());
+                let changed = match tree::changed_packages(since) {
+                    Ok(changed) => changed,
+                    Err(e) => {
+                        error!("{:?}", e);
+                        process::exit(errors::exit_code(&e));
+                    }
+                };
+                if changed.is_empty() {
+                    info!("No packages have changed in the tree.");
+                    process::exit(0);
+                }
+                info!("Packages changed in the tree: {}", changed.join(" "));
+                for package in &changed {
+                    if !request.packages.contains(package) {
+                        request.packages.push(package.clone());
+                    }
+                }
+                request.changed = changed;
+            }
+            if args.get_flag("via-daemon") {
+                build_via_daemon(&request);
+            }
             if args.contains_id("SELECT") {
                 let start_package = args.get_one::<String>("SELECT");
-                let status =
-                    actions::packages_stage_select(&instance, packages, settings, start_package)?;
-                process::exit(status);
+                let result = actions::packages_stage_select(&request, start_package)?;
+                finish_build(&result, false, args.get_one::<String>("REPORT"));
             }
-            if args.get_flag("FETCH") {
-                let packages = packages.into_iter().collect::<Vec<_>>();
-                let status = actions::package_fetch(&instance, &packages)?;
-                process::exit(status);
-            }
-            let status = actions::package_build(&instance, packages, state, settings)?;
-            println!("\x07"); // bell character
-            process::exit(status);
+            let result = if ephemeral {
+                // the stage 2 builds are refused outside of the bootstrap instances
+                let inst_config = config::InstanceConfig {
+                    bootstrap: request.stage2,
+                    ..Default::default()
+                };
+                actions::with_ephemeral_instance(&inst_config, |instance| {
+                    request.instance = instance.to_owned();
+                    build::execute(&request, None)
+                })?
+            } else {
+                build::execute(&request, None)?
+            };
+            finish_build(
+                &result,
+                request.phase == build::BuildPhase::Build,
+                args.get_one::<String>("REPORT"),
+            );
         }
         ("", _) => {
             machine::print_instances()?;
         }
+        ("list", args) if args.get_flag("watch") => {
+            let interval = *args.get_one::<u64>("interval").unwrap();
+            print_error!({
+                machine::print_instances_watch(std::time::Duration::from_secs(interval.max(1)))
+            });
+        }
         ("list", _) => {
             machine::print_instances()?;
         }
-        ("doctor", _) => {
+        ("schedule", args) => match args.subcommand() {
+            Some(("add", args)) => {
+                let packages = args
+                    .get_many::<String>("PACKAGES")
+                    .map(|p| p.cloned().collect())
+                    .unwrap_or_default();
+                print_error!({
+                    schedule::Job::parse(
+                        args.get_one::<String>("JOB").unwrap(),
+                        args.get_one::<String>("INSTANCE").cloned(),
+                        packages,
+                    )
+                    .and_then(|job| {
+                        schedule::add(
+                            &directory,
+                            &job,
+                            args.get_one::<String>("name").map(|s| s.as_str()),
+                            args.get_one::<String>("on-calendar").map(|s| s.as_str()),
+                        )
+                    })
+                    .map(|timer| {
+                        info!("Job scheduled, see `systemctl list-timers {}`.", timer);
+                    })
+                });
+            }
+            Some(("remove", args)) => {
+                print_error!({
+                    schedule::remove(&directory, args.get_one::<String>("NAME").unwrap())
+                });
+            }
+            Some(("list", _)) => {
+                print_error!({ schedule::list(&directory) });
+            }
+            _ => unreachable!(),
+        },
+        ("backup", args) => {
+            let path = user_path(args.get_one::<String>("FILE").unwrap());
+            print_error!({ backup::backup(&path, args.get_flag("layers")) });
+            info!("Workspace backed up into {}.", path.display());
+        }
+        ("restore", args) => {
+            let path = user_path(args.get_one::<String>("FILE").unwrap());
+            print_error!({ backup::restore(&path) });
+            info!("Workspace restored at {}.", directory.display());
+            info!("Please load the base system with `ciel load-os`.");
+        }
+        ("bump", args) => {
+            let package = args.get_one::<String>("PACKAGE").unwrap();
+            let bump = match args.get_one::<String>("version") {
+                Some(version) => bump::Bump::Version(version.clone()),
+                None => bump::Bump::Release,
+            };
+            let bumped = config::read_config().and_then(|c| {
+                bump::bump_package(
+                    &c,
+                    package,
+                    &bump,
+                    args.get_one::<String>("message").map(|s| s.as_str()),
+                    !args.get_flag("no-commit"),
+                )
+            });
+            match bumped {
+                Ok(bumped) if bumped.release > 0 => info!(
+                    "{} bumped to {}-{}.",
+                    bumped.package, bumped.version, bumped.release
+                ),
+                Ok(bumped) => info!("{} bumped to {}.", bumped.package, bumped.version),
+                Err(e) => {
+                    error!("{:?}", e);
+                    process::exit(errors::exit_code(&e));
+                }
+            }
+            if args.get_flag("build") {
+                let request = build::BuildRequest {
+                    instance: get_instance_option(args)?,
+                    packages: vec![package.clone()],
+                    ..Default::default()
+                };
+                let result = build::execute(&request, None)?;
+                finish_build(&result, true, None);
+            }
+        }
+        ("topic", args) => {
+            if args.get_flag("finish") {
+                print_error!({ actions::topic_finish() });
+            } else if let Some(branch) = args.get_one::<String>("BRANCH") {
+                print_error!({ actions::topic_start(branch) });
+            } else {
+                print_error!({ actions::topic_status() });
+            }
+        }
+        ("status", _) => {
+            print_error!({ actions::workspace_status() });
+        }
+        ("du", _) => {
+            print_error!({ actions::disk_usage() });
+        }
+        ("doctor", args) if output::json_output() => {
+            print_error!({ diagnose::report_json(args.get_flag("mounts")) });
+        }
+        ("doctor", args) => {
             print_error!({ diagnose::run_diagnose() });
+            if args.get_flag("mounts") {
+                print_error!({ diagnose::diagnose_mounts() });
+            }
         }
         ("repo", args) => match args.subcommand() {
             Some(("refresh", _)) => {
@@ -350,10 +1036,210 @@ fn main() -> Result<()> {
                 print_error!({ repo::deinit_repo(&cwd.join(instance)) });
                 info!("Repository has been disabled.");
             }
+            Some(("prune", args)) => {
+                let policy = repo::prune::PrunePolicy {
+                    keep_last: args.get_one::<usize>("keep").copied(),
+                    max_age: args
+                        .get_one::<u64>("max-age")
+                        .map(|days| std::time::Duration::from_secs(days * 86400)),
+                    superseded_noarch: args.get_flag("superseded-noarch"),
+                };
+                if policy.keep_last.is_none()
+                    && policy.max_age.is_none()
+                    && !policy.superseded_noarch
+                {
+                    error!(
+                        "Please specify at least one of --keep, --max-age or --superseded-noarch."
+                    );
+                    process::exit(1);
+                }
+                let dry_run = args.get_flag("dry-run");
+                let root = std::env::current_dir().unwrap().join(get_output_dir());
+                print_error!({
+                    repo::prune::prune_repo(&root, &policy, dry_run).and_then(|removed| {
+                        if removed > 0 && !dry_run {
+                            info!("Refreshing repository...");
+                            repo::refresh_repo(&root)?;
+                        }
+                        Ok(())
+                    })
+                });
+            }
+            Some(("push", args)) => {
+                let target = args.get_one::<String>("TARGET").unwrap();
+                let root = std::env::current_dir().unwrap().join(get_output_dir());
+                print_error!({ repo::push::push_repo(&root, target, args.get_flag("dry-run")) });
+            }
+            Some(("list", _)) => {
+                let root = std::env::current_dir().unwrap().join(get_output_dir());
+                print_error!({ repo::query::list_packages(&root) });
+            }
+            Some(("show", args)) => {
+                let package = args.get_one::<String>("PACKAGE").unwrap();
+                let root = std::env::current_dir().unwrap().join(get_output_dir());
+                print_error!({ repo::query::show_package(&root, package) });
+            }
+            Some(("dedupe", args)) => {
+                print_error!({
+                    repo::dedupe::output_directories()
+                        .and_then(|dirs| repo::dedupe::dedupe(&dirs, args.get_flag("dry-run")))
+                });
+            }
+            Some(("diff", args)) => {
+                let package = args.get_one::<String>("PACKAGE").unwrap();
+                let root = std::env::current_dir().unwrap().join(get_output_dir());
+                print_error!({ repo::diff::diff_package(&root, package) });
+            }
+            Some(("key", args)) => match args.subcommand() {
+                Some(("generate", _)) => {
+                    info!("Generating signing key...");
+                    print_error!({ repo::sign::generate_key() });
+                    print_error!({
+                        repo::refresh_repo(&std::env::current_dir().unwrap().join(get_output_dir()))
+                    });
+                    info!("Signing key generated, please re-initialize the repository in the instances.");
+                }
+                Some(("remove", _)) => {
+                    print_error!({ repo::sign::remove_key() });
+                    print_error!({
+                        repo::refresh_repo(&std::env::current_dir().unwrap().join(get_output_dir()))
+                    });
+                    info!("Signing key removed.");
+                }
+                Some(("export", _)) => {
+                    print_error!({ repo::sign::export_key() });
+                }
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        },
+        ("workspace", args) => match args.subcommand() {
+            Some(("relocate", _)) => {
+                print_error!({ actions::relocate_workspace() });
+            }
+            _ => unreachable!(),
+        },
+        ("dist", args) => match args.subcommand() {
+            Some(("unlock", _)) => {
+                print_error!({ dist::begin_maintenance() });
+            }
+            Some(("lock", _)) => {
+                print_error!({ dist::end_maintenance() });
+            }
             _ => unreachable!(),
         },
-        ("clean", _) => {
-            print_error!({ actions::cleanup_outputs() });
+        ("secret", args) => match args.subcommand() {
+            Some(("set", args)) => {
+                let name = args.get_one::<String>("NAME").unwrap();
+                let value = match args.get_one::<String>("VALUE") {
+                    Some(value) => value.clone(),
+                    None => read_secret_value()?,
+                };
+                print_error!({ secrets::set_secret(name, &value) });
+                info!("Secret `{}` saved.", name);
+            }
+            Some(("remove", args)) => {
+                let name = args.get_one::<String>("NAME").unwrap();
+                print_error!({ secrets::remove_secret(name) });
+                info!("Secret `{}` removed.", name);
+            }
+            Some(("list", _)) => {
+                for name in secrets::list_secrets()? {
+                    println!("{}", name);
+                }
+            }
+            _ => unreachable!(),
+        },
+        ("tree", args) => match args.subcommand() {
+            Some(("add-remote", args)) => {
+                print_error!({
+                    tree::add_remote(
+                        args.get_one::<String>("NAME").unwrap(),
+                        args.get_one::<String>("URL").unwrap(),
+                        args.get_one::<String>("branch").map(|b| b.as_str()),
+                        *args.get_one::<i32>("priority").unwrap(),
+                    )
+                });
+            }
+            Some(("remove-remote", args)) => {
+                print_error!({ tree::remove_remote(args.get_one::<String>("NAME").unwrap()) });
+            }
+            Some(("list-remotes", _)) => print_error!({ tree::list_remotes() }),
+            _ => unreachable!(),
+        },
+        ("cache", args) => match args.subcommand() {
+            Some(("list", _)) => print_error!({ cache::show_cache() }),
+            Some(("prune", args)) => {
+                let max_age = args
+                    .get_one::<u64>("max-age")
+                    .map(|days| std::time::Duration::from_secs(days * 86400));
+                let max_size = match args.get_one::<String>("max-size") {
+                    Some(size) => match common::parse_size(size) {
+                        Some(size) => Some(size),
+                        None => {
+                            error!("Invalid size: {}", size);
+                            process::exit(1);
+                        }
+                    },
+                    None => None,
+                };
+                print_error!({ cache::prune(max_age, max_size) });
+            }
+            Some(("clear", _)) => print_error!({ cache::clear() }),
+            _ => unreachable!(),
+        },
+        ("apply", args) => {
+            print_error!({
+                actions::apply_manifest(args.get_flag("yes"), args.get_flag("dry-run"))
+            });
+        }
+        ("gc", args) => {
+            print_error!({ actions::collect_garbage(args.get_flag("dry-run")) });
+        }
+        ("qa", args) => match args.subcommand() {
+            Some(("conflicts", args)) => {
+                let root = std::env::current_dir().unwrap().join(get_output_dir());
+                print_error!({
+                    repo::qa::report_conflicts(
+                        &root,
+                        args.get_one::<String>("remote").map(|s| s.as_str()),
+                    )
+                });
+            }
+            _ => unreachable!(),
+        },
+        ("daemon", args) => {
+            print_error!({ daemon::serve(args.get_one::<String>("metrics").map(|s| s.as_str())) });
+        }
+        ("stats", args) => {
+            print_error!({ stats::report(*args.get_one::<usize>("top").unwrap()) });
+        }
+        ("log", args) => match args.get_one::<String>("build") {
+            Some(package) => print_error!({ buildlog::show_latest(package) }),
+            None => print_error!({ buildlog::list_logs() }),
+        },
+        ("clean", args) => {
+            let dry_run = args.get_flag("dry-run");
+            if args.get_flag("sources") {
+                print_error!({ sources::clean(dry_run) });
+                return Ok(());
+            }
+            if dry_run {
+                print_error!({ actions::cleanup_plan().map(|plan| plan.print()) });
+            } else {
+                print_error!({ actions::cleanup_outputs() });
+            }
+            if args.get_flag("instances") {
+                if dry_run {
+                    print_error!({
+                        actions::for_each_instance(&|instance: &str| {
+                            actions::prune_plan(instance).map(|plan| plan.print())
+                        })
+                    });
+                } else {
+                    print_error!({ actions::for_each_instance(&actions::prune_instance) });
+                }
+            }
         }
         ("version", _) => {
             println!("{}", version_string);
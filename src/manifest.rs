@@ -0,0 +1,88 @@
+//! This module contains the declarative workspace manifest (`ciel.toml`) related APIs
+
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use std::{collections::BTreeMap, fmt, fs, path::Path};
+
+use crate::config::InstanceConfig;
+
+pub const MANIFEST_LOCATION: &str = "ciel.toml";
+
+/// Desired state of the workspace
+#[derive(Debug, Default, Deserialize)]
+pub struct Manifest {
+    #[serde(default)]
+    pub instances: BTreeMap<String, InstanceConfig>,
+}
+
+impl Manifest {
+    pub fn load(path: &Path) -> Result<Manifest> {
+        let data = fs::read_to_string(path)
+            .map_err(|e| anyhow!("Unable to read {}: {}", path.display(), e))?;
+
+        toml::from_str(&data).map_err(|e| anyhow!("Invalid manifest {}: {}", path.display(), e))
+    }
+}
+
+/// A change needed for the workspace to match the manifest
+#[derive(Debug, PartialEq, Eq)]
+pub enum Change {
+    Create(String, InstanceConfig),
+    Update(String, InstanceConfig),
+    Remove(String),
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Change::Create(name, _) => write!(f, "create instance {}", name),
+            Change::Update(name, _) => write!(f, "update instance {}", name),
+            Change::Remove(name) => write!(f, "remove instance {}", name),
+        }
+    }
+}
+
+/// Compute the changes needed to turn the existing instances into the ones in the manifest
+pub fn plan(manifest: &Manifest, existing: &BTreeMap<String, InstanceConfig>) -> Vec<Change> {
+    let mut changes = Vec::new();
+    for (name, desired) in manifest.instances.iter() {
+        match existing.get(name) {
+            None => changes.push(Change::Create(name.clone(), desired.clone())),
+            Some(current) if current != desired => {
+                changes.push(Change::Update(name.clone(), desired.clone()))
+            }
+            _ => (),
+        }
+    }
+    for name in existing.keys() {
+        if !manifest.instances.contains_key(name) {
+            changes.push(Change::Remove(name.clone()));
+        }
+    }
+
+    changes
+}
+
+#[test]
+fn test_plan() {
+    let manifest: Manifest = toml::from_str(
+        "[instances.main]\nquota = \"20G\"\nmounts = [\"/srv:/srv\"]\n[instances.new]\n",
+    )
+    .unwrap();
+    let mut existing = BTreeMap::new();
+    existing.insert("main".to_owned(), InstanceConfig::default());
+    existing.insert("old".to_owned(), InstanceConfig::default());
+    let changes = plan(&manifest, &existing);
+    assert_eq!(
+        changes,
+        vec![
+            Change::Update("main".to_owned(), manifest.instances["main"].clone()),
+            Change::Create("new".to_owned(), InstanceConfig::default()),
+            Change::Remove("old".to_owned()),
+        ]
+    );
+    existing.insert("main".to_owned(), manifest.instances["main"].clone());
+    existing.remove("old");
+    existing.insert("new".to_owned(), InstanceConfig::default());
+    assert!(plan(&manifest, &existing).is_empty());
+}
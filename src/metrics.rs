@@ -0,0 +1,167 @@
+//! This module contains the Prometheus metrics of the daemon
+
+use anyhow::Result;
+use console::style;
+use fs3::statvfs;
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{daemon, machine, stats, warn};
+
+/// A metric with its samples (the suffix and the labels of the series, and the value)
+struct Metric {
+    name: &'static str,
+    kind: &'static str,
+    help: &'static str,
+    samples: Vec<(String, f64)>,
+}
+
+impl Metric {
+    fn new(name: &'static str, kind: &'static str, help: &'static str, value: f64) -> Self {
+        Metric {
+            name,
+            kind,
+            help,
+            samples: vec![(String::new(), value)],
+        }
+    }
+}
+
+/// Render the metrics in the Prometheus text format
+fn render(metrics: &[Metric]) -> String {
+    let mut text = String::new();
+    for metric in metrics {
+        text.push_str(&format!("# HELP {} {}\n", metric.name, metric.help));
+        text.push_str(&format!("# TYPE {} {}\n", metric.name, metric.kind));
+        for (series, value) in metric.samples.iter() {
+            text.push_str(&format!("{}{} {}\n", metric.name, series, value));
+        }
+    }
+
+    text
+}
+
+fn collect() -> Vec<Metric> {
+    let mut metrics = Vec::new();
+    if let Ok(instances) = machine::list_instances() {
+        metrics.push(Metric {
+            name: "ciel_instances",
+            kind: "gauge",
+            help: "Number of the instances in the workspace, by their states",
+            samples: vec![
+                ("{state=\"total\"}".to_owned(), instances.len() as f64),
+                (
+                    "{state=\"mounted\"}".to_owned(),
+                    instances.iter().filter(|i| i.mounted).count() as f64,
+                ),
+                (
+                    "{state=\"running\"}".to_owned(),
+                    instances.iter().filter(|i| i.running).count() as f64,
+                ),
+            ],
+        });
+    }
+    let building = daemon::current_operation().map_or(false, |op| op.starts_with("build "));
+    metrics.push(Metric::new(
+        "ciel_builds_in_progress",
+        "gauge",
+        "Number of the builds running in the daemon",
+        if building { 1.0 } else { 0.0 },
+    ));
+    let totals = stats::totals();
+    metrics.push(Metric {
+        name: "ciel_package_builds_total",
+        kind: "counter",
+        help: "Number of the package builds recorded, by their results",
+        samples: vec![
+            ("{result=\"success\"}".to_owned(), totals.succeeded as f64),
+            ("{result=\"failure\"}".to_owned(), totals.failed as f64),
+        ],
+    });
+    metrics.push(Metric {
+        name: "ciel_package_build_duration_seconds",
+        kind: "summary",
+        help: "Wall time of the successful package builds",
+        samples: vec![
+            ("_sum".to_owned(), totals.wall_time),
+            ("_count".to_owned(), totals.succeeded as f64),
+        ],
+    });
+    if let Ok(stats) = statvfs(".") {
+        metrics.push(Metric::new(
+            "ciel_disk_free_bytes",
+            "gauge",
+            "Free space of the filesystem of the workspace",
+            stats.available_space() as f64,
+        ));
+        metrics.push(Metric::new(
+            "ciel_disk_size_bytes",
+            "gauge",
+            "Size of the filesystem of the workspace",
+            stats.total_space() as f64,
+        ));
+    }
+
+    metrics
+}
+
+fn handle_client(mut stream: TcpStream) -> Result<()> {
+    let mut request = String::new();
+    BufReader::new(&stream).read_line(&mut request)?;
+    let path = request.split_whitespace().nth(1).unwrap_or_default();
+    let (status, body) = match (request.split_whitespace().next(), path) {
+        (Some("GET"), "/metrics") => ("200 OK", render(&collect())),
+        (Some("GET"), _) => ("404 Not Found", "Not Found\n".to_owned()),
+        _ => ("405 Method Not Allowed", "Method Not Allowed\n".to_owned()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+
+    Ok(())
+}
+
+/// Serve the metrics at the address (e.g. `127.0.0.1:9100`), until killed
+pub fn serve(address: &str) -> Result<()> {
+    let listener = TcpListener::bind(address)?;
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                std::thread::spawn(move || {
+                    if let Err(e) = handle_client(stream) {
+                        warn!("Unable to serve the metrics: {}", e);
+                    }
+                });
+            }
+            Err(e) => warn!("Unable to accept the connection: {}", e),
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_render_metrics() {
+    let metrics = vec![
+        Metric::new("ciel_builds_in_progress", "gauge", "Builds running", 1.0),
+        Metric {
+            name: "ciel_package_builds_total",
+            kind: "counter",
+            help: "Builds recorded",
+            samples: vec![
+                ("{result=\"success\"}".to_owned(), 3.0),
+                ("{result=\"failure\"}".to_owned(), 0.0),
+            ],
+        },
+    ];
+    assert_eq!(
+        render(&metrics),
+        "# HELP ciel_builds_in_progress Builds running\n# TYPE ciel_builds_in_progress gauge\nciel_builds_in_progress 1\n# HELP ciel_package_builds_total Builds recorded\n# TYPE ciel_package_builds_total counter\nciel_package_builds_total{result=\"success\"} 3\nciel_package_builds_total{result=\"failure\"} 0\n"
+    );
+}
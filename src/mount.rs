@@ -0,0 +1,215 @@
+//! This module contains filesystem mounting APIs using the mount syscalls directly
+
+use anyhow::{anyhow, Result};
+use nix::errno::Errno;
+use nix::mount::{mount, MsFlags};
+use std::ffi::{CString, OsStr};
+use std::fs::{self, File};
+use std::io::Read;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::Path;
+
+const FSOPEN_CLOEXEC: libc::c_uint = 1;
+const FSCONFIG_SET_FLAG: libc::c_uint = 0;
+const FSCONFIG_SET_STRING: libc::c_uint = 1;
+const FSCONFIG_CMD_CREATE: libc::c_uint = 6;
+const FSMOUNT_CLOEXEC: libc::c_uint = 1;
+const MOVE_MOUNT_F_EMPTY_PATH: libc::c_uint = 4;
+
+/// A filesystem mount option, either a flag (e.g. `volatile`) or a key-value pair
+pub enum MountOption {
+    Flag(&'static str),
+    Value(&'static str, String),
+}
+
+/// Escape the path for use in the overlay layer options (`:` and `,` are separators)
+pub fn escape_option_path(path: &Path) -> String {
+    let mut escaped = String::new();
+    for c in path.to_string_lossy().chars() {
+        if c == ':' || c == ',' || c == '\\' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+/// Explain the common errors of mount(2)
+fn describe_errno(errno: Errno) -> &'static str {
+    match errno {
+        Errno::EPERM | Errno::EACCES => {
+            "permission denied (are you running as root or in a user namespace owning the mount?)"
+        }
+        Errno::ENODEV => "filesystem type not supported by the kernel",
+        Errno::ENOENT => "one of the directories does not exist",
+        Errno::ENOTDIR => "one of the paths is not a directory",
+        Errno::EBUSY => "the target is busy or already mounted",
+        Errno::EINVAL => "invalid mount options (see the kernel log for details)",
+        Errno::ELOOP => "too many levels of symbolic links",
+        Errno::ENOMEM => "out of memory",
+        _ => errno.desc(),
+    }
+}
+
+#[inline]
+fn cstring(s: &str) -> Result<CString> {
+    CString::new(s).map_err(|_| anyhow!("Invalid mount option: {}", s))
+}
+
+/// Read the messages logged by the kernel into the filesystem context
+fn context_messages(fs_fd: &mut File) -> String {
+    let mut messages = Vec::new();
+    let mut buf = [0u8; 4096];
+    // every read returns one message, ENODATA is returned when there are no more messages
+    while let Ok(n) = fs_fd.read(&mut buf) {
+        if n == 0 {
+            break;
+        }
+        let message = String::from_utf8_lossy(&buf[..n]);
+        // messages are prefixed with their severity (e, w or i)
+        messages.push(message.get(2..).unwrap_or_default().trim().to_string());
+    }
+
+    messages.join("; ")
+}
+
+fn fsconfig(
+    fs_fd: &File,
+    cmd: libc::c_uint,
+    key: Option<&CString>,
+    value: Option<&CString>,
+) -> libc::c_long {
+    let key = key.map(|k| k.as_ptr()).unwrap_or(std::ptr::null());
+    let value = value.map(|v| v.as_ptr()).unwrap_or(std::ptr::null());
+    // unsafe: fsconfig syscall
+    unsafe { libc::syscall(libc::SYS_fsconfig, fs_fd.as_raw_fd(), cmd, key, value, 0) }
+}
+
+/// Mount the filesystem using the new mount API (fsopen(2), fsconfig(2) and fsmount(2)),
+/// which reports the reason of the failures in detail. Returns Ok(false) if the kernel does not
+/// support the new mount API
+fn mount_new_api(
+    fstype: &str,
+    source: &str,
+    options: &[MountOption],
+    target: &Path,
+) -> Result<bool> {
+    let fstype_c = cstring(fstype)?;
+    // unsafe: fsopen syscall
+    let fd = unsafe { libc::syscall(libc::SYS_fsopen, fstype_c.as_ptr(), FSOPEN_CLOEXEC) };
+    if fd < 0 {
+        return match Errno::last() {
+            Errno::ENOSYS => Ok(false),
+            e => Err(anyhow!("Unable to mount {}: {}", fstype, describe_errno(e))),
+        };
+    }
+    // unsafe: the file descriptor is owned by us
+    let mut fs_fd = unsafe { File::from_raw_fd(fd as libc::c_int) };
+    let source_key = cstring("source")?;
+    let source_value = cstring(source)?;
+    let mut config = vec![(FSCONFIG_SET_STRING, source_key, Some(source_value))];
+    for option in options {
+        config.push(match option {
+            MountOption::Flag(key) => (FSCONFIG_SET_FLAG, cstring(key)?, None),
+            MountOption::Value(key, value) => {
+                (FSCONFIG_SET_STRING, cstring(key)?, Some(cstring(value)?))
+            }
+        });
+    }
+    for (cmd, key, value) in config.iter() {
+        if fsconfig(&fs_fd, *cmd, Some(key), value.as_ref()) < 0 {
+            let errno = Errno::last();
+            return Err(anyhow!(
+                "Unable to set the mount option `{}`: {} {}",
+                key.to_string_lossy(),
+                describe_errno(errno),
+                context_messages(&mut fs_fd)
+            ));
+        }
+    }
+    if fsconfig(&fs_fd, FSCONFIG_CMD_CREATE, None, None) < 0 {
+        let errno = Errno::last();
+        return Err(anyhow!(
+            "Unable to mount {}: {} {}",
+            fstype,
+            describe_errno(errno),
+            context_messages(&mut fs_fd)
+        ));
+    }
+    // unsafe: fsmount syscall
+    let fd = unsafe { libc::syscall(libc::SYS_fsmount, fs_fd.as_raw_fd(), FSMOUNT_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(anyhow!(
+            "Unable to mount {}: {}",
+            fstype,
+            describe_errno(Errno::last())
+        ));
+    }
+    // unsafe: the file descriptor is owned by us
+    let mnt_fd = unsafe { File::from_raw_fd(fd as libc::c_int) };
+    let empty = cstring("")?;
+    let target_c = CString::new(target.as_os_str().as_bytes())?;
+    // unsafe: move_mount syscall
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_move_mount,
+            mnt_fd.as_raw_fd(),
+            empty.as_ptr(),
+            libc::AT_FDCWD,
+            target_c.as_ptr(),
+            MOVE_MOUNT_F_EMPTY_PATH,
+        )
+    };
+    if result < 0 {
+        return Err(anyhow!(
+            "Unable to attach the filesystem to {}: {}",
+            target.display(),
+            describe_errno(Errno::last())
+        ));
+    }
+
+    Ok(true)
+}
+
+/// Mount the filesystem to the target, using the new mount API if available
+pub fn mount_fs(fstype: &str, source: &str, options: &[MountOption], target: &Path) -> Result<()> {
+    fs::create_dir_all(target)?;
+    if mount_new_api(fstype, source, options, target)? {
+        return Ok(());
+    }
+    // fallback to the mount(2) for kernels older than 5.2
+    let data = options
+        .iter()
+        .map(|option| match option {
+            MountOption::Flag(key) => key.to_string(),
+            MountOption::Value(key, value) => format!("{}={}", key, value),
+        })
+        .collect::<Vec<_>>()
+        .join(",");
+    mount(
+        Some(OsStr::new(source)),
+        target,
+        Some(OsStr::new(fstype)),
+        MsFlags::empty(),
+        Some(OsStr::new(&data)),
+    )
+    .map_err(|e| anyhow!("Unable to mount {}: {}", fstype, describe_errno(e)))?;
+
+    Ok(())
+}
+
+/// Check if the current process is running in a (non-initial) user namespace
+pub fn in_user_namespace() -> bool {
+    match fs::read_to_string("/proc/self/uid_map") {
+        Ok(map) => map.split_whitespace().collect::<Vec<_>>() != ["0", "0", "4294967295"],
+        Err(_) => false,
+    }
+}
+
+#[test]
+fn test_escape_option_path() {
+    assert_eq!(escape_option_path(Path::new("/a/b")), "/a/b");
+    assert_eq!(escape_option_path(Path::new("/a:b,c")), "/a\\:b\\,c");
+}
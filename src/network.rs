@@ -1,20 +1,42 @@
-use crate::make_progress_bar;
-use anyhow::{anyhow, Result};
+use crate::progress::{Progress, Unit};
+use crate::{errors::CielError, info, warn};
+use anyhow::{anyhow, bail, Result};
+use console::style;
 use fs3::FileExt;
 use lazy_static::lazy_static;
 use reqwest::blocking::{Client, Response};
-use serde::Deserialize;
+use reqwest::header::{
+    ACCEPT_RANGES, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, RANGE,
+};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{File, OpenOptions};
+use std::io::Read;
+use std::os::unix::fs::FileExt as _;
 use std::path::Path;
 use std::{
     sync::{
-        atomic::{AtomicUsize, Ordering},
-        Arc,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
     },
     thread::{self, sleep},
-    time::Duration,
+    time::{Duration, Instant, SystemTime},
 };
 
-const MANIFEST_URL: &str = "https://releases.aosc.io/manifest/recipe.json";
+/// Built-in mirrors of releases.aosc.io
+pub const DEFAULT_MIRRORS: &[&str] = &[
+    "https://releases.aosc.io/",
+    "https://mirrors.tuna.tsinghua.edu.cn/anthon/aosc-os/",
+    "https://mirrors.bfsu.edu.cn/anthon/aosc-os/",
+    "https://mirrors.ustc.edu.cn/anthon/aosc-os/",
+    "https://mirror.nju.edu.cn/anthon/aosc-os/",
+];
+const MANIFEST_PATH: &str = "manifest/recipe.json";
+/// The cached release manifest and its validators, stored in the tarball cache
+const MANIFEST_CACHE: &str = "recipe.json";
+const MANIFEST_CACHE_META: &str = "recipe.meta.json";
+const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct Tarball {
@@ -22,6 +44,9 @@ pub struct Tarball {
     pub date: String,
     pub path: String,
     pub sha256sum: String,
+    /// Minimum version of Ciel required to use the tarball
+    #[serde(default)]
+    pub min_ciel_version: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -37,15 +62,121 @@ pub struct Recipe {
     variants: Vec<Variant>,
 }
 
+/// Environment variables specifying the proxy, in the order of precedence
+const PROXY_ENV_VARS: &[&str] = &[
+    "https_proxy",
+    "HTTPS_PROXY",
+    "http_proxy",
+    "HTTP_PROXY",
+    "all_proxy",
+    "ALL_PROXY",
+];
+
 lazy_static! {
-    static ref GIT_PROGRESS: indicatif::ProgressStyle = indicatif::ProgressStyle::default_bar()
-        .template("[{bar:25.cyan/blue}] {pos}/{len} {msg} ({eta})")
-        .unwrap();
+    /// Proxy of the workspace being initialized, whose configuration is not saved yet
+    static ref PENDING_PROXY: Mutex<Option<String>> = Mutex::new(None);
+}
+/// Set by `ciel build --offline` (or `CIEL_OFFLINE`)
+static OFFLINE: AtomicBool = AtomicBool::new(false);
+/// Set by `--p2p` (or `CIEL_P2P`)
+static P2P: AtomicBool = AtomicBool::new(false);
+
+/// Use the proxy for the network operations of this process instead of the one in the configuration
+pub fn set_proxy(proxy: Option<String>) {
+    *PENDING_PROXY.lock().unwrap() = proxy;
+}
+
+/// Return the proxy for the network operations: `[network] proxy` in the configuration,
+/// or the one from the `https_proxy`, `http_proxy` or `all_proxy` environment variables
+pub fn proxy_url() -> Option<String> {
+    let configured = PENDING_PROXY
+        .lock()
+        .unwrap()
+        .clone()
+        .or_else(|| {
+            crate::config::read_config()
+                .ok()
+                .and_then(|c| c.network.proxy)
+        })
+        // the proxy may contain credentials stored as secrets
+        .and_then(|proxy| crate::secrets::expand_secrets(&proxy).ok());
+
+    configured.or_else(|| {
+        PROXY_ENV_VARS
+            .iter()
+            .find_map(|var| std::env::var(var).ok().filter(|v| !v.is_empty()))
+    })
+}
+
+/// Work offline regardless of the configuration (`ciel build --offline` or `CIEL_OFFLINE`)
+pub fn set_offline() {
+    OFFLINE.store(true, Ordering::SeqCst);
+}
+
+/// Check if Ciel is working offline (`ciel build --offline`, `CIEL_OFFLINE` or `[network] offline`)
+pub fn is_offline() -> bool {
+    OFFLINE.load(Ordering::SeqCst)
+        || crate::config::read_config()
+            .map(|c| c.network.offline)
+            .unwrap_or(false)
+}
+
+/// Download the OS tarballs using the metalink/torrent backend regardless of the configuration
+pub fn set_p2p() {
+    P2P.store(true, Ordering::SeqCst);
+}
+
+/// Check if the metalink/torrent backend is enabled (`--p2p`, `CIEL_P2P` or `[network] p2p`)
+pub fn is_p2p() -> bool {
+    P2P.load(Ordering::SeqCst)
+        || crate::config::read_config()
+            .map(|c| c.network.p2p)
+            .unwrap_or(false)
+}
+
+/// Fail fast instead of accessing the network when working offline
+pub fn ensure_online(action: &str) -> Result<()> {
+    if is_offline() {
+        return Err(CielError::NetworkError(format!(
+            "Unable to {}: Ciel is working offline",
+            action
+        ))
+        .into());
+    }
+
+    Ok(())
+}
+
+/// Create an HTTP client going through the proxy (if any)
+pub fn http_client() -> Result<Client> {
+    ensure_online("access the network")?;
+    let mut builder = Client::builder();
+    if let Some(proxy) = proxy_url() {
+        let proxy = reqwest::Proxy::all(&proxy)
+            .map_err(|e| anyhow!("Invalid proxy `{}`: {}", proxy, e))?
+            .no_proxy(reqwest::NoProxy::from_env());
+        builder = builder.proxy(proxy);
+    }
+
+    Ok(builder.build()?)
+}
+
+/// Return the proxy options for the Git operations (libgit2 does not read the environment variables)
+fn git_proxy_options<'a>(proxy: Option<&'a str>) -> git2::ProxyOptions<'a> {
+    let mut options = git2::ProxyOptions::new();
+    match proxy {
+        Some(proxy) => options.url(proxy),
+        // use `http.proxy` from the Git configuration
+        None => options.auto(),
+    };
+
+    options
 }
 
 /// Download a file from the web
 pub fn download_file(url: &str) -> Result<Response> {
-    let client = Client::new().get(url).send()?;
+    ensure_online(&format!("download {}", url))?;
+    let client = http_client()?.get(url).send()?;
 
     Ok(client)
 }
@@ -63,35 +194,546 @@ pub fn download_file_progress(url: &str, file: &str) -> Result<u64> {
         // fails early when there is insufficient disk space available
         output.allocate(total)?;
     }
-    let progress_bar = indicatif::ProgressBar::new(total);
-    progress_bar.set_style(
-        indicatif::ProgressStyle::default_bar()
-            .template(make_progress_bar!("{bytes}/{total_bytes}"))
-            .unwrap(),
-    );
-    progress_bar.set_draw_target(indicatif::ProgressDrawTarget::stderr_with_hz(5));
-    let mut reader = progress_bar.wrap_read(resp);
+    let progress = Progress::new(file, Unit::Bytes, Some(total).filter(|t| *t > 0));
+    let mut reader = progress.wrap_read(resp);
     std::io::copy(&mut reader, &mut output)?;
-    progress_bar.finish_and_clear();
+    progress.finish();
 
     Ok(total)
 }
 
-/// Pick the latest buildkit tarball according to the recipe
-pub fn pick_latest_tarball(arch: &str) -> Result<Tarball> {
-    let resp = Client::new().get(MANIFEST_URL).send()?;
-    let recipe: Recipe = resp.json()?;
+/// Suffixes of the descriptors published next to the releases, in the order of preference
+const P2P_DESCRIPTORS: &[&str] = &[".meta4", ".metalink", ".torrent"];
+
+/// Download the descriptor of the file at the URL, if the mirror publishes one
+fn fetch_descriptor(url: &str) -> Result<tempfile::NamedTempFile> {
+    let client = http_client()?;
+    for suffix in P2P_DESCRIPTORS {
+        let resp = match client
+            .get(format!("{}{}", url, suffix))
+            .send()
+            .and_then(|r| r.error_for_status())
+        {
+            Ok(resp) => resp,
+            Err(_) => continue,
+        };
+        // aria2c detects the type of the descriptor by its extension
+        let mut descriptor = tempfile::Builder::new().suffix(suffix).tempfile()?;
+        std::io::copy(&mut resp.take(16 * 1024 * 1024), &mut descriptor)?;
+
+        return Ok(descriptor);
+    }
+
+    Err(anyhow!("No metalink or torrent descriptor for {}", url))
+}
+
+/// Download the file using its metalink or torrent descriptor with aria2c(1), which verifies
+/// the piece hashes while downloading and fetches from the mirrors and peers at the same time.
+/// Returns the size of the file
+pub fn download_file_p2p(url: &str, file: &str) -> Result<u64> {
+    ensure_online(&format!("download {}", url))?;
+    let aria2c = which::which("aria2c")
+        .map_err(|_| anyhow!("aria2c is required for metalink or torrent downloads"))?;
+    let descriptor = fetch_descriptor(url)?;
+    // the name of the downloaded file is determined by the descriptor
+    let output = tempfile::tempdir_in(".")?;
+    let mut cmd = std::process::Command::new(aria2c);
+    cmd.arg("--dir").arg(output.path()).args([
+        "--check-integrity=true",
+        "--seed-time=0",
+        "--follow-metalink=mem",
+        "--follow-torrent=mem",
+        "--summary-interval=0",
+        "--console-log-level=warn",
+    ]);
+    if let Some(proxy) = proxy_url() {
+        cmd.arg(format!("--all-proxy={}", proxy));
+    }
+    let status = cmd.arg(descriptor.path()).status()?;
+    if !status.success() {
+        bail!("aria2c failed with {}", status);
+    }
+    let downloaded = walkdir::WalkDir::new(output.path())
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        .filter_map(|e| Some((e.metadata().ok()?.len(), e.into_path())))
+        .max_by_key(|(size, _)| *size)
+        .ok_or_else(|| anyhow!("aria2c did not download anything"))?;
+    std::fs::rename(&downloaded.1, file)?;
+
+    Ok(downloaded.0)
+}
+
+/// Files smaller than this are always downloaded using a single connection
+const MIN_SEGMENTED_SIZE: u64 = 16 * 1024 * 1024;
+const SEGMENT_RETRIES: usize = 3;
+
+/// Number of connections to use for downloading the OS tarballs (`CIEL_DOWNLOAD_SEGMENTS`)
+pub fn default_segments() -> usize {
+    std::env::var("CIEL_DOWNLOAD_SEGMENTS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(1)
+}
+
+/// Split the file into (offset, length) ranges
+fn split_ranges(total: u64, segments: usize) -> Vec<(u64, u64)> {
+    let segments = segments.max(1) as u64;
+    let size = total.div_ceil(segments);
+    (0..segments)
+        .map(|i| (i * size, size.min(total.saturating_sub(i * size))))
+        .filter(|(_, len)| *len > 0)
+        .collect()
+}
+
+/// Download the range of the file, retrying from where it stopped on errors
+fn download_range(
+    client: &Client,
+    url: &str,
+    output: &File,
+    range: (u64, u64),
+    written: &AtomicU64,
+    progress: &Progress,
+    abort: &AtomicBool,
+) -> Result<()> {
+    let (start, len) = range;
+    let mut last_error = None;
+    for _ in 0..SEGMENT_RETRIES {
+        let done = written.load(Ordering::SeqCst);
+        if done >= len || abort.load(Ordering::SeqCst) {
+            break;
+        }
+        let resp = client
+            .get(url)
+            .header(RANGE, format!("bytes={}-{}", start + done, start + len - 1))
+            .send()
+            .and_then(|r| r.error_for_status());
+        let mut resp = match resp {
+            Ok(resp) if resp.status() == StatusCode::PARTIAL_CONTENT => resp,
+            Ok(resp) => {
+                return Err(anyhow!(
+                    "Server does not support ranges ({})",
+                    resp.status()
+                ))
+            }
+            Err(e) => {
+                last_error = Some(e.into());
+                continue;
+            }
+        };
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            if abort.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+            let done = written.load(Ordering::SeqCst);
+            let n = match resp.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n.min((len - done) as usize),
+                Err(e) => {
+                    last_error = Some(e.into());
+                    break;
+                }
+            };
+            output.write_all_at(&buf[..n], start + done)?;
+            written.fetch_add(n as u64, Ordering::SeqCst);
+            progress.inc(n as u64);
+        }
+    }
+    if written.load(Ordering::SeqCst) < len && !abort.load(Ordering::SeqCst) {
+        return Err(last_error.unwrap_or_else(|| anyhow!("Connection closed prematurely")));
+    }
+
+    Ok(())
+}
+
+/// Hash the downloaded file in order, while the segments are still being downloaded
+fn hash_segments(
+    output: &File,
+    ranges: &[(u64, u64)],
+    written: &[AtomicU64],
+    abort: &AtomicBool,
+) -> Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 1024 * 1024];
+    for ((start, len), written) in ranges.iter().zip(written) {
+        let mut hashed = 0;
+        while hashed < *len {
+            if abort.load(Ordering::SeqCst) {
+                return Err(anyhow!("Download aborted"));
+            }
+            let available = written.load(Ordering::SeqCst) - hashed;
+            if available == 0 {
+                sleep(Duration::from_millis(50));
+                continue;
+            }
+            let n = available.min(buf.len() as u64) as usize;
+            output.read_exact_at(&mut buf[..n], start + hashed)?;
+            hasher.update(&buf[..n]);
+            hashed += n as u64;
+        }
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Download a file using multiple connections, each fetching a range of the file.
+/// Returns the size and the SHA-256 checksum of the file (computed while downloading).
+/// Falls back to a single connection if the server does not support ranges
+pub fn download_file_segmented(url: &str, file: &str, segments: usize) -> Result<(u64, String)> {
+    ensure_online(&format!("download {}", url))?;
+    let client = http_client()?;
+    let head = client.head(url).send()?.error_for_status()?;
+    let total = head.content_length().unwrap_or(0);
+    let ranges_supported = head
+        .headers()
+        .get(ACCEPT_RANGES)
+        .map(|v| v.as_bytes() == b"bytes")
+        .unwrap_or(false);
+    if segments <= 1 || !ranges_supported || total < MIN_SEGMENTED_SIZE {
+        let total = download_file_progress(url, file)?;
+        let checksum = crate::common::sha256sum(File::open(file)?)?;
+        return Ok((total, checksum));
+    }
+    let output = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(file)?;
+    output.allocate(total)?;
+    let ranges = split_ranges(total, segments);
+    let written = ranges.iter().map(|_| AtomicU64::new(0)).collect::<Vec<_>>();
+    let abort = AtomicBool::new(false);
+    let bars = ranges
+        .iter()
+        .enumerate()
+        .map(|(i, (_, len))| Progress::new(&format!("#{}", i + 1), Unit::Bytes, Some(*len)))
+        .collect::<Vec<_>>();
+    let (results, checksum) = thread::scope(|scope| {
+        let handles = ranges
+            .iter()
+            .zip(written.iter())
+            .zip(bars.iter())
+            .map(|((range, written), bar)| {
+                let (client, output, abort) = (&client, &output, &abort);
+                scope.spawn(move || {
+                    let result = download_range(client, url, output, *range, written, bar, abort);
+                    if result.is_err() {
+                        abort.store(true, Ordering::SeqCst);
+                    }
+                    bar.finish();
+                    result
+                })
+            })
+            .collect::<Vec<_>>();
+        let checksum = hash_segments(&output, &ranges, &written, &abort);
+        let results = handles
+            .into_iter()
+            .map(|h| {
+                h.join()
+                    .unwrap_or_else(|_| Err(anyhow!("Download thread panicked")))
+            })
+            .collect::<Vec<_>>();
+        (results, checksum)
+    });
+    for result in results {
+        result?;
+    }
+
+    Ok((total, checksum?))
+}
+
+/// Join the mirror URL and the path of the file
+#[inline]
+pub fn mirror_url(mirror: &str, path: &str) -> String {
+    format!(
+        "{}/{}",
+        mirror.trim_end_matches('/'),
+        path.trim_start_matches('/')
+    )
+}
+
+/// Measure the time needed to fetch the headers of the release manifest from the mirror
+fn probe_mirror(client: &Client, mirror: &str) -> Option<Duration> {
+    let start = Instant::now();
+    client
+        .head(mirror_url(mirror, MANIFEST_PATH))
+        .timeout(PROBE_TIMEOUT)
+        .send()
+        .ok()?
+        .error_for_status()
+        .ok()?;
+
+    Some(start.elapsed())
+}
+
+/// Sort the mirrors by their latency, unreachable mirrors are moved to the end
+pub fn rank_mirrors(mirrors: &[String]) -> Vec<String> {
+    if is_offline() {
+        return mirrors.to_vec();
+    }
+    let client = match http_client() {
+        Ok(client) => client,
+        Err(_) => return mirrors.to_vec(),
+    };
+    let latencies = thread::scope(|scope| {
+        mirrors
+            .iter()
+            .map(|mirror| {
+                let client = &client;
+                scope.spawn(move || probe_mirror(client, mirror))
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|h| h.join().ok().flatten())
+            .collect::<Vec<_>>()
+    });
+    let mut ranked = mirrors.iter().zip(latencies).collect::<Vec<_>>();
+    // `None` (unreachable) is ordered last, the sort is stable
+    ranked.sort_by_key(|(_, latency)| latency.unwrap_or(Duration::MAX));
+
+    ranked
+        .into_iter()
+        .map(|(mirror, _)| mirror.clone())
+        .collect()
+}
+
+/// Return the mirrors to use for fetching the releases, the preferred mirror (if any) comes first,
+/// followed by the configured mirrors (or the built-in ones) ordered by their latency
+pub fn select_mirrors(preferred: Option<&str>, configured: &[String]) -> Vec<String> {
+    let mirrors = if configured.is_empty() {
+        DEFAULT_MIRRORS.iter().map(|m| m.to_string()).collect()
+    } else {
+        configured.to_vec()
+    };
+    let mut selected = preferred.map(|m| vec![m.to_owned()]).unwrap_or_default();
+    let spinner = Progress::spinner("Probing mirrors...");
+    for mirror in rank_mirrors(&mirrors) {
+        if !selected.contains(&mirror) {
+            selected.push(mirror);
+        }
+    }
+    spinner.finish();
+
+    selected
+}
+
+/// Return the URLs of the tarball on each of the mirrors
+pub fn tarball_urls(tarball: &Tarball, mirrors: &[String]) -> Vec<String> {
+    mirrors
+        .iter()
+        .map(|mirror| mirror_url(mirror, &tarball.path))
+        .collect()
+}
+
+/// Validators of the cached release manifest, for the conditional requests
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ManifestCacheMeta {
+    url: String,
+    etag: Option<String>,
+    last_modified: Option<String>,
+    /// When the manifest is last fetched or revalidated, in seconds since the epoch
+    fetched: u64,
+}
+
+/// A release manifest in the cache
+struct CachedManifest {
+    content: String,
+    meta: ManifestCacheMeta,
+}
+
+impl CachedManifest {
+    fn load() -> Option<Self> {
+        let dir = crate::cache::cache_dir().ok()?;
+        let content = std::fs::read_to_string(dir.join(MANIFEST_CACHE)).ok()?;
+        let meta = std::fs::read_to_string(dir.join(MANIFEST_CACHE_META)).ok()?;
+
+        Some(CachedManifest {
+            content,
+            meta: serde_json::from_str(&meta).ok()?,
+        })
+    }
+
+    fn save(&self) -> Result<()> {
+        let dir = crate::cache::cache_dir()?;
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(dir.join(MANIFEST_CACHE), &self.content)?;
+        std::fs::write(
+            dir.join(MANIFEST_CACHE_META),
+            serde_json::to_string(&self.meta)?,
+        )?;
+
+        Ok(())
+    }
+
+    /// How long ago the manifest is fetched or revalidated
+    fn age(&self) -> Duration {
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .saturating_sub(Duration::from_secs(self.meta.fetched))
+    }
+}
+
+#[inline]
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Format the age of the cached data for the humans
+fn format_age(age: Duration) -> String {
+    let secs = age.as_secs();
+    match secs {
+        0..=59 => "just now".to_owned(),
+        60..=3599 => format!("{} minute(s) ago", secs / 60),
+        3600..=86399 => format!("{} hour(s) ago", secs / 3600),
+        _ => format!("{} day(s) ago", secs / 86400),
+    }
+}
+
+/// Fetch the release manifest from the mirror, revalidating the cached one if it is from
+/// the same mirror. Returns the manifest and whether it was modified
+fn fetch_manifest(
+    client: &Client,
+    mirror: &str,
+    cached: Option<&CachedManifest>,
+) -> Result<(CachedManifest, bool)> {
+    let url = mirror_url(mirror, MANIFEST_PATH);
+    let mut request = client.get(&url);
+    let cached = cached.filter(|c| c.meta.url == url);
+    if let Some(cached) = cached {
+        if let Some(etag) = &cached.meta.etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &cached.meta.last_modified {
+            request = request.header(IF_MODIFIED_SINCE, last_modified);
+        }
+    }
+    let resp = request.send()?;
+    if resp.status() == StatusCode::NOT_MODIFIED {
+        if let Some(cached) = cached {
+            let mut meta = cached.meta.clone();
+            meta.fetched = unix_now();
+            return Ok((
+                CachedManifest {
+                    content: cached.content.clone(),
+                    meta,
+                },
+                false,
+            ));
+        }
+    }
+    let resp = resp.error_for_status()?;
+    let header = |name| {
+        resp.headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_owned())
+    };
+    let meta = ManifestCacheMeta {
+        url,
+        etag: header(ETAG),
+        last_modified: header(LAST_MODIFIED),
+        fetched: unix_now(),
+    };
+
+    Ok((
+        CachedManifest {
+            content: resp.text()?,
+            meta,
+        },
+        true,
+    ))
+}
+
+/// Fetch the release manifest from the first mirror responding.
+/// The manifest is cached, and only re-downloaded if it is modified on the mirror.
+/// The cached manifest is used if no mirror is reachable
+fn fetch_recipe(mirrors: &[String]) -> Result<Recipe> {
+    let cached = CachedManifest::load();
+    let mut last_error: anyhow::Error =
+        CielError::NetworkError("No mirror available".to_owned()).into();
+    if is_offline() {
+        last_error = CielError::NetworkError("Ciel is working offline".to_owned()).into();
+    } else {
+        let client = http_client()?;
+        for mirror in mirrors {
+            let manifest = fetch_manifest(&client, mirror, cached.as_ref()).and_then(
+                |(manifest, modified)| {
+                    let recipe: Recipe = serde_json::from_str(&manifest.content)?;
+                    if let Err(e) = manifest.save() {
+                        warn!("Unable to cache the release manifest: {}", e);
+                    } else if !modified {
+                        info!("The release manifest is not modified, using the cached one.");
+                    }
+
+                    Ok(recipe)
+                },
+            );
+            match manifest {
+                Ok(recipe) => return Ok(recipe),
+                Err(e) => last_error = CielError::NetworkError(format!("{}: {}", mirror, e)).into(),
+            }
+        }
+    }
+    let cached = match cached {
+        Some(cached) => cached,
+        None => {
+            return Err(last_error.context("Unable to fetch the release manifest (none cached)"))
+        }
+    };
+    let age = format_age(cached.age());
+    let recipe = serde_json::from_str(&cached.content).map_err(|e| {
+        anyhow!(
+            "Unable to fetch the release manifest ({}), and the cached one (fetched {}) is corrupted: {}",
+            last_error,
+            age,
+            e
+        )
+    })?;
+    warn!(
+        "Unable to fetch the release manifest ({}), using the cached one fetched {} from {}",
+        last_error, age, cached.meta.url
+    );
+
+    Ok(recipe)
+}
+
+/// Pick the latest buildkit tarball according to the recipe (fetched from the mirrors)
+pub fn pick_latest_tarball(arch: &str, mirrors: &[String]) -> Result<Tarball> {
+    let recipe = fetch_recipe(mirrors)?;
     let buildkit = recipe
         .variants
         .into_iter()
         .find(|v| v.name == "BuildKit")
         .ok_or_else(|| anyhow!("Unable to find buildkit variant"))?;
-    let mut tarballs: Vec<Tarball> = buildkit
+    let (mut tarballs, incompatible): (Vec<Tarball>, Vec<Tarball>) = buildkit
         .tarballs
         .into_iter()
         .filter(|tarball| tarball.arch == arch)
-        .collect();
+        .partition(|tarball| {
+            tarball
+                .min_ciel_version
+                .as_deref()
+                .map(crate::compat::version_satisfies)
+                .unwrap_or(true)
+        });
     if tarballs.is_empty() {
+        if let Some(min_version) = incompatible
+            .iter()
+            .filter_map(|t| t.min_ciel_version.as_deref())
+            .next()
+        {
+            bail!(
+                "The available tarballs require Ciel {} or newer, please upgrade Ciel",
+                min_version
+            );
+        }
         return Err(anyhow!("No suitable tarball was found"));
     }
     tarballs.sort_unstable_by_key(|x| x.date.clone());
@@ -101,6 +743,7 @@ pub fn pick_latest_tarball(arch: &str) -> Result<Tarball> {
 
 /// Clone the Git repository to `root`
 pub fn download_git(uri: &str, root: &Path) -> Result<()> {
+    ensure_online(&format!("clone {}", uri))?;
     let mut callbacks = git2::RemoteCallbacks::new();
     let mut co_callback = git2::build::CheckoutBuilder::new();
     let current: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0usize));
@@ -137,23 +780,25 @@ pub fn download_git(uri: &str, root: &Path) -> Result<()> {
         total_co.store(ttl, Ordering::SeqCst);
         stage_co.store(2, Ordering::SeqCst);
     });
+    let proxy = proxy_url();
     let mut options = git2::FetchOptions::new();
     options.remote_callbacks(callbacks);
+    options.proxy_options(git_proxy_options(proxy.as_deref()));
     // drawing progress bar in a separate thread
+    let task = format!("Cloning {}", uri);
     let bar = thread::spawn(move || {
-        let progress = indicatif::ProgressBar::new(1);
-        progress.set_style(GIT_PROGRESS.clone());
+        let progress = Progress::new(&task, Unit::Objects, None);
         loop {
             let current = current.load(Ordering::SeqCst);
             let total = total.load(Ordering::SeqCst);
-            progress.set_length(total as u64);
+            progress.set_total(total as u64);
             progress.set_position(current as u64);
 
             match stage_bar.load(Ordering::SeqCst) {
                 0 => {
                     let human_bytes =
                         indicatif::HumanBytes(cur_bytes.load(Ordering::SeqCst) as u64);
-                    progress.set_message(human_bytes.to_string());
+                    progress.set_message(&human_bytes.to_string());
                 }
                 1 => progress.set_message("Resolving deltas..."),
                 2 => progress.set_message("Checking out files..."),
@@ -161,7 +806,7 @@ pub fn download_git(uri: &str, root: &Path) -> Result<()> {
             }
             sleep(Duration::from_millis(100));
         }
-        progress.finish_and_clear();
+        progress.finish();
     });
 
     git2::build::RepoBuilder::new()
@@ -174,6 +819,92 @@ pub fn download_git(uri: &str, root: &Path) -> Result<()> {
     Ok(())
 }
 
+/// Options of cloning a Git repository
+#[derive(Debug, Default, Clone)]
+pub struct CloneOptions {
+    /// Branch to check out (the default branch of the remote if None)
+    pub branch: Option<String>,
+    /// Only fetch the given number of the latest commits
+    pub depth: Option<u32>,
+    /// Only fetch the branch to check out
+    pub single_branch: bool,
+    /// Fetch the file contents on demand (a blobless partial clone)
+    pub blobless: bool,
+}
+
+impl CloneOptions {
+    /// libgit2 supports neither shallow nor partial clones, git(1) is used for those instead
+    #[inline]
+    fn needs_git_cli(&self) -> bool {
+        self.depth.is_some() || self.single_branch || self.blobless
+    }
+}
+
+/// Create a git(1) command using the proxy (if any)
+fn git_command(dir: &Path) -> std::process::Command {
+    let mut cmd = std::process::Command::new("git");
+    cmd.current_dir(dir);
+    if let Some(proxy) = proxy_url() {
+        cmd.arg("-c").arg(format!("http.proxy={}", proxy));
+    }
+
+    cmd
+}
+
+#[inline]
+fn run_git(mut cmd: std::process::Command, action: &str) -> Result<()> {
+    let status = cmd
+        .status()
+        .map_err(|e| anyhow!("Unable to run git (is Git installed?): {}", e))?;
+    if !status.success() {
+        return Err(anyhow!("Error {}", action));
+    }
+
+    Ok(())
+}
+
+/// Clone the Git repository to `root` with the options
+pub fn clone_repo(uri: &str, root: &Path, options: &CloneOptions) -> Result<()> {
+    ensure_online(&format!("clone {}", uri))?;
+    if !options.needs_git_cli() {
+        download_git(uri, root)?;
+        if let Some(branch) = &options.branch {
+            let mut repo = git2::Repository::open(root)?;
+            git_switch_branch(&mut repo, branch, None)?;
+        }
+        return Ok(());
+    }
+    let mut cmd = git_command(Path::new("."));
+    cmd.arg("clone");
+    if let Some(depth) = options.depth {
+        cmd.arg(format!("--depth={}", depth));
+        // --depth implies --single-branch
+        if !options.single_branch {
+            cmd.arg("--no-single-branch");
+        }
+    } else if options.single_branch {
+        cmd.arg("--single-branch");
+    }
+    if options.blobless {
+        cmd.arg("--filter=blob:none");
+    }
+    if let Some(branch) = &options.branch {
+        cmd.arg("--branch").arg(branch);
+    }
+    cmd.arg("--").arg(uri).arg(root);
+
+    run_git(cmd, "cloning the repository")
+}
+
+/// Check if the repository is a shallow or partial clone, which libgit2 is unable to update
+pub fn is_reduced_clone(repo: &git2::Repository) -> bool {
+    repo.is_shallow()
+        || repo
+            .config()
+            .and_then(|c| c.get_bool("remote.origin.promisor"))
+            .unwrap_or(false)
+}
+
 // other Git operations
 fn find_branch<'a>(repo: &'a git2::Repository, name: &str) -> Result<git2::Branch<'a>> {
     let branch = repo.find_branch(name, git2::BranchType::Local);
@@ -191,27 +922,29 @@ fn find_branch<'a>(repo: &'a git2::Repository, name: &str) -> Result<git2::Branc
 }
 
 pub fn fetch_repo<P: AsRef<Path>>(path: P) -> Result<git2::Repository> {
+    ensure_online(&format!("fetch {}", path.as_ref().display()))?;
     let repo = git2::Repository::open(path.as_ref())?;
+    if is_reduced_clone(&repo) {
+        let mut cmd = git_command(path.as_ref());
+        cmd.args(["fetch", "--prune", "origin"]);
+        run_git(cmd, "fetching the repository")?;
+        return Ok(repo);
+    }
     let mut remote = repo.find_remote("origin")?;
     let refs = remote.fetch_refspecs()?;
     let refspecs = refs.into_iter().flatten().collect::<Vec<_>>();
+    let proxy = proxy_url();
     let mut opts = git2::FetchOptions::new();
     opts.prune(git2::FetchPrune::On);
+    opts.proxy_options(git_proxy_options(proxy.as_deref()));
     remote.fetch(&refspecs, Some(&mut opts), None)?;
     drop(remote); // dis-own the variable `repo`
 
     Ok(repo)
 }
 
-pub fn git_switch_branch(
-    repo: &mut git2::Repository,
-    branch: &str,
-    rebase_from: Option<&str>,
-) -> Result<bool> {
-    let target_branch = find_branch(repo, branch).unwrap();
-    let branch_ref = target_branch.into_reference();
-    let branch_refname = branch_ref.name().unwrap().to_string();
-    drop(branch_ref);
+/// Stash the local modifications (including the untracked files), returns whether a stash was made
+fn stash_changes(repo: &mut git2::Repository) -> Result<bool> {
     let stasher = git2::Signature::now("ciel", "bot@aosc.io")?;
     let repo_statuses = repo.statuses(None)?;
     let is_tree_dirty = !repo_statuses.is_empty();
@@ -223,6 +956,63 @@ pub fn git_switch_branch(
             Some(git2::StashFlags::INCLUDE_UNTRACKED),
         )?;
     }
+
+    Ok(is_tree_dirty)
+}
+
+/// Fast-forward the current branch to its upstream, the local modifications are stashed
+/// and restored afterwards. Returns whether the branch is updated
+pub fn git_fast_forward(repo: &mut git2::Repository) -> Result<bool> {
+    let head = repo.head()?;
+    if !head.is_branch() {
+        return Err(anyhow!("HEAD is detached, unable to fast-forward"));
+    }
+    let head_name = head.name().unwrap_or_default().to_string();
+    let upstream = git2::Branch::wrap(head)
+        .upstream()
+        .map_err(|_| anyhow!("The current branch does not track any upstream branch"))?;
+    let target = upstream.get().peel_to_commit()?.id();
+    drop(upstream);
+    let annotated = repo.find_annotated_commit(target)?;
+    let (analysis, _) = repo.merge_analysis(&[&annotated])?;
+    drop(annotated);
+    if analysis.is_up_to_date() {
+        return Ok(false);
+    }
+    if !analysis.is_fast_forward() {
+        return Err(anyhow!(
+            "The current branch has diverged from the upstream, please merge or rebase it manually"
+        ));
+    }
+    if is_reduced_clone(repo) {
+        // the missing objects are fetched on demand by git(1)
+        let mut cmd = git_command(repo.workdir().unwrap());
+        cmd.args(["merge", "--ff-only", "--autostash", "@{upstream}"]);
+        run_git(cmd, "fast-forwarding the branch")?;
+        return Ok(true);
+    }
+    let is_tree_dirty = stash_changes(repo)?;
+    repo.find_reference(&head_name)?
+        .set_target(target, "ciel: fast-forward")?;
+    let mut opts = git2::build::CheckoutBuilder::new();
+    repo.checkout_head(Some(opts.force()))?;
+    if is_tree_dirty {
+        repo.stash_pop(0, None)?;
+    }
+
+    Ok(true)
+}
+
+pub fn git_switch_branch(
+    repo: &mut git2::Repository,
+    branch: &str,
+    rebase_from: Option<&str>,
+) -> Result<bool> {
+    let target_branch = find_branch(repo, branch).unwrap();
+    let branch_ref = target_branch.into_reference();
+    let branch_refname = branch_ref.name().unwrap().to_string();
+    drop(branch_ref);
+    let is_tree_dirty = stash_changes(repo)?;
     repo.set_head(&branch_refname)?;
     let mut opts = git2::build::CheckoutBuilder::new();
     repo.checkout_head(Some(opts.force()))?;
@@ -249,3 +1039,30 @@ pub fn git_switch_branch(
     // returns whether a stash was made
     Ok(is_tree_dirty)
 }
+
+#[test]
+fn test_split_ranges() {
+    assert_eq!(split_ranges(10, 3), vec![(0, 4), (4, 4), (8, 2)]);
+    assert_eq!(split_ranges(10, 1), vec![(0, 10)]);
+    assert_eq!(split_ranges(2, 4), vec![(0, 1), (1, 1)]);
+}
+
+#[test]
+fn test_format_age() {
+    assert_eq!(format_age(Duration::from_secs(5)), "just now");
+    assert_eq!(format_age(Duration::from_secs(150)), "2 minute(s) ago");
+    assert_eq!(format_age(Duration::from_secs(7200)), "2 hour(s) ago");
+    assert_eq!(format_age(Duration::from_secs(3 * 86400)), "3 day(s) ago");
+}
+
+#[test]
+fn test_mirror_url() {
+    assert_eq!(
+        mirror_url("https://releases.aosc.io/", "/os-amd64/buildkit.tar.xz"),
+        "https://releases.aosc.io/os-amd64/buildkit.tar.xz"
+    );
+    assert_eq!(
+        mirror_url("https://mirror.example.org/aosc-os", "manifest/recipe.json"),
+        "https://mirror.example.org/aosc-os/manifest/recipe.json"
+    );
+}
@@ -0,0 +1,169 @@
+//! This module contains the notifications of the build events
+
+use anyhow::{anyhow, bail, Result};
+use console::style;
+use serde::Serialize;
+use serde_json::json;
+use std::{fs, io::Read, path::Path};
+
+use crate::{
+    config::{NotifyConfig, NotifyTarget},
+    network::http_client,
+    secrets::expand_secrets,
+    warn,
+};
+
+/// The events to notify
+pub const EVENTS: &[&str] = &["start", "success", "failure"];
+/// Lines of the build log in the notification of a failure
+const EXCERPT_LINES: usize = 20;
+
+/// A build event, also the payload of the webhooks
+#[derive(Debug, Clone, Serialize)]
+pub struct BuildEvent<'a> {
+    /// One of [EVENTS]
+    pub event: &'static str,
+    pub instance: &'a str,
+    pub packages: &'a [String],
+    /// The package failed to build (`failure` only)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub package: Option<&'a str>,
+    /// Time spent on the build in seconds (`success` and `failure` only)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration: Option<u64>,
+    /// The last lines of the build log of the failed package
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub excerpt: Option<String>,
+}
+
+impl BuildEvent<'_> {
+    /// Render the event as a text message
+    fn message(&self, host: &str) -> String {
+        let mut message = match self.event {
+            "start" => format!(
+                "[ciel@{}] Building {} package(s) in {}: {}",
+                host,
+                self.packages.len(),
+                self.instance,
+                self.packages.join(" ")
+            ),
+            "success" => format!(
+                "[ciel@{}] Built {} package(s) in {}",
+                host,
+                self.packages.len(),
+                self.instance
+            ),
+            _ => format!(
+                "[ciel@{}] Failed to build {} in {}",
+                host,
+                self.package.unwrap_or("the packages"),
+                self.instance
+            ),
+        };
+        if let Some(duration) = self.duration {
+            message.push_str(&format!(
+                " after {:02}:{:02}:{:02}",
+                duration / 3600,
+                (duration / 60) % 60,
+                duration % 60
+            ));
+        }
+        if let Some(excerpt) = &self.excerpt {
+            message.push_str("\n\n");
+            message.push_str(excerpt);
+        }
+
+        message
+    }
+}
+
+/// Return the last lines of the (compressed) build log
+pub fn log_excerpt(log: &Path) -> Option<String> {
+    let mut content = String::new();
+    zstd::Decoder::new(fs::File::open(log).ok()?)
+        .ok()?
+        .read_to_string(&mut content)
+        .ok()?;
+    let lines = content.lines().collect::<Vec<_>>();
+
+    Some(lines[lines.len().saturating_sub(EXCERPT_LINES)..].join("\n"))
+}
+
+fn send(target: &NotifyTarget, event: &BuildEvent, host: &str) -> Result<()> {
+    let client = http_client()?;
+    let response = match target {
+        NotifyTarget::Webhook { url } => client.post(expand_secrets(url)?).json(event).send()?,
+        NotifyTarget::Telegram { token, chat } => client
+            .post(format!(
+                "https://api.telegram.org/bot{}/sendMessage",
+                expand_secrets(token)?
+            ))
+            .json(&json!({ "chat_id": chat, "text": event.message(host) }))
+            .send()?,
+        NotifyTarget::Matrix {
+            homeserver,
+            room,
+            token,
+        } => {
+            let mut url = reqwest::Url::parse(homeserver)?;
+            let txn = format!(
+                "ciel-{}-{}",
+                std::process::id(),
+                std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .map_or(0, |d| d.as_nanos())
+            );
+            url.path_segments_mut()
+                .map_err(|_| anyhow!("Invalid homeserver URL `{}`", homeserver))?
+                .pop_if_empty()
+                .extend(&["_matrix", "client", "v3", "rooms", room.as_str(), "send"])
+                .extend(&["m.room.message", txn.as_str()]);
+            client
+                .put(url)
+                .bearer_auth(expand_secrets(token)?)
+                .json(&json!({ "msgtype": "m.text", "body": event.message(host) }))
+                .send()?
+        }
+    };
+    if !response.status().is_success() {
+        bail!("the server responded with {}", response.status());
+    }
+
+    Ok(())
+}
+
+/// Send the event to the notifications subscribed to it
+pub fn notify(notifications: &[NotifyConfig], event: &BuildEvent) {
+    if notifications.is_empty() {
+        return;
+    }
+    let host = nix::unistd::gethostname()
+        .ok()
+        .and_then(|h| h.into_string().ok())
+        .unwrap_or_else(|| "unknown".to_owned());
+    for notification in notifications
+        .iter()
+        .filter(|n| n.events.is_empty() || n.events.iter().any(|e| e == event.event))
+    {
+        if let Err(e) = send(&notification.target, event, &host) {
+            warn!("Unable to send the {} notification: {}", event.event, e);
+        }
+    }
+}
+
+#[test]
+fn test_event_message() {
+    let packages = vec!["foo".to_owned(), "bar".to_owned()];
+    let event = BuildEvent {
+        event: "failure",
+        instance: "main",
+        packages: &packages,
+        package: Some("bar"),
+        duration: Some(3725),
+        excerpt: Some("error: oops".to_owned()),
+    };
+    assert_eq!(
+        event.message("builder"),
+        "[ciel@builder] Failed to build bar in main after 01:02:05\n\nerror: oops"
+    );
+}
@@ -0,0 +1,43 @@
+//! This module contains the machine-readable output APIs
+
+use anyhow::Result;
+use serde::Serialize;
+use std::{
+    fs::File,
+    os::unix::io::FromRawFd,
+    process::Stdio,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+static JSON_OUTPUT: AtomicBool = AtomicBool::new(false);
+
+/// Print the results as JSON from now on (`--json`)
+pub fn use_json_output() {
+    JSON_OUTPUT.store(true, Ordering::SeqCst);
+}
+
+/// Check if the results are printed as JSON
+#[inline]
+pub fn json_output() -> bool {
+    JSON_OUTPUT.load(Ordering::SeqCst)
+}
+
+/// Print the value as a JSON document on stdout
+pub fn print_json<T: Serialize + ?Sized>(value: &T) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+
+    Ok(())
+}
+
+/// Return the stdout for the child processes: stderr in the JSON mode (keeping stdout for the
+/// JSON document), otherwise inherited
+pub fn child_stdout() -> Stdio {
+    if !json_output() {
+        return Stdio::inherit();
+    }
+    match nix::unistd::dup(libc::STDERR_FILENO) {
+        // SAFETY: the descriptor is just duplicated and owned by nothing else
+        Ok(fd) => Stdio::from(unsafe { File::from_raw_fd(fd) }),
+        Err(_) => Stdio::inherit(),
+    }
+}
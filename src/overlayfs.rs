@@ -1,11 +1,14 @@
-use crate::common;
+use crate::config::{validate_tmpfs_size, InstanceConfig};
+use crate::mount::{escape_option_path, in_user_namespace, mount_fs, MountOption};
+use crate::{common, quota, zfs};
 use anyhow::{anyhow, bail, Context, Result};
-use libmount::{mountinfo::Parser, Overlay};
+use libmount::mountinfo::Parser;
 use nix::mount::{umount2, MntFlags};
+use std::collections::HashSet;
 use std::fs;
 use std::os::unix::ffi::OsStrExt;
 use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 use std::process::Command;
 use std::{
     ffi::OsStr,
@@ -33,6 +36,8 @@ pub trait LayerManager {
     fn is_mounted(&self, target: &Path) -> Result<bool>;
     /// Rollback the filesystem to the distribution state
     fn rollback(&mut self) -> Result<()>;
+    /// Return what rolling back removes (directories or datasets), without touching anything
+    fn rollback_targets(&self) -> Vec<String>;
     /// Commit the current state of the instance filesystem to the distribution state
     fn commit(&mut self) -> Result<()>;
     /// Un-mount the filesystem
@@ -42,10 +47,33 @@ pub trait LayerManager {
     fn get_config_layer(&mut self) -> Result<PathBuf>;
     /// Return the directory where the base layer is located
     fn get_base_layer(&mut self) -> Result<PathBuf>;
+    /// Return the directory where the writable (upper) layer is located,
+    /// or None if your backend does not expose the upper layer separately
+    fn get_upper_layer(&mut self) -> Result<Option<PathBuf>>;
     /// Set the volatile state of the instance filesystem
     fn set_volatile(&mut self, volatile: bool) -> Result<()>;
     /// Destroy the filesystem of the current instance
     fn destroy(&mut self) -> Result<()>;
+    /// Return the disk usage of the instance layers (and the base layer if requested)
+    fn usage(&mut self, include_base: bool) -> Result<LayerUsage>;
+    /// Remove the contents of the given paths (as seen in the container) from the instance layers,
+    /// returns the number of bytes freed
+    fn prune(&mut self, paths: &[String]) -> Result<u64>;
+    /// Return the paths (as seen in the container) changed in the writable layer
+    fn changes(&mut self) -> Result<Vec<PathBuf>>;
+    /// Take a snapshot of the base layer to revert to, returns the name of the snapshot
+    fn snapshot_base(&mut self) -> Result<String>;
+    /// Revert the base layer to the snapshot, the snapshot is consumed
+    fn restore_base(&mut self, snapshot: &str) -> Result<()>;
+    /// Remove the snapshot of the base layer
+    fn remove_base_snapshot(&mut self, snapshot: &str) -> Result<()>;
+}
+
+/// Disk usage of the layers, in bytes
+#[derive(Debug, Clone, Copy)]
+pub struct LayerUsage {
+    pub base: Option<u64>,
+    pub upper: u64,
 }
 
 struct OverlayFS {
@@ -55,8 +83,14 @@ struct OverlayFS {
     upper: PathBuf,
     work: PathBuf,
     volatile: bool,
+    tmpfs: Option<String>,
+    quota: Option<String>,
 }
 
+const TMPFS_LAYER_DIR: &str = "layers/tmpfs";
+/// Records the identity of the base layer at the time of mounting
+const MOUNT_STAMP: &str = "layers/mounted";
+
 /// Create a new overlay filesystem on the host system
 pub fn create_new_instance_fs<P: AsRef<Path>>(inst_path: P, inst_name: P) -> Result<()> {
     let inst = inst_path.as_ref().join(inst_name.as_ref());
@@ -77,6 +111,68 @@ enum Diff {
 }
 
 impl OverlayFS {
+    fn new(dist_path: &Path, inst_path: &Path, inst_name: &Path) -> Result<OverlayFS> {
+        let inst = inst_path.join(inst_name);
+        let inst_config = InstanceConfig::load(&inst)?;
+        let tmpfs = inst_config.tmpfs_size;
+        let rw_layers = if tmpfs.is_some() {
+            inst.join(TMPFS_LAYER_DIR)
+        } else {
+            inst.join("layers")
+        };
+        Ok(OverlayFS {
+            inst: inst.to_owned(),
+            base: dist_path.to_owned(),
+            lower: inst.join("layers/local"),
+            upper: rw_layers.join("diff"),
+            work: rw_layers.join("diff.tmp"),
+            volatile: false,
+            tmpfs,
+            quota: inst_config.quota,
+        })
+    }
+
+    #[inline]
+    fn mount_stamp(&self) -> PathBuf {
+        self.inst.join(MOUNT_STAMP)
+    }
+
+    #[inline]
+    fn tmpfs_dir(&self) -> Result<PathBuf> {
+        Ok(std::env::current_dir()?
+            .join(&self.inst)
+            .join(TMPFS_LAYER_DIR))
+    }
+
+    /// Mount the tmpfs for the upper layer, unless it is already mounted
+    fn mount_tmpfs(&self, size: &str) -> Result<()> {
+        let tmpfs_dir = self.tmpfs_dir()?;
+        if is_mounted(&tmpfs_dir, OsStr::new("tmpfs"))? {
+            return Ok(());
+        }
+        if !validate_tmpfs_size(size) {
+            bail!("Invalid tmpfs size: {}", size);
+        }
+        let options = [
+            MountOption::Value("size", size.to_owned()),
+            MountOption::Value("mode", "0755".to_owned()),
+        ];
+        mount_fs("tmpfs", "tmpfs", &options, &tmpfs_dir)
+            .map_err(|e| anyhow!("Unable to mount tmpfs for the upper layer: {}", e))?;
+
+        Ok(())
+    }
+
+    /// Un-mount the tmpfs for the upper layer (all the changes will be lost)
+    fn release_tmpfs(&self) -> Result<()> {
+        let tmpfs_dir = self.tmpfs_dir()?;
+        if is_mounted(&tmpfs_dir, OsStr::new("tmpfs"))? {
+            umount2(&tmpfs_dir, MntFlags::MNT_DETACH)?;
+        }
+
+        Ok(())
+    }
+
     /// Generate a list of changes made in the upper layer
     fn diff(&self) -> Result<Vec<Diff>> {
         let mut mods: Vec<Diff> = Vec::new();
@@ -99,9 +195,9 @@ impl OverlayFS {
                 mods.push(Diff::Symlink(rel_path.clone()));
             } else if meta.is_dir() {
                 // Deal with dirs
-                let opaque = xattr::get(&path, "trusted.overlay.opaque")?;
-                let redirect = xattr::get(&path, "trusted.overlay.redirect")?;
-                let metacopy = xattr::get(&path, "trusted.overlay.metacopy")?;
+                let opaque = overlay_xattr(&path, "opaque")?;
+                let redirect = overlay_xattr(&path, "redirect")?;
+                let metacopy = overlay_xattr(&path, "metacopy")?;
 
                 if let Some(_data) = metacopy {
                     bail!("Unsupported filesystem feature: metacopy");
@@ -164,6 +260,8 @@ impl LayerManager for OverlayFS {
     // |- upper: .ciel/container/instances/<inst_name>/diff/
     // |- lower: .ciel/container/instances/<inst_name>/local/
     // ||- lower (base): .ciel/container/dist/
+    // When the upper layer is backed by tmpfs, work and upper are located in
+    // .ciel/container/instances/<inst_name>/layers/tmpfs/ instead
     fn from_inst_dir<P: AsRef<Path>>(
         dist_path: P,
         inst_path: P,
@@ -172,34 +270,56 @@ impl LayerManager for OverlayFS {
     where
         Self: Sized,
     {
-        let dist = dist_path.as_ref();
-        let inst = inst_path.as_ref().join(inst_name.as_ref());
-        Ok(Box::new(OverlayFS {
-            inst: inst.to_owned(),
-            base: dist.to_owned(),
-            lower: inst.join("layers/local"),
-            upper: inst.join("layers/diff"),
-            work: inst.join("layers/diff.tmp"),
-            volatile: false,
-        }))
+        Ok(Box::new(OverlayFS::new(
+            dist_path.as_ref(),
+            inst_path.as_ref(),
+            inst_name.as_ref(),
+        )?))
     }
     fn mount(&mut self, to: &Path) -> Result<()> {
-        let base_dirs = [self.lower.clone(), self.base.clone()];
-        let mut overlay = Overlay::writable(
-            // base_dirs variable contains the base and lower directories
-            base_dirs.iter().map(|x| x.as_ref()),
-            self.upper.clone(),
-            self.work.clone(),
-            to,
-        );
+        let mut options = vec![
+            // the lower directories are listed from the top to the bottom
+            MountOption::Value(
+                "lowerdir",
+                format!(
+                    "{}:{}",
+                    escape_option_path(&self.lower),
+                    escape_option_path(&self.base)
+                ),
+            ),
+            MountOption::Value("upperdir", escape_option_path(&self.upper)),
+            MountOption::Value("workdir", escape_option_path(&self.work)),
+        ];
+        if let Some(size) = &self.tmpfs {
+            self.mount_tmpfs(size)?;
+        }
         // create the directories if they don't exist (work directory may be missing)
         fs::create_dir_all(&self.work)?;
         fs::create_dir_all(&self.upper)?;
         fs::create_dir_all(&self.lower)?;
+        if let (Some(quota), None) = (&self.quota, &self.tmpfs) {
+            let limit = common::parse_size(quota)
+                .ok_or_else(|| anyhow!("Invalid quota size: {}", quota))?;
+            quota::apply_project_quota(
+                &[&self.upper, &self.work],
+                quota::project_id(&self.inst)?,
+                limit,
+            )
+            .map_err(|e| {
+                anyhow!(
+                    "Unable to enforce the disk quota ({}). Is the filesystem mounted with the `prjquota` option?",
+                    e
+                )
+            })?;
+        }
         // check overlay usability
         load_overlayfs_support()?;
         if self.volatile {
-            overlay.set_options(b"volatile".to_vec());
+            options.push(MountOption::Flag("volatile"));
+        }
+        if in_user_namespace() {
+            // trusted.* xattrs are not available to unprivileged users
+            options.push(MountOption::Flag("userxattr"));
         }
         let dirty_flag = self.work.join("work/incompat");
         if dirty_flag.exists() {
@@ -208,7 +328,8 @@ impl LayerManager for OverlayFS {
             ));
         }
         // let's mount them
-        overlay.mount().map_err(|e| anyhow!("{}", e.to_string()))?;
+        mount_fs("overlay", "overlay", &options, to)?;
+        fs::write(self.mount_stamp(), base_layer_identity(&self.base)?)?;
 
         Ok(())
     }
@@ -218,7 +339,22 @@ impl LayerManager for OverlayFS {
         is_mounted(target, OsStr::new("overlay"))
     }
 
+    fn rollback_targets(&self) -> Vec<String> {
+        if self.tmpfs.is_some() {
+            return vec![format!("{} (tmpfs)", self.upper.display())];
+        }
+
+        vec![
+            self.upper.display().to_string(),
+            self.work.display().to_string(),
+        ]
+    }
+
     fn rollback(&mut self) -> Result<()> {
+        if self.tmpfs.is_some() {
+            // the upper layer will be re-created on the next mount
+            return self.release_tmpfs();
+        }
         fs::remove_dir_all(&self.upper)?;
         fs::remove_dir_all(&self.work)?;
         fs::create_dir(&self.upper)?;
@@ -257,6 +393,7 @@ impl LayerManager for OverlayFS {
 
     fn unmount(&mut self, target: &Path) -> Result<()> {
         umount2(target, MntFlags::MNT_DETACH)?;
+        fs::remove_file(self.mount_stamp()).ok();
 
         Ok(())
     }
@@ -269,7 +406,17 @@ impl LayerManager for OverlayFS {
         Ok(self.base.clone())
     }
 
+    fn get_upper_layer(&mut self) -> Result<Option<PathBuf>> {
+        if let Some(size) = &self.tmpfs {
+            self.mount_tmpfs(size)?;
+        }
+        fs::create_dir_all(&self.upper)?;
+
+        Ok(Some(self.upper.clone()))
+    }
+
     fn destroy(&mut self) -> Result<()> {
+        self.release_tmpfs()?;
         fs::remove_dir_all(&self.inst)?;
 
         Ok(())
@@ -280,6 +427,256 @@ impl LayerManager for OverlayFS {
 
         Ok(())
     }
+
+    fn usage(&mut self, include_base: bool) -> Result<LayerUsage> {
+        let base = if include_base {
+            Some(disk_usage(&self.base)?)
+        } else {
+            None
+        };
+
+        Ok(LayerUsage {
+            base,
+            upper: disk_usage(&self.upper)? + disk_usage(&self.lower)?,
+        })
+    }
+
+    fn prune(&mut self, paths: &[String]) -> Result<u64> {
+        let mut freed = 0;
+        for path in paths {
+            if let Some(path) = layer_path(&self.upper, path)? {
+                freed += remove_dir_contents(&path)?;
+            }
+        }
+
+        Ok(freed)
+    }
+
+    fn changes(&mut self) -> Result<Vec<PathBuf>> {
+        // the tmpfs upper layer is gone when it is not mounted
+        if !self.upper.is_dir() {
+            return Ok(Vec::new());
+        }
+
+        Ok(self
+            .diff()?
+            .into_iter()
+            .map(|diff| match diff {
+                Diff::Symlink(path)
+                | Diff::OverrideDir(path)
+                | Diff::NewDir(path)
+                | Diff::ModifiedDir(path)
+                | Diff::WhiteoutFile(path)
+                | Diff::File(path)
+                | Diff::RenamedDir(_, path) => Path::new("/").join(path),
+            })
+            .collect())
+    }
+
+    // committing only renames the files into the base layer (or removes them),
+    // so the hard links keep the files of the snapshot intact
+    fn snapshot_base(&mut self) -> Result<String> {
+        let snapshot = self.base.with_extension("snapshot");
+        if snapshot.exists() {
+            // left by an interrupted update
+            fs::remove_dir_all(&snapshot)?;
+        }
+        link_tree(&self.base, &snapshot)?;
+
+        Ok(snapshot.display().to_string())
+    }
+
+    fn restore_base(&mut self, snapshot: &str) -> Result<()> {
+        let snapshot = Path::new(snapshot);
+        if !snapshot.is_dir() {
+            bail!("Snapshot {} does not exist.", snapshot.display());
+        }
+        // the base layer may be a mount point, so only its contents are replaced
+        for entry in fs::read_dir(&self.base)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                fs::remove_dir_all(entry.path())?;
+            } else {
+                fs::remove_file(entry.path())?;
+            }
+        }
+        for entry in fs::read_dir(snapshot)? {
+            let entry = entry?;
+            fs::rename(entry.path(), self.base.join(entry.file_name()))?;
+        }
+        fs::set_permissions(&self.base, fs::metadata(snapshot)?.permissions())?;
+        fs::remove_dir(snapshot)?;
+
+        Ok(())
+    }
+
+    fn remove_base_snapshot(&mut self, snapshot: &str) -> Result<()> {
+        fs::remove_dir_all(snapshot)?;
+
+        Ok(())
+    }
+}
+
+/// Return a string identifying the base layer directory (changes when the directory is re-created)
+#[inline]
+fn base_layer_identity(base: &Path) -> Result<String> {
+    let meta = fs::metadata(base)?;
+
+    Ok(format!("{}:{}", meta.dev(), meta.ino()))
+}
+
+/// Problems found in the overlay mounts of the workspace
+#[derive(Debug)]
+pub enum MountIssue {
+    /// The instance is not mounted, but its work directory has leftovers from an unclean shutdown
+    OrphanedWorkDir(String),
+    /// The instance is mounted, but the base layer has been replaced since then
+    StaleMount(String),
+    /// An overlay is mounted in the workspace, but the instance no longer exists
+    DeletedInstance(String),
+}
+
+impl std::fmt::Display for MountIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MountIssue::OrphanedWorkDir(name) => write!(
+                f,
+                "{}: work directory contains leftovers from an unclean shutdown",
+                name
+            ),
+            MountIssue::StaleMount(name) => {
+                write!(f, "{}: mounted with an outdated base layer", name)
+            }
+            MountIssue::DeletedInstance(name) => {
+                write!(f, "{}: mounted, but the instance no longer exists", name)
+            }
+        }
+    }
+}
+
+/// Check the overlay mounts of the current workspace for problems
+pub fn verify() -> Result<Vec<MountIssue>> {
+    let mut issues = Vec::new();
+    if zfs::is_zfs_workspace() {
+        return Ok(issues);
+    }
+    let cwd = std::env::current_dir()?;
+    let base = Path::new(common::CIEL_DIST_DIR);
+    let base_identity = base_layer_identity(base)?;
+    let mountinfo_content: Vec<u8> = fs::read("/proc/self/mountinfo")?;
+    let mut mounted = Vec::new();
+    for mount in Parser::new(&mountinfo_content) {
+        let mount = mount?;
+        let mount_point = Path::new(&mount.mount_point);
+        if mount.fstype != OsStr::new("overlay") || mount_point.parent() != Some(cwd.as_path()) {
+            continue;
+        }
+        if let Some(name) = mount_point.file_name() {
+            mounted.push(name.to_string_lossy().to_string());
+        }
+    }
+    for name in mounted.iter() {
+        if !common::is_instance_exists(name) {
+            issues.push(MountIssue::DeletedInstance(name.clone()));
+            continue;
+        }
+        let overlay = OverlayFS::new(base, Path::new(common::CIEL_INST_DIR), Path::new(name))?;
+        if fs::read_to_string(overlay.mount_stamp()).ok().as_ref() != Some(&base_identity) {
+            issues.push(MountIssue::StaleMount(name.clone()));
+        }
+    }
+    for entry in fs::read_dir(common::CIEL_INST_DIR)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !entry.file_type()?.is_dir() || mounted.contains(&name) {
+            continue;
+        }
+        let overlay = OverlayFS::new(base, Path::new(common::CIEL_INST_DIR), Path::new(&name))?;
+        let leftovers = fs::read_dir(overlay.work.join("work"))
+            .map(|mut d| d.next().is_some())
+            .unwrap_or(false);
+        if leftovers || overlay.mount_stamp().exists() {
+            issues.push(MountIssue::OrphanedWorkDir(name));
+        }
+    }
+
+    Ok(issues)
+}
+
+/// Clear the work directory of the instance, the instance must not be mounted
+pub(crate) fn clear_work_dir(inst_name: &str) -> Result<()> {
+    let overlay = OverlayFS::new(
+        Path::new(common::CIEL_DIST_DIR),
+        Path::new(common::CIEL_INST_DIR),
+        Path::new(inst_name),
+    )?;
+    if overlay.work.is_dir() {
+        remove_dir_contents(&overlay.work)?;
+    }
+    fs::remove_file(overlay.mount_stamp()).ok();
+
+    Ok(())
+}
+
+/// Resolve the path (as seen in the container) inside a layer directory without following symlinks,
+/// returns None if the path (or one of its parents) does not exist or is a symlink
+pub(crate) fn layer_path(root: &Path, path: &str) -> Result<Option<PathBuf>> {
+    let mut resolved = root.to_path_buf();
+    for component in Path::new(path).components() {
+        match component {
+            Component::RootDir | Component::CurDir => continue,
+            Component::Normal(name) => resolved.push(name),
+            _ => bail!("Invalid path: {}", path),
+        }
+        match fs::symlink_metadata(&resolved) {
+            Ok(meta) if meta.file_type().is_symlink() => return Ok(None),
+            Ok(_) => continue,
+            Err(_) => return Ok(None),
+        }
+    }
+
+    Ok(Some(resolved))
+}
+
+/// Remove everything inside the directory (but not the directory itself),
+/// returns the number of bytes freed
+pub(crate) fn remove_dir_contents(path: &Path) -> Result<u64> {
+    if !fs::symlink_metadata(path)?.is_dir() {
+        return Ok(0);
+    }
+    let freed = disk_usage(path)?;
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            fs::remove_dir_all(entry.path())?;
+        } else {
+            fs::remove_file(entry.path())?;
+        }
+    }
+
+    Ok(freed)
+}
+
+/// Calculate the disk usage of a directory tree (like `du -s`),
+/// hardlinked files are only counted once and whiteouts are ignored
+pub(crate) fn disk_usage(path: &Path) -> Result<u64> {
+    if !path.exists() {
+        return Ok(0);
+    }
+    let mut seen = HashSet::new();
+    let mut total = 0u64;
+    for entry in walkdir::WalkDir::new(path) {
+        let meta = entry?.metadata()?;
+        if meta.file_type().is_char_device() && meta.rdev() == 0 {
+            continue;
+        }
+        if !meta.is_dir() && meta.nlink() > 1 && !seen.insert((meta.dev(), meta.ino())) {
+            continue;
+        }
+        total += meta.blocks() * 512;
+    }
+
+    Ok(total)
 }
 
 /// is_mounted: check if a path is a mountpoint with corresponding fs_type
@@ -302,6 +699,29 @@ pub(crate) fn get_overlayfs_manager(inst_name: &str) -> Result<Box<dyn LayerMana
     OverlayFS::from_inst_dir(common::CIEL_DIST_DIR, common::CIEL_INST_DIR, inst_name)
 }
 
+/// A convenience function for getting the LayerManager suitable for the current workspace
+pub(crate) fn get_layer_manager(inst_name: &str) -> Result<Box<dyn LayerManager>> {
+    if zfs::is_zfs_workspace() {
+        return zfs::ZfsLayerManager::from_inst_dir(
+            common::CIEL_DIST_DIR,
+            common::CIEL_INST_DIR,
+            inst_name,
+        );
+    }
+
+    get_overlayfs_manager(inst_name)
+}
+
+/// Read the overlay attribute of the file in the upper layer
+/// (`user.overlay.*` is used instead of `trusted.overlay.*` when mounted with `userxattr`)
+fn overlay_xattr(path: &Path, name: &str) -> Result<Option<Vec<u8>>> {
+    if let Some(value) = xattr::get(path, format!("trusted.overlay.{}", name))? {
+        return Ok(Some(value));
+    }
+
+    Ok(xattr::get(path, format!("user.overlay.{}", name))?)
+}
+
 /// Check if path have all specified prefixes (with order)
 #[inline]
 fn has_prefix(path: &Path, prefixes: &[PathBuf]) -> bool {
@@ -338,6 +758,30 @@ pub fn test_overlay_usability() -> Result<()> {
     Err(anyhow!("No overlayfs support detected"))
 }
 
+/// Re-create the directory tree at `to` with the files hard-linked to the ones in `from`
+fn link_tree(from: &Path, to: &Path) -> Result<()> {
+    for entry in walkdir::WalkDir::new(from) {
+        let entry = entry?;
+        let target = to.join(entry.path().strip_prefix(from)?);
+        if entry.file_type().is_dir() {
+            let meta = entry.metadata()?;
+            fs::create_dir(&target)?;
+            fs::set_permissions(&target, meta.permissions())?;
+            nix::unistd::chown(
+                &target,
+                Some(nix::unistd::Uid::from_raw(meta.uid())),
+                Some(nix::unistd::Gid::from_raw(meta.gid())),
+            )?;
+        } else {
+            // symbolic links are linked as they are, not followed
+            fs::hard_link(entry.path(), &target)
+                .with_context(|| format!("when linking {}", entry.path().display()))?;
+        }
+    }
+
+    Ok(())
+}
+
 /// Set permission of to according to from
 #[inline]
 fn sync_permission(from: &Path, to: &Path) -> Result<()> {
@@ -414,3 +858,29 @@ fn overlay_exec_action(action: &Diff, overlay: &OverlayFS) -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_base_snapshot() {
+    let dir = tempfile::tempdir().unwrap();
+    let base = dir.path().join("dist");
+    fs::create_dir_all(base.join("etc")).unwrap();
+    fs::write(base.join("etc/os-release"), "old").unwrap();
+    std::os::unix::fs::symlink("etc/os-release", base.join("os-release")).unwrap();
+    let mut overlay = OverlayFS::new(&base, dir.path(), Path::new("main")).unwrap();
+    let snapshot = overlay.snapshot_base().unwrap();
+    // committing renames the new files over the old ones
+    fs::write(dir.path().join("new"), "new").unwrap();
+    fs::rename(dir.path().join("new"), base.join("etc/os-release")).unwrap();
+    fs::write(base.join("junk"), "").unwrap();
+    overlay.restore_base(&snapshot).unwrap();
+    assert_eq!(
+        fs::read_to_string(base.join("etc/os-release")).unwrap(),
+        "old"
+    );
+    assert!(fs::symlink_metadata(base.join("os-release"))
+        .unwrap()
+        .file_type()
+        .is_symlink());
+    assert!(!base.join("junk").exists());
+    assert!(!Path::new(&snapshot).exists());
+}
@@ -0,0 +1,297 @@
+//! This module contains the package manager frontends run in the containers
+
+use anyhow::{bail, Result};
+use console::strip_ansi_codes;
+use std::{fmt, path::Path};
+
+use crate::{
+    actions::run_in_container_watched,
+    progress::{Progress, Unit},
+};
+
+/// An operation of the package manager
+pub struct Operation {
+    /// What the operation does, e.g. `Upgrading the packages`
+    pub description: &'static str,
+    pub args: Vec<String>,
+}
+
+impl Operation {
+    fn new(description: &'static str, args: &[&str]) -> Self {
+        Operation {
+            description,
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+        }
+    }
+}
+
+/// A line of the output of the package manager
+#[derive(Debug, Clone, PartialEq)]
+pub enum OutputLine {
+    /// The progress of the operation (in percent) with the current activity, not shown
+    Progress(f64, String),
+    Error(String),
+    Other,
+}
+
+pub trait PackageManager {
+    /// Return the name of the package manager, as in the configuration
+    fn name(&self) -> &'static str;
+    /// Return the operations refreshing the sources and upgrading all the packages, in order
+    fn upgrade(&self) -> Vec<Operation>;
+    /// Return the operation installing the packages
+    fn install(&self, packages: &[String]) -> Operation;
+    /// Classify the line of the output
+    fn parse_line(&self, line: &str) -> OutputLine;
+}
+
+/// Status messages of apt-get (`APT::Status-Fd`), in the form `<kind>:<package>:<percent>:<message>`
+fn parse_apt_status(line: &str) -> Option<OutputLine> {
+    let mut fields = line.splitn(4, ':');
+    let kind = fields.next()?;
+    let _package = fields.next()?;
+    let percent = fields.next()?.parse::<f64>().ok()?;
+    let message = fields.next()?.trim().to_owned();
+    match kind {
+        "pmstatus" | "dlstatus" => Some(OutputLine::Progress(percent, message)),
+        "pmerror" => Some(OutputLine::Error(message)),
+        _ => None,
+    }
+}
+
+pub struct Apt;
+
+impl PackageManager for Apt {
+    fn name(&self) -> &'static str {
+        "apt"
+    }
+
+    fn upgrade(&self) -> Vec<Operation> {
+        vec![
+            Operation::new(
+                "Refreshing the sources",
+                &[
+                    "/usr/bin/env",
+                    "DEBIAN_FRONTEND=noninteractive",
+                    "apt-get",
+                    "update",
+                    "-y",
+                    "--allow-releaseinfo-change",
+                ],
+            ),
+            Operation::new(
+                "Upgrading the packages",
+                &[
+                    "/usr/bin/env",
+                    "DEBIAN_FRONTEND=noninteractive",
+                    "apt-get",
+                    "-y",
+                    "-o",
+                    "APT::Status-Fd=1",
+                    "-o",
+                    "Dpkg::Options::=--force-confnew",
+                    "full-upgrade",
+                    "--autoremove",
+                    "--purge",
+                ],
+            ),
+            Operation::new("Cleaning up the package cache", &["apt-get", "clean"]),
+        ]
+    }
+
+    fn install(&self, packages: &[String]) -> Operation {
+        let mut operation = Operation::new(
+            "Installing the packages",
+            &[
+                "/usr/bin/env",
+                "DEBIAN_FRONTEND=noninteractive",
+                "apt-get",
+                "-y",
+                "-o",
+                "APT::Status-Fd=1",
+                "install",
+            ],
+        );
+        operation.args.extend(packages.iter().cloned());
+
+        operation
+    }
+
+    fn parse_line(&self, line: &str) -> OutputLine {
+        if let Some(status) = parse_apt_status(line) {
+            return status;
+        }
+        match line.strip_prefix("E: ") {
+            Some(error) => OutputLine::Error(error.trim().to_owned()),
+            None => OutputLine::Other,
+        }
+    }
+}
+
+pub struct Oma;
+
+impl PackageManager for Oma {
+    fn name(&self) -> &'static str {
+        "oma"
+    }
+
+    fn upgrade(&self) -> Vec<Operation> {
+        vec![
+            Operation::new("Refreshing the sources", &["oma", "refresh"]),
+            Operation::new(
+                "Upgrading the packages",
+                &["oma", "upgrade", "-y", "--force-confnew", "--autoremove"],
+            ),
+            Operation::new("Cleaning up the package cache", &["oma", "clean"]),
+        ]
+    }
+
+    fn install(&self, packages: &[String]) -> Operation {
+        let mut operation = Operation::new("Installing the packages", &["oma", "install", "-y"]);
+        operation.args.extend(packages.iter().cloned());
+
+        operation
+    }
+
+    fn parse_line(&self, line: &str) -> OutputLine {
+        // oma colors the prefixes of the messages
+        let line = strip_ansi_codes(line);
+        match line.trim_start().strip_prefix("ERROR ") {
+            Some(error) => OutputLine::Error(error.trim().to_owned()),
+            None => OutputLine::Other,
+        }
+    }
+}
+
+/// The package manager exited with a non-zero status
+#[derive(Debug)]
+pub struct Failure {
+    pub status: i32,
+    /// The command line of the operation
+    command: String,
+    /// The errors reported by the package manager
+    errors: Vec<String>,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.errors.is_empty() {
+            return write!(f, "`{}` exited with status {}", self.command, self.status);
+        }
+        write!(
+            f,
+            "`{}` failed (exit status {}):\n  {}",
+            self.command,
+            self.status,
+            self.errors.join("\n  ")
+        )
+    }
+}
+
+impl std::error::Error for Failure {}
+
+/// Return the package manager by its name
+pub fn by_name(name: &str) -> Result<Box<dyn PackageManager>> {
+    match name {
+        "apt" => Ok(Box::new(Apt)),
+        "oma" => Ok(Box::new(Oma)),
+        _ => bail!(
+            "Unsupported package manager `{}`: expected apt or oma",
+            name
+        ),
+    }
+}
+
+/// Return the package manager set in the configuration, or the one installed in the system
+/// at `root` (oma is preferred)
+pub fn detect(name: Option<&str>, root: &Path) -> Result<Box<dyn PackageManager>> {
+    if let Some(name) = name {
+        return by_name(name);
+    }
+    if root.join("usr/bin/oma").exists() {
+        return Ok(Box::new(Oma));
+    }
+
+    Ok(Box::new(Apt))
+}
+
+/// Run the operation in the instance, showing its progress. Fails with the errors reported by
+/// the package manager if it exits with a non-zero status
+pub fn run(instance: &str, manager: &dyn PackageManager, operation: &Operation) -> Result<()> {
+    let mut progress: Option<Progress> = None;
+    let mut errors = Vec::new();
+    let status = run_in_container_watched(instance, &operation.args, |line| {
+        match manager.parse_line(line) {
+            OutputLine::Progress(percent, message) => {
+                let progress = progress.get_or_insert_with(|| {
+                    Progress::new(operation.description, Unit::Objects, Some(100))
+                });
+                progress.set_position(percent as u64);
+                progress.set_message(&message);
+                false
+            }
+            OutputLine::Error(error) => {
+                errors.push(error);
+                true
+            }
+            OutputLine::Other => true,
+        }
+    })?;
+    if let Some(progress) = progress {
+        progress.finish();
+    }
+    if status != 0 {
+        return Err(Failure {
+            status,
+            command: operation.args.join(" "),
+            errors,
+        }
+        .into());
+    }
+
+    Ok(())
+}
+
+/// Refresh the sources and upgrade all the packages in the instance
+pub fn upgrade(instance: &str, manager: &dyn PackageManager) -> Result<()> {
+    for operation in manager.upgrade() {
+        run(instance, manager, &operation)?;
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_parse_line() {
+    assert_eq!(
+        Apt.parse_line("pmstatus:libc6:42.8571:Unpacking libc6 (amd64)"),
+        OutputLine::Progress(42.8571, "Unpacking libc6 (amd64)".to_owned())
+    );
+    assert_eq!(
+        Apt.parse_line("E: Sub-process /usr/bin/dpkg returned an error code (1)"),
+        OutputLine::Error("Sub-process /usr/bin/dpkg returned an error code (1)".to_owned())
+    );
+    assert_eq!(
+        Apt.parse_line("Reading package lists..."),
+        OutputLine::Other
+    );
+    assert_eq!(
+        Oma.parse_line("\x1b[31mERROR\x1b[0m Failed to download package"),
+        OutputLine::Error("Failed to download package".to_owned())
+    );
+    assert!(by_name("pacman").is_err());
+    assert_eq!(
+        Oma.install(&["devel-base".to_owned(), "gdb".to_owned()])
+            .args,
+        vec!["oma", "install", "-y", "devel-base", "gdb"]
+    );
+    let failure = Failure {
+        status: 100,
+        command: "apt-get full-upgrade".to_owned(),
+        errors: vec!["Unable to fetch some archives".to_owned()],
+    };
+    assert_eq!(
+        failure.to_string(),
+        "`apt-get full-upgrade` failed (exit status 100):\n  Unable to fetch some archives"
+    );
+}
@@ -0,0 +1,328 @@
+//! This module contains the preflight checks of the packages to build
+
+use anyhow::{bail, Result};
+use console::style;
+use std::{
+    collections::HashSet,
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    config::CielConfig,
+    error, info,
+    tree::{self, dependency_name, lookup, parse_assignments},
+};
+
+/// Fields every `defines` must set
+const REQUIRED_DEFINES: &[&str] = &["PKGNAME", "PKGSEC", "PKGDES"];
+
+/// A problem found in the scripts of a package
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    pub file: PathBuf,
+    /// Line number (starting from 1), if the problem is on a line
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{}:{}: {}", self.file.display(), line, self.message),
+            None => write!(f, "{}: {}", self.file.display(), self.message),
+        }
+    }
+}
+
+/// Whether the line assigns a variable with spaces around `=` (e.g. `VER = 1.0`), which the
+/// shell runs as a command instead
+fn is_spaced_assignment(line: &str) -> bool {
+    let line = line.trim();
+    let key_len = line
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .unwrap_or(line.len());
+    if key_len == 0 || line.starts_with(|c: char| c.is_ascii_digit()) {
+        return false;
+    }
+    let rest = &line[key_len..];
+    let value = match rest.trim_start().strip_prefix('=') {
+        Some(value) if !value.starts_with('=') => value,
+        _ => return false,
+    };
+
+    rest.len() != rest.trim_start().len()
+        || (value.starts_with(|c: char| c.is_whitespace()) && !value.trim().is_empty())
+}
+
+/// Check the shell syntax of the script, returning the line numbers with the problems
+fn check_syntax(script: &str) -> Vec<(usize, String)> {
+    let mut problems = Vec::new();
+    // the quote left open, and the line it was opened on
+    let mut open: Option<(usize, char)> = None;
+    for (n, line) in script.lines().enumerate() {
+        if open.is_none() && is_spaced_assignment(line) {
+            problems.push((n + 1, "no spaces are allowed around `=`".to_owned()));
+        }
+        let mut chars = line.chars();
+        let mut word_start = true;
+        while let Some(c) = chars.next() {
+            match (open, c) {
+                (Some((_, '\'')), '\'') => open = None,
+                (Some((_, '\'')), _) => (),
+                (Some(_), '\\') | (None, '\\') => {
+                    chars.next();
+                }
+                (Some(_), '"') => open = None,
+                (Some(_), _) => (),
+                (None, '#') if word_start => break,
+                (None, '\'') | (None, '"') => open = Some((n + 1, c)),
+                (None, _) => (),
+            }
+            word_start = c.is_whitespace() || c == ';';
+        }
+    }
+    if let Some((line, quote)) = open {
+        problems.push((line, format!("unterminated quote ({})", quote)));
+    }
+
+    problems
+}
+
+/// Return the line number where the variable is (last) assigned
+fn line_of(script: &str, key: &str) -> Option<usize> {
+    let prefix = format!("{}=", key);
+    script
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| line.trim_start().starts_with(&prefix))
+        .last()
+        .map(|(n, _)| n + 1)
+}
+
+#[inline]
+fn is_valid_checksum(checksum: &str) -> bool {
+    checksum == "SKIP"
+        || checksum.split_once("::").map_or(false, |(algo, hash)| {
+            !algo.is_empty() && !hash.is_empty() && hash.chars().all(|c| c.is_ascii_hexdigit())
+        })
+}
+
+/// Check the sources and their checksums in the `spec`
+fn check_spec(file: &Path, script: &str) -> Vec<Problem> {
+    let problem = |line: Option<usize>, message: String| Problem {
+        file: file.to_owned(),
+        line,
+        message,
+    };
+    let mut problems = check_syntax(script)
+        .into_iter()
+        .map(|(line, message)| problem(Some(line), message))
+        .collect::<Vec<_>>();
+    let assignments = parse_assignments(script);
+    if lookup(&assignments, "VER").is_none() {
+        problems.push(problem(None, "VER is not set".to_owned()));
+    }
+    if matches!(lookup(&assignments, "DUMMYSRC"), Some("1") | Some("true")) {
+        return problems;
+    }
+    let words = |key: &str| -> Vec<&str> {
+        lookup(&assignments, key)
+            .map(|v| v.split_whitespace().collect())
+            .unwrap_or_default()
+    };
+    // the sources may be per architecture (`SRCS__AMD64` with `CHKSUMS__AMD64`)
+    let mut suffixes = assignments
+        .iter()
+        .filter_map(|(key, _)| key.strip_prefix("SRCS"))
+        .filter(|suffix| suffix.is_empty() || suffix.starts_with("__"))
+        .collect::<Vec<_>>();
+    suffixes.sort_unstable();
+    suffixes.dedup();
+    if suffixes.is_empty() {
+        problems.push(problem(
+            None,
+            "SRCS is not set (set DUMMYSRC=1 if the package has no sources)".to_owned(),
+        ));
+    }
+    for suffix in suffixes {
+        let sources_key = format!("SRCS{}", suffix);
+        let checksums_key = format!("CHKSUMS{}", suffix);
+        let sources = words(&sources_key);
+        let checksums = words(&checksums_key);
+        if checksums.is_empty() {
+            problems.push(problem(
+                line_of(script, &sources_key),
+                format!("{} is not set", checksums_key),
+            ));
+        } else if sources.len() != checksums.len() {
+            problems.push(problem(
+                line_of(script, &checksums_key),
+                format!(
+                    "{} source(s) but {} checksum(s) in {}",
+                    sources.len(),
+                    checksums.len(),
+                    checksums_key
+                ),
+            ));
+        }
+        for checksum in checksums.iter().filter(|c| !is_valid_checksum(c)) {
+            problems.push(problem(
+                line_of(script, &checksums_key),
+                format!(
+                    "invalid checksum `{}` (expected `<algorithm>::<hash>` or SKIP)",
+                    checksum
+                ),
+            ));
+        }
+    }
+
+    problems
+}
+
+/// Check the required fields and the dependencies in the `defines`
+fn check_defines(file: &Path, script: &str, known: &HashSet<String>) -> Vec<Problem> {
+    let problem = |line: Option<usize>, message: String| Problem {
+        file: file.to_owned(),
+        line,
+        message,
+    };
+    let mut problems = check_syntax(script)
+        .into_iter()
+        .map(|(line, message)| problem(Some(line), message))
+        .collect::<Vec<_>>();
+    let assignments = parse_assignments(script);
+    for key in REQUIRED_DEFINES {
+        if lookup(&assignments, key).map_or(true, |v| v.is_empty()) {
+            problems.push(problem(None, format!("{} is not set", key)));
+        }
+    }
+    for key in ["PKGDEP", "BUILDDEP"] {
+        let deps = lookup(&assignments, key).unwrap_or_default();
+        for dep in deps.split_whitespace().map(dependency_name) {
+            if !dep.is_empty() && !dep.contains('$') && !known.contains(dep) {
+                problems.push(problem(
+                    line_of(script, key),
+                    format!("dependency `{}` is not found in the trees", dep),
+                ));
+            }
+        }
+    }
+
+    problems
+}
+
+/// Check the scripts of the package in the directory
+pub fn check_package(dir: &Path, known: &HashSet<String>) -> Vec<Problem> {
+    let spec = dir.join("spec");
+    let mut problems = match fs::read_to_string(&spec) {
+        Ok(script) => check_spec(&spec, &script),
+        Err(e) => vec![Problem {
+            file: spec,
+            line: None,
+            message: e.to_string(),
+        }],
+    };
+    let defines = tree::defines_files(dir);
+    if defines.is_empty() {
+        problems.push(Problem {
+            file: dir.join("autobuild/defines"),
+            line: None,
+            message: "the package has no defines".to_owned(),
+        });
+    }
+    for file in defines {
+        match fs::read_to_string(&file) {
+            Ok(script) => problems.extend(check_defines(&file, &script, known)),
+            Err(e) => problems.push(Problem {
+                file,
+                line: None,
+                message: e.to_string(),
+            }),
+        }
+    }
+
+    problems
+}
+
+/// Check the packages to build, failing with the problems found. The packages not found in
+/// the trees are left to ACBS
+pub fn check_packages(config: &CielConfig, packages: &[String]) -> Result<()> {
+    let dirs = packages
+        .iter()
+        .filter_map(|package| tree::find_package(config, package))
+        .collect::<Vec<_>>();
+    if dirs.is_empty() {
+        return Ok(());
+    }
+    info!("Checking the scripts of {} package(s) ...", dirs.len());
+    let known = tree::package_names(config);
+    let mut failed = 0;
+    for dir in dirs {
+        let problems = check_package(&dir, &known);
+        if !problems.is_empty() {
+            failed += 1;
+        }
+        for problem in problems {
+            error!("{}", problem);
+        }
+    }
+    if failed > 0 {
+        bail!(
+            "The preflight checks failed for {} package(s), use --no-preflight to build anyway.",
+            failed
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_check_syntax() {
+    assert!(check_syntax("VER=1.0\nSRCS=\"tbl::https://example.com/a.tar.gz\"\n").is_empty());
+    assert!(check_syntax("PKGDES=\"foo's \\\"quoted\\\" bar\" # it's fine\n").is_empty());
+    assert_eq!(
+        check_syntax("VER = 1.0\nPKGDEP=\"glibc\n  zlib\nPKGSEC=libs\n"),
+        vec![
+            (1, "no spaces are allowed around `=`".to_owned()),
+            (2, "unterminated quote (\")".to_owned()),
+        ]
+    );
+}
+
+#[test]
+fn test_check_defines() {
+    let known = vec!["glibc".to_owned()].into_iter().collect::<HashSet<_>>();
+    let problems = check_defines(
+        Path::new("defines"),
+        "PKGNAME=foo\nPKGSEC=libs\nPKGDES=\"Foo\"\nPKGDEP=\"glibc zlib>=1.2\"\n",
+        &known,
+    );
+    assert_eq!(
+        problems.iter().map(|p| p.to_string()).collect::<Vec<_>>(),
+        vec!["defines:4: dependency `zlib` is not found in the trees"]
+    );
+}
+
+#[test]
+fn test_check_spec() {
+    let problems = check_spec(
+        Path::new("spec"),
+        "VER=1.0\nSRCS=\"tbl::a git::commit=v1::b\"\nCHKSUMS=\"sha256::abc\"\n",
+    );
+    assert_eq!(
+        problems[0].to_string(),
+        "spec:3: 2 source(s) but 1 checksum(s) in CHKSUMS"
+    );
+    let spec = "VER=1.0\nSRCS__AMD64=\"file::a\"\nCHKSUMS__AMD64=\"sha256::abc\"\nSRCS__ARM64=\"file::b\"\n";
+    assert_eq!(
+        check_spec(Path::new("spec"), spec)
+            .iter()
+            .map(|p| p.to_string())
+            .collect::<Vec<_>>(),
+        vec!["spec:4: CHKSUMS__ARM64 is not set"]
+    );
+    assert!(check_spec(Path::new("spec"), "VER=1.0\n")[0]
+        .to_string()
+        .contains("SRCS is not set"));
+}
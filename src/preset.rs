@@ -0,0 +1,81 @@
+//! This module contains the helpers shared by the presets of the instances (the system call
+//! filters and the capability sets)
+
+use anyhow::{bail, Result};
+
+/// Return the preset of the name, `kind` is the name of the setting in the error
+pub fn parse<T: Copy>(kind: &str, presets: &[(&str, T)], name: &str) -> Result<T> {
+    match presets.iter().find(|(n, _)| *n == name) {
+        Some((_, preset)) => Ok(*preset),
+        None => bail!(
+            "Unknown {} preset `{}`: expected {}",
+            kind,
+            name,
+            presets
+                .iter()
+                .map(|(n, _)| *n)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
+
+/// Merge the lists of the configuration into the lists of the preset, the names in either list
+/// of the configuration override the preset. Returns the names added and removed, `verbs` are
+/// the names of the lists in the error (e.g. `("added", "dropped")`)
+pub fn merge(
+    preset: (&[&str], &[&str]),
+    add: &[String],
+    remove: &[String],
+    verbs: (&str, &str),
+) -> Result<(Vec<String>, Vec<String>)> {
+    if let Some(name) = add.iter().find(|n| remove.contains(n)) {
+        bail!("`{}` is both {} and {}", name, verbs.0, verbs.1);
+    }
+    let mut added = preset
+        .0
+        .iter()
+        .map(|n| n.to_string())
+        .filter(|n| !remove.contains(n))
+        .collect::<Vec<_>>();
+    let mut removed = preset
+        .1
+        .iter()
+        .map(|n| n.to_string())
+        .filter(|n| !add.contains(n))
+        .collect::<Vec<_>>();
+    for name in add {
+        if !added.contains(name) {
+            added.push(name.clone());
+        }
+    }
+    for name in remove {
+        if !removed.contains(name) {
+            removed.push(name.clone());
+        }
+    }
+
+    Ok((added, removed))
+}
+
+#[test]
+fn test_merge() {
+    let (added, removed) = merge(
+        (&["a", "b"], &["c"]),
+        &["c".to_owned(), "d".to_owned()],
+        &["b".to_owned()],
+        ("added", "removed"),
+    )
+    .unwrap();
+    assert_eq!(added, vec!["a", "c", "d"]);
+    assert_eq!(removed, vec!["b"]);
+    assert!(merge(
+        (&[], &[]),
+        &["a".to_owned()],
+        &["a".to_owned()],
+        ("added", "removed")
+    )
+    .is_err());
+    assert_eq!(parse("test", &[("one", 1), ("two", 2)], "two").unwrap(), 2);
+    assert!(parse("test", &[("one", 1)], "three").is_err());
+}
@@ -0,0 +1,113 @@
+//! This module contains the configuration profiles (presets) related APIs
+
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use std::{fs, path::Path};
+
+use crate::config::CielConfig;
+
+/// Profiles added by the user, stored as `<name>.toml` in this directory
+const CUSTOM_PROFILES_LOCATION: &str = ".ciel/data/profiles";
+/// Profiles shipped with Ciel
+const BUILTIN_PROFILES: &[(&str, &str)] = &[
+    ("mainline", include_str!("../profiles/mainline.toml")),
+    (
+        "stable-proposed",
+        include_str!("../profiles/stable-proposed.toml"),
+    ),
+    ("retro", include_str!("../profiles/retro.toml")),
+];
+
+/// A set of configuration values for a common use case
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Profile {
+    #[serde(default)]
+    pub description: String,
+    /// Branch of the ABBS tree
+    pub branch: Option<String>,
+    #[serde(default)]
+    pub apt_sources: Vec<String>,
+    /// Replaces the extra options of systemd-nspawn if set
+    pub nspawn_extra_options: Option<Vec<String>>,
+}
+
+impl Profile {
+    /// Apply the profile to the configuration
+    pub fn apply(&self, name: &str, config: &mut CielConfig) {
+        config.profile = Some(name.to_owned());
+        if !self.apt_sources.is_empty() {
+            config.apt_sources = self.apt_sources.join("\n");
+        }
+        if let Some(options) = &self.nspawn_extra_options {
+            config.extra_options = options.clone();
+        }
+    }
+}
+
+/// Return the names of all the available profiles (including the profiles of the workspace)
+pub fn list_profiles() -> Vec<String> {
+    let mut names = BUILTIN_PROFILES
+        .iter()
+        .map(|(name, _)| name.to_string())
+        .collect::<Vec<_>>();
+    if let Ok(dir) = fs::read_dir(CUSTOM_PROFILES_LOCATION) {
+        for entry in dir.filter_map(|e| e.ok()) {
+            let path = entry.path();
+            if path.extension().map(|ext| ext == "toml").unwrap_or(false) {
+                if let Some(name) = path.file_stem() {
+                    let name = name.to_string_lossy().to_string();
+                    if !names.contains(&name) {
+                        names.push(name);
+                    }
+                }
+            }
+        }
+    }
+
+    names
+}
+
+/// Load the profile by its name, profiles in the workspace take precedence over the built-in ones
+pub fn load_profile(name: &str) -> Result<Profile> {
+    let custom = Path::new(CUSTOM_PROFILES_LOCATION).join(format!("{}.toml", name));
+    let data = if custom.is_file() && !name.contains('/') {
+        fs::read_to_string(custom)?
+    } else if let Some((_, data)) = BUILTIN_PROFILES.iter().find(|(n, _)| *n == name) {
+        data.to_string()
+    } else {
+        return Err(anyhow!(
+            "Unknown profile `{}` (available profiles: {}).",
+            name,
+            list_profiles().join(", ")
+        ));
+    };
+
+    toml::from_str(&data).map_err(|e| anyhow!("Invalid profile `{}`: {}", name, e))
+}
+
+#[test]
+fn test_builtin_profiles() {
+    for (name, _) in BUILTIN_PROFILES {
+        let profile = load_profile(name).unwrap();
+        assert!(profile.branch.is_some());
+        assert!(!profile.apt_sources.is_empty());
+    }
+    assert!(load_profile("no-such-profile").is_err());
+}
+
+#[test]
+fn test_apply_profile() {
+    let mut config = CielConfig {
+        extra_options: vec!["--bind=/srv".to_owned()],
+        ..Default::default()
+    };
+    load_profile("mainline")
+        .unwrap()
+        .apply("mainline", &mut config);
+    assert_eq!(config.profile.as_deref(), Some("mainline"));
+    assert_eq!(config.extra_options, vec!["--bind=/srv"]);
+    let profile: Profile = toml::from_str("nspawn-extra-options = []").unwrap();
+    profile.apply("custom", &mut config);
+    assert!(config.extra_options.is_empty());
+}
@@ -0,0 +1,509 @@
+//! This module contains the progress reporting APIs of the long-running operations
+
+use console::style;
+use indicatif::HumanBytes;
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    io::Read,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{info, make_progress_bar};
+
+/// Minimum interval between two update events of the same task
+const UPDATE_INTERVAL: Duration = Duration::from_millis(200);
+/// Minimum interval between two log lines of the same task, when not attended
+const LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+lazy_static! {
+    static ref SPINNER_STYLE: indicatif::ProgressStyle =
+        indicatif::ProgressStyle::default_spinner()
+            .tick_chars("⠋⠙⠸⠴⠦⠇ ")
+            .template("{spinner:.green} {wide_msg}")
+            .unwrap();
+}
+
+/// Unit of the progress values
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Unit {
+    Bytes,
+    Objects,
+    /// Stages of a task (see [Progress::step]), or an activity without measurable progress
+    /// (a spinner) if the total is unknown
+    Steps,
+}
+
+/// A progress event, serialized as `{"event": "start", ...}`
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+pub enum ProgressEvent {
+    Start {
+        id: usize,
+        task: String,
+        unit: Unit,
+        total: Option<u64>,
+    },
+    Update {
+        id: usize,
+        current: u64,
+        total: Option<u64>,
+        /// Average speed in units per second
+        speed: u64,
+        /// Estimated remaining time in seconds
+        eta: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+    },
+    Finish {
+        id: usize,
+        current: u64,
+    },
+}
+
+/// Receiver of the progress events
+pub trait ProgressSink: Send + Sync {
+    fn handle(&self, event: &ProgressEvent);
+}
+
+/// Describe the position of a task for humans, e.g. `1.00 MiB of 4.00 MiB (25%)`
+fn describe_position(unit: Unit, current: u64, total: Option<u64>) -> String {
+    let (current_text, total_text) = match unit {
+        Unit::Bytes => (
+            HumanBytes(current).to_string(),
+            total.map(|t| HumanBytes(t).to_string()),
+        ),
+        _ => (current.to_string(), total.map(|t| t.to_string())),
+    };
+    match (total, total_text) {
+        (Some(total), Some(total_text)) if total > 0 => format!(
+            "{} of {} ({}%)",
+            current_text,
+            total_text,
+            current.min(total) * 100 / total
+        ),
+        _ => current_text,
+    }
+}
+
+/// A task drawn on the terminal, the stages of a task in steps are printed as lines instead
+struct TerminalTask {
+    bar: Option<indicatif::ProgressBar>,
+    unit: Unit,
+}
+
+/// Draws a progress bar on the terminal for each of the tasks
+struct TerminalSink {
+    multi: indicatif::MultiProgress,
+    bars: Mutex<HashMap<usize, TerminalTask>>,
+}
+
+impl TerminalSink {
+    fn new() -> Self {
+        TerminalSink {
+            multi: indicatif::MultiProgress::with_draw_target(
+                indicatif::ProgressDrawTarget::stderr_with_hz(5),
+            ),
+            bars: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl ProgressSink for TerminalSink {
+    fn handle(&self, event: &ProgressEvent) {
+        let mut bars = self.bars.lock().unwrap();
+        match event {
+            ProgressEvent::Start {
+                id,
+                task,
+                unit,
+                total,
+            } => {
+                let template = match (unit, total) {
+                    (Unit::Bytes, _) => make_progress_bar!("{msg} {bytes}/{total_bytes}"),
+                    (Unit::Objects, _) => "[{bar:25.cyan/blue}] {pos}/{len} {msg} ({eta})",
+                    (Unit::Steps, Some(_)) => {
+                        // the stages usually run commands printing to the terminal
+                        self.multi.println(task).ok();
+                        bars.insert(
+                            *id,
+                            TerminalTask {
+                                bar: None,
+                                unit: *unit,
+                            },
+                        );
+                        return;
+                    }
+                    (Unit::Steps, None) => {
+                        let bar = self.multi.add(indicatif::ProgressBar::new_spinner());
+                        bar.set_style(SPINNER_STYLE.clone());
+                        bar.set_message(task.clone());
+                        bar.enable_steady_tick(UPDATE_INTERVAL);
+                        bars.insert(
+                            *id,
+                            TerminalTask {
+                                bar: Some(bar),
+                                unit: *unit,
+                            },
+                        );
+                        return;
+                    }
+                };
+                let bar = self
+                    .multi
+                    .add(indicatif::ProgressBar::new(total.unwrap_or(0)));
+                bar.set_style(
+                    indicatif::ProgressStyle::default_bar()
+                        .template(template)
+                        .unwrap(),
+                );
+                bar.set_message(task.clone());
+                bars.insert(
+                    *id,
+                    TerminalTask {
+                        bar: Some(bar),
+                        unit: *unit,
+                    },
+                );
+            }
+            ProgressEvent::Update {
+                id,
+                current,
+                total,
+                message,
+                ..
+            } => match bars.get(id) {
+                Some(TerminalTask {
+                    bar: Some(bar),
+                    unit,
+                }) => {
+                    if let Some(total) = total.filter(|_| *unit != Unit::Steps) {
+                        bar.set_length(total);
+                    }
+                    bar.set_position(*current);
+                    if let Some(message) = message {
+                        bar.set_message(message.clone());
+                    }
+                }
+                Some(TerminalTask { bar: None, .. }) => {
+                    let line = format!(
+                        "[{}/{}] {}",
+                        current,
+                        total.unwrap_or(0),
+                        message.as_deref().unwrap_or_default()
+                    );
+                    self.multi.println(style(line).bold().to_string()).ok();
+                }
+                None => (),
+            },
+            ProgressEvent::Finish { id, .. } => {
+                if let Some(TerminalTask { bar: Some(bar), .. }) = bars.remove(id) {
+                    bar.finish_and_clear();
+                    self.multi.remove(&bar);
+                }
+            }
+        }
+    }
+}
+
+/// A task logged by [PlainSink]
+struct PlainTask {
+    task: String,
+    unit: Unit,
+    last_logged: Instant,
+}
+
+/// Prints the progress as log lines, for the logs of the unattended runs
+struct PlainSink {
+    tasks: Mutex<HashMap<usize, PlainTask>>,
+}
+
+impl ProgressSink for PlainSink {
+    fn handle(&self, event: &ProgressEvent) {
+        let mut tasks = self.tasks.lock().unwrap();
+        match event {
+            ProgressEvent::Start { id, task, unit, .. } => {
+                if task.ends_with("...") {
+                    info!("{}", task);
+                } else {
+                    info!("{}: started.", task);
+                }
+                tasks.insert(
+                    *id,
+                    PlainTask {
+                        task: task.trim_end_matches('.').to_owned(),
+                        unit: *unit,
+                        last_logged: Instant::now(),
+                    },
+                );
+            }
+            ProgressEvent::Update {
+                id,
+                current,
+                total,
+                message,
+                ..
+            } => {
+                let task = match tasks.get_mut(id) {
+                    Some(task) => task,
+                    None => return,
+                };
+                if task.unit == Unit::Steps {
+                    info!(
+                        "{}: [{}/{}] {}",
+                        task.task,
+                        current,
+                        total.unwrap_or(0),
+                        message.as_deref().unwrap_or_default()
+                    );
+                    return;
+                }
+                if task.last_logged.elapsed() < LOG_INTERVAL {
+                    return;
+                }
+                task.last_logged = Instant::now();
+                info!(
+                    "{}: {}",
+                    task.task,
+                    describe_position(task.unit, *current, *total)
+                );
+            }
+            ProgressEvent::Finish { id, current } => {
+                if let Some(task) = tasks.remove(id) {
+                    if task.unit == Unit::Steps {
+                        info!("{}: done.", task.task);
+                    } else {
+                        info!(
+                            "{}: done, {}.",
+                            task.task,
+                            describe_position(task.unit, *current, None)
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Prints the events as JSON lines to the standard error, among the log messages
+struct JsonSink;
+
+impl ProgressSink for JsonSink {
+    fn handle(&self, event: &ProgressEvent) {
+        if let Ok(line) = serde_json::to_string(event) {
+            eprintln!("{}", line);
+        }
+    }
+}
+
+/// Draw the progress bars if attended, otherwise log the progress
+fn default_sink() -> Arc<dyn ProgressSink> {
+    if console::user_attended_stderr() {
+        Arc::new(TerminalSink::new())
+    } else {
+        Arc::new(PlainSink {
+            tasks: Mutex::new(HashMap::new()),
+        })
+    }
+}
+
+lazy_static! {
+    static ref SINK: RwLock<Arc<dyn ProgressSink>> = RwLock::new(default_sink());
+}
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Report the progress as JSON lines instead of drawing progress bars
+pub fn use_json_output() {
+    set_sink(Arc::new(JsonSink));
+}
+
+/// Replace the receiver of the progress events
+pub fn set_sink(sink: Arc<dyn ProgressSink>) {
+    *SINK.write().unwrap() = sink;
+}
+
+/// Progress of a task, the events are sent to the sink when the progress is updated
+pub struct Progress {
+    id: usize,
+    unit: Unit,
+    current: AtomicU64,
+    /// 0 if the total is unknown
+    total: AtomicU64,
+    start: Instant,
+    last_update: Mutex<Option<Instant>>,
+    message: Mutex<Option<String>>,
+    finished: AtomicBool,
+    sink: Arc<dyn ProgressSink>,
+}
+
+impl Progress {
+    pub fn new(task: &str, unit: Unit, total: Option<u64>) -> Self {
+        let progress = Progress {
+            id: NEXT_ID.fetch_add(1, Ordering::SeqCst),
+            unit,
+            current: AtomicU64::new(0),
+            total: AtomicU64::new(total.unwrap_or(0)),
+            start: Instant::now(),
+            last_update: Mutex::new(None),
+            message: Mutex::new(None),
+            finished: AtomicBool::new(false),
+            sink: SINK.read().unwrap().clone(),
+        };
+        progress.sink.handle(&ProgressEvent::Start {
+            id: progress.id,
+            task: task.to_owned(),
+            unit,
+            total,
+        });
+
+        progress
+    }
+
+    /// Start an activity without measurable progress, e.g. `Removing the instance...`
+    pub fn spinner(task: &str) -> Self {
+        Progress::new(task, Unit::Steps, None)
+    }
+
+    #[inline]
+    fn total(&self) -> Option<u64> {
+        Some(self.total.load(Ordering::SeqCst)).filter(|t| *t > 0)
+    }
+
+    /// Average speed since the task is started, in units per second
+    fn speed(&self) -> u64 {
+        let elapsed = self.start.elapsed().as_secs_f64();
+        if elapsed <= 0.0 {
+            return 0;
+        }
+
+        (self.current.load(Ordering::SeqCst) as f64 / elapsed) as u64
+    }
+
+    fn update(&self, force: bool) {
+        {
+            let mut last_update = self.last_update.lock().unwrap();
+            if !force && last_update.is_some_and(|t| t.elapsed() < UPDATE_INTERVAL) {
+                return;
+            }
+            *last_update = Some(Instant::now());
+        }
+        let current = self.current.load(Ordering::SeqCst);
+        let total = self.total();
+        let speed = self.speed();
+        let eta = match total {
+            Some(total) if speed > 0 => Some(total.saturating_sub(current) / speed),
+            _ => None,
+        };
+        self.sink.handle(&ProgressEvent::Update {
+            id: self.id,
+            current,
+            total,
+            speed,
+            eta,
+            message: self.message.lock().unwrap().clone(),
+        });
+    }
+
+    pub fn inc(&self, delta: u64) {
+        self.current.fetch_add(delta, Ordering::SeqCst);
+        self.update(false);
+    }
+
+    pub fn set_position(&self, current: u64) {
+        self.current.store(current, Ordering::SeqCst);
+        self.update(false);
+    }
+
+    pub fn set_total(&self, total: u64) {
+        self.total.store(total, Ordering::SeqCst);
+        self.update(false);
+    }
+
+    pub fn set_message(&self, message: &str) {
+        *self.message.lock().unwrap() = Some(message.to_owned());
+        self.update(false);
+    }
+
+    /// Start the next stage of the task (in [Unit::Steps])
+    pub fn step(&self, message: &str) {
+        *self.message.lock().unwrap() = Some(message.to_owned());
+        self.current.fetch_add(1, Ordering::SeqCst);
+        self.update(true);
+    }
+
+    /// Mark the task as finished
+    pub fn finish(&self) {
+        if self.finished.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        // the stages are reported as they start
+        if self.unit != Unit::Steps {
+            self.update(true);
+        }
+        self.sink.handle(&ProgressEvent::Finish {
+            id: self.id,
+            current: self.current.load(Ordering::SeqCst),
+        });
+    }
+
+    /// Wrap the reader to report the bytes read
+    pub fn wrap_read<R: Read>(&self, reader: R) -> ProgressReader<'_, R> {
+        ProgressReader {
+            inner: reader,
+            progress: self,
+        }
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+/// A reader reporting the progress of reading
+pub struct ProgressReader<'a, R> {
+    inner: R,
+    progress: &'a Progress,
+}
+
+impl<R: Read> Read for ProgressReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.progress.inc(n as u64);
+
+        Ok(n)
+    }
+}
+
+#[test]
+fn test_describe_position() {
+    assert_eq!(
+        describe_position(Unit::Bytes, 1024 * 1024, Some(4 * 1024 * 1024)),
+        "1.00 MiB of 4.00 MiB (25%)"
+    );
+    assert_eq!(describe_position(Unit::Objects, 3, None), "3");
+    assert_eq!(describe_position(Unit::Objects, 3, Some(0)), "3");
+}
+
+#[test]
+fn test_event_serialization() {
+    let event = ProgressEvent::Update {
+        id: 1,
+        current: 512,
+        total: Some(1024),
+        speed: 256,
+        eta: Some(2),
+        message: None,
+    };
+    assert_eq!(
+        serde_json::to_string(&event).unwrap(),
+        r#"{"event":"update","id":1,"current":512,"total":1024,"speed":256,"eta":2}"#
+    );
+}
@@ -0,0 +1,212 @@
+//! This module contains project quota related APIs (for XFS and ext4)
+
+use anyhow::{anyhow, Result};
+use libmount::mountinfo::Parser;
+use nix::fcntl::{flock, FlockArg};
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+const Q_GETQUOTA: u32 = 0x800007;
+const Q_SETQUOTA: u32 = 0x800008;
+const PRJQUOTA: u32 = 2;
+const QIF_BLIMITS: u32 = 1;
+const FS_XFLAG_PROJINHERIT: u32 = 0x200;
+/// The next project ID to allocate in the workspace
+const PROJECT_ID_COUNTER: &str = ".ciel/data/next-project-id";
+/// Where the project ID allocated to the instance is kept, in the instance directory
+const PROJECT_ID_FILE: &str = "project-id";
+/// The IDs below are left to the projects configured by the administrator
+const FIRST_PROJECT_ID: u32 = 10000;
+
+/// struct fsxattr from <linux/fs.h>
+#[repr(C)]
+#[derive(Default)]
+struct FsXattr {
+    xflags: u32,
+    extsize: u32,
+    nextents: u32,
+    projid: u32,
+    cowextsize: u32,
+    pad: [u8; 8],
+}
+
+/// struct if_dqblk from <linux/quota.h>
+#[repr(C)]
+#[derive(Default)]
+struct IfDqblk {
+    bhardlimit: u64,
+    bsoftlimit: u64,
+    curspace: u64,
+    ihardlimit: u64,
+    isoftlimit: u64,
+    curinodes: u64,
+    btime: u64,
+    itime: u64,
+    valid: u32,
+}
+
+nix::ioctl_read!(fs_ioc_fsgetxattr, b'X', 31, FsXattr);
+nix::ioctl_write_ptr!(fs_ioc_fssetxattr, b'X', 32, FsXattr);
+
+fn stored_project_id(inst_dir: &Path) -> Option<u32> {
+    fs::read_to_string(inst_dir.join(PROJECT_ID_FILE))
+        .ok()
+        .and_then(|id| id.trim().parse().ok())
+}
+
+/// Return the project ID of the instance, allocating the next one from the counter of the
+/// workspace (skipping the IDs already used on the filesystem) if it has none yet
+pub fn project_id(inst_dir: &Path) -> Result<u32> {
+    if let Some(id) = stored_project_id(inst_dir) {
+        return Ok(id);
+    }
+    if let Some(parent) = Path::new(PROJECT_ID_COUNTER).parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut counter = File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(PROJECT_ID_COUNTER)?;
+    flock(counter.as_raw_fd(), FlockArg::LockExclusive)?;
+    let mut next = String::new();
+    counter.read_to_string(&mut next)?;
+    let mut id = next.trim().parse().unwrap_or(FIRST_PROJECT_ID);
+    // e.g. the instances of the other workspaces on the same filesystem
+    while get_quota(inst_dir, id)?.map_or(false, |q| {
+        q.curspace > 0 || q.curinodes > 0 || q.bhardlimit > 0 || q.ihardlimit > 0
+    }) {
+        id += 1;
+    }
+    fs::write(inst_dir.join(PROJECT_ID_FILE), id.to_string())?;
+    counter.set_len(0)?;
+    counter.seek(SeekFrom::Start(0))?;
+    write!(counter, "{}", id + 1)?;
+
+    Ok(id)
+}
+
+/// Find the block device backing the filesystem where the path resides
+fn find_backing_device(path: &Path) -> Result<PathBuf> {
+    let path = fs::canonicalize(path)?;
+    let mountinfo_content: Vec<u8> = fs::read("/proc/self/mountinfo")?;
+    let mut best: Option<(usize, PathBuf)> = None;
+    for mount in Parser::new(&mountinfo_content) {
+        let mount = mount?;
+        let mount_point = Path::new(&mount.mount_point);
+        if !path.starts_with(mount_point) {
+            continue;
+        }
+        let depth = mount_point.components().count();
+        // the last matching mount with the longest path wins (later mounts shadow earlier ones)
+        if best.as_ref().map(|b| b.0 <= depth).unwrap_or(true) {
+            best = Some((depth, PathBuf::from(&mount.mount_source)));
+        }
+    }
+
+    best.map(|b| b.1)
+        .ok_or_else(|| anyhow!("Unable to find the filesystem of {}", path.display()))
+}
+
+/// Return the project ID of the directory, if it passes the ID on to the new files
+fn inherited_project_id(path: &Path) -> Result<Option<u32>> {
+    let f = File::open(path)?;
+    let mut attr = FsXattr::default();
+    // unsafe: ioctl call
+    unsafe { fs_ioc_fsgetxattr(f.as_raw_fd(), &mut attr)? };
+
+    Ok((attr.xflags & FS_XFLAG_PROJINHERIT != 0).then_some(attr.projid))
+}
+
+/// Assign the project ID to the file (or directory)
+fn set_project_id(path: &Path, id: u32) -> Result<()> {
+    let f = File::open(path)?;
+    let mut attr = FsXattr::default();
+    // unsafe: ioctl calls
+    unsafe {
+        fs_ioc_fsgetxattr(f.as_raw_fd(), &mut attr)?;
+        attr.projid = id;
+        if path.is_dir() {
+            attr.xflags |= FS_XFLAG_PROJINHERIT;
+        }
+        fs_ioc_fssetxattr(f.as_raw_fd(), &attr)?;
+    }
+
+    Ok(())
+}
+
+fn quotactl(cmd: u32, path: &Path, id: u32, quota: &mut IfDqblk) -> std::io::Result<()> {
+    let device = find_backing_device(path)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::NotFound, e.to_string()))?;
+    let device = CString::new(device.as_os_str().as_bytes())?;
+    // unsafe: quotactl syscall
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_quotactl,
+            ((cmd << 8) | (PRJQUOTA & 0xff)) as libc::c_int,
+            device.as_ptr(),
+            id as libc::c_int,
+            quota as *mut IfDqblk,
+        )
+    };
+    if result < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Return the usage and the limits of the project on the filesystem where the path resides,
+/// None if the project has never been used there
+fn get_quota(path: &Path, id: u32) -> Result<Option<IfDqblk>> {
+    let mut quota = IfDqblk::default();
+    match quotactl(Q_GETQUOTA, path, id, &mut quota) {
+        Ok(()) => Ok(Some(quota)),
+        Err(e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(None),
+        Err(e) => Err(anyhow!("quotactl failed: {}", e)),
+    }
+}
+
+/// Return the disk space used by the project of the instance (in bytes), as accounted by
+/// the filesystem
+pub fn project_usage(inst_dir: &Path) -> Result<u64> {
+    let id = stored_project_id(inst_dir)
+        .ok_or_else(|| anyhow!("No project assigned to {}", inst_dir.display()))?;
+
+    Ok(get_quota(inst_dir, id)?.map_or(0, |q| q.curspace))
+}
+
+/// Assign the directories (and everything inside them) to the project,
+/// then limit the disk space the project can use. The directories already assigned are
+/// skipped, since the files created inside them inherit the project
+pub fn apply_project_quota(dirs: &[&Path], id: u32, limit: u64) -> Result<()> {
+    for dir in dirs {
+        if inherited_project_id(dir)? == Some(id) {
+            continue;
+        }
+        for entry in WalkDir::new(dir) {
+            let entry = entry?;
+            let file_type = entry.file_type();
+            // special files can not be opened for the ioctl calls
+            if file_type.is_dir() || file_type.is_file() {
+                set_project_id(entry.path(), id)?;
+            }
+        }
+    }
+    let dir = dirs.first().ok_or_else(|| anyhow!("No directory"))?;
+    let mut quota = IfDqblk {
+        // in units of 1 KiB
+        bhardlimit: limit / 1024,
+        bsoftlimit: limit / 1024,
+        valid: QIF_BLIMITS,
+        ..Default::default()
+    };
+    quotactl(Q_SETQUOTA, dir, id, &mut quota).map_err(|e| anyhow!("quotactl failed: {}", e))?;
+
+    Ok(())
+}
@@ -0,0 +1,142 @@
+//! This module contains reflink (copy-on-write copy) related APIs (for XFS and btrfs)
+
+use anyhow::{bail, Result};
+use nix::errno::Errno;
+use nix::fcntl::copy_file_range;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use crate::extract::{entry_path, Extractor};
+
+const USTAR_MAGIC_OFFSET: usize = 257;
+
+nix::ioctl_write_int!(ficlone, 0x94, 9);
+
+/// Copy the file, sharing the data blocks with the source if the filesystem supports reflinks
+pub fn copy_file(from: &Path, to: &Path) -> Result<()> {
+    let src = File::open(from)?;
+    let dst = File::create(to)?;
+    // unsafe: ioctl call
+    if unsafe { ficlone(dst.as_raw_fd(), src.as_raw_fd() as libc::c_ulong) }.is_ok() {
+        fs::set_permissions(to, src.metadata()?.permissions())?;
+        return Ok(());
+    }
+    drop(dst);
+    // std uses copy_file_range(2) when possible, which still avoids copying the data in userspace
+    fs::copy(from, to)?;
+
+    Ok(())
+}
+
+/// Replace the file with a copy of the other file sharing its data blocks, returns `false` if
+/// the filesystem does not support reflinks between them
+pub fn clone_file(from: &Path, to: &Path) -> Result<bool> {
+    let mut temp = to.as_os_str().to_owned();
+    temp.push(".ciel-clone");
+    let temp = std::path::PathBuf::from(temp);
+    let src = File::open(from)?;
+    let dst = File::create(&temp)?;
+    // unsafe: ioctl call
+    if let Err(e) = unsafe { ficlone(dst.as_raw_fd(), src.as_raw_fd() as libc::c_ulong) } {
+        fs::remove_file(&temp).ok();
+        if matches!(
+            e,
+            Errno::EOPNOTSUPP | Errno::ENOTTY | Errno::EXDEV | Errno::EINVAL
+        ) {
+            return Ok(false);
+        }
+        return Err(e.into());
+    }
+    let meta = fs::metadata(to)?;
+    fs::set_permissions(&temp, meta.permissions())?;
+    // the rename is atomic, the file is never missing
+    if let Err(e) = fs::rename(&temp, to) {
+        fs::remove_file(&temp).ok();
+        return Err(e.into());
+    }
+
+    Ok(true)
+}
+
+/// Check if the file is an uncompressed tarball
+pub fn is_plain_tarball(path: &Path) -> Result<bool> {
+    let mut header = [0u8; 512];
+    let mut f = File::open(path)?;
+    if f.read_exact(&mut header).is_err() {
+        return Ok(false);
+    }
+
+    Ok(header[USTAR_MAGIC_OFFSET..].starts_with(b"ustar"))
+}
+
+/// Copy `len` bytes starting at `offset` of the source file to the destination file,
+/// returns false if the filesystem does not support copying between the files
+fn copy_range(src: &File, offset: u64, dst: &File, len: u64) -> Result<bool> {
+    let mut off_in = offset as libc::loff_t;
+    let mut remaining = len as usize;
+    while remaining > 0 {
+        match copy_file_range(
+            src.as_raw_fd(),
+            Some(&mut off_in),
+            dst.as_raw_fd(),
+            None,
+            remaining,
+        ) {
+            Ok(0) => bail!("Unexpected end of the tarball"),
+            Ok(n) => remaining -= n,
+            Err(Errno::EXDEV)
+            | Err(Errno::ENOSYS)
+            | Err(Errno::EINVAL)
+            | Err(Errno::EOPNOTSUPP)
+                if remaining == len as usize =>
+            {
+                return Ok(false)
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(true)
+}
+
+/// Extract the uncompressed tarball and preserve all the file attributes.
+/// The file contents are copied with copy_file_range(2), so that the extracted files can share
+/// the data blocks with the tarball when it is located on the same XFS or btrfs filesystem.
+/// Only the uncompressed tarballs (e.g. decompressed by the user beforehand) can be extracted
+/// this way, the compressed OS tarballs as released are extracted as a stream, and a block is
+/// only shared when the file happens to start at a filesystem block boundary in the tarball
+pub fn extract_tarball(path: &Path, dest: &Path) -> Result<()> {
+    let tarball = File::open(path)?;
+    let mut archive = Extractor::archive(&tarball);
+    let mut extractor = Extractor::new(dest)?;
+    let dest = extractor.dest().to_owned();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            // directories, links, special files, etc.
+            extractor.unpack(&mut entry)?;
+            continue;
+        }
+        let target = dest.join(entry_path(&entry.path()?)?);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+            // do not follow the symlinks created by the previous entries out of the destination
+            if !fs::canonicalize(parent)?.starts_with(&dest) {
+                bail!("Invalid path in the tarball: {}", target.display());
+            }
+        }
+        if fs::symlink_metadata(&target).is_ok() {
+            fs::remove_file(&target)?;
+        }
+        let out = File::create(&target)?;
+        if !copy_range(&tarball, entry.raw_file_position(), &out, entry.size())? {
+            io::copy(&mut entry, &mut &out)?;
+        }
+        extractor.unpacked(&mut entry, &target)?;
+    }
+    extractor.finish()?;
+
+    Ok(())
+}
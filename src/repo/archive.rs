@@ -0,0 +1,118 @@
+//! Archiving of the package sources alongside the built packages
+
+use super::scan::sha256sum;
+use crate::{buildlog::BuildRecord, config::CielConfig, tree};
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+use xz2::write::XzEncoder;
+
+/// Where the archives are put in the output directory
+const SOURCES_DIR: &str = "sources";
+
+#[derive(Debug, Serialize)]
+struct ArchivedPackage {
+    file: String,
+    sha256: String,
+}
+
+/// The manifest of the archived sources
+#[derive(Debug, Serialize)]
+struct SourceManifest {
+    package: String,
+    version: Option<String>,
+    /// Path of the package in the tree
+    path: String,
+    /// Commit of the tree the package is from, and if the package has uncommitted changes
+    commit: Option<String>,
+    dirty: bool,
+    sources: Vec<String>,
+    checksums: Vec<String>,
+    /// When the build started, in seconds since the epoch
+    built: u64,
+    debs: Vec<ArchivedPackage>,
+}
+
+/// Return the commit of the repository containing the directory, and if the directory has
+/// uncommitted changes
+fn tree_revision(dir: &Path) -> Option<(String, bool)> {
+    let repo = git2::Repository::discover(dir).ok()?;
+    let commit = repo.head().ok()?.peel_to_commit().ok()?.id().to_string();
+    let workdir = repo.workdir()?.canonicalize().ok()?;
+    let rel_path = dir.canonicalize().ok()?;
+    let rel_path = rel_path.strip_prefix(&workdir).ok()?;
+    let mut options = git2::StatusOptions::new();
+    options
+        .include_untracked(true)
+        .pathspec(rel_path.to_string_lossy().as_ref());
+    let dirty = repo
+        .statuses(Some(&mut options))
+        .map(|s| !s.is_empty())
+        .unwrap_or(true);
+
+    Some((commit, dirty))
+}
+
+/// Archive the sources of the package built, returns the path to the archive
+pub fn archive_sources(
+    config: &CielConfig,
+    root: &Path,
+    package: &str,
+    record: &BuildRecord,
+) -> Result<PathBuf> {
+    let dir = tree::find_package(config, package)
+        .ok_or_else(|| anyhow!("Unable to find {} in the tree", package))?;
+    let spec = tree::read_package_spec(&dir)?;
+    let section_dir = dir.parent().unwrap_or(&dir);
+    let rel_path = dir
+        .strip_prefix(section_dir.parent().unwrap_or(section_dir))
+        .unwrap_or(&dir)
+        .to_path_buf();
+    let (commit, dirty) = match tree_revision(&dir) {
+        Some((commit, dirty)) => (Some(commit), dirty),
+        None => (None, true),
+    };
+    let mut debs = Vec::new();
+    for deb in record.debs.iter() {
+        debs.push(ArchivedPackage {
+            file: deb.clone(),
+            sha256: sha256sum(fs::File::open(root.join("debs").join(deb))?)?,
+        });
+    }
+    let manifest = SourceManifest {
+        package: spec.name.clone(),
+        version: spec.version.clone(),
+        path: rel_path.to_string_lossy().to_string(),
+        commit,
+        dirty,
+        sources: spec.sources,
+        checksums: spec.checksums,
+        built: record.started,
+        debs,
+    };
+
+    let output = root.join(SOURCES_DIR);
+    fs::create_dir_all(&output)?;
+    let version = spec
+        .version
+        .unwrap_or_else(|| record.started.to_string())
+        .replace(':', "+");
+    let path = output.join(format!("{}_{}.tar.xz", spec.name, version));
+    let tmp_path = path.with_extension("xz.tmp");
+    let mut builder = tar::Builder::new(XzEncoder::new(fs::File::create(&tmp_path)?, 6));
+    builder.follow_symlinks(false);
+    builder.append_dir_all(&rel_path, &dir)?;
+    let manifest = serde_json::to_vec_pretty(&manifest)?;
+    let mut header = tar::Header::new_gnu();
+    header.set_size(manifest.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(record.started);
+    builder.append_data(&mut header, "MANIFEST.json", &manifest[..])?;
+    builder.into_inner()?.finish()?;
+    fs::rename(tmp_path, &path)?;
+
+    Ok(path)
+}
@@ -0,0 +1,121 @@
+//! This module contains the de-duplication of the identical packages in the output directories
+
+use super::scan::sha256sum;
+use crate::{info, reflink};
+use anyhow::Result;
+use console::style;
+use indicatif::HumanBytes;
+use std::{collections::HashMap, fs, os::unix::fs::MetadataExt, path::PathBuf};
+use walkdir::WalkDir;
+
+/// Return the output directories in the workspace (`OUTPUT` and `OUTPUT-<branch>`)
+pub fn output_directories() -> Result<Vec<PathBuf>> {
+    let mut dirs = Vec::new();
+    for entry in fs::read_dir(".")? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if entry.file_type()?.is_dir() && (name == "OUTPUT" || name.starts_with("OUTPUT-")) {
+            dirs.push(entry.path());
+        }
+    }
+    dirs.sort();
+
+    Ok(dirs)
+}
+
+/// Replace the identical packages in the directories with reflinks (copy-on-write copies, so
+/// overwriting one of them does not change the others), returns the number of the files replaced
+/// and the space reclaimed. The packages on the filesystems without reflinks are left as is.
+pub fn dedupe(dirs: &[PathBuf], dry_run: bool) -> Result<(usize, u64)> {
+    // only the packages of the same size can be identical, which saves most of the hashing
+    let mut by_size: HashMap<u64, Vec<(PathBuf, fs::Metadata)>> = HashMap::new();
+    for dir in dirs {
+        // the packages being staged may still be written
+        for entry in WalkDir::new(dir)
+            .into_iter()
+            .filter_entry(|e| e.file_name() != super::staging::STAGING_DIR)
+            .filter_map(|e| e.ok())
+        {
+            if !entry.file_type().is_file()
+                || !entry.file_name().to_string_lossy().ends_with(".deb")
+            {
+                continue;
+            }
+            let meta = entry.metadata()?;
+            by_size
+                .entry(meta.len())
+                .or_default()
+                .push((entry.into_path(), meta));
+        }
+    }
+    let (mut replaced, mut reclaimed) = (0, 0);
+    for (size, files) in by_size.into_iter().filter(|(_, f)| f.len() > 1) {
+        let mut by_hash: HashMap<String, Vec<(PathBuf, fs::Metadata)>> = HashMap::new();
+        for (path, meta) in files {
+            let hash = sha256sum(fs::File::open(&path)?)?;
+            by_hash.entry(hash).or_default().push((path, meta));
+        }
+        for mut group in by_hash.into_values().filter(|g| g.len() > 1) {
+            group.sort_by(|a, b| a.0.cmp(&b.0));
+            let (original, original_meta) = group.remove(0);
+            for (path, meta) in group {
+                if (meta.dev(), meta.ino()) == (original_meta.dev(), original_meta.ino()) {
+                    continue;
+                }
+                if meta.dev() != original_meta.dev() {
+                    // reflinks can not cross the filesystems
+                    continue;
+                }
+                if dry_run {
+                    info!("Would share {} with {}", path.display(), original.display());
+                } else if !reflink::clone_file(&original, &path)? {
+                    continue;
+                }
+                // the file is only freed if it has no other links
+                if meta.nlink() == 1 {
+                    reclaimed += size;
+                }
+                replaced += 1;
+            }
+        }
+    }
+    info!(
+        "{} duplicated package(s) {}, {} {}.",
+        replaced,
+        if dry_run { "to share" } else { "shared" },
+        HumanBytes(reclaimed),
+        if dry_run { "to reclaim" } else { "reclaimed" }
+    );
+
+    Ok((replaced, reclaimed))
+}
+
+#[test]
+fn test_dedupe() {
+    let dir = tempfile::tempdir().unwrap();
+    let dirs = vec![dir.path().join("OUTPUT"), dir.path().join("OUTPUT-stable")];
+    for (i, output) in dirs.iter().enumerate() {
+        fs::create_dir_all(output.join("debs/f")).unwrap();
+        fs::write(output.join("debs/f/foo_1.0_amd64.deb"), b"foo").unwrap();
+        fs::write(
+            output.join("debs/f/foo-doc_1.0_amd64.deb"),
+            [b'0' + i as u8],
+        )
+        .unwrap();
+    }
+    assert_eq!(dedupe(&dirs, true).unwrap(), (1, 3));
+    let (replaced, _) = dedupe(&dirs, false).unwrap();
+    assert!(replaced <= 1);
+    // the copies are not linked, overwriting one leaves the other intact
+    let foo = dirs[1].join("debs/f/foo_1.0_amd64.deb");
+    assert_eq!(fs::metadata(&foo).unwrap().nlink(), 1);
+    fs::write(&foo, b"bar").unwrap();
+    assert_eq!(
+        fs::read(dirs[0].join("debs/f/foo_1.0_amd64.deb")).unwrap(),
+        b"foo"
+    );
+    assert_eq!(
+        fs::read(dirs[1].join("debs/f/foo-doc_1.0_amd64.deb")).unwrap(),
+        b"1"
+    );
+}
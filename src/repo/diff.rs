@@ -0,0 +1,222 @@
+//! Comparison of the two latest builds of a package in the local repository
+
+use super::{
+    prune::{compare_versions, read_deb_info, DebInfo},
+    scan::{self, DebContents},
+};
+use anyhow::{bail, Result};
+use console::style;
+use indicatif::HumanBytes;
+use std::{collections::BTreeSet, path::Path};
+
+/// The fields of the relationships compared
+const RELATION_FIELDS: &[&str] = &[
+    "Depends",
+    "Pre-Depends",
+    "Recommends",
+    "Suggests",
+    "Breaks",
+    "Conflicts",
+    "Replaces",
+    "Provides",
+];
+
+/// Split the relationship field into the individual relations
+pub(super) fn split_relations(value: Option<&str>) -> BTreeSet<String> {
+    value
+        .unwrap_or_default()
+        .split(',')
+        .map(|r| r.split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|r| !r.is_empty())
+        .collect()
+}
+
+/// Return the (removed, added) items
+fn set_diff<'a, T: Ord>(old: &'a BTreeSet<T>, new: &'a BTreeSet<T>) -> (Vec<&'a T>, Vec<&'a T>) {
+    (old.difference(new).collect(), new.difference(old).collect())
+}
+
+#[inline]
+fn size_delta(old: u64, new: u64) -> String {
+    if new >= old {
+        format!("+{}", HumanBytes(new - old))
+    } else {
+        format!("-{}", HumanBytes(old - new))
+    }
+}
+
+fn print_changes<T: std::fmt::Display>(removed: &[T], added: &[T]) {
+    for item in removed {
+        eprintln!("    {}", style(format!("- {}", item)).red());
+    }
+    for item in added {
+        eprintln!("    {}", style(format!("+ {}", item)).green());
+    }
+}
+
+/// Return the two latest versions (the older one first) of the package, of the architecture of
+/// the latest version
+fn latest_two(root: &Path, name: &str) -> Result<(DebInfo, DebInfo)> {
+    let mut debs = scan::collect_all_packages(root.join("debs"))?
+        .into_iter()
+        .filter(|e| e.file_name().to_string_lossy().starts_with(name))
+        .filter_map(|e| read_deb_info(e.path()).ok())
+        .filter(|d| d.package == name)
+        .collect::<Vec<_>>();
+    debs.sort_by(|a, b| compare_versions(&a.version, &b.version));
+    let latest = match debs.pop() {
+        Some(latest) => latest,
+        None => bail!("Package `{}` is not in the repository.", name),
+    };
+    match debs.into_iter().rev().find(|d| d.arch == latest.arch) {
+        Some(previous) => Ok((previous, latest)),
+        None => bail!(
+            "Only one version of `{}` ({}) is in the repository.",
+            name,
+            latest.version
+        ),
+    }
+}
+
+/// Return the entries (control files as `DEBIAN/...`, data files as `/...`) different in the
+/// two builds of a package, ignoring the metadata of the archives
+pub fn diff_builds(first: &Path, second: &Path) -> Result<Vec<String>> {
+    let (first, second) = (scan::read_digests(first)?, scan::read_digests(second)?);
+    let mut entries = first
+        .iter()
+        .filter(|(path, digest)| second.get(*path) != Some(digest))
+        .map(|(path, _)| path.clone())
+        .collect::<Vec<_>>();
+    entries.extend(
+        second
+            .keys()
+            .filter(|path| !first.contains_key(*path))
+            .cloned(),
+    );
+    entries.sort();
+
+    Ok(entries)
+}
+
+/// Print the differences between the two latest versions of the package: the files, the sizes,
+/// the relationships and the maintainer scripts
+pub fn diff_package(root: &Path, name: &str) -> Result<()> {
+    let (old, new) = latest_two(root, name)?;
+    let (old_contents, new_contents) = (
+        scan::read_contents(&old.path)?,
+        scan::read_contents(&new.path)?,
+    );
+    let control = |contents: &DebContents| {
+        String::from_utf8_lossy(
+            contents
+                .control
+                .get("control")
+                .map_or(&[][..], |c| c.as_slice()),
+        )
+        .to_string()
+    };
+    let (old_control, new_control) = (control(&old_contents), control(&new_contents));
+    eprintln!(
+        "{} {} -> {} ({})",
+        style(name).bold(),
+        old.version,
+        new.version,
+        new.arch
+    );
+
+    let installed_size = |control: &str| -> u64 {
+        scan::control_field(control, "Installed-Size")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(0)
+            * 1024
+    };
+    eprintln!(
+        "  Size: {} -> {} ({})",
+        HumanBytes(old.size),
+        HumanBytes(new.size),
+        size_delta(old.size, new.size)
+    );
+    eprintln!(
+        "  Installed-Size: {} -> {} ({})",
+        HumanBytes(installed_size(&old_control)),
+        HumanBytes(installed_size(&new_control)),
+        size_delta(installed_size(&old_control), installed_size(&new_control))
+    );
+
+    for field in RELATION_FIELDS {
+        let old_relations = split_relations(scan::control_field(&old_control, field));
+        let new_relations = split_relations(scan::control_field(&new_control, field));
+        let (removed, added) = set_diff(&old_relations, &new_relations);
+        if removed.is_empty() && added.is_empty() {
+            continue;
+        }
+        eprintln!("  {}:", field);
+        print_changes(&removed, &added);
+    }
+
+    let old_files = old_contents.files.keys().collect::<BTreeSet<_>>();
+    let new_files = new_contents.files.keys().collect::<BTreeSet<_>>();
+    let (removed, added) = set_diff(&old_files, &new_files);
+    let resized = old_files
+        .intersection(&new_files)
+        .filter(|f| old_contents.files[**f] != new_contents.files[**f])
+        .collect::<Vec<_>>();
+    eprintln!(
+        "  Files: {} removed, {} added, {} changed in size",
+        removed.len(),
+        added.len(),
+        resized.len()
+    );
+    print_changes(&removed, &added);
+    for file in resized {
+        let (old_size, new_size) = (old_contents.files[*file], new_contents.files[*file]);
+        eprintln!(
+            "    {} {} ({})",
+            style("~").yellow(),
+            file,
+            size_delta(old_size, new_size)
+        );
+    }
+
+    let scripts = old_contents
+        .control
+        .keys()
+        .chain(new_contents.control.keys())
+        .filter(|k| !matches!(k.as_str(), "control" | "md5sums"))
+        .collect::<BTreeSet<_>>();
+    for script in scripts {
+        match (
+            old_contents.control.get(script),
+            new_contents.control.get(script),
+        ) {
+            (Some(old_script), Some(new_script)) if old_script == new_script => (),
+            (Some(old_script), Some(new_script)) => {
+                eprintln!("  Script {} changed:", script);
+                let lines = |s: &[u8]| {
+                    String::from_utf8_lossy(s)
+                        .lines()
+                        .map(|l| l.to_owned())
+                        .collect::<BTreeSet<_>>()
+                };
+                let (old_lines, new_lines) = (lines(old_script), lines(new_script));
+                let (removed, added) = set_diff(&old_lines, &new_lines);
+                print_changes(&removed, &added);
+            }
+            (None, Some(_)) => eprintln!("  Script {} added", script),
+            (Some(_), None) => eprintln!("  Script {} removed", script),
+            (None, None) => (),
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_split_relations() {
+    let old = split_relations(Some("glibc (>= 2.36),  zlib, openssl"));
+    let new = split_relations(Some("glibc (>= 2.38), zlib"));
+    let (removed, added) = set_diff(&old, &new);
+    assert_eq!(removed, ["glibc (>= 2.36)", "openssl"]);
+    assert_eq!(added, ["glibc (>= 2.38)"]);
+    assert!(split_relations(None).is_empty());
+}
@@ -1,47 +1,155 @@
 //! Local repository
 
-use crate::info;
+use crate::progress::{Progress, Unit};
+use crate::warn;
 use anyhow::Result;
 use console::style;
+use flate2::write::GzEncoder;
 use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
 use std::io::Write;
 use std::{fs, io, path::Path};
 use time::{format_description::FormatItem, macros::format_description, OffsetDateTime};
+use xz2::write::XzEncoder;
 
+pub mod archive;
+pub mod dedupe;
+pub mod diff;
+pub mod prune;
+pub mod push;
+pub mod qa;
+pub mod query;
 mod scan;
+pub mod sign;
+pub mod staging;
+
+/// Location of the public key of the local repository in the instances
+const LOCAL_KEYRING: &str = "etc/apt/keyrings/ciel-local.gpg";
+/// Where the local repository is mounted in the instances, `/debs` is where the packages
+/// built are staged (see [staging])
+pub const LOCAL_REPO_MOUNT: &str = "/var/lib/ciel/debs/";
+/// Where the indices are written before they are moved into place, in the repository
+const NEW_INDICES: &str = ".indices.new";
+/// The files of the packages for the Contents indices, outside of the repository (not pushed)
+const FILES_CACHE: &str = ".contents-cache.json";
 
 /// Debian 822 date: "%a, %d %b %Y %H:%M:%S %z"
 const DEB822_DATE: &[FormatItem] = format_description!("[weekday repr:short], [day] [month repr:short] [year] [hour repr:24]:[minute]:[second] [offset_hour sign:mandatory][offset_minute]");
 
-fn generate_release(path: &Path) -> Result<String> {
-    let mut f = fs::File::open(path.join("Packages"))?;
-    let mut hasher = Sha256::new();
-    io::copy(&mut f, &mut hasher)?;
-    let result = hasher.finalize();
-    let meta = f.metadata()?;
+fn generate_release(path: &Path, indices: &[String]) -> Result<String> {
     let timestamp = OffsetDateTime::now_utc().format(&DEB822_DATE)?;
+    let mut release = format!("Date: {}\nSHA256:\n", timestamp);
+    for index in indices {
+        let mut f = fs::File::open(path.join(index))?;
+        let mut hasher = Sha256::new();
+        io::copy(&mut f, &mut hasher)?;
+        let result = hasher.finalize();
+        let meta = f.metadata()?;
+        release.push_str(&format!(" {:x} {} {}\n", result, meta.len(), index));
+    }
+
+    Ok(release)
+}
+
+/// Write the index and its compressed variants (.gz and .xz), returns the names of the files
+fn write_index(path: &Path, name: &str, content: &[u8]) -> Result<Vec<String>> {
+    fs::write(path.join(name), content)?;
+    let gz_name = format!("{}.gz", name);
+    let mut gz = GzEncoder::new(
+        fs::File::create(path.join(&gz_name))?,
+        flate2::Compression::default(),
+    );
+    gz.write_all(content)?;
+    gz.finish()?;
+    let xz_name = format!("{}.xz", name);
+    let mut xz = XzEncoder::new(fs::File::create(path.join(&xz_name))?, 6);
+    xz.write_all(content)?;
+    xz.finish()?;
 
-    Ok(format!(
-        "Date: {}\nSHA256:\n {:x} {} Packages\n",
-        timestamp,
-        result,
-        meta.len()
-    ))
+    Ok(vec![name.to_owned(), gz_name, xz_name])
 }
 
-/// Refresh the local repository (Update Packages file)
+/// Generate the Contents indices (`path section/package[,...]`) for each architecture,
+/// the `all` packages are listed in all of them
+fn generate_contents(packages: &[scan::ScannedPackage]) -> BTreeMap<String, Vec<u8>> {
+    let mut archs = packages
+        .iter()
+        .map(|p| p.arch.as_str())
+        .filter(|a| *a != "all")
+        .collect::<BTreeSet<_>>();
+    if archs.is_empty() {
+        archs.insert("all");
+    }
+    let mut contents = BTreeMap::new();
+    for arch in archs {
+        let mut files: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+        for package in packages
+            .iter()
+            .filter(|p| p.arch == arch || p.arch == "all")
+        {
+            for file in package.files.iter() {
+                files
+                    .entry(file.as_str())
+                    .or_default()
+                    .push(format!("{}/{}", package.section, package.package));
+            }
+        }
+        let mut index = Vec::new();
+        for (file, locations) in files {
+            index.extend(format!("{:<59} {}\n", file, locations.join(",")).as_bytes());
+        }
+        contents.insert(format!("Contents-{}", arch), index);
+    }
+
+    contents
+}
+
+/// Refresh the local repository (Update Packages, Contents and Release files)
 pub fn refresh_repo(root: &Path) -> Result<()> {
+    let _lock = staging::lock_exclusive(root)?;
     let path = root.join("debs");
-    fs::create_dir_all(&path)?;
-    let mut output = fs::File::create(path.join("Packages"))?;
+    let new = path.join(NEW_INDICES);
+    fs::create_dir_all(&new)?;
     let entries = scan::collect_all_packages(&path)?;
-    info!("Scanning {} packages...", entries.len());
-    output.write_all(&scan::scan_packages_simple(&entries, &path))?;
-    println!();
+    let progress = Progress::new(
+        "Scanning packages",
+        Unit::Objects,
+        Some(entries.len() as u64),
+    );
+    let cache = scan::FilesCache::load(&root.join(FILES_CACHE));
+    let packages = scan::scan_packages(&entries, &path, &cache, &progress);
+    progress.finish();
+    if let Err(e) = scan::FilesCache::save(&root.join(FILES_CACHE), &packages) {
+        warn!("Could not save the files of the packages: {}", e);
+    }
 
-    let release = generate_release(&path)?;
-    let mut release_file = fs::File::create(path.join("Release"))?;
+    let stanzas = packages
+        .iter()
+        .flat_map(|p| p.stanza.iter().copied())
+        .collect::<Vec<_>>();
+    let mut indices = write_index(&new, "Packages", &stanzas)?;
+    for (name, content) in generate_contents(&packages) {
+        indices.extend(write_index(&new, &name, &content)?);
+    }
+    let release = generate_release(&new, &indices)?;
+    let mut release_file = fs::File::create(new.join("Release"))?;
     release_file.write_all(release.as_bytes())?;
+    sign::sign_release(&new)?;
+    // each file is replaced atomically, the Release (referring to all the others) last
+    for name in
+        indices
+            .iter()
+            .map(String::as_str)
+            .chain(vec!["InRelease", "Release.gpg", "Release"])
+    {
+        if new.join(name).is_file() {
+            fs::rename(new.join(name), path.join(name))?;
+        } else {
+            // the signatures of a workspace without a signing key
+            fs::remove_file(path.join(name)).ok();
+        }
+    }
+    fs::remove_dir(&new).ok();
 
     Ok(())
 }
@@ -51,9 +159,19 @@ pub fn init_repo(repo_root: &Path, rootfs: &Path) -> Result<()> {
     // trigger a refresh, since the metadata is probably out of date
     refresh_repo(repo_root)?;
     fs::create_dir_all(rootfs.join("etc/apt/sources.list.d/"))?;
+    // with a signing key, apt verifies the repository like any other one
+    let options = if let Some(key) = sign::public_key() {
+        let keyring = rootfs.join(LOCAL_KEYRING);
+        fs::create_dir_all(keyring.parent().unwrap())?;
+        fs::copy(key, keyring)?;
+        format!("signed-by=/{}", LOCAL_KEYRING)
+    } else {
+        fs::remove_file(rootfs.join(LOCAL_KEYRING)).ok();
+        "trusted=yes".to_owned()
+    };
     fs::write(
         rootfs.join("etc/apt/sources.list.d/ciel-local.list"),
-        b"deb [trusted=yes] file:///debs/ /",
+        format!("deb [{}] file://{} /", options, LOCAL_REPO_MOUNT),
     )?;
 
     Ok(())
@@ -61,6 +179,8 @@ pub fn init_repo(repo_root: &Path, rootfs: &Path) -> Result<()> {
 
 /// Uninitialize the repository
 pub fn deinit_repo(rootfs: &Path) -> Result<()> {
+    fs::remove_file(rootfs.join(LOCAL_KEYRING)).ok();
+
     Ok(fs::remove_file(
         rootfs.join("etc/apt/sources.list.d/ciel-local.list"),
     )?)
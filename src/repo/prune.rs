@@ -0,0 +1,258 @@
+//! Pruning of the local repository
+
+use super::scan;
+use crate::{info, warn};
+use anyhow::{anyhow, Result};
+use console::style;
+use indicatif::HumanBytes;
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+/// Which packages to remove, every policy is applied on its own
+#[derive(Debug, Default, Clone)]
+pub struct PrunePolicy {
+    /// Keep the last N versions of each package (per architecture)
+    pub keep_last: Option<usize>,
+    /// Remove the packages older than this, the latest version of each package is always kept
+    pub max_age: Option<Duration>,
+    /// Remove the `all` packages superseded by a newer architecture-specific version
+    pub superseded_noarch: bool,
+}
+
+/// A package in the repository
+#[derive(Debug, Clone)]
+pub struct DebInfo {
+    pub path: PathBuf,
+    pub package: String,
+    pub version: String,
+    pub arch: String,
+    pub size: u64,
+    pub modified: SystemTime,
+}
+
+/// Read the name, version and architecture of the package
+pub(super) fn read_deb_info(path: &Path) -> Result<DebInfo> {
+    let control = scan::read_control(path)?;
+    let control = String::from_utf8_lossy(&control);
+    let field = |name| {
+        scan::control_field(&control, name)
+            .map(|v| v.to_owned())
+            .ok_or_else(|| anyhow!("{}: missing {} field", path.display(), name))
+    };
+    let meta = fs::metadata(path)?;
+
+    Ok(DebInfo {
+        path: path.to_path_buf(),
+        package: field("Package")?,
+        version: field("Version")?,
+        arch: field("Architecture")?,
+        size: meta.len(),
+        modified: meta.modified()?,
+    })
+}
+
+/// Compare the non-digit parts of the versions, `~` sorts before everything (even the end)
+fn compare_lexical(a: &str, b: &str) -> Ordering {
+    let order = |c: Option<char>| match c {
+        Some('~') => -1,
+        None => 0,
+        Some(c) if c.is_ascii_alphabetic() => c as i32,
+        Some(c) => c as i32 + 256,
+    };
+    let (mut a, mut b) = (a.chars(), b.chars());
+    loop {
+        let (x, y) = (a.next(), b.next());
+        if x.is_none() && y.is_none() {
+            return Ordering::Equal;
+        }
+        match order(x).cmp(&order(y)) {
+            Ordering::Equal => (),
+            other => return other,
+        }
+    }
+}
+
+/// Compare the upstream versions or the revisions as dpkg does
+fn compare_fragment(mut a: &str, mut b: &str) -> Ordering {
+    while !a.is_empty() || !b.is_empty() {
+        let split = |s: &str, digits: bool| -> usize {
+            s.find(|c: char| c.is_ascii_digit() == !digits)
+                .unwrap_or(s.len())
+        };
+        let (a_text, b_text) = (&a[..split(a, false)], &b[..split(b, false)]);
+        match compare_lexical(a_text, b_text) {
+            Ordering::Equal => (),
+            other => return other,
+        }
+        a = &a[a_text.len()..];
+        b = &b[b_text.len()..];
+        let (a_num, b_num) = (&a[..split(a, true)], &b[..split(b, true)]);
+        let parse = |n: &str| n.parse::<u128>().unwrap_or(0);
+        match parse(a_num).cmp(&parse(b_num)) {
+            Ordering::Equal => (),
+            other => return other,
+        }
+        a = &a[a_num.len()..];
+        b = &b[b_num.len()..];
+    }
+
+    Ordering::Equal
+}
+
+/// Compare two Debian package versions (`[epoch:]upstream[-revision]`)
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    let split = |v: &str| -> (u64, String, String) {
+        let (epoch, rest) = match v.split_once(':') {
+            Some((epoch, rest)) => (epoch.parse().unwrap_or(0), rest),
+            None => (0, v),
+        };
+        let (upstream, revision) = match rest.rsplit_once('-') {
+            Some((upstream, revision)) => (upstream, revision),
+            None => (rest, ""),
+        };
+
+        (epoch, upstream.to_owned(), revision.to_owned())
+    };
+    let (a, b) = (split(a), split(b));
+
+    a.0.cmp(&b.0)
+        .then_with(|| compare_fragment(&a.1, &b.1))
+        .then_with(|| compare_fragment(&a.2, &b.2))
+}
+
+/// Select the packages to remove according to the policy
+fn select_prunable(debs: &[DebInfo], policy: &PrunePolicy, now: SystemTime) -> Vec<usize> {
+    let mut groups: HashMap<(&str, &str), Vec<usize>> = HashMap::new();
+    for (i, deb) in debs.iter().enumerate() {
+        groups.entry((&deb.package, &deb.arch)).or_default().push(i);
+    }
+    let mut prunable = vec![false; debs.len()];
+    for group in groups.values_mut() {
+        // the latest version first
+        group.sort_by(|a, b| compare_versions(&debs[*b].version, &debs[*a].version));
+        for (rank, i) in group.iter().enumerate() {
+            if policy.keep_last.is_some_and(|keep| rank >= keep.max(1)) {
+                prunable[*i] = true;
+            }
+            let expired = match (policy.max_age, now.duration_since(debs[*i].modified)) {
+                (Some(max_age), Ok(age)) => age > max_age,
+                _ => false,
+            };
+            if expired && rank > 0 {
+                prunable[*i] = true;
+            }
+        }
+    }
+    if policy.superseded_noarch {
+        let mut latest_arch: HashMap<&str, &str> = HashMap::new();
+        for deb in debs.iter().filter(|d| d.arch != "all") {
+            let latest = latest_arch.entry(&deb.package).or_insert(&deb.version);
+            if compare_versions(&deb.version, latest) == Ordering::Greater {
+                *latest = &deb.version;
+            }
+        }
+        for (i, deb) in debs.iter().enumerate() {
+            if deb.arch != "all" {
+                continue;
+            }
+            if let Some(latest) = latest_arch.get(deb.package.as_str()) {
+                if compare_versions(&deb.version, latest) == Ordering::Less {
+                    prunable[i] = true;
+                }
+            }
+        }
+    }
+
+    (0..debs.len()).filter(|i| prunable[*i]).collect()
+}
+
+/// Remove the packages in the repository according to the policy, returns the number of the
+/// packages removed. With `dry_run`, the packages are only listed
+pub fn prune_repo(root: &Path, policy: &PrunePolicy, dry_run: bool) -> Result<usize> {
+    let path = root.join("debs");
+    let mut debs = Vec::new();
+    for entry in scan::collect_all_packages(&path)? {
+        match read_deb_info(entry.path()) {
+            Ok(deb) => debs.push(deb),
+            Err(e) => warn!("Skipping {}: {}", entry.path().display(), e),
+        }
+    }
+    let prunable = select_prunable(&debs, policy, SystemTime::now());
+    let mut reclaimed = 0;
+    for i in prunable.iter() {
+        let deb = &debs[*i];
+        let rel_path = deb.path.strip_prefix(&path).unwrap_or(&deb.path);
+        if dry_run {
+            info!("Would remove {}", rel_path.display());
+        } else {
+            info!("Removing {}", rel_path.display());
+            fs::remove_file(&deb.path)?;
+        }
+        reclaimed += deb.size;
+    }
+    info!(
+        "{} package(s) {}, {} {}.",
+        prunable.len(),
+        if dry_run { "to remove" } else { "removed" },
+        HumanBytes(reclaimed),
+        if dry_run { "to reclaim" } else { "reclaimed" }
+    );
+
+    Ok(prunable.len())
+}
+
+#[test]
+fn test_compare_versions() {
+    assert_eq!(compare_versions("1.0", "1.0"), Ordering::Equal);
+    assert_eq!(compare_versions("1.10", "1.9"), Ordering::Greater);
+    assert_eq!(compare_versions("1.0~rc1", "1.0"), Ordering::Less);
+    assert_eq!(compare_versions("1:0.1", "2.0"), Ordering::Greater);
+    assert_eq!(compare_versions("1.0-1", "1.0-2"), Ordering::Less);
+    assert_eq!(compare_versions("1.0a", "1.0+"), Ordering::Less);
+}
+
+#[test]
+fn test_select_prunable() {
+    let now = SystemTime::UNIX_EPOCH + Duration::from_secs(100 * 86400);
+    let deb = |package: &str, version: &str, arch: &str, days: u64| DebInfo {
+        path: PathBuf::new(),
+        package: package.to_owned(),
+        version: version.to_owned(),
+        arch: arch.to_owned(),
+        size: 0,
+        modified: now - Duration::from_secs(days * 86400),
+    };
+    let debs = vec![
+        deb("foo", "1.0", "amd64", 30),
+        deb("foo", "1.2", "amd64", 1),
+        deb("foo", "1.1", "amd64", 10),
+        deb("bar", "2.0", "all", 40),
+        deb("bar", "2.1", "amd64", 2),
+    ];
+    let policy = |keep_last, max_age, superseded_noarch| PrunePolicy {
+        keep_last,
+        max_age,
+        superseded_noarch,
+    };
+    let day = Duration::from_secs(86400);
+    assert!(select_prunable(&debs, &policy(None, None, false), now).is_empty());
+    assert_eq!(
+        select_prunable(&debs, &policy(Some(2), None, false), now),
+        [0]
+    );
+    assert_eq!(
+        select_prunable(&debs, &policy(Some(1), None, false), now),
+        [0, 2]
+    );
+    // the latest version is kept even if it is too old
+    assert_eq!(
+        select_prunable(&debs, &policy(None, Some(day * 5), false), now),
+        [0, 2]
+    );
+    assert_eq!(select_prunable(&debs, &policy(None, None, true), now), [3]);
+}
@@ -0,0 +1,316 @@
+//! Uploading of the local repository
+
+use super::scan::sha256sum;
+use crate::info;
+use anyhow::{anyhow, bail, Result};
+use console::style;
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    process::Command,
+};
+use walkdir::WalkDir;
+
+/// Checksums of the files uploaded to each of the targets
+const PUSH_STATE_DIR: &str = ".ciel/data/push";
+/// The files are uploaded to SFTP under a temporary name first, then renamed into place
+const SFTP_UPLOAD_SUFFIX: &str = ".ciel-upload";
+
+/// Where the packages are uploaded to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PushTarget {
+    Rsync(String),
+    Sftp { host: String, path: String },
+    S3(String),
+}
+
+impl PushTarget {
+    pub fn parse(target: &str) -> Result<Self> {
+        if target.starts_with("s3://") {
+            return Ok(PushTarget::S3(target.trim_end_matches('/').to_owned()));
+        }
+        if let Some(location) = target.strip_prefix("sftp://") {
+            let (host, path) = location
+                .split_once('/')
+                .ok_or_else(|| anyhow!("Missing the remote path in {}", target))?;
+            return Ok(PushTarget::Sftp {
+                host: host.to_owned(),
+                path: format!("/{}", path.trim_end_matches('/')),
+            });
+        }
+        if target.starts_with("rsync://") || target.contains(':') || Path::new(target).is_dir() {
+            return Ok(PushTarget::Rsync(target.trim_end_matches('/').to_owned()));
+        }
+
+        Err(anyhow!(
+            "Unsupported target {}: expected host:/path, rsync://, sftp:// or s3://",
+            target
+        ))
+    }
+}
+
+#[inline]
+fn is_index(path: &str) -> bool {
+    let name = path.rsplit('/').next().unwrap_or(path);
+
+    name.starts_with("Packages")
+        || name.starts_with("Contents-")
+        || matches!(name, "Release" | "InRelease" | "Release.gpg")
+}
+
+/// Return the files in the repository (relative paths), the packages first and the indices last
+fn repo_files(path: &Path) -> Result<Vec<String>> {
+    let mut files = Vec::new();
+    for entry in WalkDir::new(path) {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let rel_path = entry.path().strip_prefix(path)?;
+        files.push(rel_path.to_string_lossy().to_string());
+    }
+    files.sort_by_key(|f| (is_index(f), f.clone()));
+
+    Ok(files)
+}
+
+fn state_path(target: &str) -> PathBuf {
+    let name = target
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect::<String>();
+
+    Path::new(PUSH_STATE_DIR).join(format!("{}.json", name))
+}
+
+/// Checksums of the files being uploaded to the target, left behind if the upload is interrupted
+fn partial_state_path(target: &str) -> PathBuf {
+    state_path(target).with_extension("partial.json")
+}
+
+fn read_checksums(path: &Path) -> BTreeMap<String, String> {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|s| serde_json::from_str(&s).ok())
+        .unwrap_or_default()
+}
+
+fn write_checksums(path: &Path, checksums: &BTreeMap<String, String>) -> Result<()> {
+    fs::create_dir_all(PUSH_STATE_DIR)?;
+    fs::write(path, serde_json::to_string_pretty(checksums)?)?;
+
+    Ok(())
+}
+
+#[inline]
+fn load_state(target: &str) -> BTreeMap<String, String> {
+    read_checksums(&state_path(target))
+}
+
+#[inline]
+fn save_state(target: &str, state: &BTreeMap<String, String>) -> Result<()> {
+    write_checksums(&state_path(target), state)
+}
+
+fn run(cmd: &mut Command, tool: &str) -> Result<()> {
+    let status = cmd
+        .status()
+        .map_err(|e| anyhow!("Unable to run {} (is it installed?): {}", tool, e))?;
+    if !status.success() {
+        bail!("{} failed with {}", tool, status);
+    }
+
+    Ok(())
+}
+
+fn push_rsync(path: &Path, target: &str, dry_run: bool) -> Result<()> {
+    let files = repo_files(path)?;
+    let (debs, indices): (Vec<_>, Vec<_>) = files.into_iter().partition(|f| !is_index(f));
+    for (batch, description) in [(debs, "packages"), (indices, "indices")] {
+        if batch.is_empty() {
+            continue;
+        }
+        info!("Uploading {}...", description);
+        let mut list = tempfile::NamedTempFile::new()?;
+        list.write_all(batch.join("\n").as_bytes())?;
+        list.flush()?;
+        let mut cmd = Command::new("rsync");
+        cmd.args(["-a", "--checksum", "--partial", "--delay-updates"])
+            .arg("--files-from")
+            .arg(list.path());
+        if dry_run {
+            cmd.args(["--dry-run", "--itemize-changes"]);
+        }
+        run(
+            cmd.arg(format!("{}/", path.display()))
+                .arg(format!("{}/", target)),
+            "rsync",
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Return the files changed since the last upload to the target, with their checksums
+fn changed_files(path: &Path, target: &str) -> Result<Vec<(String, String)>> {
+    let state = load_state(target);
+    let mut changed = Vec::new();
+    for file in repo_files(path)? {
+        let checksum = sha256sum(fs::File::open(path.join(&file))?)?;
+        if state.get(&file) != Some(&checksum) {
+            changed.push((file, checksum));
+        }
+    }
+
+    Ok(changed)
+}
+
+/// Quote the path for the sftp batch file
+#[inline]
+fn sftp_quote(path: &str) -> String {
+    format!("\"{}\"", path.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn push_sftp(path: &Path, host: &str, remote: &str, target: &str, dry_run: bool) -> Result<()> {
+    let changed = changed_files(path, target)?;
+    if dry_run || changed.is_empty() {
+        for (file, _) in changed.iter() {
+            info!("Would upload {}", file);
+        }
+        return Ok(());
+    }
+    let (host, port) = match host.rsplit_once(':') {
+        Some((host, port)) => (host, Some(port)),
+        None => (host, None),
+    };
+    let mut batch = String::new();
+    let mut dirs = changed
+        .iter()
+        .filter_map(|(f, _)| Path::new(f).parent())
+        .filter(|d| !d.as_os_str().is_empty())
+        .flat_map(|d| d.ancestors().filter(|a| !a.as_os_str().is_empty()))
+        .map(|d| d.to_string_lossy().to_string())
+        .collect::<Vec<_>>();
+    dirs.sort();
+    dirs.dedup();
+    // `-` ignores the errors of the existing directories
+    batch.push_str(&format!("-mkdir {}\n", sftp_quote(remote)));
+    for dir in dirs {
+        batch.push_str(&format!(
+            "-mkdir {}\n",
+            sftp_quote(&format!("{}/{}", remote, dir))
+        ));
+    }
+    let partial_path = partial_state_path(target);
+    let partial = read_checksums(&partial_path);
+    for (file, checksum) in changed.iter() {
+        let dest = format!("{}/{}", remote, file);
+        let temp = format!("{}{}", dest, SFTP_UPLOAD_SUFFIX);
+        // only an interrupted upload of the same file is resumed (`-a`), the other changed
+        // files (e.g. the indices) are uploaded in whole
+        let put = if partial.get(file) == Some(checksum) {
+            "put -a"
+        } else {
+            "put"
+        };
+        batch.push_str(&format!(
+            "{} {} {}\nrename {} {}\n",
+            put,
+            sftp_quote(&path.join(file).to_string_lossy()),
+            sftp_quote(&temp),
+            sftp_quote(&temp),
+            sftp_quote(&dest)
+        ));
+    }
+    let mut batch_file = tempfile::NamedTempFile::new()?;
+    batch_file.write_all(batch.as_bytes())?;
+    batch_file.flush()?;
+    write_checksums(&partial_path, &changed.iter().cloned().collect())?;
+    info!("Uploading {} file(s)...", changed.len());
+    let mut cmd = Command::new("sftp");
+    if let Some(port) = port {
+        cmd.arg("-P").arg(port);
+    }
+    run(cmd.arg("-b").arg(batch_file.path()).arg(host), "sftp")?;
+    fs::remove_file(&partial_path).ok();
+    let mut state = load_state(target);
+    state.extend(changed);
+
+    save_state(target, &state)
+}
+
+fn push_s3(path: &Path, bucket: &str, target: &str, dry_run: bool) -> Result<()> {
+    let changed = changed_files(path, target)?;
+    let mut state = load_state(target);
+    for (file, checksum) in changed {
+        if dry_run {
+            info!("Would upload {}", file);
+            continue;
+        }
+        info!("Uploading {}...", file);
+        run(
+            Command::new("aws")
+                .args(["s3", "cp", "--only-show-errors"])
+                .arg(path.join(&file))
+                .arg(format!("{}/{}", bucket, file)),
+            "aws",
+        )?;
+        // recorded one by one, so an interrupted push continues from where it stopped
+        state.insert(file, checksum);
+        save_state(target, &state)?;
+    }
+
+    Ok(())
+}
+
+/// Upload the repository to the target, then run the post-push hook
+pub fn push_repo(root: &Path, target: &str, dry_run: bool) -> Result<()> {
+    let path = root.join("debs");
+    if !path.join("Packages").is_file() {
+        bail!("The repository has no index, please run `ciel repo refresh` first.");
+    }
+    if crate::config::read_config().map_or(false, |c| c.qa.gate_push) {
+        super::qa::gate(root)?;
+    }
+    match PushTarget::parse(target)? {
+        PushTarget::Rsync(dest) => push_rsync(&path, &dest, dry_run)?,
+        PushTarget::Sftp { host, path: remote } => {
+            push_sftp(&path, &host, &remote, target, dry_run)?
+        }
+        PushTarget::S3(bucket) => push_s3(&path, &bucket, target, dry_run)?,
+    }
+    if dry_run {
+        return Ok(());
+    }
+    info!("Repository uploaded to {}.", target);
+
+    crate::hooks::run_hook(
+        crate::hooks::Hook::PostPush,
+        &crate::hooks::HookContext {
+            target: Some(target),
+            ..Default::default()
+        },
+    )
+}
+
+#[test]
+fn test_parse_target() {
+    assert_eq!(
+        PushTarget::parse("repo@staging:/srv/debs/").unwrap(),
+        PushTarget::Rsync("repo@staging:/srv/debs".to_owned())
+    );
+    assert_eq!(
+        PushTarget::parse("sftp://repo@staging:2222/srv/debs").unwrap(),
+        PushTarget::Sftp {
+            host: "repo@staging:2222".to_owned(),
+            path: "/srv/debs".to_owned(),
+        }
+    );
+    assert_eq!(
+        PushTarget::parse("s3://bucket/debs/").unwrap(),
+        PushTarget::S3("s3://bucket/debs".to_owned())
+    );
+    assert!(PushTarget::parse("staging").is_err());
+}
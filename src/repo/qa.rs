@@ -0,0 +1,569 @@
+//! Quality checks of the built packages
+
+use super::{
+    diff::split_relations,
+    prune::{compare_versions, read_deb_info, DebInfo},
+    scan::{self, control_field},
+};
+use crate::{config::QaConfig, error, info, output, warn};
+use anyhow::{bail, Result};
+use console::style;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fs,
+    io::{Read, Write},
+    path::Path,
+};
+use tabwriter::TabWriter;
+
+/// The findings of the packages checked, by the paths of the packages (relative to the
+/// repository)
+const QA_LEDGER: &str = ".ciel/data/qa.json";
+/// Fields every package must have
+const REQUIRED_FIELDS: &[&str] = &[
+    "Package",
+    "Version",
+    "Architecture",
+    "Description",
+    "Section",
+];
+
+/// All the checks available
+pub const CHECKS: &[&str] = &[
+    "control-fields",
+    "maintainer",
+    "empty-package",
+    "file-conflicts",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// A problem found in a package
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Finding {
+    pub check: String,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl Finding {
+    fn new(check: &str, severity: Severity, message: String) -> Self {
+        Finding {
+            check: check.to_owned(),
+            severity,
+            message,
+        }
+    }
+}
+
+#[inline]
+fn is_valid_package_name(name: &str) -> bool {
+    name.len() >= 2
+        && name.starts_with(|c: char| c.is_ascii_lowercase() || c.is_ascii_digit())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "+-.".contains(c))
+}
+
+fn check_control_fields(control: &str, findings: &mut Vec<Finding>) {
+    for field in REQUIRED_FIELDS {
+        if control_field(control, field).map_or(true, |v| v.is_empty()) {
+            findings.push(Finding::new(
+                "control-fields",
+                Severity::Error,
+                format!("missing or empty {} field", field),
+            ));
+        }
+    }
+    if let Some(name) = control_field(control, "Package") {
+        if !name.is_empty() && !is_valid_package_name(name) {
+            findings.push(Finding::new(
+                "control-fields",
+                Severity::Error,
+                format!("invalid package name `{}`", name),
+            ));
+        }
+    }
+    if let Some(version) = control_field(control, "Version") {
+        // without the epoch
+        let upstream = version.split_once(':').map_or(version, |v| v.1);
+        if version.contains(char::is_whitespace)
+            || !upstream.starts_with(|c: char| c.is_ascii_digit())
+        {
+            findings.push(Finding::new(
+                "control-fields",
+                Severity::Error,
+                format!("invalid version `{}`", version),
+            ));
+        }
+    }
+}
+
+fn check_maintainer(control: &str, findings: &mut Vec<Finding>) {
+    match control_field(control, "Maintainer") {
+        None | Some("") => findings.push(Finding::new(
+            "maintainer",
+            Severity::Error,
+            "missing Maintainer field".to_owned(),
+        )),
+        Some(maintainer) if !(maintainer.contains('<') && maintainer.ends_with('>')) => findings
+            .push(Finding::new(
+                "maintainer",
+                Severity::Warning,
+                format!("no e-mail address in the Maintainer field `{}`", maintainer),
+            )),
+        _ => (),
+    }
+}
+
+fn check_empty(files: &BTreeMap<String, u64>, control: &str, findings: &mut Vec<Finding>) {
+    // the meta packages (e.g. with only the dependencies) are empty on purpose
+    if control_field(control, "Section") == Some("metapackages") {
+        return;
+    }
+    let has_contents = files
+        .keys()
+        .any(|f| !f.starts_with("usr/share/doc/") && !f.ends_with('/'));
+    if !has_contents {
+        findings.push(Finding::new(
+            "empty-package",
+            Severity::Warning,
+            "the package contains no files (other than the documentation)".to_owned(),
+        ));
+    }
+}
+
+/// Read the Contents index of the architecture: file -> packages
+fn read_contents_index(root: &Path, arch: &str) -> HashMap<String, Vec<String>> {
+    let content = fs::read_to_string(root.join("debs").join(format!("Contents-{}", arch)))
+        .unwrap_or_default();
+
+    parse_contents_index(&content)
+}
+
+/// Parse a Contents index (`path section/package[,...]`): file -> packages
+fn parse_contents_index(content: &str) -> HashMap<String, Vec<String>> {
+    let mut index = HashMap::new();
+    for line in content.lines() {
+        if let Some((file, locations)) = line.trim_end().rsplit_once(' ') {
+            let packages = locations
+                .split(',')
+                .map(|l| l.rsplit('/').next().unwrap_or(l).to_owned())
+                .collect();
+            index.insert(file.trim_end().to_owned(), packages);
+        }
+    }
+
+    index
+}
+
+/// Return the packages allowed to own the same files as the package: the ones it replaces,
+/// conflicts with or breaks
+fn allowed_owners(control: &str) -> BTreeSet<String> {
+    ["Replaces", "Conflicts", "Breaks"]
+        .iter()
+        .flat_map(|field| split_relations(control_field(control, field)))
+        .flat_map(|r| {
+            // alternatives are allowed as well
+            r.split('|')
+                .filter_map(|r| {
+                    r.split(|c: char| c.is_whitespace() || c == '(' || c == ':')
+                        .find(|n| !n.is_empty())
+                        .map(|n| n.to_owned())
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+fn check_conflicts(
+    files: &BTreeMap<String, u64>,
+    control: &str,
+    index: &HashMap<String, Vec<String>>,
+    findings: &mut Vec<Finding>,
+) {
+    let name = control_field(control, "Package").unwrap_or_default();
+    let allowed = allowed_owners(control);
+    let mut conflicts: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for file in files.keys() {
+        for owner in index.get(file).into_iter().flatten() {
+            if owner != name && !allowed.contains(owner) {
+                conflicts
+                    .entry(owner.as_str())
+                    .or_default()
+                    .push(file.as_str());
+            }
+        }
+    }
+    for (owner, files) in conflicts {
+        findings.push(Finding::new(
+            "file-conflicts",
+            Severity::Error,
+            format!(
+                "{} file(s) also in {} (e.g. /{}), without Replaces or Conflicts",
+                files.len(),
+                owner,
+                files[0]
+            ),
+        ));
+    }
+}
+
+/// Run the enabled checks on the package
+pub fn check_package(config: &QaConfig, root: &Path, deb: &Path) -> Result<Vec<Finding>> {
+    let contents = scan::read_contents(deb)?;
+    let control = contents
+        .control
+        .get("control")
+        .map(|c| String::from_utf8_lossy(c).to_string())
+        .unwrap_or_default();
+    let mut findings = Vec::new();
+    if config.enabled("control-fields") {
+        check_control_fields(&control, &mut findings);
+    }
+    if config.enabled("maintainer") {
+        check_maintainer(&control, &mut findings);
+    }
+    if config.enabled("empty-package") {
+        check_empty(&contents.files, &control, &mut findings);
+    }
+    if config.enabled("file-conflicts") {
+        let arch = control_field(&control, "Architecture").unwrap_or("all");
+        let index = read_contents_index(root, arch);
+        check_conflicts(&contents.files, &control, &index, &mut findings);
+    }
+
+    Ok(findings)
+}
+
+fn load_ledger() -> BTreeMap<String, Vec<Finding>> {
+    fs::read(QA_LEDGER)
+        .ok()
+        .and_then(|data| serde_json::from_slice(&data).ok())
+        .unwrap_or_default()
+}
+
+/// Check the packages produced by a build (relative to the repository), the findings are
+/// reported and recorded
+pub fn check_build(
+    config: &QaConfig,
+    root: &Path,
+    debs: &[String],
+) -> Result<BTreeMap<String, Vec<Finding>>> {
+    let mut results = BTreeMap::new();
+    for deb in debs {
+        let findings = check_package(config, root, &root.join("debs").join(deb))?;
+        for finding in findings.iter() {
+            match finding.severity {
+                Severity::Error => {
+                    error!("{}: [{}] {}", deb, finding.check, finding.message)
+                }
+                Severity::Warning => {
+                    warn!("{}: [{}] {}", deb, finding.check, finding.message)
+                }
+            }
+        }
+        results.insert(deb.clone(), findings);
+    }
+    let mut ledger = load_ledger();
+    ledger.extend(results.clone());
+    // forget the packages no longer in the repository
+    ledger.retain(|deb, _| root.join("debs").join(deb).is_file());
+    let tmp_path = Path::new(QA_LEDGER).with_extension("json.tmp");
+    fs::write(&tmp_path, serde_json::to_vec(&ledger)?)?;
+    fs::rename(tmp_path, QA_LEDGER)?;
+
+    Ok(results)
+}
+
+/// Refuse to continue if any package in the repository has errors
+pub fn gate(root: &Path) -> Result<()> {
+    let failed = load_ledger()
+        .into_iter()
+        .filter(|(deb, findings)| {
+            root.join("debs").join(deb).is_file()
+                && findings.iter().any(|f| f.severity == Severity::Error)
+        })
+        .map(|(deb, _)| deb)
+        .collect::<Vec<_>>();
+    if failed.is_empty() {
+        return Ok(());
+    }
+    for deb in failed.iter() {
+        error!("{} failed the quality checks.", deb);
+    }
+
+    bail!(
+        "{} package(s) failed the quality checks, fix or remove them before pushing.",
+        failed.len()
+    )
+}
+
+/// Packages sharing files without the relationships
+#[derive(Debug, Serialize)]
+pub struct PackageConflict {
+    pub package: String,
+    pub other: String,
+    /// The other package is in the remote repository
+    pub remote: bool,
+    pub files: Vec<String>,
+}
+
+/// A package scanned for the conflicts
+struct ScannedDeb {
+    name: String,
+    arch: String,
+    allowed: BTreeSet<String>,
+    files: Vec<String>,
+}
+
+impl ScannedDeb {
+    /// Whether the packages may be installed together (and own the same files)
+    #[inline]
+    fn coinstallable(&self, other: &ScannedDeb) -> bool {
+        self.arch == other.arch || self.arch == "all" || other.arch == "all"
+    }
+}
+
+/// Read the latest version of each package (of each architecture) in the repository
+fn scan_latest(root: &Path) -> Result<Vec<ScannedDeb>> {
+    let mut latest: BTreeMap<(String, String), DebInfo> = BTreeMap::new();
+    for entry in scan::collect_all_packages(root.join("debs"))? {
+        let info = match read_deb_info(entry.path()) {
+            Ok(info) => info,
+            Err(e) => {
+                warn!("{}", e);
+                continue;
+            }
+        };
+        let key = (info.package.clone(), info.arch.clone());
+        match latest.get(&key) {
+            Some(existing) if compare_versions(&existing.version, &info.version).is_ge() => (),
+            _ => {
+                latest.insert(key, info);
+            }
+        }
+    }
+    let mut debs = Vec::new();
+    for ((name, arch), info) in latest {
+        let contents = scan::read_contents(&info.path)?;
+        let control = contents
+            .control
+            .get("control")
+            .map(|c| String::from_utf8_lossy(c).to_string())
+            .unwrap_or_default();
+        debs.push(ScannedDeb {
+            name,
+            arch,
+            allowed: allowed_owners(&control),
+            files: contents.files.into_keys().collect(),
+        });
+    }
+
+    Ok(debs)
+}
+
+/// Find the files shared by the packages without the relationships
+fn find_conflicts(debs: &[ScannedDeb]) -> Vec<PackageConflict> {
+    let mut owners: HashMap<&str, Vec<usize>> = HashMap::new();
+    for (i, deb) in debs.iter().enumerate() {
+        for file in deb.files.iter() {
+            owners.entry(file).or_default().push(i);
+        }
+    }
+    let mut conflicts: BTreeMap<(usize, usize), Vec<String>> = BTreeMap::new();
+    for (file, owners) in owners.iter().filter(|(_, o)| o.len() > 1) {
+        for (n, &a) in owners.iter().enumerate() {
+            for &b in owners[n + 1..].iter() {
+                let (x, y) = (&debs[a], &debs[b]);
+                if x.name == y.name
+                    || !x.coinstallable(y)
+                    || x.allowed.contains(&y.name)
+                    || y.allowed.contains(&x.name)
+                {
+                    continue;
+                }
+                conflicts.entry((a, b)).or_default().push(file.to_string());
+            }
+        }
+    }
+
+    conflicts
+        .into_iter()
+        .map(|((a, b), mut files)| {
+            files.sort();
+            PackageConflict {
+                package: debs[a].name.clone(),
+                other: debs[b].name.clone(),
+                remote: false,
+                files,
+            }
+        })
+        .collect()
+}
+
+/// Find the files shared by the packages and the packages in the remote index (except the ones
+/// also in the repository, which supersede the remote ones)
+fn find_remote_conflicts(
+    debs: &[ScannedDeb],
+    index: &HashMap<String, Vec<String>>,
+) -> Vec<PackageConflict> {
+    let local = debs
+        .iter()
+        .map(|d| d.name.as_str())
+        .collect::<BTreeSet<_>>();
+    let mut conflicts = Vec::new();
+    for deb in debs {
+        let mut shared: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+        for file in deb.files.iter() {
+            for owner in index.get(file).into_iter().flatten() {
+                if !local.contains(owner.as_str()) && !deb.allowed.contains(owner) {
+                    shared.entry(owner).or_default().push(file.clone());
+                }
+            }
+        }
+        conflicts.extend(shared.into_iter().map(|(other, files)| PackageConflict {
+            package: deb.name.clone(),
+            other: other.to_owned(),
+            remote: true,
+            files,
+        }));
+    }
+
+    conflicts
+}
+
+/// Download the remote Contents index (optionally compressed with gzip or xz)
+fn fetch_remote_index(url: &str) -> Result<HashMap<String, Vec<String>>> {
+    let mut response = crate::network::download_file(url)?.error_for_status()?;
+    let mut content = String::new();
+    if url.ends_with(".gz") {
+        flate2::read::GzDecoder::new(response).read_to_string(&mut content)?;
+    } else if url.ends_with(".xz") {
+        xz2::read::XzDecoder::new(response).read_to_string(&mut content)?;
+    } else {
+        response.read_to_string(&mut content)?;
+    }
+
+    Ok(parse_contents_index(&content))
+}
+
+/// Report the files shared by the packages in the repository (and the remote repository, given
+/// the URL of its Contents index) without the Replaces, Conflicts or Breaks relationships
+pub fn report_conflicts(root: &Path, remote: Option<&str>) -> Result<()> {
+    info!("Scanning the packages for file conflicts...");
+    let debs = scan_latest(root)?;
+    let mut conflicts = find_conflicts(&debs);
+    if let Some(url) = remote {
+        let index = fetch_remote_index(url)?;
+        conflicts.extend(find_remote_conflicts(&debs, &index));
+    }
+    if output::json_output() {
+        output::print_json(&conflicts)?;
+    } else if !conflicts.is_empty() {
+        let mut formatter = TabWriter::new(std::io::stderr());
+        writeln!(&mut formatter, "PACKAGE\tCONFLICTS WITH\tFILES\tEXAMPLE")?;
+        for conflict in conflicts.iter() {
+            writeln!(
+                &mut formatter,
+                "{}\t{}{}\t{}\t/{}",
+                conflict.package,
+                conflict.other,
+                if conflict.remote { " (remote)" } else { "" },
+                conflict.files.len(),
+                conflict.files[0]
+            )?;
+        }
+        formatter.flush()?;
+    }
+    if !conflicts.is_empty() {
+        bail!(
+            "{} conflict(s) found in {} package(s).",
+            conflicts.len(),
+            debs.len()
+        );
+    }
+    info!("No conflicts found in {} package(s).", debs.len());
+
+    Ok(())
+}
+
+#[test]
+fn test_check_control_fields() {
+    let mut findings = Vec::new();
+    check_control_fields(
+        "Package: foo\nVersion: 1.0-1\nArchitecture: amd64\nSection: utils\nDescription: Foo\n",
+        &mut findings,
+    );
+    assert!(findings.is_empty());
+    check_control_fields("Package: Foo\nVersion: 1.0 1\n", &mut findings);
+    let messages = findings
+        .iter()
+        .map(|f| f.message.as_str())
+        .collect::<Vec<_>>();
+    assert!(messages.contains(&"invalid package name `Foo`"));
+    assert!(messages.contains(&"invalid version `1.0 1`"));
+    assert!(messages.contains(&"missing or empty Architecture field"));
+}
+
+#[test]
+fn test_check_conflicts() {
+    let mut files = BTreeMap::new();
+    files.insert("usr/bin/foo".to_owned(), 1);
+    let mut index = HashMap::new();
+    index.insert(
+        "usr/bin/foo".to_owned(),
+        vec!["foo".to_owned(), "bar".to_owned()],
+    );
+    let mut findings = Vec::new();
+    check_conflicts(&files, "Package: foo\n", &index, &mut findings);
+    assert_eq!(findings.len(), 1);
+    findings.clear();
+    check_conflicts(
+        &files,
+        "Package: foo\nReplaces: bar (<< 2.0)\n",
+        &index,
+        &mut findings,
+    );
+    assert!(findings.is_empty());
+}
+
+#[test]
+fn test_find_conflicts() {
+    let deb = |name: &str, arch: &str, allowed: &[&str], files: &[&str]| ScannedDeb {
+        name: name.to_owned(),
+        arch: arch.to_owned(),
+        allowed: allowed.iter().map(|a| a.to_string()).collect(),
+        files: files.iter().map(|f| f.to_string()).collect(),
+    };
+    let debs = vec![
+        deb("foo", "amd64", &[], &["usr/bin/foo", "usr/bin/tool"]),
+        deb("bar", "all", &[], &["usr/bin/tool"]),
+        deb("baz", "amd64", &["foo"], &["usr/bin/foo"]),
+        deb("foo", "arm64", &[], &["usr/bin/foo"]),
+    ];
+    let conflicts = find_conflicts(&debs);
+    assert_eq!(conflicts.len(), 1);
+    assert_eq!(conflicts[0].package, "foo");
+    assert_eq!(conflicts[0].other, "bar");
+    assert_eq!(conflicts[0].files, vec!["usr/bin/tool"]);
+    let index = parse_contents_index("usr/bin/tool    utils/bar,utils/qux\n");
+    let conflicts = find_remote_conflicts(&debs, &index);
+    assert_eq!(conflicts.len(), 2);
+    assert!(conflicts.iter().all(|c| c.other == "qux" && c.remote));
+}
+
+#[test]
+fn test_allowed_owners() {
+    let allowed = allowed_owners("Replaces: foo (<< 2.0), bar | baz\nBreaks: qux:amd64\n");
+    assert_eq!(
+        allowed.into_iter().collect::<Vec<_>>(),
+        vec!["bar", "baz", "foo", "qux"]
+    );
+}
@@ -0,0 +1,218 @@
+//! Queries over the packages in the local repository (from the `Packages` index, traced back to
+//! the builds recorded)
+
+use super::prune::compare_versions;
+use crate::{
+    buildlog::{self, BuildRecord},
+    info, output,
+};
+use anyhow::{bail, Result};
+use console::style;
+use indicatif::HumanBytes;
+use serde::Serialize;
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap},
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+use tabwriter::TabWriter;
+
+/// A package in the index
+#[derive(Debug, Clone, Serialize)]
+pub struct PackageEntry {
+    pub package: String,
+    pub version: String,
+    pub arch: String,
+    pub size: u64,
+    pub filename: String,
+    pub depends: Option<String>,
+    /// All the fields of the stanza
+    #[serde(skip)]
+    pub fields: BTreeMap<String, String>,
+    pub build: Option<BuildRecord>,
+}
+
+/// Return the latest build record of each package file
+fn load_build_records() -> HashMap<String, BuildRecord> {
+    let mut records = HashMap::new();
+    for record in buildlog::load_records().into_iter().filter(|r| r.success()) {
+        for deb in record.debs.iter() {
+            records.insert(deb.clone(), record.clone());
+        }
+    }
+
+    records
+}
+
+/// Parse the stanzas of the index, the continuation lines are joined
+fn parse_stanzas(index: &str) -> Vec<BTreeMap<String, String>> {
+    let mut stanzas = Vec::new();
+    for block in index.split("\n\n").filter(|b| !b.trim().is_empty()) {
+        let mut fields: BTreeMap<String, String> = BTreeMap::new();
+        let mut last_key: Option<String> = None;
+        for line in block.lines() {
+            if line.starts_with(' ') || line.starts_with('\t') {
+                if let Some(value) = last_key.as_ref().and_then(|k| fields.get_mut(k)) {
+                    value.push('\n');
+                    value.push_str(line.trim());
+                }
+                continue;
+            }
+            if let Some((key, value)) = line.split_once(':') {
+                fields.insert(key.to_owned(), value.trim().to_owned());
+                last_key = Some(key.to_owned());
+            }
+        }
+        stanzas.push(fields);
+    }
+
+    stanzas
+}
+
+/// Return the latest version of each package in the indices (e.g. the APT lists of an instance)
+pub fn available_versions(indices: &[PathBuf]) -> HashMap<String, String> {
+    let mut versions: HashMap<String, String> = HashMap::new();
+    for index in indices {
+        let content = match fs::read_to_string(index) {
+            Ok(content) => content,
+            Err(_) => continue,
+        };
+        for mut stanza in parse_stanzas(&content) {
+            let (package, version) = match (stanza.remove("Package"), stanza.remove("Version")) {
+                (Some(package), Some(version)) => (package, version),
+                _ => continue,
+            };
+            match versions.get(&package) {
+                Some(latest) if compare_versions(latest, &version) != Ordering::Less => (),
+                _ => {
+                    versions.insert(package, version);
+                }
+            }
+        }
+    }
+
+    versions
+}
+
+/// Return the packages in the index of the repository, sorted by the names
+pub fn read_index(root: &Path) -> Result<Vec<PackageEntry>> {
+    let index = root.join("debs/Packages");
+    if !index.is_file() {
+        bail!("The repository has no index, please run `ciel repo refresh` first.");
+    }
+    let records = load_build_records();
+    let mut entries = parse_stanzas(&fs::read_to_string(index)?)
+        .into_iter()
+        .map(|fields| {
+            let field = |name: &str| fields.get(name).cloned().unwrap_or_default();
+            let filename = field("Filename");
+            PackageEntry {
+                package: field("Package"),
+                version: field("Version"),
+                arch: field("Architecture"),
+                size: field("Size").parse().unwrap_or(0),
+                depends: fields.get("Depends").cloned(),
+                build: records.get(&filename).cloned(),
+                filename,
+                fields,
+            }
+        })
+        .collect::<Vec<_>>();
+    entries.sort_by(|a, b| {
+        a.package
+            .cmp(&b.package)
+            .then_with(|| compare_versions(&a.version, &b.version))
+    });
+
+    Ok(entries)
+}
+
+/// Print the packages in the repository
+pub fn list_packages(root: &Path) -> Result<()> {
+    let entries = read_index(root)?;
+    if output::json_output() {
+        return output::print_json(&entries);
+    }
+    let mut formatter = TabWriter::new(std::io::stderr());
+    writeln!(&mut formatter, "PACKAGE\tVERSION\tARCH\tSIZE")?;
+    for entry in entries.iter() {
+        writeln!(
+            &mut formatter,
+            "{}\t{}\t{}\t{}",
+            entry.package,
+            entry.version,
+            entry.arch,
+            HumanBytes(entry.size)
+        )?;
+    }
+    formatter.flush()?;
+    info!(
+        "{} package(s), {} in total.",
+        entries.len(),
+        HumanBytes(entries.iter().map(|e| e.size).sum())
+    );
+
+    Ok(())
+}
+
+/// Print the details of all the versions of the package
+pub fn show_package(root: &Path, name: &str) -> Result<()> {
+    let entries = read_index(root)?
+        .into_iter()
+        .filter(|e| e.package == name)
+        .collect::<Vec<_>>();
+    if entries.is_empty() {
+        bail!("Package `{}` is not in the repository.", name);
+    }
+    if output::json_output() {
+        return output::print_json(&entries);
+    }
+    for entry in entries.iter() {
+        eprintln!(
+            "{} {} ({})",
+            style(&entry.package).bold(),
+            entry.version,
+            entry.arch
+        );
+        for key in [
+            "Depends",
+            "Recommends",
+            "Provides",
+            "Section",
+            "Filename",
+            "SHA256",
+        ] {
+            if let Some(value) = entry.fields.get(key) {
+                eprintln!("  {}: {}", key, value);
+            }
+        }
+        eprintln!("  Size: {}", HumanBytes(entry.size));
+        match &entry.build {
+            Some(build) => {
+                let started = time::OffsetDateTime::from_unix_timestamp(build.started as i64)
+                    .map(|t| t.to_string())
+                    .unwrap_or_else(|_| build.started.to_string());
+                eprintln!("  Built: in {} at {}", build.instance, started);
+                if Path::new(&build.log).is_file() {
+                    eprintln!("  Build log: {}", build.log);
+                }
+            }
+            None => eprintln!("  Built: unknown"),
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_parse_stanzas() {
+    let stanzas = parse_stanzas(
+        "Package: foo\nVersion: 1.0\nDescription: Foo\n more text\n\nPackage: bar\nVersion: 2.0\n\n",
+    );
+    assert_eq!(stanzas.len(), 2);
+    assert_eq!(stanzas[0]["Package"], "foo");
+    assert_eq!(stanzas[0]["Description"], "Foo\nmore text");
+    assert_eq!(stanzas[1]["Version"], "2.0");
+}
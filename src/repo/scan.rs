@@ -1,15 +1,18 @@
-use crate::error;
+use crate::{error, progress::Progress};
 use anyhow::{anyhow, Result};
 use ar::Archive as ArArchive;
 use console::style;
 use faster_hex::hex_string;
 use flate2::read::GzDecoder;
 use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::io::SeekFrom;
 use std::{
-    fs::File,
-    io::{Read, Seek, Write},
+    collections::{BTreeMap, HashMap},
+    fs::{self, File},
+    io::{Read, Seek},
+    os::unix::fs::MetadataExt,
     path::Path,
 };
 use tar::Archive as TarArchive;
@@ -19,6 +22,70 @@ use xz2::read::XzDecoder;
 enum TarFormat {
     Xzip,
     Gzip,
+    Zstd,
+    Plain,
+}
+
+/// A package scanned for the indices
+pub struct ScannedPackage {
+    /// The stanza of the package in the Packages index
+    pub stanza: Vec<u8>,
+    pub package: String,
+    pub section: String,
+    pub arch: String,
+    /// Files in the package (without the leading `./`), for the Contents indices
+    pub files: Vec<String>,
+    /// Path of the package (relative to the repository)
+    pub filename: String,
+    /// Size and mtime of the package, to tell whether the files cached are still valid
+    pub stamp: (u64, i64, i64),
+}
+
+/// The files of the packages scanned before (by the filenames), so that only the packages
+/// added or replaced since the last refresh are decompressed for the Contents indices
+#[derive(Default, Serialize, Deserialize)]
+pub struct FilesCache {
+    entries: HashMap<String, ((u64, i64, i64), Vec<String>)>,
+}
+
+impl FilesCache {
+    /// Load the cache, an empty one if missing or unreadable
+    pub fn load(path: &Path) -> Self {
+        fs::read(path)
+            .ok()
+            .and_then(|c| serde_json::from_slice(&c).ok())
+            .unwrap_or_default()
+    }
+
+    /// Save the files of the packages scanned (dropping the ones of the packages removed)
+    pub fn save(path: &Path, packages: &[ScannedPackage]) -> Result<()> {
+        let cache = FilesCache {
+            entries: packages
+                .iter()
+                .map(|p| (p.filename.clone(), (p.stamp, p.files.clone())))
+                .collect(),
+        };
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(&cache)?)?;
+        fs::rename(&tmp, path)?;
+
+        Ok(())
+    }
+
+    fn get(&self, filename: &str, stamp: (u64, i64, i64)) -> Option<&Vec<String>> {
+        self.entries
+            .get(filename)
+            .filter(|(s, _)| *s == stamp)
+            .map(|(_, files)| files)
+    }
+}
+
+/// Return the value of the field in the control file
+pub(super) fn control_field<'a>(control: &'a str, name: &str) -> Option<&'a str> {
+    control.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        (key == name).then_some(value.trim())
+    })
 }
 
 fn collect_control<R: Read>(reader: R) -> Result<Vec<u8>> {
@@ -36,11 +103,33 @@ fn collect_control<R: Read>(reader: R) -> Result<Vec<u8>> {
     Err(anyhow!("Could not read control file"))
 }
 
-fn open_compressed_control<R: Read>(reader: R, format: &TarFormat) -> Result<Vec<u8>> {
-    match format {
-        TarFormat::Xzip => collect_control(XzDecoder::new(reader)),
-        TarFormat::Gzip => collect_control(GzDecoder::new(reader)),
+fn collect_files<R: Read>(reader: R) -> Result<Vec<String>> {
+    let mut tar = TarArchive::new(reader);
+    let mut files = Vec::new();
+    for entry in tar.entries()? {
+        let entry = entry?;
+        if entry.header().entry_type().is_dir() {
+            continue;
+        }
+        let path = String::from_utf8_lossy(&entry.path_bytes()).to_string();
+        files.push(path.trim_start_matches("./").to_owned());
     }
+
+    Ok(files)
+}
+
+#[inline]
+fn decompress<'a, R: Read + 'a>(reader: R, format: &TarFormat) -> Result<Box<dyn Read + 'a>> {
+    Ok(match format {
+        TarFormat::Xzip => Box::new(XzDecoder::new(reader)),
+        TarFormat::Gzip => Box::new(GzDecoder::new(reader)),
+        TarFormat::Zstd => Box::new(zstd::Decoder::new(reader)?),
+        TarFormat::Plain => Box::new(reader),
+    })
+}
+
+fn open_compressed_control<R: Read>(reader: R, format: &TarFormat) -> Result<Vec<u8>> {
+    collect_control(decompress(reader, format)?)
 }
 
 fn determine_format(format: &[u8]) -> Result<TarFormat> {
@@ -48,6 +137,10 @@ fn determine_format(format: &[u8]) -> Result<TarFormat> {
         Ok(TarFormat::Xzip)
     } else if format.ends_with(b".gz") {
         Ok(TarFormat::Gzip)
+    } else if format.ends_with(b".zst") {
+        Ok(TarFormat::Zstd)
+    } else if format.ends_with(b".tar") {
+        Ok(TarFormat::Plain)
     } else {
         Err(anyhow!("Unknown format: {:?}", format))
     }
@@ -71,24 +164,192 @@ fn open_deb_simple<R: Read>(reader: R) -> Result<Vec<u8>> {
     Err(anyhow!("data archive not found or format unsupported"))
 }
 
-fn scan_single_deb_simple<P: AsRef<Path>>(path: P, root: P) -> Result<Vec<u8>> {
+/// Read the control file and the list of the files in the package
+fn open_deb_full<R: Read>(reader: R) -> Result<(Vec<u8>, Vec<String>)> {
+    let mut deb = ArArchive::new(reader);
+    let mut control = None;
+    while let Some(entry) = deb.next_entry() {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(_) => continue,
+        };
+        let filename = entry.header().identifier().to_owned();
+        if filename.starts_with(b"control.tar") {
+            control = Some(open_compressed_control(
+                entry,
+                &determine_format(&filename)?,
+            )?);
+        } else if filename.starts_with(b"data.tar") {
+            let files = collect_files(decompress(entry, &determine_format(&filename)?)?)?;
+            // control.tar always comes before data.tar
+            let control = control.ok_or_else(|| anyhow!("control archive not found"))?;
+            return Ok((control, files));
+        }
+    }
+
+    Err(anyhow!("data archive not found or format unsupported"))
+}
+
+/// The control files and the data files of a package
+pub(super) struct DebContents {
+    /// The control files (`control` and the maintainer scripts)
+    pub control: BTreeMap<String, Vec<u8>>,
+    /// Files in the package with their sizes
+    pub files: BTreeMap<String, u64>,
+}
+
+fn read_tar_entries<R: Read>(
+    reader: R,
+    contents: bool,
+) -> Result<BTreeMap<String, (u64, Vec<u8>)>> {
+    let mut tar = TarArchive::new(reader);
+    let mut entries = BTreeMap::new();
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+        if entry.header().entry_type().is_dir() {
+            continue;
+        }
+        let path = String::from_utf8_lossy(&entry.path_bytes()).to_string();
+        let mut buf = Vec::new();
+        if contents {
+            entry.read_to_end(&mut buf)?;
+        }
+        entries.insert(
+            path.trim_start_matches("./").to_owned(),
+            (entry.header().size()?, buf),
+        );
+    }
+
+    Ok(entries)
+}
+
+/// Read the control files and the list of the files (with the sizes) of the package
+pub(super) fn read_contents(path: &Path) -> Result<DebContents> {
+    let mut deb = ArArchive::new(File::open(path)?);
+    let mut control = None;
+    while let Some(entry) = deb.next_entry() {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(_) => continue,
+        };
+        let filename = entry.header().identifier().to_owned();
+        let format = match determine_format(&filename) {
+            Ok(format) => format,
+            Err(_) => continue,
+        };
+        if filename.starts_with(b"control.tar") {
+            let entries = read_tar_entries(decompress(entry, &format)?, true)?;
+            control = Some(entries.into_iter().map(|(k, v)| (k, v.1)).collect());
+        } else if filename.starts_with(b"data.tar") {
+            let entries = read_tar_entries(decompress(entry, &format)?, false)?;
+            return Ok(DebContents {
+                control: control.ok_or_else(|| anyhow!("control archive not found"))?,
+                files: entries.into_iter().map(|(k, v)| (k, v.0)).collect(),
+            });
+        }
+    }
+
+    Err(anyhow!("data archive not found or format unsupported"))
+}
+
+/// Read the digests of the entries in the tar archive (symlinks as their targets)
+fn read_tar_digests<R: Read>(reader: R, prefix: &str) -> Result<BTreeMap<String, String>> {
+    let mut tar = TarArchive::new(reader);
+    let mut digests = BTreeMap::new();
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+        let kind = entry.header().entry_type();
+        let digest = if kind.is_dir() {
+            continue;
+        } else if kind.is_symlink() || kind.is_hard_link() {
+            let target = entry.link_name()?.unwrap_or_default();
+            format!("-> {}", target.display())
+        } else {
+            sha256sum(&mut entry)?
+        };
+        let path = String::from_utf8_lossy(&entry.path_bytes()).to_string();
+        digests.insert(
+            format!("{}{}", prefix, path.trim_start_matches("./")),
+            digest,
+        );
+    }
+
+    Ok(digests)
+}
+
+/// Read the digests of the control files (as `DEBIAN/...`) and the data files (as `/...`),
+/// the metadata of the archives (e.g. the mtimes, the owners and the order of the entries)
+/// and the compression are not included
+pub(super) fn read_digests(path: &Path) -> Result<BTreeMap<String, String>> {
+    let mut deb = ArArchive::new(File::open(path)?);
+    let mut digests = BTreeMap::new();
+    while let Some(entry) = deb.next_entry() {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(_) => continue,
+        };
+        let filename = entry.header().identifier().to_owned();
+        let format = match determine_format(&filename) {
+            Ok(format) => format,
+            Err(_) => continue,
+        };
+        if filename.starts_with(b"control.tar") {
+            digests.extend(read_tar_digests(decompress(entry, &format)?, "DEBIAN/")?);
+        } else if filename.starts_with(b"data.tar") {
+            digests.extend(read_tar_digests(decompress(entry, &format)?, "/")?);
+        }
+    }
+
+    Ok(digests)
+}
+
+/// Read the control file of the package
+pub(super) fn read_control(path: &Path) -> Result<Vec<u8>> {
+    open_deb_simple(File::open(path)?)
+}
+
+fn scan_single_deb<P: AsRef<Path>>(path: P, root: P, cache: &FilesCache) -> Result<ScannedPackage> {
     let mut f = File::open(path.as_ref())?;
+    let meta = f.metadata()?;
+    let stamp = (meta.len(), meta.mtime(), meta.mtime_nsec());
+    let rel_path = path
+        .as_ref()
+        .strip_prefix(root)?
+        .to_string_lossy()
+        .to_string();
     let sha256 = sha256sum(&mut f)?;
     let actual_size = f.stream_position()?;
     f.seek(SeekFrom::Start(0))?;
-    let mut control = open_deb_simple(f)?;
+    let (mut control, files) = match cache.get(&rel_path, stamp) {
+        Some(files) => (open_deb_simple(f)?, files.clone()),
+        None => open_deb_full(f)?,
+    };
+    let fields = String::from_utf8_lossy(&control).to_string();
+    let field = |name| control_field(&fields, name).unwrap_or_default().to_owned();
+    let (package, arch) = (field("Package"), field("Architecture"));
+    let section = match control_field(&fields, "Section") {
+        Some(section) => section.to_owned(),
+        None => "misc".to_owned(),
+    };
     control.reserve(128);
     if control.ends_with(&b"\n\n"[..]) {
         control.pop();
     }
-    let rel_path = path.as_ref().strip_prefix(root)?;
     control.extend(format!("Size: {}\n", actual_size).as_bytes());
-    control.extend(format!("Filename: {}\n", rel_path.to_string_lossy()).as_bytes());
+    control.extend(format!("Filename: {}\n", rel_path).as_bytes());
     control.extend(b"SHA256: ");
     control.extend(sha256.as_bytes());
     control.extend(b"\n\n");
 
-    Ok(control)
+    Ok(ScannedPackage {
+        stanza: control,
+        package,
+        section,
+        arch,
+        files,
+        filename: rel_path,
+        stamp,
+    })
 }
 
 /// Calculate the Sha256 checksum of the given stream
@@ -108,22 +369,27 @@ fn is_tarball(entry: &DirEntry) -> bool {
         .unwrap_or(false)
 }
 
-pub fn scan_packages_simple(entries: &[DirEntry], root: &Path) -> Vec<u8> {
+/// Scan the packages in parallel (the files of the packages unchanged taken from the cache),
+/// the packages failed to scan are skipped
+pub fn scan_packages(
+    entries: &[DirEntry],
+    root: &Path,
+    cache: &FilesCache,
+    progress: &Progress,
+) -> Vec<ScannedPackage> {
     entries
         .par_iter()
-        .map(|entry| -> Vec<u8> {
+        .filter_map(|entry| {
             let path = entry.path();
-            print!(".");
-            std::io::stderr().flush().ok();
-            match scan_single_deb_simple(path, root) {
-                Ok(entry) => entry,
+            progress.inc(1);
+            match scan_single_deb(path, root, cache) {
+                Ok(entry) => Some(entry),
                 Err(err) => {
                     error!("{:?}", err);
-                    Vec::new()
+                    None
                 }
             }
         })
-        .flatten()
         .collect()
 }
 
@@ -0,0 +1,131 @@
+//! Signing of the local repository
+
+use anyhow::{anyhow, bail, Result};
+use std::{
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// GnuPG home holding the signing key of the workspace
+const KEY_HOME: &str = ".ciel/data/repo-key";
+/// The exported (binary) public key
+const PUBLIC_KEY: &str = "public.gpg";
+
+#[inline]
+fn key_home() -> Result<PathBuf> {
+    Ok(std::env::current_dir()?.join(KEY_HOME))
+}
+
+#[inline]
+fn gpg(home: &Path) -> Command {
+    let mut cmd = Command::new("gpg");
+    cmd.arg("--homedir").arg(home).args(["--batch", "--yes"]);
+
+    cmd
+}
+
+fn run_gpg(cmd: &mut Command) -> Result<()> {
+    let output = cmd
+        .output()
+        .map_err(|e| anyhow!("Unable to run gpg (is GnuPG installed?): {}", e))?;
+    if !output.status.success() {
+        bail!(
+            "gpg failed:\n{}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(())
+}
+
+/// Return the public key of the workspace, if a signing key is generated
+pub fn public_key() -> Option<PathBuf> {
+    let path = key_home().ok()?.join(PUBLIC_KEY);
+
+    path.is_file().then_some(path)
+}
+
+/// Generate the signing key of the workspace
+pub fn generate_key() -> Result<()> {
+    let home = key_home()?;
+    if home.join(PUBLIC_KEY).is_file() {
+        bail!("The workspace already has a signing key.");
+    }
+    fs::create_dir_all(&home)?;
+    // GnuPG refuses to use a home accessible by the others
+    fs::set_permissions(&home, fs::Permissions::from_mode(0o700))?;
+    let workspace = std::env::current_dir()?;
+    let name = workspace
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    run_gpg(
+        gpg(&home)
+            // the key is used non-interactively, so it is not protected by a passphrase
+            .args(["--pinentry-mode", "loopback", "--passphrase", ""])
+            .arg("--quick-generate-key")
+            .arg(format!("Ciel local repository ({})", name))
+            .args(["ed25519", "sign", "never"]),
+    )?;
+    run_gpg(
+        gpg(&home)
+            .arg("--output")
+            .arg(home.join(PUBLIC_KEY))
+            .arg("--export"),
+    )?;
+
+    Ok(())
+}
+
+/// Remove the signing key of the workspace
+pub fn remove_key() -> Result<()> {
+    let home = key_home()?;
+    if !home.exists() {
+        bail!("The workspace does not have a signing key.");
+    }
+    fs::remove_dir_all(home)?;
+
+    Ok(())
+}
+
+/// Print the armored public key for the other machines to trust the repository
+pub fn export_key() -> Result<()> {
+    if public_key().is_none() {
+        bail!("The workspace does not have a signing key, please run `ciel repo key generate`.");
+    }
+    let status = gpg(&key_home()?).args(["--armor", "--export"]).status()?;
+    if !status.success() {
+        bail!("gpg failed with {}", status);
+    }
+
+    Ok(())
+}
+
+/// Sign the Release file in the directory (producing InRelease and Release.gpg),
+/// or remove the stale signatures if the workspace has no signing key
+pub fn sign_release(dir: &Path) -> Result<()> {
+    let release = dir.join("Release");
+    if public_key().is_none() {
+        fs::remove_file(dir.join("InRelease")).ok();
+        fs::remove_file(dir.join("Release.gpg")).ok();
+        return Ok(());
+    }
+    let home = key_home()?;
+    run_gpg(
+        gpg(&home)
+            .arg("--clearsign")
+            .arg("--output")
+            .arg(dir.join("InRelease"))
+            .arg(&release),
+    )?;
+    run_gpg(
+        gpg(&home)
+            .args(["--armor", "--detach-sign", "--output"])
+            .arg(dir.join("Release.gpg"))
+            .arg(&release),
+    )?;
+
+    Ok(())
+}
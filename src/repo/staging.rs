@@ -0,0 +1,124 @@
+//! Staging of the packages built into the local repository
+
+use anyhow::Result;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+use walkdir::WalkDir;
+
+use crate::common::FileLock;
+
+/// The staging directories in the output directory, one per instance
+pub const STAGING_DIR: &str = ".staging";
+/// The lock file in the output directory (outside of the repository itself)
+const LOCK_FILE: &str = ".repo.lock";
+
+/// Lock the repository in the output directory for reading the indices, blocks while it is
+/// changed
+pub fn lock_shared(root: &Path) -> Result<FileLock> {
+    FileLock::shared(&root.join(LOCK_FILE))
+}
+
+/// Lock the repository in the output directory for changing it, blocks while it is read or
+/// changed
+pub fn lock_exclusive(root: &Path) -> Result<FileLock> {
+    FileLock::exclusive(&root.join(LOCK_FILE))
+}
+
+/// Return the staging directory of the instance in the output directory
+pub fn staging_dir(root: &Path, instance: &str) -> PathBuf {
+    root.join(STAGING_DIR).join(instance)
+}
+
+/// Move the file into place, copying it if it is on another filesystem
+fn move_file(from: &Path, to: &Path) -> Result<()> {
+    if fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    let mut temp = to.as_os_str().to_owned();
+    temp.push(".ciel-staging");
+    let temp = PathBuf::from(temp);
+    fs::copy(from, &temp)?;
+    // the rename is atomic, the package is never seen half-written
+    if let Err(e) = fs::rename(&temp, to) {
+        fs::remove_file(&temp).ok();
+        return Err(e.into());
+    }
+    fs::remove_file(from)?;
+
+    Ok(())
+}
+
+/// Remove the contents of the directory, but not the directory itself (it is mounted)
+fn clear_dir(dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(dir).into_iter().flatten() {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            fs::remove_dir_all(entry.path())?;
+        } else {
+            fs::remove_file(entry.path())?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Move the packages staged by the instance into the repository, returns the files merged
+/// (relative to the repository)
+pub fn merge(root: &Path, instance: &str) -> Result<Vec<String>> {
+    let _lock = lock_exclusive(root)?;
+    let staging = staging_dir(root, instance);
+    let debs = root.join("debs");
+    let mut merged = Vec::new();
+    for entry in WalkDir::new(&staging)
+        .min_depth(1)
+        .into_iter()
+        .filter_map(|e| e.ok())
+    {
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let path = entry.path().strip_prefix(&staging)?;
+        let target = debs.join(path);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        move_file(entry.path(), &target)?;
+        merged.push(path.to_string_lossy().to_string());
+    }
+    clear_dir(&staging)?;
+    merged.sort();
+
+    Ok(merged)
+}
+
+/// Discard the packages staged by the instance (e.g. of a failed build)
+pub fn discard(root: &Path, instance: &str) -> Result<()> {
+    clear_dir(&staging_dir(root, instance))
+}
+
+#[test]
+fn test_merge() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path();
+    let staging = staging_dir(root, "main");
+    fs::create_dir_all(staging.join("amd64/libs")).unwrap();
+    fs::create_dir_all(root.join("debs/amd64/libs")).unwrap();
+    fs::write(staging.join("amd64/libs/foo_1.0_amd64.deb"), "new").unwrap();
+    fs::write(root.join("debs/amd64/libs/bar_1.0_amd64.deb"), "old").unwrap();
+    assert_eq!(
+        merge(root, "main").unwrap(),
+        vec!["amd64/libs/foo_1.0_amd64.deb"]
+    );
+    assert_eq!(
+        fs::read_to_string(root.join("debs/amd64/libs/foo_1.0_amd64.deb")).unwrap(),
+        "new"
+    );
+    // the staging directory is kept for the mount
+    assert!(staging.is_dir());
+    assert_eq!(fs::read_dir(&staging).unwrap().count(), 0);
+    fs::write(staging.join("partial.deb"), "").unwrap();
+    discard(root, "main").unwrap();
+    assert!(merge(root, "main").unwrap().is_empty());
+}
@@ -0,0 +1,209 @@
+//! This module contains the CI report formats of the build results
+
+use anyhow::Result;
+use std::{fs, path::Path};
+
+use crate::{
+    build::{BuildResult, PackageResult, PackageStatus},
+    notify::log_excerpt,
+};
+
+fn xml_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            // not allowed in XML 1.0, e.g. the terminal control sequences in the logs
+            c if (c as u32) < 0x20 && !matches!(c, '\t' | '\n' | '\r') => (),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// Escape the message of a GitHub Actions workflow command
+fn annotation_escape(text: &str, property: bool) -> String {
+    let escaped = text
+        .replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A");
+    if property {
+        escaped.replace(':', "%3A").replace(',', "%2C")
+    } else {
+        escaped
+    }
+}
+
+/// Return where the log of the package is (the URL if the logs are published)
+fn log_location(package: &PackageResult, log_url: Option<&str>) -> Option<String> {
+    let log = package.log.as_deref()?;
+    match log_url {
+        Some(url) => Some(format!(
+            "{}/{}",
+            url.trim_end_matches('/'),
+            Path::new(log).file_name()?.to_string_lossy()
+        )),
+        None => Some(log.to_owned()),
+    }
+}
+
+/// Return the reason of the failure of the package, if it failed
+fn failure(package: &PackageResult) -> Option<(&'static str, String)> {
+    match package.status {
+        PackageStatus::Failed { exit_status } => Some((
+            "ftbfs",
+            format!(
+                "{} failed to build (exit status {})",
+                package.package, exit_status
+            ),
+        )),
+        PackageStatus::TimedOut => Some((
+            "timeout",
+            format!("{} ran out of the time limit", package.package),
+        )),
+        _ => None,
+    }
+}
+
+/// Render the result as a JUnit XML report, `excerpt` returns the end of the log of a package
+fn junit<F: Fn(&PackageResult) -> Option<String>>(
+    result: &BuildResult,
+    log_url: Option<&str>,
+    excerpt: F,
+) -> String {
+    let failures = result
+        .packages
+        .iter()
+        .filter(|p| failure(p).is_some())
+        .count();
+    let skipped = result
+        .packages
+        .iter()
+        .filter(|p| matches!(p.status, PackageStatus::Pending | PackageStatus::Skipped))
+        .count();
+    let mut xml = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<testsuites>\n  <testsuite name=\"ciel build ({})\" tests=\"{}\" failures=\"{}\" skipped=\"{}\" time=\"{}\">\n",
+        xml_escape(&result.instance),
+        result.packages.len(),
+        failures,
+        skipped,
+        result.duration
+    );
+    for package in result.packages.iter() {
+        xml.push_str(&format!(
+            "    <testcase classname=\"{}\" name=\"{}\" time=\"{}\"",
+            xml_escape(&result.instance),
+            xml_escape(&package.package),
+            package.duration.unwrap_or(0)
+        ));
+        if let Some((kind, message)) = failure(package) {
+            let mut details = String::new();
+            if let Some(log) = log_location(package, log_url) {
+                details.push_str(&format!("Log: {}\n\n", log));
+            }
+            if let Some(excerpt) = excerpt(package) {
+                details.push_str(&excerpt);
+            }
+            xml.push_str(&format!(
+                ">\n      <failure type=\"{}\" message=\"{}\">{}</failure>\n    </testcase>\n",
+                kind,
+                xml_escape(&message),
+                xml_escape(&details)
+            ));
+            continue;
+        }
+        match package.status {
+            PackageStatus::Pending => {
+                xml.push_str(">\n      <skipped message=\"not built\"/>\n    </testcase>\n")
+            }
+            PackageStatus::Skipped => xml.push_str(
+                ">\n      <skipped message=\"already in the repository\"/>\n    </testcase>\n",
+            ),
+            _ => xml.push_str("/>\n"),
+        }
+    }
+    xml.push_str("  </testsuite>\n</testsuites>\n");
+
+    xml
+}
+
+/// Return the GitHub Actions annotations of the failures
+fn annotations(result: &BuildResult, log_url: Option<&str>) -> Vec<String> {
+    result
+        .packages
+        .iter()
+        .filter_map(|package| {
+            let (_, message) = failure(package)?;
+            let file = package
+                .log
+                .as_deref()
+                .map(|log| format!("file={},", annotation_escape(log, true)))
+                .unwrap_or_default();
+            let message = match log_location(package, log_url) {
+                Some(log) => format!("{}, see the log at {}", message, log),
+                None => message,
+            };
+            Some(format!(
+                "::error {}title={}::{}",
+                file,
+                annotation_escape(&format!("{} failed to build", package.package), true),
+                annotation_escape(&message, false)
+            ))
+        })
+        .collect()
+}
+
+/// Write the report of the build into the file (JSON if it ends with `.json`, otherwise
+/// JUnit XML)
+pub fn write_report(result: &BuildResult, path: &Path, log_url: Option<&str>) -> Result<()> {
+    let content = if path.extension().map_or(false, |ext| ext == "json") {
+        serde_json::to_string_pretty(result)?
+    } else {
+        junit(result, log_url, |p| {
+            p.log.as_deref().and_then(|log| log_excerpt(Path::new(log)))
+        })
+    };
+    fs::write(path, content)?;
+
+    Ok(())
+}
+
+/// Print the failures as annotations, if running in GitHub Actions
+pub fn print_annotations(result: &BuildResult, log_url: Option<&str>) {
+    if std::env::var("GITHUB_ACTIONS").as_deref() != Ok("true") {
+        return;
+    }
+    for annotation in annotations(result, log_url) {
+        // stdout is taken by the JSON document, the runner reads the commands from stderr too
+        if crate::output::json_output() {
+            eprintln!("{}", annotation);
+        } else {
+            println!("{}", annotation);
+        }
+    }
+}
+
+#[test]
+fn test_junit_report() {
+    let mut result = BuildResult::new("main", &["foo".to_owned(), "bar".to_owned()]);
+    result.packages[0].status = PackageStatus::Built;
+    result.packages[0].duration = Some(10);
+    result.packages[1].status = PackageStatus::Failed { exit_status: 1 };
+    result.packages[1].log = Some(".ciel/logs/bar-1.log.zst".to_owned());
+    result.duration = 20;
+    let xml = junit(&result, Some("https://logs.example.org/"), |_| {
+        Some("make: *** <all> Error 1".to_owned())
+    });
+    assert!(xml.contains("tests=\"2\" failures=\"1\" skipped=\"0\" time=\"20\""));
+    assert!(xml.contains("<testcase classname=\"main\" name=\"foo\" time=\"10\"/>"));
+    assert!(xml.contains("<failure type=\"ftbfs\" message=\"bar failed to build (exit status 1)\">Log: https://logs.example.org/bar-1.log.zst\n\nmake: *** &lt;all&gt; Error 1</failure>"));
+    assert_eq!(
+        annotations(&result, None),
+        vec!["::error file=.ciel/logs/bar-1.log.zst,title=bar failed to build::bar failed to build (exit status 1), see the log at .ciel/logs/bar-1.log.zst"]
+    );
+}
@@ -0,0 +1,134 @@
+//! This module contains the network policy of the builds
+
+use anyhow::{anyhow, bail, Result};
+use console::style;
+use std::{
+    fs,
+    io::Write,
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use crate::{info, warn};
+
+/// Users in the container still allowed to access the network
+const ALLOWED_USERS: &[&str] = &["_apt", "systemd-resolve"];
+
+/// Find the UIDs of the users in the passwd file
+fn find_uids(passwd: &str, users: &[&str]) -> Vec<u32> {
+    passwd
+        .lines()
+        .filter_map(|l| {
+            let mut fields = l.split(':');
+            let name = fields.next()?;
+            let uid = fields.nth(1)?;
+            if users.contains(&name) {
+                uid.parse().ok()
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Network access of a container blocked, until dropped
+pub struct NetworkBlock {
+    table: String,
+}
+
+impl NetworkBlock {
+    /// Block the network access of the container (rooted at the path) in the cgroup, APT is
+    /// still allowed to reach the network if `allow_apt`
+    pub fn apply(root: &Path, cgroup: &Path, allow_apt: bool) -> Result<Self> {
+        let cgroup = cgroup
+            .strip_prefix("/sys/fs/cgroup")
+            .map_err(|_| anyhow!("{} is not a cgroup v2 path", cgroup.display()))?;
+        let level = cgroup.components().count();
+        let matches = format!("socket cgroupv2 level {} \"{}\"", level, cgroup.display());
+        let passwd = fs::read_to_string(root.join("etc/passwd")).unwrap_or_default();
+        let allowed: &[&str] = if allow_apt { ALLOWED_USERS } else { &[] };
+        let uids = find_uids(&passwd, allowed)
+            .iter()
+            .map(|uid| uid.to_string())
+            .collect::<Vec<_>>();
+        let table = format!("ciel_{}", std::process::id());
+        let mut rules = format!(
+            "table inet {} {{\n  chain output {{\n    type filter hook output priority 0; policy accept;\n    {} oifname \"lo\" accept\n",
+            table, matches
+        );
+        if !uids.is_empty() {
+            rules.push_str(&format!(
+                "    {} meta skuid {{ {} }} accept\n",
+                matches,
+                uids.join(", ")
+            ));
+        }
+        rules.push_str(&format!("    {} counter reject\n  }}\n}}\n", matches));
+        nft(&["-f", "-"], Some(&rules))?;
+        info!("Network access of the build is blocked.");
+
+        Ok(NetworkBlock { table })
+    }
+
+    /// Return the number of the packets blocked so far
+    pub fn blocked_packets(&self) -> Result<u64> {
+        let rules = nft(&["list", "table", "inet", &self.table], None)?;
+        let packets = rules
+            .lines()
+            .filter_map(|l| l.split_once(" counter packets "))
+            .filter_map(|(_, counter)| counter.split_whitespace().next()?.parse::<u64>().ok())
+            .sum();
+
+        Ok(packets)
+    }
+
+    /// Remove the block, reporting the attempts of the package to access the network
+    pub fn finish(self, package: &str) {
+        match self.blocked_packets() {
+            Ok(0) => (),
+            Ok(packets) => warn!(
+                "{} tried to access the network during the build ({} packet(s) blocked).",
+                package, packets
+            ),
+            Err(e) => warn!("Unable to read the network block counter: {}", e),
+        }
+    }
+}
+
+impl Drop for NetworkBlock {
+    fn drop(&mut self) {
+        if let Err(e) = nft(&["delete", "table", "inet", &self.table], None) {
+            warn!("Unable to unblock the network access: {}", e);
+        }
+    }
+}
+
+/// Run nft with the arguments (and the input), returns the output
+fn nft(args: &[&str], input: Option<&str>) -> Result<String> {
+    let mut child = Command::new("nft")
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| anyhow!("Unable to run nft: {}", e))?;
+    if let (Some(input), Some(stdin)) = (input, child.stdin.as_mut()) {
+        stdin.write_all(input.as_bytes())?;
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        bail!(
+            "nft failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+#[test]
+fn test_find_uids() {
+    let passwd = "root:x:0:0::/root:/bin/bash\n_apt:x:42:65534::/nonexistent:/bin/false\nsystemd-resolve:x:193:193::/:/usr/sbin/nologin\n";
+    assert_eq!(find_uids(passwd, ALLOWED_USERS), vec![42, 193]);
+    assert!(find_uids(passwd, &["nobody"]).is_empty());
+}
@@ -0,0 +1,292 @@
+//! This module contains the scheduled tasks of the workspace
+
+use anyhow::{bail, Result};
+use console::style;
+use std::{fs, io::Write, path::Path, process::Command};
+use tabwriter::TabWriter;
+
+use crate::{info, machine, warn};
+
+const SYSTEM_UNIT_DIR: &str = "/etc/systemd/system";
+const UNIT_PREFIX: &str = "ciel-";
+
+/// A recurring job
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Job {
+    UpdateOs,
+    Gc,
+    /// Build the packages (as given to `ciel build`) in the instance
+    Build {
+        instance: Option<String>,
+        packages: Vec<String>,
+    },
+}
+
+impl Job {
+    /// Return the job by its name on the command line
+    pub fn parse(name: &str, instance: Option<String>, packages: Vec<String>) -> Result<Self> {
+        match name {
+            "update-os" => Ok(Job::UpdateOs),
+            "gc" => Ok(Job::Gc),
+            "build" if packages.is_empty() => bail!("Please specify the packages to build."),
+            "build" => Ok(Job::Build { instance, packages }),
+            _ => bail!("Unknown job `{}`: expected update-os, gc or build", name),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Job::UpdateOs => "update-os",
+            Job::Gc => "gc",
+            Job::Build { .. } => "build",
+        }
+    }
+
+    /// Return when the job runs unless specified, in systemd.time(7)
+    pub fn default_calendar(&self) -> &'static str {
+        match self {
+            Job::UpdateOs | Job::Build { .. } => "daily",
+            Job::Gc => "weekly",
+        }
+    }
+
+    fn description(&self) -> &'static str {
+        match self {
+            Job::UpdateOs => "Update the base system",
+            Job::Gc => "Reclaim the resources no longer used",
+            Job::Build { .. } => "Rebuild the packages",
+        }
+    }
+
+    /// Return the arguments of Ciel running the job
+    fn args(&self) -> Vec<String> {
+        match self {
+            Job::UpdateOs => vec!["update-os".to_owned()],
+            Job::Gc => vec!["gc".to_owned()],
+            Job::Build { instance, packages } => {
+                let mut args = vec!["build".to_owned()];
+                if let Some(instance) = instance {
+                    args.push("-i".to_owned());
+                    args.push(instance.clone());
+                }
+                args.extend(packages.iter().cloned());
+                args
+            }
+        }
+    }
+}
+
+/// Return the prefix of the units of the workspace, e.g. `ciel-ws-1a2b3c4d-`
+fn workspace_prefix(workspace: &Path) -> Result<String> {
+    let id = machine::new_container_name(workspace)?
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+
+    Ok(format!("{}{}-", UNIT_PREFIX, id))
+}
+
+/// Quote the argument for `ExecStart=` (see systemd.service(5)), the specifiers are escaped
+fn quote_arg(arg: &str) -> String {
+    let arg = arg.replace('%', "%%");
+    if !arg.is_empty()
+        && !arg
+            .chars()
+            .any(|c| c.is_whitespace() || matches!(c, '"' | '\'' | '\\' | ';' | '$'))
+    {
+        return arg;
+    }
+
+    format!(
+        "\"{}\"",
+        arg.replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('$', "$$")
+    )
+}
+
+/// Render the service and the timer of the job
+fn render_units(job: &Job, calendar: &str, workspace: &Path, exe: &Path) -> (String, String) {
+    let workspace = workspace.to_string_lossy();
+    let mut command = vec![
+        exe.to_string_lossy().to_string(),
+        "--workspace".to_owned(),
+        workspace.to_string(),
+        "-b".to_owned(),
+    ];
+    command.extend(job.args());
+    let exec = command
+        .iter()
+        .map(|arg| quote_arg(arg))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let description = format!(
+        "Ciel: {} in {}",
+        job.description(),
+        workspace.replace('%', "%%")
+    );
+    let service = format!(
+        "[Unit]\nDescription={}\nWants=network-online.target\nAfter=network-online.target\n\n[Service]\nType=oneshot\nWorkingDirectory={}\nExecStart={}\n",
+        description,
+        // not quoted, only the specifiers are expanded
+        workspace.replace('%', "%%"),
+        exec
+    );
+    let timer = format!(
+        "[Unit]\nDescription={} (timer)\n\n[Timer]\nOnCalendar={}\nPersistent=true\n\n[Install]\nWantedBy=timers.target\n",
+        description, calendar
+    );
+
+    (service, timer)
+}
+
+fn systemctl(args: &[&str]) -> Result<()> {
+    let status = Command::new("systemctl").args(args).status()?;
+    if !status.success() {
+        bail!("`systemctl {}` exited with {}", args.join(" "), status);
+    }
+
+    Ok(())
+}
+
+/// Check the calendar event with systemd-analyze(1), if available
+fn validate_calendar(calendar: &str) -> Result<()> {
+    let output = match Command::new("systemd-analyze")
+        .args(["calendar", calendar])
+        .output()
+    {
+        Ok(output) => output,
+        Err(_) => return Ok(()),
+    };
+    if !output.status.success() {
+        bail!(
+            "Invalid calendar event `{}`: {}",
+            calendar,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(())
+}
+
+/// Schedule the job (named `name`, the name of the job by default) in the workspace, then
+/// enable the timer. Returns the name of the timer
+pub fn add(
+    workspace: &Path,
+    job: &Job,
+    name: Option<&str>,
+    calendar: Option<&str>,
+) -> Result<String> {
+    let name = name.unwrap_or_else(|| job.name());
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        bail!("Invalid job name `{}`.", name);
+    }
+    let calendar = calendar.unwrap_or_else(|| job.default_calendar());
+    validate_calendar(calendar)?;
+    let exe = std::env::current_exe()?;
+    let (service, timer) = render_units(job, calendar, workspace, &exe);
+    let dir = Path::new(SYSTEM_UNIT_DIR);
+    fs::create_dir_all(dir)?;
+    let unit = format!("{}{}", workspace_prefix(workspace)?, name);
+    fs::write(dir.join(format!("{}.service", unit)), service)?;
+    fs::write(dir.join(format!("{}.timer", unit)), timer)?;
+    systemctl(&["daemon-reload"])?;
+    systemctl(&["enable", "--now", &format!("{}.timer", unit)])?;
+
+    Ok(format!("{}.timer", unit))
+}
+
+/// Disable and remove the scheduled job of the workspace
+pub fn remove(workspace: &Path, name: &str) -> Result<()> {
+    let dir = Path::new(SYSTEM_UNIT_DIR);
+    let unit = format!("{}{}", workspace_prefix(workspace)?, name);
+    let timer = dir.join(format!("{}.timer", unit));
+    if !timer.is_file() {
+        bail!("Job `{}` is not scheduled in this workspace.", name);
+    }
+    if let Err(e) = systemctl(&["disable", "--now", &format!("{}.timer", unit)]) {
+        warn!("Unable to disable the timer: {}", e);
+    }
+    fs::remove_file(timer)?;
+    fs::remove_file(dir.join(format!("{}.service", unit))).ok();
+    systemctl(&["daemon-reload"])?;
+    info!("Job `{}` removed.", name);
+
+    Ok(())
+}
+
+/// List the jobs scheduled in the workspace, with when they run
+pub fn list(workspace: &Path) -> Result<()> {
+    let prefix = workspace_prefix(workspace)?;
+    let mut jobs = Vec::new();
+    for entry in fs::read_dir(SYSTEM_UNIT_DIR).into_iter().flatten() {
+        let entry = entry?;
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        let name = match file_name
+            .strip_prefix(&prefix)
+            .and_then(|name| name.strip_suffix(".timer"))
+        {
+            Some(name) => name.to_owned(),
+            None => continue,
+        };
+        let calendar = fs::read_to_string(entry.path())?
+            .lines()
+            .find_map(|line| line.strip_prefix("OnCalendar=").map(str::to_owned))
+            .unwrap_or_default();
+        jobs.push((name, calendar));
+    }
+    if jobs.is_empty() {
+        info!("No jobs are scheduled in this workspace.");
+        return Ok(());
+    }
+    jobs.sort();
+    let mut formatter = TabWriter::new(std::io::stderr());
+    writeln!(&mut formatter, "JOB\tWHEN")?;
+    for (name, calendar) in jobs {
+        writeln!(&mut formatter, "{}\t{}", name, calendar)?;
+    }
+    formatter.flush()?;
+
+    Ok(())
+}
+
+#[test]
+fn test_render_units() {
+    assert_eq!(quote_arg("update-os"), "update-os");
+    assert_eq!(quote_arg("/srv/my ws"), "\"/srv/my ws\"");
+    assert_eq!(quote_arg("100%"), "100%%");
+    let job = Job::parse(
+        "build",
+        Some("main".to_owned()),
+        vec!["@/srv/list".to_owned()],
+    )
+    .unwrap();
+    let (service, timer) = render_units(
+        &job,
+        "daily",
+        Path::new("/srv/ws"),
+        Path::new("/usr/bin/ciel"),
+    );
+    assert!(service
+        .contains("ExecStart=/usr/bin/ciel --workspace /srv/ws -b build -i main @/srv/list\n"));
+    assert!(service.contains("WorkingDirectory=/srv/ws\n"));
+    let (service, _) = render_units(
+        &job,
+        "daily",
+        Path::new("/srv/my ws"),
+        Path::new("/usr/bin/ciel"),
+    );
+    assert!(service.contains("WorkingDirectory=/srv/my ws\n"));
+    assert!(timer.contains("OnCalendar=daily\n"));
+    assert!(Job::parse("build", None, Vec::new()).is_err());
+}
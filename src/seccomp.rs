@@ -0,0 +1,210 @@
+//! This module contains the system call filters of the instances
+
+use anyhow::{bail, Result};
+use lazy_static::lazy_static;
+use std::{collections::HashSet, process::Command};
+
+use crate::{config::SeccompConfig, preset};
+
+/// The groups of system calls defined by systemd (see `systemd-analyze syscall-filter`)
+const SYSCALL_GROUPS: &[&str] = &[
+    "@aio",
+    "@basic-io",
+    "@chown",
+    "@clock",
+    "@cpu-emulation",
+    "@debug",
+    "@default",
+    "@file-system",
+    "@io-event",
+    "@ipc",
+    "@keyring",
+    "@known",
+    "@memlock",
+    "@module",
+    "@mount",
+    "@network-io",
+    "@obsolete",
+    "@pkey",
+    "@privileged",
+    "@process",
+    "@raw-io",
+    "@reboot",
+    "@resources",
+    "@sandbox",
+    "@setuid",
+    "@signal",
+    "@swap",
+    "@sync",
+    "@system-service",
+    "@timer",
+];
+
+lazy_static! {
+    /// The system calls known to systemd, None if systemd-analyze(1) is not available
+    static ref KNOWN_SYSCALLS: Option<HashSet<String>> = known_syscalls();
+}
+
+/// A preset of the system call filter, on top of the system calls systemd-nspawn allows
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Preset {
+    /// Allows what the builds need on top of systemd-nspawn's list (`swapcontext`)
+    Default,
+    /// Also allows the system calls used by qemu-user and the debuggers, for the instances
+    /// building foreign architectures with binfmt_misc
+    PermissiveForQemu,
+    /// Also denies the system calls the builds should never use (e.g. changing the clock or
+    /// loading the kernel modules), even with extra capabilities
+    Hardened,
+}
+
+impl Preset {
+    pub const NAMES: &'static [&'static str] = crate::cli::SECCOMP_PRESETS;
+
+    pub fn parse(name: &str) -> Result<Self> {
+        preset::parse(
+            "seccomp",
+            &[
+                (Preset::NAMES[0], Preset::Default),
+                (Preset::NAMES[1], Preset::PermissiveForQemu),
+                (Preset::NAMES[2], Preset::Hardened),
+            ],
+            name,
+        )
+    }
+
+    fn allowed(&self) -> &'static [&'static str] {
+        match self {
+            Preset::Default | Preset::Hardened => &["swapcontext"],
+            Preset::PermissiveForQemu => &[
+                "swapcontext",
+                "personality",
+                "@cpu-emulation",
+                "@debug",
+                "@obsolete",
+            ],
+        }
+    }
+
+    fn denied(&self) -> &'static [&'static str] {
+        match self {
+            Preset::Default | Preset::PermissiveForQemu => &[],
+            Preset::Hardened => &[
+                "@clock",
+                "@cpu-emulation",
+                "@debug",
+                "@module",
+                "@obsolete",
+                "@raw-io",
+                "@reboot",
+                "@swap",
+            ],
+        }
+    }
+}
+
+/// Return the system calls known to systemd (`systemd-analyze syscall-filter @known`)
+fn known_syscalls() -> Option<HashSet<String>> {
+    let output = Command::new("systemd-analyze")
+        .args(["syscall-filter", "--no-pager", "@known"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())?;
+    let known = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with(['#', '@']))
+        .map(str::to_owned)
+        .collect::<HashSet<_>>();
+
+    Some(known).filter(|known| !known.is_empty())
+}
+
+/// Check the name of a system call (e.g. `ptrace`) or a group (e.g. `@debug`), the system calls
+/// are looked up in `known` if available
+fn validate_name(name: &str, known: Option<&HashSet<String>>) -> Result<()> {
+    if name.starts_with('@') {
+        if !SYSCALL_GROUPS.contains(&name) {
+            bail!("unknown system call group `{}`", name);
+        }
+    } else if name.is_empty()
+        || !name
+            .bytes()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == b'_')
+    {
+        bail!("invalid system call name `{}`", name);
+    } else if known.map_or(false, |known| !known.contains(name)) {
+        bail!("unknown system call `{}`", name);
+    }
+
+    Ok(())
+}
+
+/// Merge the lists of the configuration into the preset, returns the allowed and the denied
+/// system calls
+fn merge(config: &SeccompConfig) -> Result<(Vec<String>, Vec<String>)> {
+    let base = Preset::parse(config.preset.as_deref().unwrap_or("default"))?;
+    for name in config.allow.iter().chain(config.deny.iter()) {
+        validate_name(name, KNOWN_SYSCALLS.as_ref())?;
+    }
+
+    preset::merge(
+        (base.allowed(), base.denied()),
+        &config.allow,
+        &config.deny,
+        ("allowed", "denied"),
+    )
+}
+
+/// Return the options of systemd-nspawn filtering the system calls of the instance
+pub fn nspawn_options(config: &SeccompConfig) -> Result<Vec<String>> {
+    let (allowed, denied) = merge(config)?;
+    let mut options = Vec::new();
+    if !allowed.is_empty() {
+        options.push(format!("--system-call-filter={}", allowed.join(" ")));
+    }
+    if !denied.is_empty() {
+        options.push(format!("--system-call-filter=~{}", denied.join(" ")));
+    }
+
+    Ok(options)
+}
+
+#[test]
+fn test_nspawn_options() {
+    assert_eq!(
+        nspawn_options(&SeccompConfig::default()).unwrap(),
+        vec!["--system-call-filter=swapcontext"]
+    );
+    let config = SeccompConfig {
+        preset: Some("hardened".to_owned()),
+        allow: vec!["@debug".to_owned()],
+        deny: vec!["swapcontext".to_owned(), "@keyring".to_owned()],
+    };
+    assert_eq!(
+        nspawn_options(&config).unwrap(),
+        vec![
+            "--system-call-filter=@debug",
+            "--system-call-filter=~@clock @cpu-emulation @module @obsolete @raw-io @reboot @swap swapcontext @keyring"
+        ]
+    );
+    let invalid = |preset: &str, allow: &str, deny: &str| {
+        nspawn_options(&SeccompConfig {
+            preset: Some(preset.to_owned()),
+            allow: vec![allow.to_owned()],
+            deny: vec![deny.to_owned()],
+        })
+        .is_err()
+    };
+    assert!(invalid("strict", "ptrace", "@swap"));
+    assert!(invalid("default", "@debugging", "@swap"));
+    assert!(invalid("default", "ptrace(2)", "@swap"));
+    assert!(invalid("default", "ptrace", "ptrace"));
+    assert!(!invalid("permissive-for-qemu", "ptrace", "@swap"));
+    let known = vec!["ptrace".to_owned()]
+        .into_iter()
+        .collect::<HashSet<_>>();
+    assert!(validate_name("ptrace", Some(&known)).is_ok());
+    assert!(validate_name("ptracee", Some(&known)).is_err());
+    assert!(validate_name("ptracee", None).is_ok());
+}
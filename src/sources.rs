@@ -0,0 +1,157 @@
+//! This module contains the source cache related APIs
+
+use anyhow::Result;
+use console::style;
+use indicatif::HumanBytes;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+use walkdir::WalkDir;
+
+use crate::{common::FileLock, info};
+
+/// The source cache in the workspace
+pub const SOURCE_CACHE: &str = "SRCS";
+/// The lock file of the source cache (not bind-mounted, not evicted)
+const LOCK_FILE: &str = ".ciel/data/sources.lock";
+
+/// Lock the cache for using it (e.g. building or fetching), blocks while it is evicted
+pub fn lock_shared() -> Result<FileLock> {
+    FileLock::shared(Path::new(LOCK_FILE))
+}
+
+/// Lock the cache for removing the sources, blocks while it is used
+pub fn lock_exclusive() -> Result<FileLock> {
+    FileLock::exclusive(Path::new(LOCK_FILE))
+}
+
+/// A source in the cache (a tarball or a VCS checkout)
+struct CachedSource {
+    path: PathBuf,
+    size: u64,
+    /// When the source was last modified (or accessed, whichever is later)
+    used: SystemTime,
+}
+
+fn list_sources() -> Result<Vec<CachedSource>> {
+    let mut sources = Vec::new();
+    let dir = match fs::read_dir(SOURCE_CACHE) {
+        Ok(dir) => dir,
+        Err(_) => return Ok(sources),
+    };
+    for entry in dir {
+        let path = entry?.path();
+        let mut size = 0;
+        let mut used = SystemTime::UNIX_EPOCH;
+        for file in WalkDir::new(&path).into_iter().filter_map(|e| e.ok()) {
+            let meta = match file.metadata() {
+                Ok(meta) => meta,
+                Err(_) => continue,
+            };
+            if meta.is_file() {
+                size += meta.len();
+            }
+            for time in [meta.modified(), meta.accessed()].iter().flatten() {
+                used = used.max(*time);
+            }
+        }
+        sources.push(CachedSource { path, size, used });
+    }
+    sources.sort_by_key(|s| s.used);
+
+    Ok(sources)
+}
+
+/// Select the least recently used sources to remove until the cache fits within `max_size`,
+/// the sources must be sorted by the last use
+fn select_evictions(sizes: &[u64], max_size: u64) -> usize {
+    let mut total: u64 = sizes.iter().sum();
+    let mut count = 0;
+    for size in sizes {
+        if total <= max_size {
+            break;
+        }
+        total -= size;
+        count += 1;
+    }
+
+    count
+}
+
+/// Remove the least recently used sources until the cache fits within `max_size`, skipped if
+/// the cache is in use by other builds
+pub fn evict(max_size: u64) -> Result<()> {
+    let _lock = match FileLock::try_exclusive(Path::new(LOCK_FILE))? {
+        Some(lock) => lock,
+        None => {
+            info!("The source cache is in use, skipping the eviction.");
+            return Ok(());
+        }
+    };
+    let sources = list_sources()?;
+    let count = select_evictions(
+        &sources.iter().map(|s| s.size).collect::<Vec<_>>(),
+        max_size,
+    );
+    if count == 0 {
+        return Ok(());
+    }
+    let mut reclaimed = 0;
+    for source in sources.iter().take(count) {
+        if source.path.is_dir() {
+            fs::remove_dir_all(&source.path)?;
+        } else {
+            fs::remove_file(&source.path)?;
+        }
+        reclaimed += source.size;
+    }
+    info!(
+        "Evicted {} source(s) from the source cache, {} reclaimed.",
+        count,
+        HumanBytes(reclaimed)
+    );
+
+    Ok(())
+}
+
+/// Remove all the sources in the cache (or only list them in a dry run)
+pub fn clean(dry_run: bool) -> Result<()> {
+    let _lock = lock_exclusive()?;
+    let sources = list_sources()?;
+    let reclaimed: u64 = sources.iter().map(|s| s.size).sum();
+    if dry_run {
+        for source in sources.iter() {
+            info!("Would delete {}", source.path.display());
+        }
+        info!(
+            "{} source(s) can be removed, {} can be reclaimed.",
+            sources.len(),
+            HumanBytes(reclaimed)
+        );
+        return Ok(());
+    }
+    for source in sources.iter() {
+        if source.path.is_dir() {
+            fs::remove_dir_all(&source.path)?;
+        } else {
+            fs::remove_file(&source.path)?;
+        }
+    }
+    info!(
+        "Removed {} source(s) from the source cache, {} reclaimed.",
+        sources.len(),
+        HumanBytes(reclaimed)
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_select_evictions() {
+    assert_eq!(select_evictions(&[10, 20, 30], 100), 0);
+    assert_eq!(select_evictions(&[10, 20, 30], 50), 1);
+    assert_eq!(select_evictions(&[10, 20, 30], 30), 2);
+    assert_eq!(select_evictions(&[10, 20, 30], 0), 3);
+}
@@ -0,0 +1,197 @@
+//! This module contains the build statistics related APIs
+
+use anyhow::Result;
+use console::style;
+use indicatif::HumanBytes;
+use lazy_static::lazy_static;
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+use tabwriter::TabWriter;
+
+use crate::{
+    buildlog::{self, BuildRecord},
+    info,
+};
+
+lazy_static! {
+    /// The totals of the records read so far, with the offset in the ledger to read from
+    static ref TOTALS: Mutex<(u64, BuildTotals)> = Mutex::new((0, BuildTotals::default()));
+}
+
+/// Usage counters of a cgroup, taken before the build
+pub struct CgroupSnapshot {
+    path: PathBuf,
+    cpu_usec: Option<u64>,
+}
+
+#[inline]
+fn read_cpu_usec(cgroup: &Path) -> Option<u64> {
+    let stat = fs::read_to_string(cgroup.join("cpu.stat")).ok()?;
+    stat.lines()
+        .find_map(|l| l.strip_prefix("usage_usec "))
+        .and_then(|v| v.trim().parse().ok())
+}
+
+impl CgroupSnapshot {
+    pub fn take(cgroup: &Path) -> Self {
+        CgroupSnapshot {
+            path: cgroup.to_path_buf(),
+            cpu_usec: read_cpu_usec(cgroup),
+        }
+    }
+
+    /// Return the CPU time (in seconds) used since the snapshot
+    pub fn cpu_time(&self) -> Option<f64> {
+        let (before, after) = (self.cpu_usec?, read_cpu_usec(&self.path)?);
+
+        Some(after.saturating_sub(before) as f64 / 1_000_000.0)
+    }
+
+    /// Return the peak memory usage of the cgroup (since the container is started)
+    pub fn peak_memory(&self) -> Option<u64> {
+        fs::read_to_string(self.path.join("memory.peak"))
+            .ok()?
+            .trim()
+            .parse()
+            .ok()
+    }
+}
+
+/// Totals of the builds recorded
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct BuildTotals {
+    pub succeeded: usize,
+    pub failed: usize,
+    /// Wall time of the successful builds in seconds
+    pub wall_time: f64,
+}
+
+impl BuildTotals {
+    fn add(&mut self, record: &BuildRecord) {
+        if record.success() {
+            self.succeeded += 1;
+            self.wall_time += record.wall_time;
+        } else {
+            self.failed += 1;
+        }
+    }
+}
+
+/// Return the totals of the builds recorded, only the records added since the last call are
+/// read (e.g. for each scrape of the metrics)
+pub fn totals() -> BuildTotals {
+    let mut guard = TOTALS.lock().unwrap_or_else(|e| e.into_inner());
+    let (offset, totals) = &mut *guard;
+    let (records, new_offset) = match buildlog::read_records(*offset) {
+        Ok(read) => read,
+        Err(_) => return *totals,
+    };
+    // the ledger has been replaced, the records are read from the start
+    if new_offset < *offset {
+        *totals = BuildTotals::default();
+    }
+    for record in records.iter() {
+        totals.add(record);
+    }
+    *offset = new_offset;
+
+    *totals
+}
+
+#[inline]
+fn format_seconds(seconds: f64) -> String {
+    let seconds = seconds.round() as u64;
+    format!(
+        "{:02}:{:02}:{:02}",
+        seconds / 3600,
+        (seconds / 60) % 60,
+        seconds % 60
+    )
+}
+
+/// Return the change of the latest value relative to the mean of the previous values
+fn trend(values: &[f64]) -> Option<f64> {
+    let (latest, previous) = values.split_last()?;
+    if previous.is_empty() {
+        return None;
+    }
+    let mean = previous.iter().sum::<f64>() / previous.len() as f64;
+    if mean <= 0.0 {
+        return None;
+    }
+
+    Some((latest - mean) / mean * 100.0)
+}
+
+/// Print the slowest packages (by their latest successful builds), with the trends
+/// of the wall time
+pub fn report(top: usize) -> Result<()> {
+    let ledger = buildlog::load_records();
+    if ledger.is_empty() {
+        info!("No builds recorded yet.");
+        return Ok(());
+    }
+    let mut packages: BTreeMap<&str, Vec<&BuildRecord>> = BTreeMap::new();
+    for stats in ledger.iter().filter(|s| s.success()) {
+        packages.entry(&stats.package).or_default().push(stats);
+    }
+    let mut latest = packages
+        .iter()
+        .filter_map(|(name, builds)| Some((*name, *builds.last()?, builds)))
+        .collect::<Vec<_>>();
+    latest.sort_by(|a, b| b.1.wall_time.total_cmp(&a.1.wall_time));
+
+    let mut formatter = TabWriter::new(std::io::stderr());
+    writeln!(
+        &mut formatter,
+        "PACKAGE\tWALL TIME\tCPU TIME\tPEAK MEMORY\tARTIFACTS\tBUILDS\tTREND"
+    )?;
+    for (name, stats, builds) in latest.iter().take(top) {
+        let wall_times = builds.iter().map(|s| s.wall_time).collect::<Vec<_>>();
+        let trend = match trend(&wall_times) {
+            Some(t) if t > 10.0 => style(format!("{:+.0}%", t)).red(),
+            Some(t) if t < -10.0 => style(format!("{:+.0}%", t)).green(),
+            Some(t) => style(format!("{:+.0}%", t)),
+            None => style("-".to_owned()),
+        };
+        writeln!(
+            &mut formatter,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            name,
+            format_seconds(stats.wall_time),
+            stats
+                .cpu_time
+                .map_or_else(|| "-".to_owned(), format_seconds),
+            stats
+                .peak_memory
+                .map_or_else(|| "-".to_owned(), |m| HumanBytes(m).to_string()),
+            HumanBytes(stats.artifact_size),
+            builds.len(),
+            trend
+        )?;
+    }
+    formatter.flush()?;
+    let failed = ledger.iter().filter(|s| !s.success()).count();
+    info!(
+        "{} build(s) of {} package(s) recorded, {} failed, {} in total.",
+        ledger.len(),
+        packages.len(),
+        failed,
+        format_seconds(ledger.iter().map(|s| s.wall_time).sum())
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_trend() {
+    assert_eq!(trend(&[]), None);
+    assert_eq!(trend(&[10.0]), None);
+    assert_eq!(trend(&[10.0, 20.0, 30.0]), Some(100.0));
+    assert_eq!(trend(&[100.0, 50.0]), Some(-50.0));
+}
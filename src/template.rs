@@ -0,0 +1,137 @@
+//! This module contains the workspace template related APIs (the layout is in the README)
+
+use anyhow::{anyhow, Result};
+use console::style;
+use serde::Deserialize;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+use tempfile::TempDir;
+use walkdir::WalkDir;
+
+use crate::{config::CielConfig, hooks::HOOKS_LOCATION, info, network::download_git};
+
+const TEMPLATE_MANIFEST: &str = "template.toml";
+const TEMPLATE_CONFIG: &str = "config.toml";
+const TEMPLATE_APT_SOURCES: &str = "apt-sources.list";
+const TEMPLATE_HOOKS: &str = "hooks";
+
+/// Description of the template
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TemplateManifest {
+    #[serde(default)]
+    pub description: String,
+    /// Configuration keys the user must provide values for
+    #[serde(default)]
+    pub required: Vec<String>,
+    /// Target architecture of the workspace
+    pub arch: Option<String>,
+    /// Branch of the ABBS tree
+    pub branch: Option<String>,
+}
+
+/// A workspace template fetched from a Git repository
+pub struct Template {
+    pub manifest: TemplateManifest,
+    /// The cloned repository, removed when the template is dropped
+    dir: TempDir,
+}
+
+impl Template {
+    /// Fetch the template from the Git repository
+    pub fn fetch(url: &str) -> Result<Template> {
+        info!("Fetching template from {}...", url);
+        let dir = tempfile::tempdir()?;
+        download_git(url, dir.path())
+            .map_err(|e| anyhow!("Unable to fetch the template: {}", e))?;
+        let manifest_path = dir.path().join(TEMPLATE_MANIFEST);
+        let manifest = if manifest_path.is_file() {
+            toml::from_str(&fs::read_to_string(manifest_path)?)
+                .map_err(|e| anyhow!("Invalid template manifest: {}", e))?
+        } else {
+            TemplateManifest::default()
+        };
+        if !manifest.description.is_empty() {
+            info!("Using template: {}", manifest.description);
+        }
+
+        Ok(Template { manifest, dir })
+    }
+
+    #[inline]
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.path().join(name)
+    }
+
+    /// Apply the configuration values and the extra sources of the template
+    pub fn apply(&self, config: &mut CielConfig) -> Result<()> {
+        let config_path = self.path(TEMPLATE_CONFIG);
+        if config_path.is_file() {
+            let table: toml::Table = toml::from_str(&fs::read_to_string(config_path)?)
+                .map_err(|e| anyhow!("Invalid template configuration: {}", e))?;
+            config.merge(table)?;
+        }
+        let sources_path = self.path(TEMPLATE_APT_SOURCES);
+        if sources_path.is_file() {
+            let sources = fs::read_to_string(sources_path)?;
+            let mut apt_sources = config.apt_sources.trim_end().to_owned();
+            for line in sources.lines().filter(|l| !l.trim().is_empty()) {
+                apt_sources.push('\n');
+                apt_sources.push_str(line);
+            }
+            config.apt_sources = apt_sources.trim_start().to_owned();
+        }
+
+        Ok(())
+    }
+
+    /// Return the hook scripts of the template (relative to the hooks directory)
+    pub fn hooks(&self) -> Result<Vec<PathBuf>> {
+        let hooks = self.path(TEMPLATE_HOOKS);
+        let mut scripts = Vec::new();
+        if !hooks.is_dir() {
+            return Ok(scripts);
+        }
+        for entry in WalkDir::new(&hooks).sort_by_file_name() {
+            let entry = entry?;
+            if entry.file_type().is_file() {
+                scripts.push(entry.path().strip_prefix(&hooks)?.to_owned());
+            }
+        }
+
+        Ok(scripts)
+    }
+
+    /// Print the hook scripts of the template, for the user to review them before they are
+    /// installed (and run by the later operations)
+    pub fn show_hooks(&self) -> Result<()> {
+        for script in self.hooks()? {
+            let content = fs::read(self.path(TEMPLATE_HOOKS).join(&script))?;
+            info!("Hook {}:", style(script.display()).bold());
+            eprintln!("{}", String::from_utf8_lossy(&content));
+        }
+
+        Ok(())
+    }
+
+    /// Install the hook scripts of the template into the workspace
+    pub fn install_hooks(&self) -> Result<()> {
+        let hooks = self.path(TEMPLATE_HOOKS);
+        if !hooks.is_dir() {
+            return Ok(());
+        }
+        for entry in WalkDir::new(&hooks) {
+            let entry = entry?;
+            let target = Path::new(HOOKS_LOCATION).join(entry.path().strip_prefix(&hooks)?);
+            if entry.file_type().is_dir() {
+                fs::create_dir_all(target)?;
+            } else if entry.file_type().is_file() {
+                fs::copy(entry.path(), target)?;
+            }
+        }
+
+        Ok(())
+    }
+}
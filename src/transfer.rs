@@ -0,0 +1,448 @@
+//! This module contains file transfer APIs between the host and the instances
+
+use crate::common::{glob_match, is_instance_exists, user_path};
+use crate::machine::mount_layers;
+use crate::overlayfs::{get_layer_manager, LayerManager};
+use crate::{errors::CielError, info, reflink, warn};
+use anyhow::{anyhow, bail, Result};
+use console::style;
+use nix::unistd::{fchownat, FchownatFlags, Gid, Uid};
+use std::fs::{self, Metadata};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{symlink, FileTypeExt, MetadataExt};
+use std::path::{Component, Path, PathBuf};
+use walkdir::WalkDir;
+
+const OPAQUE_XATTR: &str = "trusted.overlay.opaque";
+
+/// One side of a file transfer
+#[derive(Debug)]
+enum Location {
+    Host(PathBuf),
+    Instance(String, PathBuf),
+}
+
+/// Split `<instance>:<path>` into the instance name and the path
+fn split_location(spec: &str) -> Option<(&str, &str)> {
+    let (instance, path) = spec.split_once(':')?;
+    if instance.is_empty() || instance.contains('/') {
+        return None;
+    }
+
+    Some((instance, path))
+}
+
+fn parse_location(spec: &str) -> Result<Location> {
+    match split_location(spec) {
+        // a host file may contain a colon in its name as well
+        Some((instance, path)) if !user_path(spec).exists() => {
+            if !is_instance_exists(instance) {
+                return Err(CielError::InstanceNotFound(instance.to_owned()).into());
+            }
+            Ok(Location::Instance(instance.to_owned(), PathBuf::from(path)))
+        }
+        _ => Ok(Location::Host(user_path(spec))),
+    }
+}
+
+#[inline]
+fn is_whiteout(meta: &Metadata) -> bool {
+    meta.file_type().is_char_device() && meta.rdev() == 0
+}
+
+/// Convert the path in the instance to a relative path, rejecting any `..` components
+fn normalize(path: &Path) -> Result<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::RootDir | Component::CurDir => continue,
+            Component::Normal(name) => normalized.push(name),
+            _ => bail!("Invalid path: {}", path.display()),
+        }
+    }
+
+    Ok(normalized)
+}
+
+/// Resolve the path in the instance under the given root without following symlinks
+/// (symlinks in the instance point to paths in the instance, not on the host)
+fn resolve(root: &Path, path: &Path) -> Result<PathBuf> {
+    let normalized = normalize(path)?;
+    let mut resolved = root.to_path_buf();
+    if let Some(parent) = normalized.parent() {
+        for component in parent.components() {
+            resolved.push(component);
+            match fs::symlink_metadata(&resolved) {
+                Ok(meta) if meta.is_dir() => continue,
+                _ => bail!(
+                    "{}: no such directory in the instance (symlinks are not followed)",
+                    Path::new("/").join(parent).display()
+                ),
+            }
+        }
+    }
+
+    Ok(root.join(normalized))
+}
+
+/// Find the topmost layer containing the path, honoring whiteouts and opaque directories
+fn merged_lookup(layers: &[PathBuf], rel: &Path) -> Result<Option<PathBuf>> {
+    let depth = rel.components().count();
+    for layer in layers {
+        let mut current = layer.clone();
+        let mut opaque = false;
+        for (i, component) in rel.components().enumerate() {
+            current.push(component);
+            let meta = match fs::symlink_metadata(&current) {
+                Ok(meta) => meta,
+                Err(_) => break,
+            };
+            if is_whiteout(&meta) {
+                return Ok(None);
+            }
+            if i + 1 == depth {
+                return Ok(Some(current));
+            }
+            if meta.file_type().is_symlink() {
+                bail!(
+                    "{}: symlinks in the instance are not followed",
+                    Path::new("/").join(rel).display()
+                );
+            }
+            if !meta.is_dir() {
+                // a file hides everything below it
+                return Ok(None);
+            }
+            if xattr::get(&current, OPAQUE_XATTR)?.as_deref() == Some(b"y") {
+                opaque = true;
+            }
+        }
+        if depth == 0 {
+            return Ok(Some(current));
+        }
+        if opaque {
+            return Ok(None);
+        }
+    }
+
+    Ok(None)
+}
+
+/// Create the parent directories of the path in the upper layer, copying them from the lower layers
+fn prepare_upper_parents(layers: &[PathBuf], rel: &Path) -> Result<()> {
+    let upper = layers
+        .first()
+        .ok_or_else(|| anyhow!("No upper layer available"))?;
+    let parent = match rel.parent() {
+        Some(parent) => parent,
+        None => return Ok(()),
+    };
+    let mut current = PathBuf::new();
+    for component in parent.components() {
+        current.push(component);
+        let upper_dir = upper.join(&current);
+        if fs::symlink_metadata(&upper_dir)
+            .map(|m| m.is_dir())
+            .unwrap_or(false)
+        {
+            continue;
+        }
+        match merged_lookup(layers, &current)? {
+            Some(lower_dir) if fs::symlink_metadata(&lower_dir)?.is_dir() => {
+                fs::create_dir(&upper_dir)?;
+                copy_metadata(&lower_dir, &upper_dir)?;
+            }
+            _ => bail!(
+                "{}: no such directory in the instance",
+                Path::new("/").join(&current).display()
+            ),
+        }
+    }
+
+    Ok(())
+}
+
+/// Copy the ownership, permissions and extended attributes of the file
+fn copy_metadata(from: &Path, to: &Path) -> Result<()> {
+    let meta = fs::symlink_metadata(from)?;
+    fchownat(
+        None,
+        to,
+        Some(Uid::from_raw(meta.uid())),
+        Some(Gid::from_raw(meta.gid())),
+        FchownatFlags::NoFollowSymlink,
+    )?;
+    if meta.file_type().is_symlink() {
+        return Ok(());
+    }
+    // chown(2) clears the setuid bits, so the permissions are applied afterwards
+    fs::set_permissions(to, meta.permissions())?;
+    for name in xattr::list(from)? {
+        // overlay internal attributes are only meaningful in their original layer
+        if name.as_bytes().starts_with(b"trusted.overlay.") {
+            continue;
+        }
+        if let Some(value) = xattr::get(from, &name)? {
+            xattr::set(to, &name, &value)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Remove the non-directory file (e.g. a whiteout) at the path if it exists
+#[inline]
+fn remove_existing(path: &Path) -> Result<()> {
+    if let Ok(meta) = fs::symlink_metadata(path) {
+        if !meta.is_dir() {
+            fs::remove_file(path)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Recursively copy the files, returns the number of files copied.
+/// If `into_upper` is true, directories replacing whiteouts are marked as opaque
+fn copy_tree(from: &Path, to: &Path, into_upper: bool) -> Result<usize> {
+    let mut dirs = Vec::new();
+    let mut count = 0;
+    for entry in WalkDir::new(from) {
+        let entry = entry?;
+        let rel_path = entry.path().strip_prefix(from)?;
+        let target = if rel_path.as_os_str().is_empty() {
+            to.to_path_buf()
+        } else {
+            to.join(rel_path)
+        };
+        let file_type = entry.file_type();
+        if file_type.is_dir() {
+            let existing = fs::symlink_metadata(&target).ok();
+            if !existing.as_ref().map(|m| m.is_dir()).unwrap_or(false) {
+                let whiteout = existing.as_ref().map(is_whiteout).unwrap_or(false);
+                remove_existing(&target)?;
+                fs::create_dir(&target)?;
+                if into_upper && whiteout {
+                    // do not let the deleted contents in the lower layers re-appear
+                    xattr::set(&target, OPAQUE_XATTR, b"y")?;
+                }
+            }
+            dirs.push((entry.path().to_path_buf(), target));
+            continue;
+        } else if file_type.is_symlink() {
+            remove_existing(&target)?;
+            symlink(fs::read_link(entry.path())?, &target)?;
+        } else if file_type.is_file() {
+            remove_existing(&target)?;
+            reflink::copy_file(entry.path(), &target)?;
+        } else {
+            warn!("Skipping special file: {}", entry.path().display());
+            continue;
+        }
+        copy_metadata(entry.path(), &target)?;
+        count += 1;
+    }
+    // directories may be read-only, so their metadata is applied last
+    for (from, to) in dirs.iter().rev() {
+        copy_metadata(from, to)?;
+    }
+
+    Ok(count)
+}
+
+/// Return the merged filesystem of the instance, mounting it temporarily if needed
+fn merged_root(man: &mut dyn LayerManager, instance: &str) -> Result<(PathBuf, bool)> {
+    let target = std::env::current_dir()?.join(instance);
+    if man.is_mounted(&target)? {
+        return Ok((target, false));
+    }
+    mount_layers(man, instance)?;
+
+    Ok((target, true))
+}
+
+fn copy_to_instance(from: &Path, instance: &str, to: &Path) -> Result<usize> {
+    let name = from
+        .file_name()
+        .ok_or_else(|| anyhow!("Invalid source path: {}", from.display()))?;
+    fs::symlink_metadata(from).map_err(|e| anyhow!("Unable to read {}: {}", from.display(), e))?;
+    let man = &mut *get_layer_manager(instance)?;
+    let target = std::env::current_dir()?.join(instance);
+    if !man.is_mounted(&target)? {
+        if let Some(upper) = man.get_upper_layer()? {
+            // write into the upper layer directly, as if the changes are made in the container
+            let layers = vec![upper, man.get_config_layer()?, man.get_base_layer()?];
+            let mut rel = normalize(to)?;
+            if let Some(existing) = merged_lookup(&layers, &rel)? {
+                if fs::symlink_metadata(existing)?.is_dir() {
+                    rel.push(name);
+                }
+            }
+            prepare_upper_parents(&layers, &rel)?;
+            return copy_tree(from, &layers[0].join(rel), true);
+        }
+    }
+    let (root, temporary) = merged_root(man, instance)?;
+    let result = resolve(&root, to).and_then(|mut dest| {
+        if fs::symlink_metadata(&dest)
+            .map(|m| m.is_dir())
+            .unwrap_or(false)
+        {
+            dest.push(name);
+        }
+        copy_tree(from, &dest, false)
+    });
+    if temporary {
+        man.unmount(&root)?;
+    }
+
+    result
+}
+
+fn copy_from_instance(instance: &str, from: &Path, to: &Path) -> Result<usize> {
+    let man = &mut *get_layer_manager(instance)?;
+    let (root, temporary) = merged_root(man, instance)?;
+    let result = resolve(&root, from).and_then(|source| {
+        fs::symlink_metadata(&source)
+            .map_err(|e| anyhow!("Unable to read {}: {}", from.display(), e))?;
+        let name = source
+            .file_name()
+            .ok_or_else(|| anyhow!("Invalid source path: {}", from.display()))?;
+        let dest = if to.is_dir() {
+            to.join(name)
+        } else {
+            to.to_path_buf()
+        };
+        copy_tree(&source, &dest, false)
+    });
+    if temporary {
+        man.unmount(&root)?;
+    }
+
+    result
+}
+
+/// Find the paths under the root matching the glob pattern (see [glob_match]), relative to
+/// the root. The directories matched are not descended into
+fn find_matching(root: &Path, pattern: &str) -> Result<Vec<PathBuf>> {
+    let pattern = normalize(Path::new(pattern))?;
+    // start from the directory before the first wildcard
+    let prefix = pattern
+        .components()
+        .take_while(|c| {
+            !c.as_os_str()
+                .to_string_lossy()
+                .contains(|c| c == '*' || c == '?')
+        })
+        .collect::<PathBuf>();
+    let pattern = pattern.to_string_lossy();
+    let start = root.join(&prefix);
+    if fs::symlink_metadata(&start).is_err() {
+        return Ok(Vec::new());
+    }
+    let mut found = Vec::new();
+    let mut it = WalkDir::new(&start).into_iter();
+    while let Some(entry) = it.next() {
+        let entry = entry?;
+        let rel = entry.path().strip_prefix(root)?;
+        if glob_match(&pattern, &rel.to_string_lossy()) {
+            found.push(rel.to_path_buf());
+            if entry.file_type().is_dir() {
+                it.skip_current_dir();
+            }
+        }
+    }
+
+    Ok(found)
+}
+
+/// Copy the files in the instance matching the glob patterns into the directory, keeping
+/// their paths. Only the files in the upper layer are looked for, if it is available.
+/// Returns the paths copied
+pub fn save_matching(instance: &str, patterns: &[String], to: &Path) -> Result<Vec<PathBuf>> {
+    let man = &mut *get_layer_manager(instance)?;
+    let (root, temporary) = merged_root(man, instance)?;
+    let result = man.get_upper_layer().and_then(|upper| {
+        let search = upper.unwrap_or_else(|| root.clone());
+        let mut saved: Vec<PathBuf> = Vec::new();
+        for pattern in patterns {
+            for path in find_matching(&search, pattern)? {
+                let source = root.join(&path);
+                // whiteouts in the upper layer are not in the merged filesystem
+                if saved.iter().any(|s| path.starts_with(s))
+                    || fs::symlink_metadata(&source).is_err()
+                {
+                    continue;
+                }
+                let dest = to.join(&path);
+                if let Some(parent) = dest.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                copy_tree(&source, &dest, false)?;
+                saved.push(path);
+            }
+        }
+        Ok(saved)
+    });
+    if temporary {
+        man.unmount(&root)?;
+    }
+
+    result
+}
+
+/// Copy the paths saved by [save_matching] back into the instance, returns the number of
+/// files copied
+pub fn restore_saved(instance: &str, from: &Path, paths: &[PathBuf]) -> Result<usize> {
+    let man = &mut *get_layer_manager(instance)?;
+    let (root, temporary) = merged_root(man, instance)?;
+    let mut result = Ok(0);
+    for path in paths {
+        result = result.and_then(|count| {
+            let dest = root.join(path);
+            if let Some(parent) = dest.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            Ok(count + copy_tree(&from.join(path), &dest, false)?)
+        });
+    }
+    if temporary {
+        man.unmount(&root)?;
+    }
+
+    result
+}
+
+/// Copy files between the host and an instance, `<instance>:<path>` refers to a path in the instance.
+/// Ownership, permissions and extended attributes are preserved
+pub fn copy(src: &str, dst: &str) -> Result<()> {
+    let (instance, count) = match (parse_location(src)?, parse_location(dst)?) {
+        (Location::Instance(instance, from), Location::Host(to)) => {
+            let count = copy_from_instance(&instance, &from, &to)?;
+            (instance, count)
+        }
+        (Location::Host(from), Location::Instance(instance, to)) => {
+            let count = copy_to_instance(&from, &instance, &to)?;
+            (instance, count)
+        }
+        (Location::Host(_), Location::Host(_)) => {
+            bail!("One of the paths must be in an instance (e.g. `main:/root`).")
+        }
+        _ => bail!("Copying files between instances is not supported."),
+    };
+    info!("{}: {} file(s) copied.", instance, count);
+
+    Ok(())
+}
+
+#[test]
+fn test_split_location() {
+    assert_eq!(
+        split_location("main:/etc/os-release"),
+        Some(("main", "/etc/os-release"))
+    );
+    assert_eq!(split_location("main:"), Some(("main", "")));
+    assert_eq!(split_location("./main:/etc"), None);
+    assert_eq!(split_location(":/etc"), None);
+    assert_eq!(split_location("/etc/os-release"), None);
+}
@@ -0,0 +1,189 @@
+//! This module contains the trash of the removed workspaces
+
+use anyhow::{anyhow, bail, Result};
+use console::style;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{common::CIEL_DIST_DIR, warn, zfs};
+
+/// The trash in the workspace directory
+pub const TRASH_DIR: &str = ".ciel-trash";
+const INFO_FILE: &str = "trash.toml";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct TrashInfo {
+    /// When the workspace was removed, in seconds since the epoch
+    removed: u64,
+    /// When the trash expires, in seconds since the epoch
+    expires: u64,
+    /// The dist dataset moved aside (ZFS based workspaces only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dataset: Option<String>,
+}
+
+/// A removed workspace in the trash
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrashEntry {
+    pub id: String,
+    pub path: PathBuf,
+    info: TrashInfo,
+}
+
+impl TrashEntry {
+    pub fn is_expired(&self) -> bool {
+        now() >= self.info.expires
+    }
+}
+
+#[inline]
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Describe the retention period (in seconds) for humans, e.g. `7 day(s)`
+pub fn describe_retention(retention: u64) -> String {
+    if retention >= 86400 {
+        format!("{} day(s)", retention / 86400)
+    } else if retention >= 3600 {
+        format!("{} hour(s)", retention / 3600)
+    } else {
+        format!("{} minute(s)", retention / 60)
+    }
+}
+
+/// List the removed workspaces in the trash of the workspace directory, the oldest first
+pub fn list(workspace: &Path) -> Result<Vec<TrashEntry>> {
+    let mut entries = Vec::new();
+    let dir = match fs::read_dir(workspace.join(TRASH_DIR)) {
+        Ok(dir) => dir,
+        Err(_) => return Ok(entries),
+    };
+    for entry in dir {
+        let entry = entry?;
+        let info = match fs::read_to_string(entry.path().join(INFO_FILE))
+            .ok()
+            .and_then(|content| toml::from_str::<TrashInfo>(&content).ok())
+        {
+            Some(info) => info,
+            None => {
+                warn!("{} is not a removed workspace.", entry.path().display());
+                continue;
+            }
+        };
+        entries.push(TrashEntry {
+            id: entry.file_name().to_string_lossy().to_string(),
+            path: entry.path(),
+            info,
+        });
+    }
+    entries.sort_by_key(|e| e.info.removed);
+
+    Ok(entries)
+}
+
+/// List the removed workspaces whose retention period has expired
+pub fn expired(workspace: &Path) -> Result<Vec<TrashEntry>> {
+    Ok(list(workspace)?
+        .into_iter()
+        .filter(|e| e.is_expired())
+        .collect())
+}
+
+/// Move the workspace data (`.ciel`) into the trash, to be kept for `retention` seconds
+pub fn move_to_trash(workspace: &Path, retention: u64) -> Result<TrashEntry> {
+    let removed = now();
+    let id = removed.to_string();
+    let path = workspace.join(TRASH_DIR).join(&id);
+    if path.exists() {
+        bail!("{} already exists.", path.display());
+    }
+    fs::create_dir_all(&path)?;
+    let dist = workspace.join(CIEL_DIST_DIR);
+    let dataset = if zfs::is_zfs_workspace() {
+        Some(zfs::trash_dist_dataset(&dist, &id)?)
+    } else {
+        None
+    };
+    let info = TrashInfo {
+        removed,
+        expires: removed + retention,
+        dataset,
+    };
+    fs::write(path.join(INFO_FILE), toml::to_string(&info)?)?;
+    if let Err(e) = fs::rename(workspace.join(".ciel"), path.join(".ciel")) {
+        // put the workspace back the way it was
+        if let Some(dataset) = &info.dataset {
+            zfs::restore_dist_dataset(dataset, &dist)?;
+        }
+        fs::remove_dir_all(&path)?;
+        return Err(anyhow!(
+            "Unable to move the workspace into the trash: {}",
+            e
+        ));
+    }
+
+    Ok(TrashEntry { id, path, info })
+}
+
+/// Restore the removed workspace (the latest one if `id` is not specified) from the trash
+pub fn restore(workspace: &Path, id: Option<&str>) -> Result<TrashEntry> {
+    if workspace.join(".ciel").exists() {
+        bail!("This directory already contains a Ciel workspace.");
+    }
+    let mut entries = list(workspace)?;
+    let entry = match id {
+        Some(id) => entries.into_iter().find(|e| e.id == id),
+        None => entries.pop(),
+    }
+    .ok_or_else(|| anyhow!("No removed workspace to restore."))?;
+    if entry.is_expired() {
+        warn!(
+            "The retention period of {} has expired, restoring it anyway.",
+            entry.id
+        );
+    }
+    fs::rename(entry.path.join(".ciel"), workspace.join(".ciel"))?;
+    if let Some(dataset) = &entry.info.dataset {
+        zfs::restore_dist_dataset(dataset, &workspace.join(CIEL_DIST_DIR))?;
+    }
+    fs::remove_dir_all(&entry.path)?;
+    // only removed if empty
+    fs::remove_dir(workspace.join(TRASH_DIR)).ok();
+
+    Ok(entry)
+}
+
+/// Delete the removed workspace permanently
+pub fn purge(entry: &TrashEntry) -> Result<()> {
+    if let Some(dataset) = &entry.info.dataset {
+        zfs::destroy_dataset(dataset)?;
+    }
+    fs::remove_dir_all(&entry.path)?;
+    if let Some(trash) = entry.path.parent() {
+        fs::remove_dir(trash).ok();
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_trash_info() {
+    let info = TrashInfo {
+        removed: 1_700_000_000,
+        expires: 1_700_604_800,
+        dataset: None,
+    };
+    let content = toml::to_string(&info).unwrap();
+    assert_eq!(content, "removed = 1700000000\nexpires = 1700604800\n");
+    assert_eq!(toml::from_str::<TrashInfo>(&content).unwrap(), info);
+    assert_eq!(describe_retention(7 * 86400), "7 day(s)");
+    assert_eq!(describe_retention(7200), "2 hour(s)");
+}
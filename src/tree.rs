@@ -0,0 +1,688 @@
+//! This module contains the ABBS tree source and overlay trees related APIs
+
+use anyhow::{anyhow, bail, Result};
+use console::style;
+use nix::{
+    mount::{umount2, MntFlags},
+    sys::stat::{makedev, mknod, Mode, SFlag},
+};
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    ffi::OsStr,
+    fs,
+    io::Write,
+    os::unix::fs::FileTypeExt,
+    path::{Path, PathBuf},
+};
+use tabwriter::TabWriter;
+
+use crate::{
+    cli::GIT_TREE_URL,
+    config::{read_config_file, CielConfig, TreeOverlay},
+    info,
+    mount::{escape_option_path, mount_fs, MountOption},
+    network::{clone_repo, fetch_repo, git_fast_forward, CloneOptions},
+    overlayfs::is_mounted,
+    warn,
+};
+
+/// Location of the cloned overlay trees
+const OVERLAY_TREES_DIR: &str = ".ciel/data/trees";
+/// Mount point of the merged tree
+const MERGED_TREE_DIR: &str = ".ciel/data/merged-tree";
+/// The top layer of the merged tree, hiding the Git metadata of the trees
+const MERGED_TREE_WHITEOUTS: &str = ".ciel/data/merged-tree-whiteouts";
+/// The commit of the ABBS tree the last successful build was done at
+const BUILT_REVISION_FILE: &str = ".ciel/data/built-revision";
+
+/// Return the URL of the ABBS tree of the workspace
+pub fn tree_url(config: &CielConfig) -> &str {
+    config.tree.url.as_deref().unwrap_or(GIT_TREE_URL)
+}
+
+#[inline]
+fn overlay_path(name: &str) -> PathBuf {
+    Path::new(OVERLAY_TREES_DIR).join(name)
+}
+
+#[inline]
+fn validate_overlay_name(name: &str) -> Result<()> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        bail!(
+            "Invalid tree name `{}`: only letters, digits, `-` and `_` are allowed",
+            name
+        );
+    }
+
+    Ok(())
+}
+
+/// Return the overlay trees from the lowest priority to the highest
+fn sorted_overlays(config: &CielConfig) -> Vec<(&String, &TreeOverlay)> {
+    let mut overlays = config.tree.overlays.iter().collect::<Vec<_>>();
+    // the sort is stable, so the overlays of the same priority are ordered by the names
+    overlays.sort_by_key(|(_, overlay)| overlay.priority);
+
+    overlays
+}
+
+/// Add an overlay tree and clone it into the workspace
+pub fn add_remote(name: &str, url: &str, branch: Option<&str>, priority: i32) -> Result<()> {
+    validate_overlay_name(name)?;
+    let mut config = read_config_file()?;
+    if config.tree.overlays.contains_key(name) {
+        bail!("Tree `{}` already exists.", name);
+    }
+    let path = overlay_path(name);
+    if path.exists() {
+        warn!("Removing the stale clone of `{}`...", name);
+        fs::remove_dir_all(&path)?;
+    }
+    info!("Cloning tree `{}` from {}...", name, url);
+    let options = CloneOptions {
+        branch: branch.map(|b| b.to_owned()),
+        ..Default::default()
+    };
+    clone_repo(url, &path, &options)?;
+    config.tree.overlays.insert(
+        name.to_owned(),
+        TreeOverlay {
+            url: url.to_owned(),
+            branch: branch.map(|b| b.to_owned()),
+            priority,
+        },
+    );
+    config.save_config_file()?;
+    info!(
+        "Tree `{}` added, it will be merged into /tree when the instances are started.",
+        name
+    );
+
+    Ok(())
+}
+
+/// Remove the overlay tree and its clone
+pub fn remove_remote(name: &str) -> Result<()> {
+    let mut config = read_config_file()?;
+    if config.tree.overlays.remove(name).is_none() {
+        bail!("Tree `{}` does not exist.", name);
+    }
+    let path = overlay_path(name);
+    if path.exists() {
+        fs::remove_dir_all(path)?;
+    }
+    config.save_config_file()?;
+    info!("Tree `{}` removed.", name);
+
+    Ok(())
+}
+
+/// Print the ABBS tree and the overlay trees
+pub fn list_remotes() -> Result<()> {
+    let config = read_config_file()?;
+    let mut formatter = TabWriter::new(std::io::stderr());
+    writeln!(&mut formatter, "NAME\tPRIORITY\tBRANCH\tURL")?;
+    writeln!(
+        &mut formatter,
+        "TREE\t-\t{}\t{}",
+        config.tree.branch.as_deref().unwrap_or("-"),
+        tree_url(&config)
+    )?;
+    for (name, overlay) in sorted_overlays(&config).into_iter().rev() {
+        writeln!(
+            &mut formatter,
+            "{}\t{}\t{}\t{}",
+            name,
+            overlay.priority,
+            overlay.branch.as_deref().unwrap_or("-"),
+            overlay.url
+        )?;
+    }
+    formatter.flush()?;
+
+    Ok(())
+}
+
+/// Fetch and fast-forward all the overlay trees
+pub fn update_overlays() -> Result<()> {
+    let config = read_config_file()?;
+    for (name, overlay) in config.tree.overlays.iter() {
+        let path = overlay_path(name);
+        if !path.is_dir() {
+            info!("Cloning tree `{}`...", name);
+            let options = CloneOptions {
+                branch: overlay.branch.clone(),
+                ..Default::default()
+            };
+            clone_repo(&overlay.url, &path, &options)?;
+            continue;
+        }
+        info!("Updating tree `{}`...", name);
+        let mut repo = fetch_repo(&path)?;
+        if let Err(e) = git_fast_forward(&mut repo) {
+            warn!("Unable to fast-forward tree `{}`: {}", name, e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Create the whiteout of `.git` in the directory, hiding it in the layers below
+fn create_git_whiteout(dir: &Path) -> Result<()> {
+    let whiteout = dir.join(".git");
+    if fs::symlink_metadata(&whiteout).map_or(false, |m| m.file_type().is_char_device()) {
+        return Ok(());
+    }
+    fs::create_dir_all(dir)?;
+    // an overlayfs whiteout is a character device numbered 0:0
+    mknod(&whiteout, SFlag::S_IFCHR, Mode::empty(), makedev(0, 0))
+        .map_err(|e| anyhow!("Unable to create {}: {}", whiteout.display(), e))?;
+
+    Ok(())
+}
+
+/// Mount the ABBS tree merged with the overlay trees, returns the path to the merged tree,
+/// or None if there are no overlay trees. The merged tree is read-only and has no Git
+/// metadata (the `.git` of each tree would be merged into a broken repository), the trees
+/// are still edited and committed in `TREE` and `.ciel/data/trees`
+pub fn mount_merged_tree(config: &CielConfig) -> Result<Option<PathBuf>> {
+    if config.tree.overlays.is_empty() {
+        return Ok(None);
+    }
+    let cwd = std::env::current_dir()?;
+    let target = cwd.join(MERGED_TREE_DIR);
+    // remount to pick up the changes made to the trees since the last mount,
+    // the running containers still see the previous mount
+    if is_mounted(&target, OsStr::new("overlay"))? {
+        umount2(&target, MntFlags::MNT_DETACH)
+            .map_err(|e| anyhow!("Unable to un-mount the merged tree: {}", e))?;
+    }
+    // the lower directories are listed from the top to the bottom
+    let whiteouts = cwd.join(MERGED_TREE_WHITEOUTS);
+    create_git_whiteout(&whiteouts)?;
+    let mut lower = vec![escape_option_path(&whiteouts)];
+    for (name, _) in sorted_overlays(config).into_iter().rev() {
+        let path = cwd.join(overlay_path(name));
+        if !path.is_dir() {
+            bail!(
+                "Tree `{}` is not cloned yet, please run `ciel update-tree`",
+                name
+            );
+        }
+        lower.push(escape_option_path(&path));
+    }
+    lower.push(escape_option_path(&cwd.join("TREE")));
+    // without an upper directory, the merged tree is read-only
+    mount_fs(
+        "overlay",
+        "overlay",
+        &[MountOption::Value("lowerdir", lower.join(":"))],
+        &target,
+    )?;
+
+    Ok(Some(target))
+}
+
+/// Version and dependencies of a package in the tree
+#[derive(Debug, Clone, Default)]
+pub struct PackageSpec {
+    pub name: String,
+    /// `[epoch:]VER[-REL]`, or None if it can not be determined without running the scripts
+    pub version: Option<String>,
+    pub build_deps: Vec<String>,
+    /// The upstream sources (`SRCS`) and their checksums (`CHKSUMS`), as written in the spec
+    pub sources: Vec<String>,
+    pub checksums: Vec<String>,
+}
+
+/// Return the directories of the trees, from the highest priority to the lowest
+fn tree_search_paths(config: &CielConfig) -> Vec<PathBuf> {
+    let mut paths = sorted_overlays(config)
+        .into_iter()
+        .rev()
+        .map(|(name, _)| overlay_path(name))
+        .collect::<Vec<_>>();
+    paths.push(PathBuf::from("TREE"));
+
+    paths
+}
+
+/// Return the directory of the package (`name` or `section/name`) in the trees
+pub fn find_package(config: &CielConfig, package: &str) -> Option<PathBuf> {
+    for tree in tree_search_paths(config) {
+        if package.contains('/') {
+            let path = tree.join(package);
+            if path.join("spec").is_file() {
+                return Some(path);
+            }
+            continue;
+        }
+        let sections = match fs::read_dir(&tree) {
+            Ok(sections) => sections,
+            Err(_) => continue,
+        };
+        for section in sections.filter_map(|s| s.ok()) {
+            let path = section.path().join(package);
+            if path.join("spec").is_file() {
+                return Some(path);
+            }
+        }
+    }
+
+    None
+}
+
+/// Parse the simple `KEY=value` assignments of a shell script, the multi-line values are joined
+pub(crate) fn parse_assignments(script: &str) -> Vec<(String, String)> {
+    let mut assignments = Vec::new();
+    let mut lines = script.lines();
+    while let Some(line) = lines.next() {
+        let line = line.trim();
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) if key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') => {
+                (key, value)
+            }
+            _ => continue,
+        };
+        let mut value = value.to_owned();
+        let quote = value.chars().next().filter(|c| *c == '"' || *c == '\'');
+        if let Some(quote) = quote {
+            // continue until the closing quote
+            while value.len() < 2 || !value.ends_with(quote) {
+                match lines.next() {
+                    Some(next) => {
+                        if value.ends_with('\\') {
+                            value.pop();
+                        }
+                        value.push(' ');
+                        value.push_str(next.trim());
+                    }
+                    None => break,
+                }
+            }
+            value = value.trim_matches(quote).to_owned();
+        }
+        while value.ends_with('\\') {
+            value.pop();
+            match lines.next() {
+                Some(next) => {
+                    value.push(' ');
+                    value.push_str(next.trim());
+                }
+                None => break,
+            }
+        }
+        assignments.push((key.to_owned(), value.trim().to_owned()));
+    }
+
+    assignments
+}
+
+#[inline]
+pub(crate) fn lookup<'a>(assignments: &'a [(String, String)], key: &str) -> Option<&'a str> {
+    assignments
+        .iter()
+        .rev()
+        .find(|(k, _)| k == key)
+        .map(|(_, v)| v.as_str())
+}
+
+/// Return the name of the package in the dependency, e.g. `zlib` for `zlib>=1.2`
+pub(crate) fn dependency_name(dep: &str) -> &str {
+    dep.split(|c| matches!(c, '<' | '>' | '=' | ':'))
+        .next()
+        .unwrap_or(dep)
+}
+
+/// Return the `defines` of the package in the directory: `autobuild/defines`, or those of the
+/// sub-packages (`autobuild/<n>-<name>/defines`) for the split packages
+pub fn defines_files(dir: &Path) -> Vec<PathBuf> {
+    let defines = dir.join("autobuild/defines");
+    if defines.is_file() {
+        return vec![defines];
+    }
+    let mut files = fs::read_dir(dir.join("autobuild"))
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok())
+                .map(|e| e.path().join("defines"))
+                .filter(|path| path.is_file())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    files.sort();
+
+    files
+}
+
+/// Return the names of all the packages in the trees, including the sub-packages and the
+/// names provided (`PKGPROV`)
+pub fn package_names(config: &CielConfig) -> HashSet<String> {
+    let mut names = HashSet::new();
+    for tree in tree_search_paths(config) {
+        let packages = fs::read_dir(&tree)
+            .into_iter()
+            .flatten()
+            .filter_map(|s| s.ok())
+            .filter_map(|section| fs::read_dir(section.path()).ok())
+            .flatten()
+            .filter_map(|p| p.ok());
+        for package in packages {
+            for defines in defines_files(&package.path()) {
+                let assignments = match fs::read_to_string(&defines) {
+                    Ok(content) => parse_assignments(&content),
+                    Err(_) => continue,
+                };
+                names.extend(lookup(&assignments, "PKGNAME").map(str::to_owned));
+                names.extend(
+                    lookup(&assignments, "PKGPROV")
+                        .unwrap_or_default()
+                        .split_whitespace()
+                        .map(|name| dependency_name(name).to_owned()),
+                );
+            }
+        }
+    }
+
+    names
+}
+
+/// Read the version and the build dependencies of the package in the directory
+pub fn read_package_spec(dir: &Path) -> Result<PackageSpec> {
+    let spec = parse_assignments(&fs::read_to_string(dir.join("spec"))?);
+    let defines = fs::read_to_string(dir.join("autobuild/defines"))
+        .map(|d| parse_assignments(&d))
+        .unwrap_or_default();
+    let name = lookup(&defines, "PKGNAME")
+        .map(|n| n.to_owned())
+        .or_else(|| dir.file_name().map(|n| n.to_string_lossy().to_string()))
+        .ok_or_else(|| anyhow!("Unable to determine the name of {}", dir.display()))?;
+    let version = lookup(&spec, "VER").map(|ver| {
+        let mut version = ver.to_owned();
+        if let Some(rel) = lookup(&spec, "REL").filter(|r| *r != "0") {
+            version = format!("{}-{}", version, rel);
+        }
+        if let Some(epoch) = lookup(&defines, "PKGEPOCH").filter(|e| *e != "0") {
+            version = format!("{}:{}", epoch, version);
+        }
+        version
+    });
+    // the versions using variables are left to the build scripts
+    let version = version.filter(|v| !v.contains('$'));
+    let build_deps = ["PKGDEP", "BUILDDEP"]
+        .iter()
+        .filter_map(|key| lookup(&defines, key))
+        .flat_map(|deps| deps.split_whitespace())
+        .map(|dep| dependency_name(dep).to_owned())
+        .filter(|dep| !dep.is_empty() && !dep.contains('$'))
+        .collect();
+
+    let words = |key: &str| -> Vec<String> {
+        lookup(&spec, key)
+            .map(|v| v.split_whitespace().map(|w| w.to_owned()).collect())
+            .unwrap_or_default()
+    };
+
+    Ok(PackageSpec {
+        name,
+        version,
+        build_deps,
+        sources: words("SRCS"),
+        checksums: words("CHKSUMS"),
+    })
+}
+
+/// Index the packages in the trees by their names (`PKGNAME`), the packages in the trees of
+/// higher priorities take precedence
+pub fn index_trees(config: &CielConfig) -> HashMap<String, (PathBuf, PackageSpec)> {
+    let mut index = HashMap::new();
+    for tree in tree_search_paths(config) {
+        let sections = match fs::read_dir(&tree) {
+            Ok(sections) => sections,
+            Err(_) => continue,
+        };
+        for section in sections.filter_map(|s| s.ok()) {
+            let packages = match fs::read_dir(section.path()) {
+                Ok(packages) => packages,
+                Err(_) => continue,
+            };
+            for package in packages.filter_map(|p| p.ok()) {
+                let path = package.path();
+                if !path.join("spec").is_file() {
+                    continue;
+                }
+                if let Ok(spec) = read_package_spec(&path) {
+                    index.entry(spec.name.clone()).or_insert((path, spec));
+                }
+            }
+        }
+    }
+
+    index
+}
+
+fn visit<F: Fn(&str) -> Vec<String>>(
+    package: &str,
+    deps: &F,
+    visited: &mut HashMap<String, bool>,
+    stack: &mut Vec<String>,
+    order: &mut Vec<String>,
+) -> Result<()> {
+    match visited.get(package) {
+        Some(true) => return Ok(()),
+        Some(false) => {
+            let start = stack.iter().position(|p| p == package).unwrap_or(0);
+            let mut cycle = stack[start..].to_vec();
+            cycle.push(package.to_owned());
+            bail!("Dependency cycle detected: {}", cycle.join(" -> "));
+        }
+        None => (),
+    }
+    // false: being visited, true: done
+    visited.insert(package.to_owned(), false);
+    stack.push(package.to_owned());
+    for dep in deps(package) {
+        visit(&dep, deps, visited, stack, order)?;
+    }
+    stack.pop();
+    visited.insert(package.to_owned(), true);
+    order.push(package.to_owned());
+
+    Ok(())
+}
+
+/// Order the packages so the dependencies (as returned by `deps`) come first,
+/// the dependencies not in the list are added as well. Fails if there are dependency cycles
+fn topological_order<F: Fn(&str) -> Vec<String>>(
+    packages: &[String],
+    deps: F,
+) -> Result<Vec<String>> {
+    let mut visited = HashMap::new();
+    let mut order = Vec::new();
+    for package in packages {
+        visit(package, &deps, &mut visited, &mut Vec::new(), &mut order)?;
+    }
+
+    Ok(order)
+}
+
+/// Plan the build of the packages: the packages are ordered so the dependencies are built first,
+/// and the dependencies in the trees for which `needs_build` returns true are included
+pub fn plan_build<F: Fn(&PackageSpec) -> bool>(
+    config: &CielConfig,
+    packages: &[String],
+    needs_build: F,
+) -> Result<Vec<String>> {
+    let index = index_trees(config);
+    // package names to the names requested (e.g. `section/name`)
+    let mut requested = HashMap::new();
+    let mut names = Vec::new();
+    for package in packages {
+        let name = find_package(config, package)
+            .and_then(|dir| read_package_spec(&dir).ok())
+            .map(|spec| spec.name)
+            .unwrap_or_else(|| {
+                warn!(
+                    "{} is not found in the trees, its dependencies are unknown.",
+                    package
+                );
+                package.clone()
+            });
+        requested.insert(name.clone(), package.clone());
+        names.push(name);
+    }
+    let deps = |name: &str| -> Vec<String> {
+        let spec = match index.get(name) {
+            Some((_, spec)) => spec,
+            None => return Vec::new(),
+        };
+        spec.build_deps
+            .iter()
+            .filter(|dep| *dep != name)
+            .filter(|dep| {
+                requested.contains_key(*dep)
+                    || index.get(*dep).map_or(false, |(_, spec)| needs_build(spec))
+            })
+            .cloned()
+            .collect()
+    };
+    let order = topological_order(&names, deps)?;
+
+    Ok(order
+        .into_iter()
+        .map(|name| match requested.get(&name) {
+            Some(package) => package.clone(),
+            None => {
+                let dir = &index[&name].0;
+                let package = dir.file_name().unwrap().to_string_lossy().to_string();
+                info!("Including dependency {}.", package);
+                package
+            }
+        })
+        .collect())
+}
+
+/// Return the package directories (`section/name`) the changed paths of the tree belong to
+fn package_dirs<'a, I: IntoIterator<Item = &'a Path>>(paths: I) -> BTreeSet<PathBuf> {
+    paths
+        .into_iter()
+        .filter_map(|path| {
+            let mut components = path.components();
+            let section = components.next()?.as_os_str();
+            let name = components.next()?.as_os_str();
+            // the files at the top of the sections (e.g. `groups/<name>`) are not packages
+            components.next()?;
+            if section.to_string_lossy().starts_with('.') {
+                return None;
+            }
+            Some(Path::new(section).join(name))
+        })
+        .collect()
+}
+
+/// Return the packages of the ABBS tree changed (including the uncommitted changes) since the
+/// revision, or since the last successful build if not specified. The removed packages are
+/// ignored
+pub fn changed_packages(since: Option<&str>) -> Result<Vec<String>> {
+    let repo = git2::Repository::open("TREE")?;
+    let since = match since {
+        Some(since) => since.to_owned(),
+        None => fs::read_to_string(BUILT_REVISION_FILE)
+            .map(|revision| revision.trim().to_owned())
+            .map_err(|_| {
+                anyhow!("No successful build recorded yet, please specify the revision to compare with.")
+            })?,
+    };
+    let tree = repo
+        .revparse_single(&since)
+        .and_then(|object| object.peel_to_tree())
+        .map_err(|e| anyhow!("Unable to find revision `{}` in the tree: {}", since, e))?;
+    let mut options = git2::DiffOptions::new();
+    options.include_untracked(true).recurse_untracked_dirs(true);
+    let diff = repo.diff_tree_to_workdir_with_index(Some(&tree), Some(&mut options))?;
+    let paths = diff
+        .deltas()
+        .flat_map(|delta| {
+            [
+                delta.old_file().path().map(Path::to_path_buf),
+                delta.new_file().path().map(Path::to_path_buf),
+            ]
+        })
+        .flatten()
+        .collect::<Vec<_>>();
+
+    Ok(package_dirs(paths.iter().map(PathBuf::as_path))
+        .into_iter()
+        .filter(|dir| Path::new("TREE").join(dir).join("spec").is_file())
+        .filter_map(|dir| Some(dir.file_name()?.to_string_lossy().to_string()))
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect())
+}
+
+/// Record the current commit of the ABBS tree as the revision of the last successful build
+pub fn record_built_revision() -> Result<()> {
+    let repo = git2::Repository::open("TREE")?;
+    let commit = repo.head()?.peel_to_commit()?;
+    fs::write(BUILT_REVISION_FILE, format!("{}\n", commit.id()))?;
+
+    Ok(())
+}
+
+#[test]
+fn test_topological_order() {
+    let graph: HashMap<&str, Vec<&str>> = vec![
+        ("app", vec!["lib", "tool"]),
+        ("lib", vec!["base"]),
+        ("tool", vec!["base"]),
+        ("base", vec![]),
+    ]
+    .into_iter()
+    .collect();
+    let deps = |p: &str| -> Vec<String> { graph[p].iter().map(|d| d.to_string()).collect() };
+    let order = topological_order(&["app".to_owned(), "lib".to_owned()], deps).unwrap();
+    assert_eq!(order, ["base", "lib", "tool", "app"]);
+    let cyclic = |p: &str| -> Vec<String> { vec![if p == "a" { "b" } else { "a" }.to_owned()] };
+    let err = topological_order(&["a".to_owned()], cyclic).unwrap_err();
+    assert_eq!(err.to_string(), "Dependency cycle detected: a -> b -> a");
+}
+
+#[test]
+fn test_parse_assignments() {
+    let assignments = parse_assignments(
+        "VER=1.2.3\nREL=2\n# comment\nPKGDEP=\"glibc \\\n    zlib>=1.2\"\nBUILDDEP=\"cmake\n    ninja\"\nPKGDES='A package'\n",
+    );
+    assert_eq!(lookup(&assignments, "VER"), Some("1.2.3"));
+    assert_eq!(lookup(&assignments, "REL"), Some("2"));
+    assert_eq!(lookup(&assignments, "BUILDDEP"), Some("cmake ninja"));
+    assert_eq!(lookup(&assignments, "PKGDES"), Some("A package"));
+    assert!(lookup(&assignments, "PKGDEP")
+        .unwrap()
+        .contains("zlib>=1.2"));
+}
+
+#[test]
+fn test_package_dirs() {
+    let paths = [
+        "app-utils/foo/spec",
+        "app-utils/foo/autobuild/defines",
+        "core-libs/bar/autobuild/patches/0001-fix.patch",
+        "groups/base",
+        "README.md",
+        ".github/workflows/ci.yml",
+    ]
+    .iter()
+    .map(Path::new)
+    .collect::<Vec<_>>();
+    assert_eq!(
+        package_dirs(paths).into_iter().collect::<Vec<_>>(),
+        vec![
+            PathBuf::from("app-utils/foo"),
+            PathBuf::from("core-libs/bar")
+        ]
+    );
+}
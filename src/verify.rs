@@ -0,0 +1,83 @@
+//! This module contains the signature verification APIs of the OS tarballs
+
+use anyhow::{anyhow, bail, Result};
+use console::style;
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::{info, network::download_file};
+
+/// Locations of the pinned keyrings, the first one containing any keyring is used
+const KEYRING_DIRS: &[&str] = &[
+    ".ciel/data/keyrings",
+    "/usr/local/share/ciel/keyrings",
+    "/usr/share/ciel/keyrings",
+];
+const SIGNATURE_SUFFIX: &str = ".sig";
+
+/// Return the pinned keyrings (`*.gpg` files)
+pub fn pinned_keyrings() -> Result<Vec<PathBuf>> {
+    for dir in KEYRING_DIRS {
+        let dir = Path::new(dir);
+        if !dir.is_dir() {
+            continue;
+        }
+        let mut keyrings = fs::read_dir(dir)?
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| p.is_file() && p.extension().map(|e| e == "gpg").unwrap_or(false))
+            .collect::<Vec<_>>();
+        if !keyrings.is_empty() {
+            keyrings.sort();
+            return Ok(keyrings);
+        }
+    }
+
+    Err(anyhow!(
+        "No pinned keyring found (looked in {}), pass --no-verify to skip the signature verification",
+        KEYRING_DIRS.join(", ")
+    ))
+}
+
+/// Download the detached signature of the tarball at the URL
+fn fetch_signature(url: &str) -> Result<tempfile::NamedTempFile> {
+    let sig_url = format!("{}{}", url, SIGNATURE_SUFFIX);
+    let mut sig = tempfile::NamedTempFile::new()?;
+    let mut resp = download_file(&sig_url)
+        .and_then(|r| Ok(r.error_for_status()?))
+        .map_err(|e| anyhow!("Unable to download the signature {}: {}", sig_url, e))?;
+    resp.copy_to(&mut sig)?;
+    sig.flush()?;
+
+    Ok(sig)
+}
+
+/// Verify the tarball downloaded from the URL against its detached signature (`<url>.sig`)
+/// using the pinned keyrings only, the keys in the user's GnuPG keyring are never trusted
+pub fn verify_tarball(tarball: &Path, url: &str) -> Result<()> {
+    let keyrings = pinned_keyrings()?;
+    info!("Verifying tarball signature...");
+    let sig = fetch_signature(url)?;
+    let mut cmd = Command::new("gpgv");
+    for keyring in keyrings.iter() {
+        cmd.arg("--keyring").arg(keyring);
+    }
+    let output = cmd
+        .arg(sig.path())
+        .arg(tarball)
+        .output()
+        .map_err(|e| anyhow!("Unable to run gpgv (is GnuPG installed?): {}", e))?;
+    if !output.status.success() {
+        bail!(
+            "Signature verification failed:\n{}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    info!("Signature verified.");
+
+    Ok(())
+}
@@ -0,0 +1,624 @@
+//! This module contains the ZFS dataset based layer manager
+
+use crate::common::CIEL_DIST_DIR;
+use crate::overlayfs::{
+    disk_usage, is_mounted, layer_path, remove_dir_contents, LayerManager, LayerUsage,
+};
+use crate::{reflink, warn};
+use adler32::adler32;
+use anyhow::{anyhow, bail, Result};
+use console::style;
+use libmount::mountinfo::Parser;
+use rand::random;
+use std::ffi::OsStr;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use walkdir::WalkDir;
+
+/// f_type of ZFS as reported by statfs(2)
+const ZFS_SUPER_MAGIC: i64 = 0x2fc12fc1;
+const SNAPSHOT_PREFIX: &str = "ciel-";
+/// Snapshot of the instance dataset right after it is cloned (with the configuration layer),
+/// which the instance is rolled back to
+const CLEAN_SNAPSHOT: &str = "clean";
+
+pub struct ZfsLayerManager {
+    inst: PathBuf,
+    name: String,
+    base: PathBuf,
+    local: PathBuf,
+    dist_dataset: String,
+    dataset: String,
+    volatile: bool,
+}
+
+/// Invoke the zfs command and return its output
+fn zfs<S: AsRef<OsStr>>(args: &[S]) -> Result<String> {
+    let output = Command::new("zfs")
+        .args(args)
+        .output()
+        .map_err(|e| anyhow!("Unable to execute zfs: {}", e))?;
+    if !output.status.success() {
+        bail!(
+            "zfs command failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+#[inline]
+fn dataset_exists(dataset: &str) -> bool {
+    zfs(&["list", "-H", "-o", "name", dataset]).is_ok()
+}
+
+/// Return the space used by the dataset (excluding the space shared with its origin)
+fn dataset_used(dataset: &str) -> Result<u64> {
+    let used = zfs(&["get", "-Hp", "-o", "value", "used", dataset])?;
+
+    used.parse::<u64>()
+        .map_err(|e| anyhow!("Unable to parse dataset usage `{}`: {}", used, e))
+}
+
+/// Return the name of the dataset containing the path
+fn dataset_of(path: &Path) -> Result<String> {
+    zfs(&[
+        OsStr::new("list"),
+        OsStr::new("-H"),
+        OsStr::new("-o"),
+        OsStr::new("name"),
+        path.as_os_str(),
+    ])
+}
+
+/// Check if the given path resides on a ZFS filesystem
+pub fn is_on_zfs<P: AsRef<Path>>(path: P) -> bool {
+    match nix::sys::statfs::statfs(path.as_ref()) {
+        Ok(stat) => stat.filesystem_type().0 as i64 == ZFS_SUPER_MAGIC,
+        Err(_) => false,
+    }
+}
+
+/// Check if the dist layer of the current workspace is a ZFS dataset
+pub fn is_zfs_workspace() -> bool {
+    match std::env::current_dir() {
+        Ok(cwd) => is_dataset_mounted_at(&cwd.join(CIEL_DIST_DIR)),
+        Err(_) => false,
+    }
+}
+
+/// Check if a dataset is mounted at the path
+fn is_dataset_mounted_at(dist: &Path) -> bool {
+    let mountinfo_content = match fs::read("/proc/self/mountinfo") {
+        Ok(content) => content,
+        Err(_) => return false,
+    };
+    // a dataset is mounted with its root, unlike the bind mount of a directory on ZFS
+    Parser::new(&mountinfo_content).flatten().any(|mount| {
+        mount.mount_point == dist
+            && mount.fstype == OsStr::new("zfs")
+            && mount.root == OsStr::new("/")
+    })
+}
+
+/// Create the dataset for the dist layer if the workspace is located on ZFS
+pub fn create_workspace_datasets() -> Result<()> {
+    let cwd = std::env::current_dir()?;
+    let dist = cwd.join(CIEL_DIST_DIR);
+    if !is_on_zfs(&cwd) || is_zfs_workspace() {
+        return Ok(());
+    }
+    if fs::read_dir(&dist)?.next().is_some() {
+        // never hide an existing dist layer behind an empty dataset
+        return Ok(());
+    }
+    let parent = dataset_of(&cwd)?;
+    let hash = adler32(cwd.as_os_str().as_bytes())?;
+    zfs(&[
+        "create",
+        "-p",
+        "-o",
+        &format!("mountpoint={}", dist.display()),
+        &format!("{}/ciel-{:x}/dist", parent, hash),
+    ])?;
+
+    Ok(())
+}
+
+/// Update the mount point of the dist dataset to the current location of the workspace
+pub fn update_dist_mountpoint() -> Result<()> {
+    let dist = std::env::current_dir()?.join(CIEL_DIST_DIR);
+    let dataset = dataset_of(&dist)?;
+    zfs(&["set", &format!("mountpoint={}", dist.display()), &dataset])?;
+
+    Ok(())
+}
+
+/// Move the dist dataset (mounted at `dist`) out of the way as `<dataset>-trash-<id>`, leaving it
+/// un-mounted, returns the new name of the dataset
+pub fn trash_dist_dataset(dist: &Path, id: &str) -> Result<String> {
+    let dataset = dataset_of(dist)?;
+    let trashed = format!("{}-trash-{}", dataset, id);
+    zfs(&["umount", &dataset])?;
+    zfs(&["rename", &dataset, &trashed])?;
+    zfs(&["set", "mountpoint=none", &trashed])?;
+
+    Ok(trashed)
+}
+
+/// Take the dist dataset back from the trash, mounting it at `dist`
+pub fn restore_dist_dataset(trashed: &str, dist: &Path) -> Result<()> {
+    let (dataset, _) = trashed
+        .rsplit_once("-trash-")
+        .ok_or_else(|| anyhow!("`{}` is not a dataset in the trash", trashed))?;
+    zfs(&["rename", trashed, dataset])?;
+    zfs(&["set", &format!("mountpoint={}", dist.display()), dataset])?;
+    if !is_dataset_mounted_at(dist) {
+        zfs(&["mount", dataset])?;
+    }
+
+    Ok(())
+}
+
+/// Destroy the dataset with its snapshots and the instance datasets cloned from it
+pub fn destroy_dataset(dataset: &str) -> Result<()> {
+    zfs(&["destroy", "-R", dataset])?;
+
+    Ok(())
+}
+
+/// Set the dist dataset to read-only (or writable)
+pub fn set_dist_readonly(readonly: bool) -> Result<()> {
+    let dist = std::env::current_dir()?.join(CIEL_DIST_DIR);
+    let dataset = dataset_of(&dist)?;
+    zfs(&[
+        "set",
+        if readonly {
+            "readonly=on"
+        } else {
+            "readonly=off"
+        },
+        &dataset,
+    ])?;
+
+    Ok(())
+}
+
+/// List the snapshots of the dist dataset which are no longer used by any instance
+/// (except for the latest one), returns the snapshot names and their sizes
+pub fn list_unused_snapshots() -> Result<Vec<(String, u64)>> {
+    let dist = std::env::current_dir()?.join(CIEL_DIST_DIR);
+    let dataset = dataset_of(&dist)?;
+    let snapshots = zfs(&[
+        "list",
+        "-Hp",
+        "-t",
+        "snapshot",
+        "-o",
+        "name,used,clones",
+        "-s",
+        "creation",
+        "-d",
+        "1",
+        &dataset,
+    ])?;
+    let mut snapshots = snapshots
+        .lines()
+        .filter(|s| s.contains(&format!("@{}", SNAPSHOT_PREFIX)))
+        .collect::<Vec<_>>();
+    // the latest snapshot will be used for the next instance
+    snapshots.pop();
+    let mut unused = Vec::new();
+    for line in snapshots {
+        let mut fields = line.split('\t');
+        let name = fields.next().unwrap_or_default();
+        let used = fields
+            .next()
+            .and_then(|u| u.parse::<u64>().ok())
+            .unwrap_or(0);
+        let clones = fields.next().unwrap_or_default();
+        if clones.is_empty() || clones == "-" {
+            unused.push((name.to_owned(), used));
+        }
+    }
+
+    Ok(unused)
+}
+
+/// Destroy the snapshot of the dist dataset
+pub fn destroy_snapshot(snapshot: &str) -> Result<()> {
+    if !snapshot.contains(&format!("@{}", SNAPSHOT_PREFIX)) {
+        bail!("Not a snapshot created by Ciel: {}", snapshot);
+    }
+    zfs(&["destroy", snapshot])?;
+
+    Ok(())
+}
+
+/// Copy the contents of a directory into another one, overwriting existing files
+fn copy_dir_contents(from: &Path, to: &Path) -> Result<()> {
+    for entry in WalkDir::new(from).into_iter().skip(1) {
+        let entry = entry?;
+        let rel_path = entry.path().strip_prefix(from)?;
+        let target = to.join(rel_path);
+        let file_type = entry.file_type();
+        if file_type.is_dir() {
+            fs::create_dir_all(&target)?;
+        } else if file_type.is_symlink() {
+            fs::remove_file(&target).ok();
+            std::os::unix::fs::symlink(fs::read_link(entry.path())?, &target)?;
+        } else {
+            reflink::copy_file(entry.path(), &target)?;
+        }
+    }
+
+    Ok(())
+}
+
+impl ZfsLayerManager {
+    #[inline]
+    fn target(&self) -> Result<PathBuf> {
+        Ok(std::env::current_dir()?.join(&self.name))
+    }
+
+    /// Return the latest snapshot of the dist layer, creating one if the dist layer has changed
+    fn ensure_base_snapshot(&self) -> Result<String> {
+        let snapshots = zfs(&[
+            "list",
+            "-H",
+            "-t",
+            "snapshot",
+            "-o",
+            "name",
+            "-s",
+            "creation",
+            "-d",
+            "1",
+            &self.dist_dataset,
+        ])?;
+        let written = zfs(&["get", "-Hp", "-o", "value", "written", &self.dist_dataset])?;
+        let latest = snapshots
+            .lines()
+            .filter(|s| s.contains(&format!("@{}", SNAPSHOT_PREFIX)))
+            .last();
+        if let Some(latest) = latest {
+            if written == "0" {
+                return Ok(latest.to_string());
+            }
+        }
+        let snapshot = format!(
+            "{}@{}{:x}",
+            self.dist_dataset,
+            SNAPSHOT_PREFIX,
+            random::<u32>()
+        );
+        zfs(&["snapshot", &snapshot])?;
+
+        Ok(snapshot)
+    }
+
+    /// Clone the instance dataset from the dist layer if it does not exist yet
+    fn ensure_clone(&self) -> Result<()> {
+        if dataset_exists(&self.dataset) {
+            return Ok(());
+        }
+        let snapshot = self.ensure_base_snapshot()?;
+        let target = self.target()?;
+        zfs(&[
+            "clone",
+            "-p",
+            "-o",
+            "canmount=noauto",
+            "-o",
+            &format!("mountpoint={}", target.display()),
+            &snapshot,
+            &self.dataset,
+        ])?;
+        // populate the configuration layer
+        if self.local.is_dir() {
+            fs::create_dir_all(&target)?;
+            zfs(&["mount", &self.dataset])?;
+            let result = copy_dir_contents(&self.local, &target);
+            zfs(&["unmount", &self.dataset])?;
+            result?;
+        }
+        zfs(&["snapshot", &self.clean_snapshot()])?;
+
+        Ok(())
+    }
+
+    #[inline]
+    fn clean_snapshot(&self) -> String {
+        format!("{}@{}", self.dataset, CLEAN_SNAPSHOT)
+    }
+
+    /// Return the snapshot of the current dist dataset by the name of the snapshot
+    /// (the dataset the snapshot was taken from may have been renamed since)
+    fn base_snapshot(&self, snapshot: &str) -> Result<String> {
+        let (_, name) = snapshot
+            .rsplit_once('@')
+            .ok_or_else(|| anyhow!("`{}` is not a snapshot", snapshot))?;
+
+        Ok(format!("{}@{}", self.dist_dataset, name))
+    }
+
+    /// Replace the dist dataset with the dataset (which has been promoted)
+    fn replace_dist_dataset(&self, dataset: &str) -> Result<()> {
+        let retired = format!("{}-{:x}", self.dist_dataset, random::<u32>());
+        zfs(&["set", "mountpoint=none", &self.dist_dataset])?;
+        zfs(&["rename", &self.dist_dataset, &retired])?;
+        zfs(&["unmount", dataset]).ok();
+        zfs(&["rename", dataset, &self.dist_dataset])?;
+        zfs(&["set", "canmount=on", &self.dist_dataset])?;
+        zfs(&[
+            "set",
+            &format!("mountpoint={}", self.base.display()),
+            &self.dist_dataset,
+        ])?;
+        zfs(&["mount", &self.dist_dataset]).ok();
+        if let Err(e) = zfs(&["destroy", "-r", &retired]) {
+            // other instances may still be cloned from the retired dataset
+            warn!(
+                "Unable to destroy the previous dist layer {}: {}",
+                retired, e
+            );
+        }
+
+        Ok(())
+    }
+}
+
+impl LayerManager for ZfsLayerManager {
+    fn name() -> String
+    where
+        Self: Sized,
+    {
+        "zfs".to_owned()
+    }
+    // The ZFS structure looks like this:
+    // |- dist: <workspace dataset>/ciel-<hash>/dist, mounted at .ciel/container/dist/
+    // ||- instance: <workspace dataset>/ciel-<hash>/instances/<inst_name>, a clone of dist
+    // The configuration layer is kept in .ciel/container/instances/<inst_name>/layers/local/
+    // and is copied over every time the instance dataset is re-cloned
+    fn from_inst_dir<P: AsRef<Path>>(
+        dist_path: P,
+        inst_path: P,
+        inst_name: P,
+    ) -> Result<Box<dyn LayerManager>>
+    where
+        Self: Sized,
+    {
+        let dist = std::env::current_dir()?.join(dist_path.as_ref());
+        let inst = inst_path.as_ref().join(inst_name.as_ref());
+        let name = inst_name
+            .as_ref()
+            .to_str()
+            .ok_or_else(|| anyhow!("Instance name is not valid unicode."))?;
+        let dist_dataset = dataset_of(&dist)?;
+        let root = dist_dataset
+            .rsplit_once('/')
+            .ok_or_else(|| anyhow!("Dist layer is not a child dataset: {}", dist_dataset))?
+            .0;
+        Ok(Box::new(ZfsLayerManager {
+            local: inst.join("layers/local"),
+            inst,
+            name: name.to_owned(),
+            base: dist,
+            dataset: format!("{}/instances/{}", root, name),
+            dist_dataset,
+            volatile: false,
+        }))
+    }
+
+    fn mount(&mut self, to: &Path) -> Result<()> {
+        self.ensure_clone()?;
+        zfs(&[
+            "set",
+            &format!("mountpoint={}", to.display()),
+            &self.dataset,
+        ])?;
+        zfs(&[
+            "set",
+            if self.volatile {
+                "sync=disabled"
+            } else {
+                "sync=standard"
+            },
+            &self.dataset,
+        ])?;
+        zfs(&["mount", &self.dataset])?;
+
+        Ok(())
+    }
+
+    fn is_mounted(&self, target: &Path) -> Result<bool> {
+        is_mounted(target, OsStr::new("zfs"))
+    }
+
+    fn rollback_targets(&self) -> Vec<String> {
+        vec![self.dataset.clone()]
+    }
+
+    fn rollback(&mut self) -> Result<()> {
+        if dataset_exists(&self.clean_snapshot()) {
+            zfs(&["rollback", "-r", &self.clean_snapshot()])?;
+            return Ok(());
+        }
+        // e.g. the configuration layer has changed since
+        if dataset_exists(&self.dataset) {
+            zfs(&["destroy", "-r", &self.dataset])?;
+        }
+        self.ensure_clone()
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        self.ensure_clone()?;
+        // the snapshot would become one of the dist dataset
+        if dataset_exists(&self.clean_snapshot()) {
+            zfs(&["destroy", &self.clean_snapshot()])?;
+        }
+        // make the instance dataset independent from the dist dataset
+        zfs(&["promote", &self.dataset])?;
+        self.replace_dist_dataset(&self.dataset)?;
+        // re-create the instance from the new dist layer
+        self.ensure_clone()
+    }
+
+    fn unmount(&mut self, _target: &Path) -> Result<()> {
+        zfs(&["unmount", &self.dataset])?;
+
+        Ok(())
+    }
+
+    fn get_config_layer(&mut self) -> Result<PathBuf> {
+        fs::create_dir_all(&self.local)?;
+        // the configuration layer is only copied into the clone, so the next rollback clones
+        // the instance again to pick up the changes
+        if dataset_exists(&self.clean_snapshot()) {
+            zfs(&["destroy", &self.clean_snapshot()])?;
+        }
+
+        Ok(self.local.clone())
+    }
+
+    fn get_base_layer(&mut self) -> Result<PathBuf> {
+        Ok(self.base.clone())
+    }
+
+    fn get_upper_layer(&mut self) -> Result<Option<PathBuf>> {
+        // the instance dataset contains the whole filesystem
+        Ok(None)
+    }
+
+    fn set_volatile(&mut self, volatile: bool) -> Result<()> {
+        self.volatile = volatile;
+
+        Ok(())
+    }
+
+    fn destroy(&mut self) -> Result<()> {
+        if dataset_exists(&self.dataset) {
+            zfs(&["destroy", "-r", &self.dataset])?;
+        }
+        fs::remove_dir_all(&self.inst)?;
+
+        Ok(())
+    }
+
+    fn usage(&mut self, include_base: bool) -> Result<LayerUsage> {
+        let base = if include_base {
+            Some(dataset_used(&self.dist_dataset)?)
+        } else {
+            None
+        };
+        let upper = if dataset_exists(&self.dataset) {
+            dataset_used(&self.dataset)?
+        } else {
+            0
+        };
+
+        Ok(LayerUsage {
+            base,
+            upper: upper + disk_usage(&self.local)?,
+        })
+    }
+
+    fn prune(&mut self, paths: &[String]) -> Result<u64> {
+        self.ensure_clone()?;
+        let target = self.target()?;
+        let mounted = self.is_mounted(&target)?;
+        if !mounted {
+            fs::create_dir_all(&target)?;
+            zfs(&["mount", &self.dataset])?;
+        }
+        let mut freed = Ok(0);
+        for path in paths {
+            freed = freed.and_then(|freed| match layer_path(&target, path)? {
+                Some(path) => Ok(freed + remove_dir_contents(&path)?),
+                None => Ok(freed),
+            });
+        }
+        if !mounted {
+            zfs(&["unmount", &self.dataset])?;
+        }
+
+        freed
+    }
+
+    fn changes(&mut self) -> Result<Vec<PathBuf>> {
+        if !dataset_exists(&self.dataset) {
+            return Ok(Vec::new());
+        }
+        let origin = zfs(&["get", "-H", "-o", "value", "origin", &self.dataset])?;
+        let target = self.target()?;
+        let mounted = self.is_mounted(&target)?;
+        if !mounted {
+            fs::create_dir_all(&target)?;
+            zfs(&["mount", &self.dataset])?;
+        }
+        let diff = zfs(&["diff", "-H", &origin, &self.dataset]);
+        if !mounted {
+            zfs(&["unmount", &self.dataset])?;
+        }
+        let mut changes = Vec::new();
+        for line in diff?.lines() {
+            // `<change>\t<path>`, or `R\t<from>\t<to>` for the renamed files
+            let path = match line
+                .rsplit('\t')
+                .next()
+                .and_then(|path| Path::new(path).strip_prefix(&target).ok())
+            {
+                Some(path) => path,
+                None => continue,
+            };
+            // the configuration layer is copied into the clone
+            if !self.local.join(path).exists() {
+                changes.push(Path::new("/").join(path));
+            }
+        }
+
+        Ok(changes)
+    }
+
+    // taken before the instance dataset is cloned, the instance is then cloned from this
+    // snapshot, which moves along with the instance dataset when it is committed
+    fn snapshot_base(&mut self) -> Result<String> {
+        let snapshot = format!(
+            "{}@{}update-{:x}",
+            self.dist_dataset,
+            SNAPSHOT_PREFIX,
+            random::<u32>()
+        );
+        zfs(&["snapshot", &snapshot])?;
+
+        Ok(snapshot)
+    }
+
+    fn restore_base(&mut self, snapshot: &str) -> Result<()> {
+        let snapshot = self.base_snapshot(snapshot)?;
+        let restored = format!("{}-restore-{:x}", self.dist_dataset, random::<u32>());
+        zfs(&[
+            "clone",
+            "-o",
+            "canmount=noauto",
+            "-o",
+            "mountpoint=none",
+            &snapshot,
+            &restored,
+        ])?;
+        zfs(&["promote", &restored])?;
+        self.replace_dist_dataset(&restored)?;
+        if let Err(e) = destroy_snapshot(&snapshot) {
+            warn!("Unable to destroy the snapshot {}: {}", snapshot, e);
+        }
+
+        Ok(())
+    }
+
+    fn remove_base_snapshot(&mut self, snapshot: &str) -> Result<()> {
+        destroy_snapshot(&self.base_snapshot(snapshot)?)
+    }
+}